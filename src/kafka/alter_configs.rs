@@ -0,0 +1,308 @@
+// IncrementalAlterConfigs (key 44): config overrides of topics set or
+// removed by the active controller, each resource checked and reported on
+// its own. Only topics have dynamic configs here, and only SET and DELETE
+// are taken: APPEND and SUBTRACT would edit list values in place.
+//
+// https://kafka.apache.org/protocol.html#The_Messages_IncrementalAlterConfigs
+use crate::kafka::metadata_records::CONFIG_RESOURCE_TOPIC;
+use crate::kafka::{controller, errors, parser, raft, writer};
+use std::io::{Read, Write};
+use std::sync::Arc;
+
+pub const CONFIG_OPERATION_SET: i8 = 0;
+pub const CONFIG_OPERATION_DELETE: i8 = 1;
+
+#[derive(Debug, Clone, Default)]
+pub struct AlterableConfig {
+    pub name: String,
+    pub operation: i8,
+    pub value: Option<String>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct AlterConfigsResource {
+    pub resource_type: i8,
+    pub resource_name: String,
+    pub configs: Vec<AlterableConfig>,
+}
+
+#[derive(Debug, Clone)]
+pub struct IncrementalAlterConfigsRequest {
+    pub resources: Vec<AlterConfigsResource>,
+    pub validate_only: bool,
+}
+
+impl IncrementalAlterConfigsRequest {
+    pub fn new<R: Read>(req: &mut R, flexible: bool) -> errors::Result<Self> {
+        let mut resources = vec![];
+        for _ in 0..parser::read_array_len(req, flexible)?.unwrap_or_default() {
+            let resource_type = parser::read_byte(req)?;
+            let resource_name = String::from_utf8(parser::read_string(req, flexible)?)?;
+            let mut configs = vec![];
+            for _ in 0..parser::read_array_len(req, flexible)?.unwrap_or_default() {
+                let name = String::from_utf8(parser::read_string(req, flexible)?)?;
+                let operation = parser::read_byte(req)?;
+                let value = parser::read_nullable_string(req, flexible)?
+                    .map(String::from_utf8)
+                    .transpose()?;
+                if flexible {
+                    parser::skip_tagged_fields(req)?;
+                }
+                configs.push(AlterableConfig {
+                    name,
+                    operation,
+                    value,
+                });
+            }
+            if flexible {
+                parser::skip_tagged_fields(req)?;
+            }
+            resources.push(AlterConfigsResource {
+                resource_type,
+                resource_name,
+                configs,
+            });
+        }
+        let validate_only = parser::read_byte(req)? != 0;
+        if flexible {
+            parser::skip_tagged_fields(req)?;
+        }
+        Ok(Self {
+            resources,
+            validate_only,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct AlterConfigsResourceResponse {
+    pub error_code: i16,
+    pub error_message: Option<String>,
+    pub resource_type: i8,
+    pub resource_name: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct IncrementalAlterConfigsResponse {
+    pub throttle_time_ms: i32,
+    pub responses: Vec<AlterConfigsResourceResponse>,
+}
+
+impl IncrementalAlterConfigsResponse {
+    pub fn new(
+        request: &IncrementalAlterConfigsRequest,
+        controller: &Arc<controller::Controller>,
+    ) -> Self {
+        let responses = request
+            .resources
+            .iter()
+            .map(|resource| {
+                let altered = alter(resource, request.validate_only, controller);
+                let (error_code, error_message) = match altered {
+                    Ok(()) => (0, None),
+                    Err(e) => {
+                        println!("IncrementalAlterConfigs - {}: {e}", resource.resource_name);
+                        (controller::error_code(&e), Some(e.to_string()))
+                    }
+                };
+                AlterConfigsResourceResponse {
+                    error_code,
+                    error_message,
+                    resource_type: resource.resource_type,
+                    resource_name: resource.resource_name.clone(),
+                }
+            })
+            .collect();
+        Self {
+            throttle_time_ms: 0,
+            responses,
+        }
+    }
+
+    pub fn serialize<W: Write>(&self, resp: &mut W, flexible: bool) -> errors::Result<()> {
+        writer::write_bytes(resp, &self.throttle_time_ms)?;
+        writer::write_array_len(resp, self.responses.len(), flexible)?;
+        for response in &self.responses {
+            writer::write_bytes(resp, &response.error_code)?;
+            writer::write_nullable_string(
+                resp,
+                response.error_message.as_ref().map(String::as_bytes),
+                flexible,
+            )?;
+            writer::write_bytes(resp, &response.resource_type)?;
+            writer::write_string(resp, response.resource_name.as_bytes(), flexible)?;
+            writer::write_tagged_fields(resp, flexible)?;
+        }
+        writer::write_tagged_fields(resp, flexible)
+    }
+}
+
+fn alter(
+    resource: &AlterConfigsResource,
+    validate_only: bool,
+    controller: &Arc<controller::Controller>,
+) -> errors::Result<()> {
+    if resource.resource_type != CONFIG_RESOURCE_TOPIC {
+        return Err(errors::KafkaErrors::RequestFailed(
+            raft::INVALID_REQUEST,
+            format!(
+                "configs of resource type {} can't be altered",
+                resource.resource_type
+            ),
+        )
+        .into());
+    }
+    let configs = resource
+        .configs
+        .iter()
+        .map(|config| match config.operation {
+            CONFIG_OPERATION_SET if config.value.is_some() => {
+                Ok((config.name.clone(), config.value.clone()))
+            }
+            CONFIG_OPERATION_DELETE => Ok((config.name.clone(), None)),
+            CONFIG_OPERATION_SET => Err(errors::KafkaErrors::RequestFailed(
+                raft::INVALID_REQUEST,
+                format!("no value to set {} to", config.name),
+            )),
+            op => Err(errors::KafkaErrors::RequestFailed(
+                raft::INVALID_REQUEST,
+                format!("config operation {op} on {} isn't supported", config.name),
+            )),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    controller.alter_topic_configs(&resource.resource_name, &configs, validate_only)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kafka::config;
+    use crate::kafka::controller::NewTopic;
+    use crate::kafka::metadata::MetadataCache;
+    use crate::kafka::testing::temp_root;
+    use std::io::Cursor;
+
+    // a request the way a client lays it out, one resource per entry with
+    // its (name, operation, value) changes
+    type Changes<'a> = &'a [(&'a str, i8, Option<&'a str>)];
+
+    fn encode(flexible: bool, resources: &[(i8, &str, Changes)], validate_only: bool) -> Vec<u8> {
+        let out = &mut vec![];
+        writer::write_array_len(out, resources.len(), flexible).unwrap();
+        for (resource_type, resource_name, configs) in resources {
+            writer::write_bytes(out, resource_type).unwrap();
+            writer::write_string(out, resource_name.as_bytes(), flexible).unwrap();
+            writer::write_array_len(out, configs.len(), flexible).unwrap();
+            for (name, operation, value) in configs.iter() {
+                writer::write_string(out, name.as_bytes(), flexible).unwrap();
+                writer::write_bytes(out, operation).unwrap();
+                writer::write_nullable_string(out, value.map(str::as_bytes), flexible).unwrap();
+                writer::write_tagged_fields(out, flexible).unwrap();
+            }
+            writer::write_tagged_fields(out, flexible).unwrap();
+        }
+        writer::write_bool(out, validate_only).unwrap();
+        writer::write_tagged_fields(out, flexible).unwrap();
+        out.clone()
+    }
+
+    // (error code, resource type, resource name) of every resource
+    fn decode(data: &[u8], flexible: bool) -> Vec<(i16, i8, Vec<u8>)> {
+        let r = &mut Cursor::new(data);
+        assert_eq!(parser::read_int(r).unwrap(), 0); // throttle time
+        let mut responses = vec![];
+        for _ in 0..parser::read_array_len(r, flexible).unwrap().unwrap() {
+            let error_code = parser::read_short(r).unwrap();
+            parser::read_nullable_string(r, flexible).unwrap();
+            let resource_type = parser::read_byte(r).unwrap();
+            let resource_name = parser::read_string(r, flexible).unwrap();
+            if flexible {
+                parser::skip_tagged_fields(r).unwrap();
+            }
+            responses.push((error_code, resource_type, resource_name));
+        }
+        if flexible {
+            parser::skip_tagged_fields(r).unwrap();
+        }
+        assert_eq!(r.position() as usize, data.len());
+        responses
+    }
+
+    #[test]
+    fn test_incremental_alter_configs() {
+        let root = temp_root("alter-configs-test");
+        let metadata = Arc::new(MetadataCache::default());
+        let controller = Arc::new(controller::open_single_voter(&root, &metadata));
+        let topic = NewTopic {
+            name: "orders".to_string(),
+            num_partitions: 1,
+            replication_factor: 1,
+            configs: vec![("cleanup.policy".to_string(), "compact".to_string())],
+            ..Default::default()
+        };
+        controller.create_topic(&topic, false).unwrap();
+        let alter = |flexible: bool, resources: &[(i8, &str, Changes)], validate_only: bool| {
+            let data = encode(flexible, resources, validate_only);
+            let request =
+                IncrementalAlterConfigsRequest::new(&mut Cursor::new(data), flexible).unwrap();
+            let mut out = vec![];
+            IncrementalAlterConfigsResponse::new(&request, &controller)
+                .serialize(&mut out, flexible)
+                .unwrap();
+            decode(&out, flexible)
+                .into_iter()
+                .map(|(error_code, _, _)| error_code)
+                .collect::<Vec<_>>()
+        };
+        let set = |value| {
+            [(
+                config::RETENTION_MS_CONFIG,
+                CONFIG_OPERATION_SET,
+                Some(value),
+            )]
+        };
+
+        // only checked
+        assert_eq!(
+            alter(
+                true,
+                &[(CONFIG_RESOURCE_TOPIC, "orders", &set("1000"))],
+                true
+            ),
+            vec![0]
+        );
+        assert_eq!(
+            metadata.image().topic_config("orders").retention_ms,
+            config::TopicConfig::default().retention_ms
+        );
+
+        let delete_policy = [(config::CLEANUP_POLICY_CONFIG, CONFIG_OPERATION_DELETE, None)];
+        let resources: &[(i8, &str, Changes)] = &[
+            (CONFIG_RESOURCE_TOPIC, "orders", &set("1000")),
+            (CONFIG_RESOURCE_TOPIC, "orders", &delete_policy),
+            (CONFIG_RESOURCE_TOPIC, "orders", &set("soon")),
+            (CONFIG_RESOURCE_TOPIC, "missing", &set("1000")),
+            (4, "1", &set("1000")), // a broker
+            (
+                CONFIG_RESOURCE_TOPIC,
+                "orders",
+                &[("retention.ms", 2, Some("1"))],
+            ), // APPEND
+        ];
+        assert_eq!(
+            alter(false, resources, false),
+            vec![
+                0,
+                0,
+                controller::INVALID_CONFIG,
+                controller::UNKNOWN_TOPIC_OR_PARTITION,
+                raft::INVALID_REQUEST,
+                raft::INVALID_REQUEST
+            ]
+        );
+        let config = metadata.image().topic_config("orders");
+        assert_eq!(config.retention_ms, 1000);
+        assert!(!config.cleanup_policy.compact);
+        let _ = std::fs::remove_dir_all(&root);
+    }
+}
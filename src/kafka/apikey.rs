@@ -4,17 +4,49 @@ use std::io::prelude::*;
 use std::io::Read;
 
 const FETCH_APIKEY: u16 = 1;
+const METADATA_APIKEY: u16 = 3;
 const API_VERSIONS_APIKEY: u16 = 18;
+const CREATE_TOPICS_APIKEY: u16 = 19;
+const DELETE_TOPICS_APIKEY: u16 = 20;
 const DESCRIBE_PARTITIONS_APIKEY: u16 = 75;
 const PRODUCE_APIKEY: u16 = 0;
+const ALTER_REPLICA_LOG_DIRS_APIKEY: u16 = 34;
+const DESCRIBE_LOG_DIRS_APIKEY: u16 = 35;
+const INCREMENTAL_ALTER_CONFIGS_APIKEY: u16 = 44;
+const DESCRIBE_CLUSTER_APIKEY: u16 = 60;
+const VOTE_APIKEY: u16 = 52;
+const BEGIN_QUORUM_EPOCH_APIKEY: u16 = 53;
+const END_QUORUM_EPOCH_APIKEY: u16 = 54;
+const DESCRIBE_QUORUM_APIKEY: u16 = 55;
+const FETCH_SNAPSHOT_APIKEY: u16 = 59;
+const BROKER_REGISTRATION_APIKEY: u16 = 62;
+const ADD_RAFT_VOTER_APIKEY: u16 = 80;
+const REMOVE_RAFT_VOTER_APIKEY: u16 = 81;
+const UPDATE_RAFT_VOTER_APIKEY: u16 = 82;
 
 #[repr(u16)]
 #[derive(Debug, Copy, Clone)]
 pub enum ApiKey {
     Fetch = FETCH_APIKEY,
+    Metadata = METADATA_APIKEY,
     ApiVersions = API_VERSIONS_APIKEY,
+    CreateTopics = CREATE_TOPICS_APIKEY,
+    DeleteTopics = DELETE_TOPICS_APIKEY,
     DescribeTopicPartitions = DESCRIBE_PARTITIONS_APIKEY,
     Produce = PRODUCE_APIKEY,
+    AlterReplicaLogDirs = ALTER_REPLICA_LOG_DIRS_APIKEY,
+    DescribeLogDirs = DESCRIBE_LOG_DIRS_APIKEY,
+    IncrementalAlterConfigs = INCREMENTAL_ALTER_CONFIGS_APIKEY,
+    DescribeCluster = DESCRIBE_CLUSTER_APIKEY,
+    Vote = VOTE_APIKEY,
+    BeginQuorumEpoch = BEGIN_QUORUM_EPOCH_APIKEY,
+    EndQuorumEpoch = END_QUORUM_EPOCH_APIKEY,
+    DescribeQuorum = DESCRIBE_QUORUM_APIKEY,
+    FetchSnapshot = FETCH_SNAPSHOT_APIKEY,
+    BrokerRegistration = BROKER_REGISTRATION_APIKEY,
+    AddRaftVoter = ADD_RAFT_VOTER_APIKEY,
+    RemoveRaftVoter = REMOVE_RAFT_VOTER_APIKEY,
+    UpdateRaftVoter = UPDATE_RAFT_VOTER_APIKEY,
 }
 
 impl ApiKey {
@@ -23,6 +55,34 @@ impl ApiKey {
         b.read_exact(&mut b0)?;
         Ok(Self::try_from(u16::from_be_bytes(b0))?)
     }
+
+    // first version using flexible encoding (compact strings/arrays and
+    // tagged fields), in both the bodies and the request/response headers
+    pub fn is_flexible(&self, version: u16) -> bool {
+        let first_flexible = match self {
+            Self::Fetch => 12,
+            Self::Metadata => 9,
+            Self::ApiVersions => 3,
+            Self::CreateTopics => 5,
+            Self::DeleteTopics => 4,
+            Self::DescribeTopicPartitions => 0,
+            Self::Produce => 9,
+            Self::AlterReplicaLogDirs => 2,
+            Self::DescribeLogDirs => 2,
+            Self::IncrementalAlterConfigs => 1,
+            Self::DescribeCluster => 0,
+            Self::Vote => 0,
+            Self::BeginQuorumEpoch => 1,
+            Self::EndQuorumEpoch => 1,
+            Self::DescribeQuorum => 0,
+            Self::FetchSnapshot => 0,
+            Self::BrokerRegistration => 0,
+            Self::AddRaftVoter => 0,
+            Self::RemoveRaftVoter => 0,
+            Self::UpdateRaftVoter => 0,
+        };
+        version >= first_flexible
+    }
 }
 
 impl From<ApiKey> for u16 {
@@ -35,9 +95,25 @@ impl fmt::Display for ApiKey {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Self::Fetch => write!(f, "fetch"),
+            Self::Metadata => write!(f, "metadata"),
             Self::ApiVersions => write!(f, "api-versions"),
+            Self::CreateTopics => write!(f, "create-topics"),
+            Self::DeleteTopics => write!(f, "delete-topics"),
             Self::DescribeTopicPartitions => write!(f, "describe-topic-partitions"),
             Self::Produce => write!(f, "produce"),
+            Self::AlterReplicaLogDirs => write!(f, "alter-replica-log-dirs"),
+            Self::DescribeLogDirs => write!(f, "describe-log-dirs"),
+            Self::IncrementalAlterConfigs => write!(f, "incremental-alter-configs"),
+            Self::DescribeCluster => write!(f, "describe-cluster"),
+            Self::Vote => write!(f, "vote"),
+            Self::BeginQuorumEpoch => write!(f, "begin-quorum-epoch"),
+            Self::EndQuorumEpoch => write!(f, "end-quorum-epoch"),
+            Self::DescribeQuorum => write!(f, "describe-quorum"),
+            Self::FetchSnapshot => write!(f, "fetch-snapshot"),
+            Self::BrokerRegistration => write!(f, "broker-registration"),
+            Self::AddRaftVoter => write!(f, "add-raft-voter"),
+            Self::RemoveRaftVoter => write!(f, "remove-raft-voter"),
+            Self::UpdateRaftVoter => write!(f, "update-raft-voter"),
         }
     }
 }
@@ -47,10 +123,26 @@ impl TryFrom<u16> for ApiKey {
     fn try_from(value: u16) -> Result<Self, Self::Error> {
         match value {
             FETCH_APIKEY => Ok(Self::Fetch),
+            METADATA_APIKEY => Ok(Self::Metadata),
             API_VERSIONS_APIKEY => Ok(Self::ApiVersions),
+            CREATE_TOPICS_APIKEY => Ok(Self::CreateTopics),
+            DELETE_TOPICS_APIKEY => Ok(Self::DeleteTopics),
             DESCRIBE_PARTITIONS_APIKEY => Ok(Self::DescribeTopicPartitions),
             PRODUCE_APIKEY => Ok(Self::Produce),
-            i @ (0..=75) => Err(KafkaErrors::Unimplemented(format!("apikey {i}"))),
+            ALTER_REPLICA_LOG_DIRS_APIKEY => Ok(Self::AlterReplicaLogDirs),
+            DESCRIBE_LOG_DIRS_APIKEY => Ok(Self::DescribeLogDirs),
+            INCREMENTAL_ALTER_CONFIGS_APIKEY => Ok(Self::IncrementalAlterConfigs),
+            DESCRIBE_CLUSTER_APIKEY => Ok(Self::DescribeCluster),
+            VOTE_APIKEY => Ok(Self::Vote),
+            BEGIN_QUORUM_EPOCH_APIKEY => Ok(Self::BeginQuorumEpoch),
+            END_QUORUM_EPOCH_APIKEY => Ok(Self::EndQuorumEpoch),
+            DESCRIBE_QUORUM_APIKEY => Ok(Self::DescribeQuorum),
+            FETCH_SNAPSHOT_APIKEY => Ok(Self::FetchSnapshot),
+            BROKER_REGISTRATION_APIKEY => Ok(Self::BrokerRegistration),
+            ADD_RAFT_VOTER_APIKEY => Ok(Self::AddRaftVoter),
+            REMOVE_RAFT_VOTER_APIKEY => Ok(Self::RemoveRaftVoter),
+            UPDATE_RAFT_VOTER_APIKEY => Ok(Self::UpdateRaftVoter),
+            i @ (0..=82) => Err(KafkaErrors::Unimplemented(format!("apikey {i}"))),
             i => Err(KafkaErrors::InvalidApiKey(format!("invalid apikey {i}"))),
         }
     }
@@ -62,7 +154,24 @@ pub struct SupportedApiKeys {
     pub key: u16,
 }
 
-pub const SUPPORTED_APIKEYS: &[SupportedApiKeys; 4] = &[
+impl SupportedApiKeys {
+    // the voters' own traffic, only advertised and served on controller
+    // listeners
+    pub fn controller_only(&self) -> bool {
+        matches!(
+            self.key,
+            VOTE_APIKEY
+                | BEGIN_QUORUM_EPOCH_APIKEY
+                | END_QUORUM_EPOCH_APIKEY
+                | FETCH_SNAPSHOT_APIKEY
+                | ADD_RAFT_VOTER_APIKEY
+                | REMOVE_RAFT_VOTER_APIKEY
+                | UPDATE_RAFT_VOTER_APIKEY
+        )
+    }
+}
+
+pub const SUPPORTED_APIKEYS: &[SupportedApiKeys; 20] = &[
     // API Versions request
     SupportedApiKeys {
         min: super::MIN_SUPPORTED_API_VERSION,
@@ -75,6 +184,12 @@ pub const SUPPORTED_APIKEYS: &[SupportedApiKeys; 4] = &[
         max: super::MAX_SUPPORTED_FETCH_VER,
         key: FETCH_APIKEY,
     },
+    // Metadata
+    SupportedApiKeys {
+        min: super::MIN_SUPPORTED_METADATA_VER,
+        max: super::MAX_SUPPORTED_METADATA_VER,
+        key: METADATA_APIKEY,
+    },
     // Describe partitions
     SupportedApiKeys {
         min: super::MIN_SUPPORTED_DESCRIBE_PARTITION_VER,
@@ -87,4 +202,94 @@ pub const SUPPORTED_APIKEYS: &[SupportedApiKeys; 4] = &[
         max: super::MAX_SUPPORTED_PRODUCE_VERSION,
         key: PRODUCE_APIKEY,
     },
+    // Alter replica log dirs
+    SupportedApiKeys {
+        min: super::MIN_SUPPORTED_ALTER_REPLICA_LOG_DIRS_VER,
+        max: super::MAX_SUPPORTED_ALTER_REPLICA_LOG_DIRS_VER,
+        key: ALTER_REPLICA_LOG_DIRS_APIKEY,
+    },
+    // Describe log dirs
+    SupportedApiKeys {
+        min: super::MIN_SUPPORTED_DESCRIBE_LOG_DIRS_VER,
+        max: super::MAX_SUPPORTED_DESCRIBE_LOG_DIRS_VER,
+        key: DESCRIBE_LOG_DIRS_APIKEY,
+    },
+    // Create topics
+    SupportedApiKeys {
+        min: super::MIN_SUPPORTED_CREATE_TOPICS_VER,
+        max: super::MAX_SUPPORTED_CREATE_TOPICS_VER,
+        key: CREATE_TOPICS_APIKEY,
+    },
+    // Delete topics
+    SupportedApiKeys {
+        min: super::MIN_SUPPORTED_DELETE_TOPICS_VER,
+        max: super::MAX_SUPPORTED_DELETE_TOPICS_VER,
+        key: DELETE_TOPICS_APIKEY,
+    },
+    // Incremental alter configs
+    SupportedApiKeys {
+        min: super::MIN_SUPPORTED_INCREMENTAL_ALTER_CONFIGS_VER,
+        max: super::MAX_SUPPORTED_INCREMENTAL_ALTER_CONFIGS_VER,
+        key: INCREMENTAL_ALTER_CONFIGS_APIKEY,
+    },
+    // Describe cluster
+    SupportedApiKeys {
+        min: super::MIN_SUPPORTED_DESCRIBE_CLUSTER_VER,
+        max: super::MAX_SUPPORTED_DESCRIBE_CLUSTER_VER,
+        key: DESCRIBE_CLUSTER_APIKEY,
+    },
+    // Vote
+    SupportedApiKeys {
+        min: super::MIN_SUPPORTED_VOTE_VER,
+        max: super::MAX_SUPPORTED_VOTE_VER,
+        key: VOTE_APIKEY,
+    },
+    // Begin quorum epoch
+    SupportedApiKeys {
+        min: super::MIN_SUPPORTED_BEGIN_QUORUM_EPOCH_VER,
+        max: super::MAX_SUPPORTED_BEGIN_QUORUM_EPOCH_VER,
+        key: BEGIN_QUORUM_EPOCH_APIKEY,
+    },
+    // End quorum epoch
+    SupportedApiKeys {
+        min: super::MIN_SUPPORTED_END_QUORUM_EPOCH_VER,
+        max: super::MAX_SUPPORTED_END_QUORUM_EPOCH_VER,
+        key: END_QUORUM_EPOCH_APIKEY,
+    },
+    // Describe quorum
+    SupportedApiKeys {
+        min: super::MIN_SUPPORTED_DESCRIBE_QUORUM_VER,
+        max: super::MAX_SUPPORTED_DESCRIBE_QUORUM_VER,
+        key: DESCRIBE_QUORUM_APIKEY,
+    },
+    // Fetch snapshot
+    SupportedApiKeys {
+        min: super::MIN_SUPPORTED_FETCH_SNAPSHOT_VER,
+        max: super::MAX_SUPPORTED_FETCH_SNAPSHOT_VER,
+        key: FETCH_SNAPSHOT_APIKEY,
+    },
+    // Broker registration
+    SupportedApiKeys {
+        min: super::MIN_SUPPORTED_BROKER_REGISTRATION_VER,
+        max: super::MAX_SUPPORTED_BROKER_REGISTRATION_VER,
+        key: BROKER_REGISTRATION_APIKEY,
+    },
+    // Add raft voter
+    SupportedApiKeys {
+        min: super::MIN_SUPPORTED_ADD_RAFT_VOTER_VER,
+        max: super::MAX_SUPPORTED_ADD_RAFT_VOTER_VER,
+        key: ADD_RAFT_VOTER_APIKEY,
+    },
+    // Remove raft voter
+    SupportedApiKeys {
+        min: super::MIN_SUPPORTED_REMOVE_RAFT_VOTER_VER,
+        max: super::MAX_SUPPORTED_REMOVE_RAFT_VOTER_VER,
+        key: REMOVE_RAFT_VOTER_APIKEY,
+    },
+    // Update raft voter
+    SupportedApiKeys {
+        min: super::MIN_SUPPORTED_UPDATE_RAFT_VOTER_VER,
+        max: super::MAX_SUPPORTED_UPDATE_RAFT_VOTER_VER,
+        key: UPDATE_RAFT_VOTER_APIKEY,
+    },
 ];
@@ -1,32 +1,104 @@
 // implements Kafka body
-use crate::kafka::{apikey, errors, fetch, header, partitions, produce};
+use crate::kafka::{
+    alter_configs, apikey, cluster, create_topics, delete_topics, errors, fetch, header, logdirs,
+    metadata_api, partitions, produce, quorum, registration, voters,
+};
 use std::fmt;
 use std::io::Read;
 
 #[derive(Debug, Clone)]
 pub enum RequestBody {
+    AddRaftVoter(voters::AddRaftVoterRequest),
+    AlterReplicaLogDirs(logdirs::AlterReplicaLogDirsRequest),
     ApiVersions(u32, u8), // throttle_ms and tagged buffer etc
+    BeginQuorumEpoch(quorum::BeginQuorumEpochRequest),
+    BrokerRegistration(registration::BrokerRegistrationRequest),
+    CreateTopics(create_topics::CreateTopicsRequest),
+    DeleteTopics(delete_topics::DeleteTopicsRequest),
+    DescribeCluster(cluster::DescribeClusterRequest),
+    DescribeLogDirs(logdirs::DescribeLogDirsRequest),
     DescribePartitions(partitions::PartitionsRequest),
+    DescribeQuorum(quorum::DescribeQuorumRequest),
+    EndQuorumEpoch(quorum::EndQuorumEpochRequest),
     Fetch(fetch::FetchRequest),
+    FetchSnapshot(quorum::FetchSnapshotRequest),
+    IncrementalAlterConfigs(alter_configs::IncrementalAlterConfigsRequest),
+    Metadata(metadata_api::MetadataRequest),
     Produce(produce::ProduceRequest),
+    RemoveRaftVoter(voters::RemoveRaftVoterRequest),
+    UpdateRaftVoter(voters::UpdateRaftVoterRequest),
+    Vote(quorum::VoteRequest),
 }
 
 impl RequestBody {
     pub fn new<R: Read>(req: &mut R, t: &header::RequestHeader) -> errors::Result<Self> {
-        let s = match t.get_api_key() {
-            apikey::ApiKey::Fetch => RequestBody::Fetch(fetch::FetchRequest::new(req)?),
-            apikey::ApiKey::ApiVersions => RequestBody::ApiVersions(0, 0),
-            apikey::ApiKey::DescribeTopicPartitions => {
-                RequestBody::DescribePartitions(partitions::PartitionsRequest::new(req)?)
-            }
-            apikey::ApiKey::Produce => RequestBody::Produce(produce::ProduceRequest::new(req)?),
-        };
+        let s =
+            match t.get_api_key() {
+                apikey::ApiKey::Fetch => RequestBody::Fetch(fetch::FetchRequest::new(
+                    req,
+                    t.get_api_ver(),
+                    t.is_flexible(),
+                )?),
+                apikey::ApiKey::Metadata => RequestBody::Metadata(
+                    metadata_api::MetadataRequest::new(req, t.get_api_ver(), t.is_flexible())?,
+                ),
+                apikey::ApiKey::ApiVersions => RequestBody::ApiVersions(0, 0),
+                apikey::ApiKey::CreateTopics => RequestBody::CreateTopics(
+                    create_topics::CreateTopicsRequest::new(req, t.get_api_ver(), t.is_flexible())?,
+                ),
+                apikey::ApiKey::DeleteTopics => RequestBody::DeleteTopics(
+                    delete_topics::DeleteTopicsRequest::new(req, t.get_api_ver(), t.is_flexible())?,
+                ),
+                apikey::ApiKey::IncrementalAlterConfigs => RequestBody::IncrementalAlterConfigs(
+                    alter_configs::IncrementalAlterConfigsRequest::new(req, t.is_flexible())?,
+                ),
+                apikey::ApiKey::DescribeTopicPartitions => {
+                    RequestBody::DescribePartitions(partitions::PartitionsRequest::new(req)?)
+                }
+                apikey::ApiKey::Produce => RequestBody::Produce(produce::ProduceRequest::new(req)?),
+                apikey::ApiKey::AlterReplicaLogDirs => RequestBody::AlterReplicaLogDirs(
+                    logdirs::AlterReplicaLogDirsRequest::new(req, t.is_flexible())?,
+                ),
+                apikey::ApiKey::DescribeCluster => RequestBody::DescribeCluster(
+                    cluster::DescribeClusterRequest::new(req, t.get_api_ver())?,
+                ),
+                apikey::ApiKey::DescribeLogDirs => RequestBody::DescribeLogDirs(
+                    logdirs::DescribeLogDirsRequest::new(req, t.get_api_ver(), t.is_flexible())?,
+                ),
+                apikey::ApiKey::Vote => {
+                    RequestBody::Vote(quorum::VoteRequest::new(req, t.get_api_ver())?)
+                }
+                apikey::ApiKey::BeginQuorumEpoch => RequestBody::BeginQuorumEpoch(
+                    quorum::BeginQuorumEpochRequest::new(req, t.get_api_ver(), t.is_flexible())?,
+                ),
+                apikey::ApiKey::EndQuorumEpoch => RequestBody::EndQuorumEpoch(
+                    quorum::EndQuorumEpochRequest::new(req, t.get_api_ver(), t.is_flexible())?,
+                ),
+                apikey::ApiKey::DescribeQuorum => RequestBody::DescribeQuorum(
+                    quorum::DescribeQuorumRequest::new(req, t.get_api_ver())?,
+                ),
+                apikey::ApiKey::FetchSnapshot => {
+                    RequestBody::FetchSnapshot(quorum::FetchSnapshotRequest::new(req)?)
+                }
+                apikey::ApiKey::BrokerRegistration => RequestBody::BrokerRegistration(
+                    registration::BrokerRegistrationRequest::new(req, t.get_api_ver())?,
+                ),
+                apikey::ApiKey::AddRaftVoter => RequestBody::AddRaftVoter(
+                    voters::AddRaftVoterRequest::new(req, t.get_api_ver())?,
+                ),
+                apikey::ApiKey::RemoveRaftVoter => {
+                    RequestBody::RemoveRaftVoter(voters::RemoveRaftVoterRequest::new(req)?)
+                }
+                apikey::ApiKey::UpdateRaftVoter => {
+                    RequestBody::UpdateRaftVoter(voters::UpdateRaftVoterRequest::new(req)?)
+                }
+            };
         Ok(s)
     }
 }
 
 impl fmt::Display for RequestBody {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}", "Not implemented yet!")
+        write!(f, "Not implemented yet!")
     }
 }
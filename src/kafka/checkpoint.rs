@@ -0,0 +1,70 @@
+// offset checkpoint files, same layout the java broker uses:
+//   <version>
+//   <number of entries>
+//   <topic> <partition> <offset>
+use crate::kafka::errors::{self, KafkaErrors};
+use std::collections::HashMap;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+const CHECKPOINT_VERSION: u32 = 0;
+
+pub type TopicPartition = (String, i32);
+
+#[derive(Debug, Clone)]
+pub struct OffsetCheckpoint {
+    path: PathBuf,
+}
+
+impl OffsetCheckpoint {
+    pub fn new<P: AsRef<Path>>(path: P) -> Self {
+        Self {
+            path: path.as_ref().to_path_buf(),
+        }
+    }
+
+    pub fn read(&self) -> errors::Result<HashMap<TopicPartition, u64>> {
+        let mut offsets = HashMap::new();
+        let Ok(contents) = fs::read_to_string(&self.path) else {
+            return Ok(offsets);
+        };
+        let mut lines = contents.lines();
+        let version = lines.next().unwrap_or_default().trim().parse::<u32>()?;
+        if version != CHECKPOINT_VERSION {
+            return Err(KafkaErrors::InvalidCheckpoint(format!(
+                "{}: unsupported version {version}",
+                self.path.display()
+            ))
+            .into());
+        }
+        let count = lines.next().unwrap_or_default().trim().parse::<usize>()?;
+        for line in lines.take(count) {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if let [topic, partition, offset] = fields[..] {
+                offsets.insert((topic.to_string(), partition.parse()?), offset.parse()?);
+            } else {
+                return Err(KafkaErrors::InvalidCheckpoint(format!(
+                    "{}: malformed line '{line}'",
+                    self.path.display()
+                ))
+                .into());
+            }
+        }
+        Ok(offsets)
+    }
+
+    // writes to a temp file first so a crash never leaves a torn checkpoint
+    pub fn write(&self, offsets: &HashMap<TopicPartition, u64>) -> errors::Result<()> {
+        let tmp = self.path.with_extension("tmp");
+        let mut f = fs::File::create(&tmp)?;
+        writeln!(f, "{CHECKPOINT_VERSION}")?;
+        writeln!(f, "{}", offsets.len())?;
+        for ((topic, partition), offset) in offsets {
+            writeln!(f, "{topic} {partition} {offset}")?;
+        }
+        f.sync_all()?;
+        fs::rename(&tmp, &self.path)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,481 @@
+// log cleaner for topics with cleanup.policy=compact (or compact,delete)
+//
+// Each pass looks at the "dirty" part of a log, i.e. everything written since
+// the last pass and not protected by min.compaction.lag.ms, and builds a map
+// of key -> latest offset over it. Every closed segment up to that point is
+// then rewritten keeping only the latest record for each key. Offsets are
+// never changed, so consumers see gaps rather than renumbered records.
+//
+// Tombstones (records with a null value) are kept for delete.retention.ms
+// after the segment holding them was first cleaned, so that slow consumers
+// still get to see the delete. The segment mtime records that time.
+//
+// The same pass also applies time based retention for topics that have the
+// delete policy, on its own or combined with compact.
+use crate::kafka::checkpoint::{OffsetCheckpoint, TopicPartition};
+use crate::kafka::log::{self, LogManager, PartitionLog};
+use crate::kafka::records::BatchHeader;
+use crate::kafka::{config, errors, metadata, records};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+pub const CLEANER_CHECKPOINT_FILE: &str = "cleaner-offset-checkpoint";
+
+// latest offset seen for every key in the dirty section
+#[derive(Debug, Default)]
+pub struct OffsetMap {
+    map: HashMap<Vec<u8>, u64>,
+}
+
+impl OffsetMap {
+    pub fn put(&mut self, key: &[u8], offset: u64) {
+        self.map
+            .entry(key.to_vec())
+            .and_modify(|o| *o = (*o).max(offset))
+            .or_insert(offset);
+    }
+
+    pub fn get(&self, key: &[u8]) -> Option<u64> {
+        self.map.get(key).copied()
+    }
+
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct CleanerStats {
+    pub segments_rewritten: usize,
+    pub records_removed: usize,
+    pub segments_deleted: usize,
+}
+
+pub struct LogCleaner {
+    logs: Arc<LogManager>,
+    metadata: Arc<metadata::MetadataCache>,
+}
+
+impl LogCleaner {
+    pub fn new(logs: Arc<LogManager>, metadata: Arc<metadata::MetadataCache>) -> Self {
+        Self { logs, metadata }
+    }
+
+    pub fn start(self, backoff_ms: u64) -> thread::JoinHandle<()> {
+        thread::spawn(move || loop {
+            if let Err(e) = self.clean_all() {
+                println!("log cleaner pass failed: {e}");
+            }
+            thread::sleep(Duration::from_millis(backoff_ms));
+        })
+    }
+
+    // one pass over every online log dir, each with its own checkpoint. A
+    // dir that fails is taken offline and the others still get cleaned.
+    pub fn clean_all(&self) -> errors::Result<CleanerStats> {
+        let mut total = CleanerStats::default();
+        for dir in self.logs.online_dirs() {
+            match self.clean_dir(&dir) {
+                Ok(stats) => {
+                    total.segments_rewritten += stats.segments_rewritten;
+                    total.records_removed += stats.records_removed;
+                    total.segments_deleted += stats.segments_deleted;
+                }
+                Err(e) if log::is_storage_error(&e) => {
+                    println!("log cleaner failed on {}: {e}", dir.display());
+                    self.logs.mark_dir_offline(&dir);
+                }
+                Err(e) => println!("log cleaner skipped {}: {e}", dir.display()),
+            }
+        }
+        if total.segments_rewritten > 0 || total.segments_deleted > 0 {
+            println!("log cleaner pass done: {total:?}");
+        }
+        Ok(total)
+    }
+
+    fn clean_dir(&self, dir: &Path) -> errors::Result<CleanerStats> {
+        let checkpoint = OffsetCheckpoint::new(dir.join(CLEANER_CHECKPOINT_FILE));
+        let mut dirty_offsets = checkpoint.read()?;
+        let mut total = CleanerStats::default();
+        let now = log::now_ms();
+        let logs = self.logs.logs_in(dir);
+
+        for log in &logs {
+            let mut log = log.lock().unwrap();
+            let tp = log.topic_partition();
+            let config = self.metadata.image().topic_config(&tp.0);
+
+            if config.cleanup_policy.compact {
+                let first_dirty = dirty_offsets.get(&tp).copied().unwrap_or(0);
+                let (next_dirty, stats) = clean_log(&mut log, &config, first_dirty, now)?;
+                dirty_offsets.insert(tp.clone(), next_dirty);
+                total.segments_rewritten += stats.segments_rewritten;
+                total.records_removed += stats.records_removed;
+            }
+            if config.cleanup_policy.delete {
+                // with tiered storage only segments already copied may go,
+                // and local.retention.ms applies instead of retention.ms
+                let tiered_upto = match self.logs.remote() {
+                    Some(remote) if config.remote_storage_enable => Some(remote.copied_upto(&tp)?),
+                    _ => None,
+                };
+                total.segments_deleted +=
+                    delete_expired_segments(&mut log, &config, now, tiered_upto)?;
+            }
+        }
+
+        // forget partitions that no longer live in this dir
+        let live: Vec<TopicPartition> = logs
+            .iter()
+            .map(|l| l.lock().unwrap().topic_partition())
+            .collect();
+        dirty_offsets.retain(|tp, _| live.contains(tp));
+        checkpoint.write(&dirty_offsets)?;
+        Ok(total)
+    }
+}
+
+// compacts one log, returning the new first dirty offset
+pub fn clean_log(
+    log: &mut PartitionLog,
+    config: &config::TopicConfig,
+    first_dirty: u64,
+    now: i64,
+) -> errors::Result<(u64, CleanerStats)> {
+    let mut stats = CleanerStats::default();
+    let first_uncleanable = first_uncleanable_offset(log, config, now);
+    let first_dirty = first_dirty.max(log.log_start_offset);
+    if first_dirty >= first_uncleanable {
+        return Ok((first_dirty, stats));
+    }
+
+    let cleanable: Vec<log::LogSegment> = log
+        .segments
+        .values()
+        .filter(|s| s.base_offset < first_uncleanable)
+        .cloned()
+        .collect();
+    let (dirty_bytes, total_bytes) = cleanable.iter().fold((0, 0), |(d, t), s| {
+        if s.base_offset >= first_dirty {
+            (d + s.size, t + s.size)
+        } else {
+            (d, t + s.size)
+        }
+    });
+    if total_bytes == 0
+        || (dirty_bytes as f64 / total_bytes as f64) < config.min_cleanable_dirty_ratio
+    {
+        return Ok((first_dirty, stats));
+    }
+
+    let offset_map = build_offset_map(&cleanable, first_dirty)?;
+    println!(
+        "cleaning {}-{}: offsets [{first_dirty}, {first_uncleanable}), {} keys",
+        log.topic,
+        log.partition,
+        offset_map.len()
+    );
+
+    let delete_horizon = now - config.delete_retention_ms;
+    for segment in &cleanable {
+        // segments below first_dirty went through an earlier pass, the rest
+        // are cleaned for the first time now
+        let first_clean = segment.base_offset >= first_dirty;
+        let cleaned_at = segment.last_modified_ms();
+        let drop_tombstones = !first_clean && cleaned_at < delete_horizon;
+        let mut changed = false;
+        let mut retained_batches = vec![];
+        for view in segment.batches_from(segment.base_offset)? {
+            let view = view?;
+            // control markers are transaction bookkeeping and legacy entries
+            // can't be rebuilt, both are left alone. Compressed batches are
+            // rewritten with their own codec.
+            if view.is_control() || view.is_legacy() {
+                retained_batches.push(view.load()?);
+                continue;
+            }
+            let mut retained = vec![];
+            let mut count = 0;
+            for record in view.records()? {
+                let record = record?;
+                count += 1;
+                if should_retain(&view, &record, &offset_map, drop_tombstones) {
+                    retained.push(record);
+                }
+            }
+            let batch = view.load()?;
+            if retained.len() == count {
+                retained_batches.push(batch);
+                continue;
+            }
+            changed = true;
+            stats.records_removed += count - retained.len();
+            if !retained.is_empty() {
+                retained_batches.push(batch.with_records(&retained)?);
+            }
+        }
+        if changed {
+            log.replace_segment(segment.base_offset, &retained_batches)?;
+            stats.segments_rewritten += 1;
+        }
+        // keep the first clean time across rewrites
+        if first_clean || changed {
+            let stamp = if first_clean { now } else { cleaned_at };
+            if let Some(s) = log.segments.get(&segment.base_offset) {
+                s.set_last_modified_ms(stamp)?;
+            }
+        }
+    }
+    Ok((first_uncleanable, stats))
+}
+
+// the active segment is never cleaned, nor is anything newer than
+// min.compaction.lag.ms
+fn first_uncleanable_offset(log: &PartitionLog, config: &config::TopicConfig, now: i64) -> u64 {
+    let active = log.active_segment().base_offset;
+    if config.min_compaction_lag_ms <= 0 {
+        return active;
+    }
+    let lag_horizon = now - config.min_compaction_lag_ms;
+    log.segments
+        .values()
+        .find(|s| s.max_timestamp > lag_horizon)
+        .map_or(active, |s| s.base_offset.min(active))
+}
+
+fn build_offset_map(segments: &[log::LogSegment], first_dirty: u64) -> errors::Result<OffsetMap> {
+    let mut map = OffsetMap::default();
+    for segment in segments.iter().filter(|s| s.base_offset >= first_dirty) {
+        for view in segment.batches_from(segment.base_offset)? {
+            let view = view?;
+            if view.is_control() {
+                continue;
+            }
+            for record in view.records()? {
+                let record = record?;
+                if let Some(key) = &record.key {
+                    map.put(key, view.base_offset() + record.offset_delta as u64);
+                }
+            }
+        }
+    }
+    Ok(map)
+}
+
+fn should_retain(
+    batch: &impl BatchHeader,
+    record: &records::KafkaRecord,
+    offset_map: &OffsetMap,
+    drop_tombstones: bool,
+) -> bool {
+    // compacted topics should only ever see keyed records, keep anything else
+    let Some(key) = &record.key else {
+        return true;
+    };
+    let offset = batch.base_offset() + record.offset_delta as u64;
+    if offset_map.get(key).is_some_and(|latest| latest > offset) {
+        return false;
+    }
+    !(record.value.is_none() && drop_tombstones)
+}
+
+// retention.ms for logs with the delete policy: whole closed segments go
+// once their newest record is past the limit
+pub fn delete_expired_segments(
+    log: &mut PartitionLog,
+    config: &config::TopicConfig,
+    now: i64,
+    tiered_upto: Option<u64>,
+) -> errors::Result<usize> {
+    let retention_ms = match tiered_upto {
+        Some(_) => config.effective_local_retention_ms(),
+        None => config.retention_ms,
+    };
+    if retention_ms < 0 {
+        return Ok(0);
+    }
+    let horizon = now - retention_ms;
+    let active = log.active_segment().base_offset;
+    let expired: Vec<u64> = log
+        .segments
+        .values()
+        .take_while(|s| s.base_offset != active)
+        .take_while(|s| tiered_upto.map_or(true, |upto| s.next_offset <= upto))
+        .take_while(|s| {
+            let newest = if s.max_timestamp >= 0 {
+                s.max_timestamp
+            } else {
+                s.last_modified_ms()
+            };
+            newest < horizon
+        })
+        .map(|s| s.base_offset)
+        .collect();
+    for base_offset in &expired {
+        log.delete_segment(*base_offset)?;
+    }
+    if !expired.is_empty() {
+        println!(
+            "{}-{}: deleted {} expired segments, log start offset now {}",
+            log.topic,
+            log.partition,
+            expired.len(),
+            log.log_start_offset
+        );
+    }
+    Ok(expired.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kafka::testing::{record, records_batch, temp_root};
+
+    // one record per (key, value) pair
+    fn batch(timestamp: i64, entries: &[(&[u8], Option<&[u8]>)]) -> Vec<u8> {
+        let records = entries
+            .iter()
+            .enumerate()
+            .map(|(i, (key, value))| records::KafkaRecord {
+                key: Some(key.to_vec()),
+                value: value.map(<[u8]>::to_vec),
+                ..record(i as i32)
+            })
+            .collect();
+        records::RecordsBatch {
+            base_timestamp: timestamp,
+            max_timestamp: timestamp,
+            ..records_batch(records)
+        }
+        .to_bytes()
+        .unwrap()
+    }
+
+    fn all_records(log: &PartitionLog) -> Vec<(u64, Vec<u8>, Option<Vec<u8>>)> {
+        let mut out = vec![];
+        for segment in log.segments.values() {
+            for view in segment.batches_from(segment.base_offset).unwrap() {
+                let b = view.unwrap().load().unwrap();
+                assert_eq!(b.crc(), b.compute_crc());
+                for r in b.records().unwrap() {
+                    out.push((
+                        b.base_offset() + r.offset_delta as u64,
+                        r.key.unwrap(),
+                        r.value,
+                    ));
+                }
+            }
+        }
+        out
+    }
+
+    #[test]
+    fn test_compaction_keeps_latest_and_offsets() {
+        let root = temp_root("cleaner-test");
+        let mut log = PartitionLog::open(&root, "changelog", 0, 0).unwrap();
+        let config = config::TopicConfig {
+            cleanup_policy: config::CleanupPolicy::parse("compact"),
+            min_cleanable_dirty_ratio: 0.0,
+            ..Default::default()
+        };
+        let now = log::now_ms();
+
+        log.append(
+            &batch(now, &[(b"a", Some(b"1")), (b"b", Some(b"1"))]),
+            &config,
+        )
+        .unwrap();
+        log.append(&batch(now, &[(b"a", Some(b"2"))]), &config)
+            .unwrap();
+        log.roll().unwrap();
+        // b deleted, but the tombstone is still within delete.retention.ms
+        log.append(&batch(now, &[(b"b", None)]), &config).unwrap();
+        log.roll().unwrap();
+
+        let (next_dirty, stats) = clean_log(&mut log, &config, 0, now).unwrap();
+        assert_eq!(next_dirty, 4);
+        assert_eq!(stats.records_removed, 2);
+        assert_eq!(
+            all_records(&log),
+            vec![
+                (2, b"a".to_vec(), Some(b"2".to_vec())),
+                (3, b"b".to_vec(), None)
+            ]
+        );
+
+        // once delete.retention.ms has passed since that first clean the
+        // tombstone goes too, on the next pass over new dirty data
+        log.append(&batch(now, &[(b"c", Some(b"1"))]), &config)
+            .unwrap();
+        log.roll().unwrap();
+        let later = now + config.delete_retention_ms + 1;
+        let (_, stats) = clean_log(&mut log, &config, next_dirty, later).unwrap();
+        assert_eq!(stats.records_removed, 1);
+        assert_eq!(
+            all_records(&log),
+            vec![
+                (2, b"a".to_vec(), Some(b"2".to_vec())),
+                (4, b"c".to_vec(), Some(b"1".to_vec()))
+            ]
+        );
+        assert_eq!(log.next_offset(), 5);
+
+        // reopening sees the rewritten segments
+        let reopened = PartitionLog::open(&root, "changelog", 0, 0).unwrap();
+        assert_eq!(reopened.next_offset(), 5);
+        let _ = std::fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn test_old_tombstone_kept_after_first_clean() {
+        let root = temp_root("cleaner-old-test");
+        let mut log = PartitionLog::open(&root, "changelog", 0, 0).unwrap();
+        let config = config::TopicConfig {
+            cleanup_policy: config::CleanupPolicy::parse("compact"),
+            min_cleanable_dirty_ratio: 0.0,
+            ..Default::default()
+        };
+        let now = log::now_ms();
+
+        // a delete produced with a timestamp far older than delete.retention.ms
+        let old = now - 10 * config.delete_retention_ms;
+        log.append(&batch(old, &[(b"a", Some(b"1"))]), &config)
+            .unwrap();
+        log.append(&batch(old, &[(b"a", None)]), &config).unwrap();
+        log.roll().unwrap();
+
+        let (next_dirty, _) = clean_log(&mut log, &config, 0, now).unwrap();
+        assert_eq!(all_records(&log), vec![(1, b"a".to_vec(), None)]);
+
+        // still there on a later pass within delete.retention.ms
+        log.append(&batch(now, &[(b"b", Some(b"1"))]), &config)
+            .unwrap();
+        log.roll().unwrap();
+        let (next_dirty, _) = clean_log(&mut log, &config, next_dirty, now + 1).unwrap();
+        assert_eq!(
+            all_records(&log),
+            vec![
+                (1, b"a".to_vec(), None),
+                (2, b"b".to_vec(), Some(b"1".to_vec()))
+            ]
+        );
+
+        log.append(&batch(now, &[(b"c", Some(b"1"))]), &config)
+            .unwrap();
+        log.roll().unwrap();
+        let later = now + config.delete_retention_ms + 1;
+        clean_log(&mut log, &config, next_dirty, later).unwrap();
+        assert_eq!(
+            all_records(&log),
+            vec![
+                (2, b"b".to_vec(), Some(b"1".to_vec())),
+                (3, b"c".to_vec(), Some(b"1".to_vec()))
+            ]
+        );
+        let _ = std::fs::remove_dir_all(&root);
+    }
+}
@@ -0,0 +1,74 @@
+// a blocking connection to another node, for the requests this node sends
+// itself: raft votes, epoch changes and metadata fetches between
+// controllers, and broker registrations with the active controller
+use crate::kafka::{apikey, errors, parser, writer};
+use std::io::{Cursor, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+const CLIENT_ID: &[u8] = b"raft-client";
+
+pub struct NodeClient {
+    stream: TcpStream,
+    correlation_id: i32,
+}
+
+impl NodeClient {
+    pub fn connect(host: &str, port: u16, timeout: Duration) -> errors::Result<Self> {
+        let address = (host, port).to_socket_addrs()?.next().ok_or_else(|| {
+            errors::KafkaErrors::RequestFailed(-1, format!("{host}:{port} doesn't resolve"))
+        })?;
+        let stream = TcpStream::connect_timeout(&address, timeout)?;
+        stream.set_read_timeout(Some(timeout))?;
+        stream.set_write_timeout(Some(timeout))?;
+        stream.set_nodelay(true)?;
+        Ok(Self {
+            stream,
+            correlation_id: 0,
+        })
+    }
+
+    // sends one request and returns the response body, past its header
+    pub fn send(
+        &mut self,
+        api_key: apikey::ApiKey,
+        version: u16,
+        body: &[u8],
+    ) -> errors::Result<Cursor<Vec<u8>>> {
+        self.correlation_id += 1;
+        let flexible = api_key.is_flexible(version);
+        let mut request = vec![];
+        writer::write_bytes(&mut request, &u16::from(api_key))?;
+        writer::write_bytes(&mut request, &version)?;
+        writer::write_bytes(&mut request, &self.correlation_id)?;
+        // the client id is a plain nullable string even in flexible headers
+        writer::write_nullable_string(&mut request, Some(CLIENT_ID), false)?;
+        writer::write_tagged_fields(&mut request, flexible)?;
+        request.extend_from_slice(body);
+
+        self.stream
+            .write_all(&(request.len() as i32).to_be_bytes())?;
+        self.stream.write_all(&request)?;
+
+        let mut size = [0_u8; 4];
+        self.stream.read_exact(&mut size)?;
+        let mut response = vec![0_u8; i32::from_be_bytes(size).max(0) as usize];
+        self.stream.read_exact(&mut response)?;
+        let mut response = Cursor::new(response);
+        let correlation_id = parser::read_int(&mut response)?;
+        if correlation_id != self.correlation_id {
+            return Err(errors::KafkaErrors::RequestFailed(
+                -1,
+                format!(
+                    "{api_key} response for correlation id {correlation_id}, expected {}",
+                    self.correlation_id
+                ),
+            )
+            .into());
+        }
+        if flexible {
+            parser::skip_tagged_fields(&mut response)?;
+        }
+        Ok(response)
+    }
+}
@@ -0,0 +1,250 @@
+// DescribeCluster (key 60): the cluster id, the controller and either the
+// brokers or the controllers. Brokers come from the registrations in the
+// metadata image and are only described on broker listeners, controllers
+// are the quorum voters and only described on the controller listener.
+//
+// https://kafka.apache.org/protocol.html#The_Messages_DescribeCluster
+use crate::kafka::metadata_api::{
+    self, MetadataResponseBroker, AUTHORIZED_OPERATIONS_OMITTED, CLUSTER_OPERATIONS,
+};
+use crate::kafka::{config, controller, errors, metadata, parser, writer};
+use std::io::{Read, Write};
+use std::sync::Arc;
+
+const UNSUPPORTED_ENDPOINT_TYPE: i16 = 119;
+
+pub const ENDPOINT_TYPE_BROKER: i8 = 1;
+pub const ENDPOINT_TYPE_CONTROLLER: i8 = 2;
+
+#[derive(Debug, Clone)]
+pub struct DescribeClusterRequest {
+    pub version: u16,
+    pub include_cluster_authorized_operations: bool,
+    pub endpoint_type: i8, // v1+
+}
+
+impl DescribeClusterRequest {
+    // always flexible
+    pub fn new<R: Read>(req: &mut R, version: u16) -> errors::Result<Self> {
+        let include_cluster_authorized_operations = parser::read_byte(req)? != 0;
+        let endpoint_type = if version >= 1 {
+            parser::read_byte(req)?
+        } else {
+            ENDPOINT_TYPE_BROKER
+        };
+        parser::skip_tagged_fields(req)?;
+        Ok(Self {
+            version,
+            include_cluster_authorized_operations,
+            endpoint_type,
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct DescribeClusterResponse {
+    pub version: u16,
+    pub throttle_time_ms: i32,
+    pub error_code: i16,
+    pub error_message: Option<String>,
+    pub endpoint_type: i8,
+    pub cluster_id: String,
+    pub controller_id: i32,
+    pub brokers: Vec<MetadataResponseBroker>,
+    pub cluster_authorized_operations: i32,
+}
+
+impl DescribeClusterResponse {
+    pub fn new(
+        request: &DescribeClusterRequest,
+        roles: config::ListenerRoles,
+        metadata: &Arc<metadata::MetadataCache>,
+        controller: &Arc<controller::Controller>,
+    ) -> Self {
+        let (error_code, error_message, brokers) = match request.endpoint_type {
+            ENDPOINT_TYPE_BROKER if roles.broker => {
+                (0, None, metadata_api::live_brokers(&metadata.image()))
+            }
+            // the quorum voters, at their controller listeners
+            ENDPOINT_TYPE_CONTROLLER if roles.controller => (
+                0,
+                None,
+                controller
+                    .raft()
+                    .voters()
+                    .iter()
+                    .map(|v| MetadataResponseBroker {
+                        node_id: v.id,
+                        host: v.host.as_bytes().to_vec(),
+                        port: v.port as i32,
+                        rack: None,
+                    })
+                    .collect(),
+            ),
+            ENDPOINT_TYPE_BROKER => (
+                UNSUPPORTED_ENDPOINT_TYPE,
+                Some("controllers can't describe brokers".to_string()),
+                vec![],
+            ),
+            ENDPOINT_TYPE_CONTROLLER => (
+                UNSUPPORTED_ENDPOINT_TYPE,
+                Some("brokers can't describe controllers".to_string()),
+                vec![],
+            ),
+            other => (
+                UNSUPPORTED_ENDPOINT_TYPE,
+                Some(format!("endpoint type {other} isn't supported")),
+                vec![],
+            ),
+        };
+        Self {
+            version: request.version,
+            throttle_time_ms: 0,
+            error_code,
+            error_message,
+            endpoint_type: request.endpoint_type,
+            cluster_id: controller.cluster_id().to_string(),
+            controller_id: controller.controller_id(),
+            brokers,
+            cluster_authorized_operations: if request.include_cluster_authorized_operations {
+                CLUSTER_OPERATIONS
+            } else {
+                AUTHORIZED_OPERATIONS_OMITTED
+            },
+        }
+    }
+
+    pub fn serialize<W: Write>(&self, resp: &mut W) -> errors::Result<()> {
+        writer::write_bytes(resp, &self.throttle_time_ms)?;
+        writer::write_bytes(resp, &self.error_code)?;
+        writer::write_nullable_string(
+            resp,
+            self.error_message.as_ref().map(String::as_bytes),
+            true,
+        )?;
+        if self.version >= 1 {
+            writer::write_bytes(resp, &self.endpoint_type)?;
+        }
+        writer::write_string(resp, self.cluster_id.as_bytes(), true)?;
+        writer::write_bytes(resp, &self.controller_id)?;
+        writer::write_array_len(resp, self.brokers.len(), true)?;
+        for b in &self.brokers {
+            writer::write_bytes(resp, &b.node_id)?;
+            writer::write_string(resp, &b.host, true)?;
+            writer::write_bytes(resp, &b.port)?;
+            writer::write_nullable_string(resp, b.rack.as_ref().map(String::as_bytes), true)?;
+            writer::write_tagged_fields(resp, true)?;
+        }
+        writer::write_bytes(resp, &self.cluster_authorized_operations)?;
+        writer::write_tagged_fields(resp, true)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kafka::testing::temp_root;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_listener_roles() {
+        let config = config::BrokerConfig {
+            listeners: vec![
+                config::Listener::parse("PLAINTEXT://:9092").unwrap(),
+                config::Listener::parse("CONTROLLER://:9093").unwrap(),
+            ],
+            ..Default::default()
+        };
+        let roles = |l: &config::Listener| {
+            let roles = config.listener_roles(l);
+            (roles.broker, roles.controller)
+        };
+        assert_eq!(roles(&config.listeners[0]), (true, false));
+        assert_eq!(roles(&config.listeners[1]), (false, true));
+
+        // a lone listener serves both
+        let config = config::BrokerConfig::default();
+        let roles = config.listener_roles(&config.listeners[0]);
+        assert!(roles.broker && roles.controller);
+    }
+
+    #[test]
+    fn test_describe_cluster() {
+        let root = temp_root("describe-cluster");
+        let metadata = Arc::new(metadata::MetadataCache::default());
+        let controller = Arc::new(controller::open_single_voter(&root, &metadata));
+        let request = |endpoint_type| DescribeClusterRequest {
+            version: 1,
+            include_cluster_authorized_operations: true,
+            endpoint_type,
+        };
+        let broker = config::ListenerRoles {
+            broker: true,
+            controller: false,
+        };
+        let quorum = config::ListenerRoles {
+            broker: false,
+            controller: true,
+        };
+
+        // each side only describes its own kind of node
+        for (endpoint_type, roles) in [
+            (ENDPOINT_TYPE_CONTROLLER, broker),
+            (ENDPOINT_TYPE_BROKER, quorum),
+            (3, broker),
+        ] {
+            let response = DescribeClusterResponse::new(
+                &request(endpoint_type),
+                roles,
+                &metadata,
+                &controller,
+            );
+            assert_eq!(response.error_code, UNSUPPORTED_ENDPOINT_TYPE);
+            assert!(response.error_message.is_some());
+            assert!(response.brokers.is_empty());
+        }
+
+        let response = DescribeClusterResponse::new(
+            &request(ENDPOINT_TYPE_CONTROLLER),
+            quorum,
+            &metadata,
+            &controller,
+        );
+        assert_eq!(response.error_code, 0);
+        let voter = &controller.raft().voters()[0];
+        let mut data = vec![];
+        response.serialize(&mut data).unwrap();
+        let r = &mut Cursor::new(&data[..]);
+        assert_eq!(parser::read_int(r).unwrap(), 0); // throttle
+        assert_eq!(parser::read_short(r).unwrap(), 0);
+        assert_eq!(parser::read_nullable_string(r, true).unwrap(), None);
+        assert_eq!(parser::read_byte(r).unwrap(), ENDPOINT_TYPE_CONTROLLER);
+        assert_eq!(parser::read_string(r, true).unwrap(), b"test");
+        assert_eq!(parser::read_int(r).unwrap(), 1); // controller id
+        assert_eq!(parser::read_array_len(r, true).unwrap(), Some(1));
+        assert_eq!(parser::read_int(r).unwrap(), voter.id);
+        assert_eq!(parser::read_string(r, true).unwrap(), voter.host.as_bytes());
+        assert_eq!(parser::read_int(r).unwrap(), voter.port as i32);
+        assert_eq!(parser::read_nullable_string(r, true).unwrap(), None); // rack
+        parser::skip_tagged_fields(r).unwrap();
+        assert_eq!(parser::read_int(r).unwrap(), CLUSTER_OPERATIONS);
+        parser::skip_tagged_fields(r).unwrap();
+        assert_eq!(r.position() as usize, data.len());
+
+        // v0 has no endpoint type
+        let response = DescribeClusterResponse {
+            version: 0,
+            ..DescribeClusterResponse::new(
+                &request(ENDPOINT_TYPE_BROKER),
+                broker,
+                &metadata,
+                &controller,
+            )
+        };
+        let mut v0 = vec![];
+        response.serialize(&mut v0).unwrap();
+        assert_eq!(v0[6..8], [0, 5]); // null error message, then "test"
+        let _ = std::fs::remove_dir_all(&root);
+    }
+}
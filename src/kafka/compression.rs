@@ -0,0 +1,198 @@
+// record batch compression
+//
+// The codec lives in the lowest 3 bits of the batch attributes. Only the
+// records section is compressed, the 61 byte batch header stays readable.
+// Snappy uses the xerial framing the java client writes, lz4 the standard
+// frame format with independent 64KB blocks.
+use crate::kafka::errors;
+use std::io::{Read, Write};
+
+const SNAPPY_XERIAL_MAGIC: [u8; 8] = [0x82, b'S', b'N', b'A', b'P', b'P', b'Y', 0];
+const SNAPPY_XERIAL_HEADER_LEN: usize = 16; // magic + version + compatible version
+const SNAPPY_XERIAL_BLOCK_SIZE: usize = 32 * 1024;
+const ZSTD_LEVEL: i32 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompressionType {
+    None,
+    Gzip,
+    Snappy,
+    Lz4,
+    Zstd,
+}
+
+impl CompressionType {
+    pub fn from_id(id: i16) -> errors::Result<Self> {
+        match id {
+            0 => Ok(Self::None),
+            1 => Ok(Self::Gzip),
+            2 => Ok(Self::Snappy),
+            3 => Ok(Self::Lz4),
+            4 => Ok(Self::Zstd),
+            i => Err(
+                errors::KafkaErrors::CorruptRecord(format!("unknown compression codec {i}")).into(),
+            ),
+        }
+    }
+
+    pub fn id(&self) -> i16 {
+        match self {
+            Self::None => 0,
+            Self::Gzip => 1,
+            Self::Snappy => 2,
+            Self::Lz4 => 3,
+            Self::Zstd => 4,
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "none" | "uncompressed" => Some(Self::None),
+            "gzip" => Some(Self::Gzip),
+            "snappy" => Some(Self::Snappy),
+            "lz4" => Some(Self::Lz4),
+            "zstd" => Some(Self::Zstd),
+            _ => None,
+        }
+    }
+
+    pub fn compress(&self, data: &[u8]) -> errors::Result<Vec<u8>> {
+        match self {
+            Self::None => Ok(data.to_vec()),
+            Self::Gzip => {
+                let mut encoder =
+                    flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(data)?;
+                Ok(encoder.finish()?)
+            }
+            Self::Snappy => snappy_xerial_compress(data),
+            Self::Lz4 => {
+                let info = lz4_flex::frame::FrameInfo::new()
+                    .block_mode(lz4_flex::frame::BlockMode::Independent)
+                    .block_size(lz4_flex::frame::BlockSize::Max64KB);
+                let mut encoder = lz4_flex::frame::FrameEncoder::with_frame_info(info, Vec::new());
+                encoder.write_all(data)?;
+                encoder
+                    .finish()
+                    .map_err(|e| errors::KafkaErrors::CorruptRecord(format!("lz4: {e}")).into())
+            }
+            Self::Zstd => Ok(zstd::stream::encode_all(data, ZSTD_LEVEL)?),
+        }
+    }
+
+    pub fn decompress(&self, data: &[u8]) -> errors::Result<Vec<u8>> {
+        let corrupt = |e: std::io::Error| -> anyhow::Error {
+            errors::KafkaErrors::CorruptRecord(format!("{self} decompression failed: {e}")).into()
+        };
+        let mut out = vec![];
+        match self {
+            Self::None => out.extend_from_slice(data),
+            Self::Gzip => {
+                flate2::read::GzDecoder::new(data)
+                    .read_to_end(&mut out)
+                    .map_err(corrupt)?;
+            }
+            Self::Snappy => out = snappy_xerial_decompress(data)?,
+            Self::Lz4 => {
+                lz4_flex::frame::FrameDecoder::new(data)
+                    .read_to_end(&mut out)
+                    .map_err(corrupt)?;
+            }
+            Self::Zstd => out = zstd::stream::decode_all(data).map_err(corrupt)?,
+        }
+        Ok(out)
+    }
+}
+
+impl std::fmt::Display for CompressionType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::None => write!(f, "none"),
+            Self::Gzip => write!(f, "gzip"),
+            Self::Snappy => write!(f, "snappy"),
+            Self::Lz4 => write!(f, "lz4"),
+            Self::Zstd => write!(f, "zstd"),
+        }
+    }
+}
+
+// the compression.type topic setting: keep whatever the producer used, or
+// have the broker (re)compress everything with one codec
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CompressionConfig {
+    #[default]
+    Producer,
+    Codec(CompressionType),
+}
+
+impl CompressionConfig {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.trim().to_lowercase().as_str() {
+            "producer" => Some(Self::Producer),
+            other => CompressionType::parse(other).map(Self::Codec),
+        }
+    }
+
+    // codec a batch produced with `used` gets stored with
+    pub fn target(&self, used: CompressionType) -> CompressionType {
+        match self {
+            Self::Producer => used,
+            Self::Codec(c) => *c,
+        }
+    }
+}
+
+impl std::fmt::Display for CompressionConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Producer => write!(f, "producer"),
+            Self::Codec(CompressionType::None) => write!(f, "uncompressed"),
+            Self::Codec(c) => write!(f, "{c}"),
+        }
+    }
+}
+
+fn snappy_xerial_compress(data: &[u8]) -> errors::Result<Vec<u8>> {
+    let mut out = SNAPPY_XERIAL_MAGIC.to_vec();
+    out.extend_from_slice(&1_i32.to_be_bytes()); // version
+    out.extend_from_slice(&1_i32.to_be_bytes()); // minimum compatible version
+    let mut encoder = snap::raw::Encoder::new();
+    for block in data.chunks(SNAPPY_XERIAL_BLOCK_SIZE) {
+        let compressed = encoder
+            .compress_vec(block)
+            .map_err(|e| errors::KafkaErrors::CorruptRecord(format!("snappy: {e}")))?;
+        out.extend_from_slice(&(compressed.len() as i32).to_be_bytes());
+        out.extend_from_slice(&compressed);
+    }
+    Ok(out)
+}
+
+// accepts xerial framed data as well as a bare snappy block, which is what
+// some non java clients send
+fn snappy_xerial_decompress(data: &[u8]) -> errors::Result<Vec<u8>> {
+    let corrupt = |e: snap::Error| {
+        errors::KafkaErrors::CorruptRecord(format!("snappy decompression failed: {e}"))
+    };
+    let mut decoder = snap::raw::Decoder::new();
+    if !data.starts_with(&SNAPPY_XERIAL_MAGIC) {
+        return Ok(decoder.decompress_vec(data).map_err(corrupt)?);
+    }
+    let mut out = vec![];
+    let mut rest = data.get(SNAPPY_XERIAL_HEADER_LEN..).unwrap_or_default();
+    while !rest.is_empty() {
+        let Some((len, block)) = rest.split_first_chunk::<4>() else {
+            return Err(
+                errors::KafkaErrors::CorruptRecord("truncated snappy block".to_string()).into(),
+            );
+        };
+        let len = i32::from_be_bytes(*len) as usize;
+        if len > block.len() {
+            return Err(
+                errors::KafkaErrors::CorruptRecord("truncated snappy block".to_string()).into(),
+            );
+        }
+        out.extend_from_slice(&decoder.decompress_vec(&block[..len]).map_err(corrupt)?);
+        rest = &block[len..];
+    }
+    Ok(out)
+}
@@ -0,0 +1,529 @@
+// broker configuration (server.properties) and topic level configuration,
+// as carried by ConfigRecords in the metadata log
+use crate::kafka::compression::CompressionConfig;
+use crate::kafka::errors;
+use std::collections::HashMap;
+
+pub const CLEANUP_POLICY_CONFIG: &str = "cleanup.policy";
+pub const COMPRESSION_TYPE_CONFIG: &str = "compression.type";
+pub const DELETE_RETENTION_MS_CONFIG: &str = "delete.retention.ms";
+pub const FLUSH_MESSAGES_CONFIG: &str = "flush.messages";
+pub const FLUSH_MS_CONFIG: &str = "flush.ms";
+pub const LOCAL_RETENTION_MS_CONFIG: &str = "local.retention.ms";
+pub const MAX_MESSAGE_BYTES_CONFIG: &str = "max.message.bytes";
+pub const MESSAGE_TIMESTAMP_AFTER_MAX_MS_CONFIG: &str = "message.timestamp.after.max.ms";
+pub const MESSAGE_TIMESTAMP_BEFORE_MAX_MS_CONFIG: &str = "message.timestamp.before.max.ms";
+pub const MESSAGE_TIMESTAMP_TYPE_CONFIG: &str = "message.timestamp.type";
+pub const MIN_COMPACTION_LAG_MS_CONFIG: &str = "min.compaction.lag.ms";
+pub const MIN_CLEANABLE_DIRTY_RATIO_CONFIG: &str = "min.cleanable.dirty.ratio";
+pub const REMOTE_STORAGE_ENABLE_CONFIG: &str = "remote.storage.enable";
+pub const RETENTION_MS_CONFIG: &str = "retention.ms";
+pub const SEGMENT_BYTES_CONFIG: &str = "segment.bytes";
+
+pub const AUTO_CREATE_TOPICS_ENABLE_CONFIG: &str = "auto.create.topics.enable";
+pub const CONTROLLER_LISTENER_NAMES_CONFIG: &str = "controller.listener.names";
+pub const CONTROLLER_QUORUM_VOTERS_CONFIG: &str = "controller.quorum.voters";
+pub const CONTROLLER_QUORUM_BOOTSTRAP_SERVERS_CONFIG: &str = "controller.quorum.bootstrap.servers";
+pub const CONTROLLER_QUORUM_ELECTION_TIMEOUT_MS_CONFIG: &str =
+    "controller.quorum.election.timeout.ms";
+pub const CONTROLLER_QUORUM_ELECTION_BACKOFF_MAX_MS_CONFIG: &str =
+    "controller.quorum.election.backoff.max.ms";
+pub const CONTROLLER_QUORUM_FETCH_TIMEOUT_MS_CONFIG: &str = "controller.quorum.fetch.timeout.ms";
+pub const DEFAULT_REPLICATION_FACTOR_CONFIG: &str = "default.replication.factor";
+pub const LISTENERS_CONFIG: &str = "listeners";
+pub const LOG_DIRS_CONFIG: &str = "log.dirs";
+pub const LOG_CLEANER_BACKOFF_MS_CONFIG: &str = "log.cleaner.backoff.ms";
+pub const LOG_FLUSH_SCHEDULER_INTERVAL_MS_CONFIG: &str = "log.flush.scheduler.interval.ms";
+// not a stock kafka setting: fsync the log before answering acks=-1 produces
+pub const LOG_FLUSH_ON_ACKS_ALL_CONFIG: &str = "log.flush.on.acks.all";
+pub const NODE_ID_CONFIG: &str = "node.id";
+pub const NUM_PARTITIONS_CONFIG: &str = "num.partitions";
+pub const METADATA_LOG_DIR_CONFIG: &str = "metadata.log.dir";
+pub const METADATA_LOG_MAX_RECORD_BYTES_BETWEEN_SNAPSHOTS_CONFIG: &str =
+    "metadata.log.max.record.bytes.between.snapshots";
+pub const METADATA_LOG_SEGMENT_BYTES_CONFIG: &str = "metadata.log.segment.bytes";
+pub const METADATA_MAX_RETENTION_BYTES_CONFIG: &str = "metadata.max.retention.bytes";
+pub const REMOTE_LOG_STORAGE_SYSTEM_ENABLE_CONFIG: &str = "remote.log.storage.system.enable";
+pub const REMOTE_LOG_MANAGER_TASK_INTERVAL_MS_CONFIG: &str = "remote.log.manager.task.interval.ms";
+// not a stock kafka setting: where the filesystem remote storage keeps its data
+pub const REMOTE_LOG_STORAGE_DIR_CONFIG: &str = "remote.log.storage.dir";
+
+// local.retention.ms value meaning "same as retention.ms"
+pub const LOCAL_RETENTION_MS_SAME_AS_RETENTION: i64 = -2;
+
+#[derive(Debug, Clone)]
+pub struct BrokerConfig {
+    pub node_id: i32,
+    pub auto_create_topics_enable: bool,
+    pub default_replication_factor: i16,
+    pub num_partitions: i32,
+    pub listeners: Vec<Listener>,
+    pub controller_listener_names: Vec<String>,
+    // empty runs a quorum of this node alone, unless the voters are in the
+    // metadata log
+    pub controller_quorum_voters: Vec<QuorumVoter>,
+    // where a controller of a dynamic quorum looks for the leader
+    pub controller_quorum_bootstrap_servers: Vec<(String, u16)>,
+    pub controller_quorum_election_timeout_ms: u64,
+    pub controller_quorum_election_backoff_max_ms: u64,
+    pub controller_quorum_fetch_timeout_ms: u64,
+    pub log_dirs: Vec<String>,
+    pub log_cleaner_backoff_ms: u64,
+    pub log_flush_scheduler_interval_ms: u64,
+    pub log_flush_on_acks_all: bool,
+    pub metadata_log_dir: Option<String>, // first of log_dirs if unset
+    pub metadata_log_max_record_bytes_between_snapshots: u64,
+    pub metadata_log_segment_bytes: u64,
+    // segments a snapshot covers are dropped while the log is bigger
+    pub metadata_max_retention_bytes: u64,
+    pub remote_log_storage_system_enable: bool,
+    pub remote_log_manager_task_interval_ms: u64,
+    pub remote_log_storage_dir: String,
+}
+
+impl Default for BrokerConfig {
+    fn default() -> Self {
+        Self {
+            node_id: 1,
+            auto_create_topics_enable: true,
+            default_replication_factor: 1,
+            num_partitions: 1,
+            listeners: vec![Listener {
+                name: "PLAINTEXT".to_string(),
+                host: String::new(),
+                port: 9092,
+            }],
+            controller_listener_names: vec!["CONTROLLER".to_string()],
+            controller_quorum_voters: vec![],
+            controller_quorum_bootstrap_servers: vec![],
+            controller_quorum_election_timeout_ms: 1_000,
+            controller_quorum_election_backoff_max_ms: 1_000,
+            controller_quorum_fetch_timeout_ms: 2_000,
+            log_dirs: vec![super::LOG_DIR.to_string()],
+            log_cleaner_backoff_ms: 15_000,
+            log_flush_scheduler_interval_ms: 1_000,
+            log_flush_on_acks_all: false,
+            metadata_log_dir: None,
+            metadata_log_max_record_bytes_between_snapshots: 20 * 1024 * 1024,
+            metadata_log_segment_bytes: 1024 * 1024 * 1024,
+            metadata_max_retention_bytes: 100 * 1024 * 1024,
+            remote_log_storage_system_enable: false,
+            remote_log_manager_task_interval_ms: 30_000,
+            remote_log_storage_dir: "/tmp/kraft-remote-storage".to_string(),
+        }
+    }
+}
+
+impl BrokerConfig {
+    // loads a java style properties file, e.g. the server.properties the
+    // broker gets started with. No file means all defaults.
+    pub fn load(path: Option<&str>) -> errors::Result<Self> {
+        let Some(path) = path else {
+            return Ok(Self::default());
+        };
+        let contents = std::fs::read_to_string(path)?;
+        Ok(Self::from_props(&parse_properties(&contents)))
+    }
+
+    pub fn from_props(props: &HashMap<String, String>) -> Self {
+        let mut config = Self::default();
+        props.iter().for_each(|(k, v)| match k.as_str() {
+            LOG_DIRS_CONFIG | "log.dir" => {
+                config.log_dirs = v
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|d| !d.is_empty())
+                    .map(String::from)
+                    .collect();
+            }
+            NODE_ID_CONFIG => parse_into(v, &mut config.node_id),
+            AUTO_CREATE_TOPICS_ENABLE_CONFIG => {
+                parse_into(v, &mut config.auto_create_topics_enable);
+            }
+            DEFAULT_REPLICATION_FACTOR_CONFIG => {
+                parse_into(v, &mut config.default_replication_factor);
+            }
+            NUM_PARTITIONS_CONFIG => parse_into(v, &mut config.num_partitions),
+            LISTENERS_CONFIG => config.listeners = parse_list(v, Listener::parse),
+            CONTROLLER_LISTENER_NAMES_CONFIG => {
+                config.controller_listener_names = parse_list(v, |n| Some(n.to_string()));
+            }
+            CONTROLLER_QUORUM_VOTERS_CONFIG => {
+                config.controller_quorum_voters = parse_list(v, QuorumVoter::parse);
+            }
+            CONTROLLER_QUORUM_BOOTSTRAP_SERVERS_CONFIG => {
+                config.controller_quorum_bootstrap_servers = parse_list(v, |s| {
+                    let (host, port) = s.rsplit_once(':')?;
+                    Some((host.to_string(), port.parse().ok()?))
+                });
+            }
+            CONTROLLER_QUORUM_ELECTION_TIMEOUT_MS_CONFIG => {
+                parse_into(v, &mut config.controller_quorum_election_timeout_ms);
+            }
+            CONTROLLER_QUORUM_ELECTION_BACKOFF_MAX_MS_CONFIG => {
+                parse_into(v, &mut config.controller_quorum_election_backoff_max_ms);
+            }
+            CONTROLLER_QUORUM_FETCH_TIMEOUT_MS_CONFIG => {
+                parse_into(v, &mut config.controller_quorum_fetch_timeout_ms);
+            }
+            LOG_CLEANER_BACKOFF_MS_CONFIG => parse_into(v, &mut config.log_cleaner_backoff_ms),
+            LOG_FLUSH_SCHEDULER_INTERVAL_MS_CONFIG => {
+                parse_into(v, &mut config.log_flush_scheduler_interval_ms);
+            }
+            LOG_FLUSH_ON_ACKS_ALL_CONFIG => parse_into(v, &mut config.log_flush_on_acks_all),
+            METADATA_LOG_DIR_CONFIG => config.metadata_log_dir = Some(v.clone()),
+            METADATA_LOG_MAX_RECORD_BYTES_BETWEEN_SNAPSHOTS_CONFIG => {
+                parse_into(
+                    v,
+                    &mut config.metadata_log_max_record_bytes_between_snapshots,
+                );
+            }
+            METADATA_LOG_SEGMENT_BYTES_CONFIG => {
+                parse_into(v, &mut config.metadata_log_segment_bytes);
+            }
+            METADATA_MAX_RETENTION_BYTES_CONFIG => {
+                parse_into(v, &mut config.metadata_max_retention_bytes);
+            }
+            REMOTE_LOG_STORAGE_SYSTEM_ENABLE_CONFIG => {
+                parse_into(v, &mut config.remote_log_storage_system_enable);
+            }
+            REMOTE_LOG_MANAGER_TASK_INTERVAL_MS_CONFIG => {
+                parse_into(v, &mut config.remote_log_manager_task_interval_ms);
+            }
+            REMOTE_LOG_STORAGE_DIR_CONFIG => config.remote_log_storage_dir = v.clone(),
+            _ => (),
+        });
+        if config.log_dirs.is_empty() {
+            config.log_dirs = Self::default().log_dirs;
+        }
+        config
+    }
+
+    // where the __cluster_metadata log lives
+    pub fn metadata_log_dir(&self) -> &str {
+        self.metadata_log_dir
+            .as_deref()
+            .unwrap_or(&self.log_dirs[0])
+    }
+
+    // the listener other controllers reach this one on, the first one if
+    // none is named in controller.listener.names
+    pub fn controller_listener(&self) -> Option<&Listener> {
+        self.listeners
+            .iter()
+            .find(|l| self.controller_listener_names.contains(&l.name))
+            .or(self.listeners.first())
+    }
+
+    // the listeners clients use, as opposed to the controller ones
+    pub fn broker_listeners(&self) -> impl Iterator<Item = &Listener> {
+        self.listeners
+            .iter()
+            .filter(|l| !self.controller_listener_names.contains(&l.name))
+    }
+
+    // which side of the node answers requests arriving on a listener
+    pub fn listener_roles(&self, listener: &Listener) -> ListenerRoles {
+        ListenerRoles {
+            broker: self.broker_listeners().any(|l| l == listener),
+            controller: self.controller_listener() == Some(listener),
+        }
+    }
+}
+
+// a listener serves the broker, the controller or, when the controller
+// listener is also the only one, both
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ListenerRoles {
+    pub broker: bool,
+    pub controller: bool,
+}
+
+// a listener as in listeners=PLAINTEXT://localhost:9092. An empty host
+// binds every interface.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Listener {
+    pub name: String,
+    pub host: String,
+    pub port: u16,
+}
+
+impl Listener {
+    pub fn parse(s: &str) -> Option<Self> {
+        let (name, address) = s.split_once("://")?;
+        let (host, port) = address.rsplit_once(':')?;
+        Some(Self {
+            name: name.to_string(),
+            host: host.to_string(),
+            port: port.parse().ok()?,
+        })
+    }
+
+    pub fn bind_address(&self) -> String {
+        let host = if self.host.is_empty() {
+            "0.0.0.0"
+        } else {
+            &self.host
+        };
+        format!("{host}:{}", self.port)
+    }
+
+    // the host other nodes and clients are told to connect to
+    pub fn advertised_host(&self) -> &str {
+        if self.host.is_empty() {
+            "localhost"
+        } else {
+            &self.host
+        }
+    }
+}
+
+// a controller quorum member as in controller.quorum.voters=1@localhost:9093
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QuorumVoter {
+    pub id: i32,
+    pub host: String,
+    pub port: u16,
+}
+
+impl QuorumVoter {
+    pub fn parse(s: &str) -> Option<Self> {
+        let (id, address) = s.split_once('@')?;
+        let (host, port) = address.rsplit_once(':')?;
+        Some(Self {
+            id: id.parse().ok()?,
+            host: host.to_string(),
+            port: port.parse().ok()?,
+        })
+    }
+}
+
+pub fn parse_properties(contents: &str) -> HashMap<String, String> {
+    contents
+        .lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty() && !l.starts_with('#') && !l.starts_with('!'))
+        .filter_map(|l| l.split_once(['=', ':']))
+        .map(|(k, v)| (k.trim().to_string(), v.trim().to_string()))
+        .collect()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CleanupPolicy {
+    pub compact: bool,
+    pub delete: bool,
+}
+
+impl Default for CleanupPolicy {
+    fn default() -> Self {
+        Self {
+            compact: false,
+            delete: true,
+        }
+    }
+}
+
+impl CleanupPolicy {
+    // accepts "delete", "compact" and any comma separated combination of the two
+    pub fn parse(s: &str) -> Self {
+        let mut policy = Self {
+            compact: false,
+            delete: false,
+        };
+        s.split(',').map(str::trim).for_each(|p| match p {
+            "compact" => policy.compact = true,
+            "delete" => policy.delete = true,
+            other => println!("ignoring unknown cleanup policy: {other}"),
+        });
+        if !policy.compact && !policy.delete {
+            return Self::default();
+        }
+        policy
+    }
+}
+
+impl std::fmt::Display for CleanupPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match (self.compact, self.delete) {
+            (true, true) => write!(f, "compact,delete"),
+            (true, false) => write!(f, "compact"),
+            _ => write!(f, "delete"),
+        }
+    }
+}
+
+// whose clock record timestamps come from: the producer's, or the broker's
+// at the time the batch is appended
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TimestampType {
+    #[default]
+    CreateTime,
+    LogAppendTime,
+}
+
+impl TimestampType {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s.trim() {
+            "CreateTime" => Some(Self::CreateTime),
+            "LogAppendTime" => Some(Self::LogAppendTime),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for TimestampType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::CreateTime => write!(f, "CreateTime"),
+            Self::LogAppendTime => write!(f, "LogAppendTime"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TopicConfig {
+    pub cleanup_policy: CleanupPolicy,
+    pub compression_type: CompressionConfig,
+    pub delete_retention_ms: i64,
+    pub flush_messages: u64,
+    pub flush_ms: i64,
+    pub local_retention_ms: i64,
+    pub max_message_bytes: u64,
+    pub message_timestamp_after_max_ms: i64,
+    pub message_timestamp_before_max_ms: i64,
+    pub message_timestamp_type: TimestampType,
+    pub min_compaction_lag_ms: i64,
+    pub min_cleanable_dirty_ratio: f64,
+    pub remote_storage_enable: bool,
+    pub retention_ms: i64,
+    pub segment_bytes: u64,
+}
+
+impl Default for TopicConfig {
+    fn default() -> Self {
+        Self {
+            cleanup_policy: CleanupPolicy::default(),
+            compression_type: CompressionConfig::default(),
+            delete_retention_ms: 86_400_000,
+            flush_messages: i64::MAX as u64,
+            flush_ms: i64::MAX,
+            local_retention_ms: LOCAL_RETENTION_MS_SAME_AS_RETENTION,
+            max_message_bytes: 1_048_588,
+            message_timestamp_after_max_ms: 3_600_000,
+            message_timestamp_before_max_ms: i64::MAX,
+            message_timestamp_type: TimestampType::default(),
+            min_compaction_lag_ms: 0,
+            min_cleanable_dirty_ratio: 0.5,
+            remote_storage_enable: false,
+            retention_ms: 604_800_000,
+            segment_bytes: 1_073_741_824,
+        }
+    }
+}
+
+impl TopicConfig {
+    // builds the effective config, falling back to defaults for anything
+    // missing or malformed
+    pub fn from_props(props: &HashMap<String, String>) -> Self {
+        let mut config = Self::default();
+        props.iter().for_each(|(k, v)| match k.as_str() {
+            CLEANUP_POLICY_CONFIG => config.cleanup_policy = CleanupPolicy::parse(v),
+            COMPRESSION_TYPE_CONFIG => match CompressionConfig::parse(v) {
+                Some(c) => config.compression_type = c,
+                None => println!("ignoring unknown compression.type: {v}"),
+            },
+            DELETE_RETENTION_MS_CONFIG => {
+                parse_into(v, &mut config.delete_retention_ms);
+            }
+            FLUSH_MESSAGES_CONFIG => parse_into(v, &mut config.flush_messages),
+            FLUSH_MS_CONFIG => parse_into(v, &mut config.flush_ms),
+            LOCAL_RETENTION_MS_CONFIG => parse_into(v, &mut config.local_retention_ms),
+            MAX_MESSAGE_BYTES_CONFIG => parse_into(v, &mut config.max_message_bytes),
+            MESSAGE_TIMESTAMP_AFTER_MAX_MS_CONFIG => {
+                parse_into(v, &mut config.message_timestamp_after_max_ms);
+            }
+            MESSAGE_TIMESTAMP_BEFORE_MAX_MS_CONFIG => {
+                parse_into(v, &mut config.message_timestamp_before_max_ms);
+            }
+            MESSAGE_TIMESTAMP_TYPE_CONFIG => match TimestampType::parse(v) {
+                Some(t) => config.message_timestamp_type = t,
+                None => println!("ignoring unknown message.timestamp.type: {v}"),
+            },
+            MIN_COMPACTION_LAG_MS_CONFIG => {
+                parse_into(v, &mut config.min_compaction_lag_ms);
+            }
+            MIN_CLEANABLE_DIRTY_RATIO_CONFIG => {
+                parse_into(v, &mut config.min_cleanable_dirty_ratio);
+            }
+            REMOTE_STORAGE_ENABLE_CONFIG => parse_into(v, &mut config.remote_storage_enable),
+            RETENTION_MS_CONFIG => parse_into(v, &mut config.retention_ms),
+            SEGMENT_BYTES_CONFIG => parse_into(v, &mut config.segment_bytes),
+            _ => (),
+        });
+        config
+    }
+
+    // why a topic config can't be set, if it can't: a name that isn't one
+    // or a value that doesn't parse. Unlike from_props nothing is ignored.
+    pub fn validate(name: &str, value: &str) -> Result<(), String> {
+        let v = value.trim();
+        let valid = match name {
+            CLEANUP_POLICY_CONFIG => v
+                .split(',')
+                .map(str::trim)
+                .all(|p| p == "compact" || p == "delete"),
+            COMPRESSION_TYPE_CONFIG => CompressionConfig::parse(v).is_some(),
+            MESSAGE_TIMESTAMP_TYPE_CONFIG => TimestampType::parse(v).is_some(),
+            DELETE_RETENTION_MS_CONFIG
+            | FLUSH_MS_CONFIG
+            | LOCAL_RETENTION_MS_CONFIG
+            | MESSAGE_TIMESTAMP_AFTER_MAX_MS_CONFIG
+            | MESSAGE_TIMESTAMP_BEFORE_MAX_MS_CONFIG
+            | MIN_COMPACTION_LAG_MS_CONFIG
+            | RETENTION_MS_CONFIG => v.parse::<i64>().is_ok(),
+            FLUSH_MESSAGES_CONFIG | MAX_MESSAGE_BYTES_CONFIG | SEGMENT_BYTES_CONFIG => {
+                v.parse::<u64>().is_ok()
+            }
+            MIN_CLEANABLE_DIRTY_RATIO_CONFIG => {
+                v.parse::<f64>().is_ok_and(|r| (0.0..=1.0).contains(&r))
+            }
+            REMOTE_STORAGE_ENABLE_CONFIG => v.parse::<bool>().is_ok(),
+            _ => return Err(format!("unknown topic config {name}")),
+        };
+        if !valid {
+            return Err(format!("invalid value {value} for topic config {name}"));
+        }
+        Ok(())
+    }
+
+    // how long segments stay on local disk once they are in the remote tier
+    pub fn effective_local_retention_ms(&self) -> i64 {
+        if self.local_retention_ms == LOCAL_RETENTION_MS_SAME_AS_RETENTION {
+            self.retention_ms
+        } else {
+            self.local_retention_ms
+        }
+    }
+}
+
+// a comma separated list, malformed entries left out
+fn parse_list<T>(v: &str, parse: impl Fn(&str) -> Option<T>) -> Vec<T> {
+    v.split(',')
+        .map(str::trim)
+        .filter(|e| !e.is_empty())
+        .filter_map(|e| {
+            let parsed = parse(e);
+            if parsed.is_none() {
+                println!("ignoring malformed list entry: {e}");
+            }
+            parsed
+        })
+        .collect()
+}
+
+fn parse_into<T: std::str::FromStr>(v: &str, field: &mut T) {
+    match v.trim().parse::<T>() {
+        Ok(parsed) => *field = parsed,
+        Err(_) => println!("ignoring malformed config value: {v}"),
+    }
+}
@@ -0,0 +1,584 @@
+// the controller: the one writer of __cluster_metadata, on whichever node
+// leads the raft quorum. Every change is a batch of metadata records
+// appended through raft, and once a majority has it, applied through the
+// listener so the caller returns with the new image already published.
+// Changes are serialized on a lock, so each one is checked against an image
+// holding all earlier ones. Other nodes turn changes down with
+// NOT_CONTROLLER.
+use crate::kafka::metadata::MetadataCache;
+use crate::kafka::metadata_listener::MetadataListener;
+use crate::kafka::metadata_records::{
+    BrokerEndpoint, KafkaRecordBrokerEpochRecord, KafkaRecordConfigRecord,
+    KafkaRecordPartitionRecord, KafkaRecordRegisterBrokerRecord, KafkaRecordRemoveTopicRecord,
+    KafkaRecordTopicRecord, KafkaRecordValue, CONFIG_RESOURCE_TOPIC,
+};
+use crate::kafka::{config, errors, raft, storage};
+use std::collections::BTreeSet;
+use std::fs::File;
+use std::io::Read;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+// error codes of the checks done before anything is written
+pub const UNKNOWN_SERVER_ERROR: i16 = -1;
+pub const UNKNOWN_TOPIC_OR_PARTITION: i16 = 3;
+pub const INVALID_TOPIC_EXCEPTION: i16 = 17;
+pub const TOPIC_ALREADY_EXISTS: i16 = 36;
+pub const INVALID_PARTITIONS: i16 = 37;
+pub const INVALID_REPLICATION_FACTOR: i16 = 38;
+pub const INVALID_REPLICA_ASSIGNMENT: i16 = 39;
+pub const INVALID_CONFIG: i16 = 40;
+
+const MAX_TOPIC_NAME_LEN: usize = 249;
+
+// a topic to create, from a partition count and replication factor or,
+// with both at -1, the replicas of each partition
+#[derive(Debug, Clone, Default)]
+pub struct NewTopic {
+    pub name: String,
+    pub num_partitions: i32,
+    pub replication_factor: i16,
+    pub assignments: Vec<(i32, Vec<i32>)>,
+    pub configs: Vec<(String, String)>,
+}
+
+pub struct Controller {
+    node_id: i32,
+    cluster_id: String,
+    // topics created on first use, with the broker's defaults
+    auto_create_topics_enable: bool,
+    num_partitions: i32,
+    default_replication_factor: i16,
+    // what the first leader writes to an empty log, from bootstrap.checkpoint
+    bootstrap: Vec<KafkaRecordValue>,
+    // held from the checks of a change until it's applied
+    lock: Mutex<()>,
+    raft: Arc<raft::RaftClient>,
+    metadata: Arc<MetadataCache>,
+    listener: Arc<Mutex<MetadataListener>>,
+}
+
+impl Controller {
+    pub fn open(
+        config: &config::BrokerConfig,
+        cluster_id: String,
+        metadata: Arc<MetadataCache>,
+        listener: Arc<Mutex<MetadataListener>>,
+        raft: Arc<raft::RaftClient>,
+    ) -> errors::Result<Self> {
+        Ok(Self {
+            node_id: config.node_id,
+            cluster_id,
+            auto_create_topics_enable: config.auto_create_topics_enable,
+            num_partitions: config.num_partitions,
+            default_replication_factor: config.default_replication_factor,
+            bootstrap: storage::bootstrap_records(config)?,
+            lock: Mutex::new(()),
+            raft,
+            metadata,
+            listener,
+        })
+    }
+
+    pub fn cluster_id(&self) -> &str {
+        &self.cluster_id
+    }
+
+    pub fn raft(&self) -> &Arc<raft::RaftClient> {
+        &self.raft
+    }
+
+    // the node leading the quorum, -1 while there's an election
+    pub fn controller_id(&self) -> i32 {
+        self.raft.leader_id().unwrap_or(-1)
+    }
+
+    // registers a broker, unfenced right away as there are no heartbeats
+    // to wait for. The epoch is the offset of the registration.
+    pub fn register_broker(
+        &self,
+        broker_id: i32,
+        incarnation_id: [u8; 16],
+        end_points: Vec<BrokerEndpoint>,
+        log_dirs: Vec<[u8; 16]>,
+    ) -> errors::Result<i64> {
+        let _guard = self.begin()?;
+        let last = self.append_with(|offset| {
+            let epoch = offset as i64;
+            let registration = KafkaRecordRegisterBrokerRecord {
+                version: if log_dirs.is_empty() { 1 } else { 3 },
+                broker_id,
+                incarnation_id,
+                broker_epoch: epoch,
+                end_points,
+                fenced: true,
+                log_dirs,
+                ..Default::default()
+            };
+            let unfence = KafkaRecordBrokerEpochRecord {
+                version: 0,
+                id: broker_id,
+                epoch,
+            };
+            Ok(vec![
+                KafkaRecordValue::KafkaRecordRegisterBrokerType(registration),
+                KafkaRecordValue::KafkaRecordUnfenceBrokerType(unfence),
+            ])
+        })?;
+        // the registration is the batch's first record, the unfence the last
+        Ok(last.map_or(-1, |last| last as i64 - 1))
+    }
+
+    // creates a topic a client asked for without creating it first, None
+    // if auto.create.topics.enable is off
+    pub fn auto_create_topic(&self, name: &str) -> errors::Result<Option<u128>> {
+        if !self.auto_create_topics_enable {
+            return Ok(None);
+        }
+        let topic = NewTopic {
+            name: name.to_string(),
+            num_partitions: self.num_partitions,
+            replication_factor: self.default_replication_factor,
+            ..Default::default()
+        };
+        let (id, _) = self.create_topic(&topic, false)?;
+        println!("auto created topic {name}");
+        Ok(Some(id))
+    }
+
+    // creates a topic with its config overrides, the id and the replicas
+    // of each partition. With validate_only nothing is written, the topic
+    // is only checked the same way.
+    pub fn create_topic(
+        &self,
+        topic: &NewTopic,
+        validate_only: bool,
+    ) -> errors::Result<(u128, Vec<Vec<i32>>)> {
+        let name = topic.name.as_str();
+        validate_topic_name(name)?;
+        if !topic.assignments.is_empty()
+            && (topic.num_partitions != -1 || topic.replication_factor != -1)
+        {
+            return Err(request_failed(
+                raft::INVALID_REQUEST,
+                "both a partition count or replication factor and an assignment were given"
+                    .to_string(),
+            ));
+        }
+        for (key, value) in &topic.configs {
+            config::TopicConfig::validate(key, value)
+                .map_err(|message| request_failed(INVALID_CONFIG, message))?;
+        }
+        let _guard = self.begin()?;
+        let image = self.metadata.image();
+        if image.topic_by_name(name).is_some() {
+            return Err(request_failed(
+                TOPIC_ALREADY_EXISTS,
+                format!("topic '{name}' already exists"),
+            ));
+        }
+        // '.' and '_' end up the same in metric names
+        let normalized = name.replace('.', "_");
+        if let Some(other) = image
+            .topic_ids
+            .keys()
+            .find(|other| other.replace('.', "_") == normalized)
+        {
+            return Err(request_failed(
+                INVALID_TOPIC_EXCEPTION,
+                format!("topic '{name}' collides with existing topic '{other}'"),
+            ));
+        }
+        // brokers that registered and may hold replicas, just ourselves
+        // until any did
+        let mut brokers = image
+            .brokers
+            .values()
+            .filter(|b| !b.fenced)
+            .map(|b| b.id)
+            .collect::<Vec<_>>();
+        if brokers.is_empty() {
+            brokers.push(self.node_id);
+        }
+        let assignment = if topic.assignments.is_empty() {
+            self.assign_replicas(topic, &brokers)?
+        } else {
+            validate_assignment(&topic.assignments, &brokers)?
+        };
+        // nothing is created, so there's no id either
+        if validate_only {
+            return Ok((0, assignment));
+        }
+        let topic_uuid = loop {
+            let uuid = random_uuid()?;
+            if image.topic(u128::from_be_bytes(uuid)).is_none() {
+                break uuid;
+            }
+        };
+        let mut records = vec![KafkaRecordValue::KafkaRecordTopicRecordType(
+            KafkaRecordTopicRecord {
+                version: 0,
+                topic_name: name.as_bytes().to_vec(),
+                topic_uuid,
+            },
+        )];
+        for (partition_id, replicas) in assignment.iter().enumerate() {
+            records.push(KafkaRecordValue::KafkaRecordPartitionType(
+                KafkaRecordPartitionRecord {
+                    partition_id: partition_id as i32,
+                    topic_uuid,
+                    replicas: replicas.clone(),
+                    isr: replicas.clone(),
+                    leader: replicas[0],
+                    leader_epoch: 0,
+                    partition_epoch: 0,
+                    ..Default::default()
+                },
+            ));
+        }
+        for (key, value) in &topic.configs {
+            records.push(config_record(name, key, Some(value)));
+        }
+        self.append(&records)?;
+        Ok((u128::from_be_bytes(topic_uuid), assignment))
+    }
+
+    // spreads partitions round robin over the brokers, the first one led
+    // by this node. -1 stands for the broker's default.
+    fn assign_replicas(&self, topic: &NewTopic, brokers: &[i32]) -> errors::Result<Vec<Vec<i32>>> {
+        let num_partitions = match topic.num_partitions {
+            -1 => self.num_partitions,
+            n => n,
+        };
+        let replication_factor = match topic.replication_factor {
+            -1 => self.default_replication_factor,
+            n => n,
+        };
+        if num_partitions <= 0 {
+            return Err(request_failed(
+                INVALID_PARTITIONS,
+                format!("number of partitions must be larger than 0, not {num_partitions}"),
+            ));
+        }
+        if replication_factor <= 0 || replication_factor as usize > brokers.len() {
+            return Err(request_failed(
+                INVALID_REPLICATION_FACTOR,
+                format!(
+                    "replication factor {replication_factor} can't be reached with {} brokers",
+                    brokers.len()
+                ),
+            ));
+        }
+        let start = brokers.iter().position(|b| *b == self.node_id).unwrap_or(0);
+        Ok((0..num_partitions as usize)
+            .map(|p| {
+                (0..replication_factor as usize)
+                    .map(|r| brokers[(start + p + r) % brokers.len()])
+                    .collect()
+            })
+            .collect())
+    }
+
+    // removes a topic and, with it, its config overrides
+    pub fn delete_topic(&self, name: &str) -> errors::Result<u128> {
+        let _guard = self.begin()?;
+        let Some(id) = self.metadata.image().topic_id(name) else {
+            return Err(request_failed(
+                UNKNOWN_TOPIC_OR_PARTITION,
+                format!("topic '{name}' doesn't exist"),
+            ));
+        };
+        self.append(&[KafkaRecordValue::KafkaRecordRemoveTopicType(
+            KafkaRecordRemoveTopicRecord {
+                version: 0,
+                topic_uuid: id.to_be_bytes(),
+            },
+        )])?;
+        Ok(id)
+    }
+
+    // sets (or with None, removes) config overrides of a topic. With
+    // validate_only nothing is written, the changes are only checked.
+    pub fn alter_topic_configs(
+        &self,
+        name: &str,
+        configs: &[(String, Option<String>)],
+        validate_only: bool,
+    ) -> errors::Result<()> {
+        for (key, value) in configs {
+            if let Some(value) = value {
+                config::TopicConfig::validate(key, value)
+                    .map_err(|message| request_failed(INVALID_CONFIG, message))?;
+            }
+        }
+        let _guard = self.begin()?;
+        if self.metadata.image().topic_by_name(name).is_none() {
+            return Err(request_failed(
+                UNKNOWN_TOPIC_OR_PARTITION,
+                format!("topic '{name}' doesn't exist"),
+            ));
+        }
+        if validate_only {
+            return Ok(());
+        }
+        let records = configs
+            .iter()
+            .map(|(key, value)| config_record(name, key, value.as_ref()))
+            .collect::<Vec<_>>();
+        self.append(&records)
+    }
+
+    // takes the change lock as the active controller with an image that
+    // has everything committed so far. The first controller of a cluster
+    // writes the bootstrap records before anything else.
+    fn begin(&self) -> errors::Result<MutexGuard<'_, ()>> {
+        let guard = self.lock.lock().unwrap();
+        self.raft.wait_for_leadership()?;
+        self.listener.lock().unwrap().poll()?;
+        let image = self.metadata.image();
+        if !image
+            .features
+            .contains_key(storage::METADATA_VERSION_FEATURE)
+            && !self.bootstrap.is_empty()
+        {
+            self.append(&self.bootstrap)?;
+            println!("bootstrapped the metadata log");
+        }
+        Ok(guard)
+    }
+
+    fn append(&self, records: &[KafkaRecordValue]) -> errors::Result<()> {
+        self.append_with(|_| Ok(records.to_vec())).map(|_| ())
+    }
+
+    // writes records as a single batch and applies them before returning.
+    // records gets the offset the batch starts at.
+    fn append_with(
+        &self,
+        records: impl FnOnce(u64) -> errors::Result<Vec<KafkaRecordValue>>,
+    ) -> errors::Result<Option<u64>> {
+        let Some(last) = self.raft.append(records)? else {
+            return Ok(None);
+        };
+        self.listener.lock().unwrap().poll()?;
+        if self.metadata.image().offset.map_or(true, |o| o < last) {
+            return Err(errors::KafkaErrors::StorageError(format!(
+                "metadata up to {last} was committed but not applied"
+            ))
+            .into());
+        }
+        Ok(Some(last))
+    }
+}
+
+// the error code for a failed controller call
+pub fn error_code(e: &anyhow::Error) -> i16 {
+    match e.downcast_ref::<errors::KafkaErrors>() {
+        Some(errors::KafkaErrors::RequestFailed(code, _)) => *code,
+        _ => UNKNOWN_SERVER_ERROR,
+    }
+}
+
+// a fresh version 4 uuid
+pub fn random_uuid() -> errors::Result<[u8; 16]> {
+    let mut uuid = [0_u8; 16];
+    File::open("/dev/urandom")?.read_exact(&mut uuid)?;
+    uuid[6] = (uuid[6] & 0x0f) | 0x40;
+    uuid[8] = (uuid[8] & 0x3f) | 0x80;
+    Ok(uuid)
+}
+
+// same rules as the java broker: ascii letters, digits, '.', '_' and '-'
+fn validate_topic_name(name: &str) -> errors::Result<()> {
+    let problem = if name.is_empty() {
+        Some("topic name is empty".to_string())
+    } else if name == "." || name == ".." {
+        Some(format!("topic name can't be '{name}'"))
+    } else if name.len() > MAX_TOPIC_NAME_LEN {
+        Some(format!(
+            "topic name is longer than {MAX_TOPIC_NAME_LEN} characters"
+        ))
+    } else if !name
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-'))
+    {
+        Some(format!("topic name '{name}' has illegal characters"))
+    } else {
+        None
+    };
+    match problem {
+        Some(message) => Err(request_failed(INVALID_TOPIC_EXCEPTION, message)),
+        None => Ok(()),
+    }
+}
+
+// an explicit assignment must cover partitions 0 to n-1, each with the
+// same number of distinct, registered brokers. The replicas by partition.
+fn validate_assignment(
+    assignments: &[(i32, Vec<i32>)],
+    brokers: &[i32],
+) -> errors::Result<Vec<Vec<i32>>> {
+    let invalid = |message: String| request_failed(INVALID_REPLICA_ASSIGNMENT, message);
+    let mut sorted = assignments.iter().collect::<Vec<_>>();
+    sorted.sort_by_key(|(partition, _)| *partition);
+    let mut replicas = vec![];
+    for (expected, (partition, ids)) in sorted.into_iter().enumerate() {
+        if *partition != expected as i32 {
+            return Err(invalid(format!(
+                "partitions must be numbered 0 to {}, not {partition}",
+                assignments.len() - 1
+            )));
+        }
+        if ids.is_empty() {
+            return Err(invalid(format!("partition {partition} has no replicas")));
+        }
+        if ids.len() != assignments[0].1.len() {
+            return Err(invalid(
+                "all partitions must have the same number of replicas".to_string(),
+            ));
+        }
+        if let Some(id) = ids.iter().find(|id| !brokers.contains(id)) {
+            return Err(invalid(format!(
+                "partition {partition} is assigned to unknown broker {id}"
+            )));
+        }
+        if ids.iter().collect::<BTreeSet<_>>().len() != ids.len() {
+            return Err(invalid(format!(
+                "partition {partition} has duplicate replicas"
+            )));
+        }
+        replicas.push(ids.clone());
+    }
+    Ok(replicas)
+}
+
+fn config_record(topic: &str, key: &str, value: Option<&String>) -> KafkaRecordValue {
+    KafkaRecordValue::KafkaRecordConfigType(KafkaRecordConfigRecord {
+        version: 0,
+        resource_type: CONFIG_RESOURCE_TOPIC,
+        resource_name: topic.as_bytes().to_vec(),
+        name: key.as_bytes().to_vec(),
+        value: value.map(|v| v.as_bytes().to_vec()),
+    })
+}
+
+fn request_failed(code: i16, message: String) -> anyhow::Error {
+    errors::KafkaErrors::RequestFailed(code, message).into()
+}
+
+// a controller over a metadata log under root, the only voter of its
+// quorum, so it leads as soon as it's open
+#[cfg(test)]
+pub fn open_single_voter(root: &std::path::Path, metadata: &Arc<MetadataCache>) -> Controller {
+    use crate::kafka::log;
+    use crate::kafka::snapshot::SnapshotGenerator;
+
+    let dir = root.join(log::partition_dir_name(log::METADATA_TOPIC, 0));
+    let config = config::BrokerConfig {
+        metadata_log_dir: Some(root.display().to_string()),
+        ..Default::default()
+    };
+    let raft = Arc::new(raft::RaftClient::open(&config, "test".to_string()).unwrap());
+    let listener = Arc::new(Mutex::new(MetadataListener::new(
+        &dir,
+        Arc::clone(metadata),
+        SnapshotGenerator::new(&dir, u64::MAX),
+        raft.high_watermark(),
+    )));
+    Controller::open(
+        &config,
+        "test".to_string(),
+        Arc::clone(metadata),
+        listener,
+        raft,
+    )
+    .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kafka::testing::temp_root;
+
+    #[test]
+    fn test_create_and_delete_topic() {
+        let root = temp_root("controller-test");
+        let metadata = Arc::new(MetadataCache::default());
+        let controller = open_single_voter(&root, &metadata);
+        assert_eq!(controller.raft().leader_id(), Some(1));
+
+        let topic = |name: &str, num_partitions: i32| NewTopic {
+            name: name.to_string(),
+            num_partitions,
+            replication_factor: 1,
+            ..Default::default()
+        };
+        let orders = NewTopic {
+            configs: vec![("cleanup.policy".to_string(), "compact".to_string())],
+            ..topic("orders", 2)
+        };
+        // checked but not created, so without an id
+        assert_eq!(controller.create_topic(&orders, true).unwrap().0, 0);
+        assert!(metadata.image().topic_by_name("orders").is_none());
+        let (id, replicas) = controller.create_topic(&orders, false).unwrap();
+        assert_eq!(replicas, vec![vec![1], vec![1]]);
+        let image = metadata.image();
+        assert_eq!(image.topic_by_name("orders").unwrap().id, id);
+        let partition = image.partition("orders", 1).unwrap();
+        assert_eq!((partition.leader, partition.isr.clone()), (1, vec![1]));
+        assert!(image.topic_config("orders").cleanup_policy.compact);
+
+        let code =
+            |topic: NewTopic| error_code(&controller.create_topic(&topic, true).unwrap_err());
+        assert_eq!(code(topic("orders", 1)), TOPIC_ALREADY_EXISTS);
+        assert_eq!(code(topic("a/b", 1)), INVALID_TOPIC_EXCEPTION);
+        assert_eq!(code(topic("x", 0)), INVALID_PARTITIONS);
+        let configured = |key: &str, value: &str| NewTopic {
+            configs: vec![(key.to_string(), value.to_string())],
+            ..topic("x", 1)
+        };
+        assert_eq!(code(configured("retention.ms", "abc")), INVALID_CONFIG);
+        assert_eq!(
+            code(configured("cleanup.policy", "compact,trim")),
+            INVALID_CONFIG
+        );
+        assert_eq!(code(configured("no.such.config", "1")), INVALID_CONFIG);
+        controller
+            .create_topic(&configured("retention.ms", "-1"), true)
+            .unwrap();
+        controller
+            .create_topic(&topic("web.logs", 1), false)
+            .unwrap();
+        assert_eq!(code(topic("web_logs", 1)), INVALID_TOPIC_EXCEPTION);
+        let assigned = |assignments: Vec<(i32, Vec<i32>)>| NewTopic {
+            num_partitions: -1,
+            replication_factor: -1,
+            assignments,
+            ..topic("x", 0)
+        };
+        assert_eq!(
+            code(assigned(vec![(0, vec![1]), (2, vec![1])])),
+            INVALID_REPLICA_ASSIGNMENT
+        );
+        assert_eq!(
+            code(assigned(vec![(0, vec![2])])),
+            INVALID_REPLICA_ASSIGNMENT
+        );
+        assert_eq!(
+            controller
+                .create_topic(&assigned(vec![(1, vec![1]), (0, vec![1])]), true)
+                .unwrap()
+                .1,
+            vec![vec![1], vec![1]]
+        );
+
+        assert_eq!(controller.delete_topic("orders").unwrap(), id);
+        let image = metadata.image();
+        assert!(image.topic_by_name("orders").is_none());
+        assert!(image.configs.is_empty());
+        assert_eq!(
+            error_code(&controller.delete_topic("orders").unwrap_err()),
+            UNKNOWN_TOPIC_OR_PARTITION
+        );
+        let _ = std::fs::remove_dir_all(&root);
+    }
+}
@@ -0,0 +1,432 @@
+// CreateTopics (key 19): topics created by the active controller, from a
+// partition count and replication factor or an explicit assignment, each
+// checked and reported on its own. The partitions this node holds get
+// their log right away.
+//
+// https://kafka.apache.org/protocol.html#The_Messages_CreateTopics
+use crate::kafka::controller::{self, NewTopic};
+use crate::kafka::{errors, log, parser, raft, writer};
+use std::collections::HashSet;
+use std::io::{Read, Write};
+use std::sync::Arc;
+
+// where a config returned for a new topic comes from
+const DYNAMIC_TOPIC_CONFIG: i8 = 1;
+
+#[derive(Debug, Clone)]
+pub struct CreateTopicsRequest {
+    pub version: u16,
+    pub topics: Vec<NewTopic>,
+    pub validate_only: bool, // v1+
+}
+
+impl CreateTopicsRequest {
+    pub fn new<R: Read>(req: &mut R, version: u16, flexible: bool) -> errors::Result<Self> {
+        let mut topics = vec![];
+        for _ in 0..parser::read_array_len(req, flexible)?.unwrap_or_default() {
+            let name = String::from_utf8(parser::read_string(req, flexible)?)?;
+            let num_partitions = parser::read_int(req)?;
+            let replication_factor = parser::read_short(req)?;
+            let mut assignments = vec![];
+            for _ in 0..parser::read_array_len(req, flexible)?.unwrap_or_default() {
+                let partition = parser::read_int(req)?;
+                let mut broker_ids = vec![];
+                for _ in 0..parser::read_array_len(req, flexible)?.unwrap_or_default() {
+                    broker_ids.push(parser::read_int(req)?);
+                }
+                assignments.push((partition, broker_ids));
+                if flexible {
+                    parser::skip_tagged_fields(req)?;
+                }
+            }
+            let mut configs = vec![];
+            for _ in 0..parser::read_array_len(req, flexible)?.unwrap_or_default() {
+                let key = String::from_utf8(parser::read_string(req, flexible)?)?;
+                // a null value leaves the default
+                if let Some(value) = parser::read_nullable_string(req, flexible)? {
+                    configs.push((key, String::from_utf8(value)?));
+                }
+                if flexible {
+                    parser::skip_tagged_fields(req)?;
+                }
+            }
+            if flexible {
+                parser::skip_tagged_fields(req)?;
+            }
+            topics.push(NewTopic {
+                name,
+                num_partitions,
+                replication_factor,
+                assignments,
+                configs,
+            });
+        }
+        // the timeout: topics are created before the controller returns
+        parser::read_int(req)?;
+        let validate_only = version >= 1 && parser::read_byte(req)? != 0;
+        if flexible {
+            parser::skip_tagged_fields(req)?;
+        }
+        Ok(Self {
+            version,
+            topics,
+            validate_only,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct CreateTopicsResponseTopic {
+    pub name: String,
+    pub topic_id: u128, // v7+
+    pub error_code: i16,
+    pub error_message: Option<String>,          // v1+
+    pub num_partitions: i32,                    // v5+
+    pub replication_factor: i16,                // v5+
+    pub configs: Option<Vec<(String, String)>>, // v5+
+}
+
+#[derive(Debug, Clone)]
+pub struct CreateTopicsResponse {
+    pub version: u16,
+    pub throttle_time_ms: i32, // v2+
+    pub topics: Vec<CreateTopicsResponseTopic>,
+}
+
+impl CreateTopicsResponse {
+    pub fn new(
+        request: &CreateTopicsRequest,
+        logs: &Arc<log::LogManager>,
+        controller: &Arc<controller::Controller>,
+    ) -> Self {
+        // a name given more than once is turned down every time
+        let mut seen = HashSet::new();
+        let duplicates = request
+            .topics
+            .iter()
+            .filter(|t| !seen.insert(t.name.as_str()))
+            .map(|t| t.name.clone())
+            .collect::<HashSet<_>>();
+        let topics = request
+            .topics
+            .iter()
+            .map(|topic| {
+                if duplicates.contains(&topic.name) {
+                    return failed(
+                        topic,
+                        raft::INVALID_REQUEST,
+                        format!("topic '{}' was given more than once", topic.name),
+                    );
+                }
+                match controller.create_topic(topic, request.validate_only) {
+                    Ok((topic_id, replicas)) => {
+                        if !request.validate_only {
+                            create_logs(topic, &replicas, logs, controller.raft().node_id());
+                        }
+                        CreateTopicsResponseTopic {
+                            name: topic.name.clone(),
+                            topic_id,
+                            num_partitions: replicas.len() as i32,
+                            replication_factor: replicas[0].len() as i16,
+                            configs: Some(topic.configs.clone()),
+                            ..Default::default()
+                        }
+                    }
+                    Err(e) => {
+                        println!("CreateTopics - {}: {e}", topic.name);
+                        failed(topic, controller::error_code(&e), e.to_string())
+                    }
+                }
+            })
+            .collect();
+        Self {
+            version: request.version,
+            throttle_time_ms: 0,
+            topics,
+        }
+    }
+
+    pub fn serialize<W: Write>(&self, resp: &mut W, flexible: bool) -> errors::Result<()> {
+        if self.version >= 2 {
+            writer::write_bytes(resp, &self.throttle_time_ms)?;
+        }
+        writer::write_array_len(resp, self.topics.len(), flexible)?;
+        for topic in &self.topics {
+            writer::write_string(resp, topic.name.as_bytes(), flexible)?;
+            if self.version >= 7 {
+                writer::write_bytes(resp, &topic.topic_id)?;
+            }
+            writer::write_bytes(resp, &topic.error_code)?;
+            if self.version >= 1 {
+                writer::write_nullable_string(
+                    resp,
+                    topic.error_message.as_ref().map(String::as_bytes),
+                    flexible,
+                )?;
+            }
+            if self.version >= 5 {
+                writer::write_bytes(resp, &topic.num_partitions)?;
+                writer::write_bytes(resp, &topic.replication_factor)?;
+                match &topic.configs {
+                    Some(configs) => {
+                        writer::write_array_len(resp, configs.len(), flexible)?;
+                        for (name, value) in configs {
+                            writer::write_string(resp, name.as_bytes(), flexible)?;
+                            writer::write_nullable_string(resp, Some(value.as_bytes()), flexible)?;
+                            writer::write_bool(resp, false)?; // read only
+                            writer::write_bytes(resp, &DYNAMIC_TOPIC_CONFIG)?;
+                            writer::write_bool(resp, false)?; // sensitive
+                            writer::write_tagged_fields(resp, flexible)?;
+                        }
+                    }
+                    // null, v5+ is always flexible
+                    None => writer::write_uvarint(resp, 0)?,
+                }
+            }
+            writer::write_tagged_fields(resp, flexible)?;
+        }
+        writer::write_tagged_fields(resp, flexible)
+    }
+}
+
+fn failed(topic: &NewTopic, error_code: i16, message: String) -> CreateTopicsResponseTopic {
+    CreateTopicsResponseTopic {
+        name: topic.name.clone(),
+        error_code,
+        error_message: Some(message),
+        num_partitions: -1,
+        replication_factor: -1,
+        ..Default::default()
+    }
+}
+
+// opens the logs of the partitions with a replica here, the other brokers
+// create theirs on first use
+fn create_logs(topic: &NewTopic, replicas: &[Vec<i32>], logs: &Arc<log::LogManager>, node_id: i32) {
+    for (partition, ids) in replicas.iter().enumerate() {
+        if !ids.contains(&node_id) {
+            continue;
+        }
+        if let Err(e) = logs.get_or_open(&topic.name, partition as i32) {
+            println!("CreateTopics - {}-{partition}: {e}", topic.name);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kafka::config;
+    use crate::kafka::metadata::MetadataCache;
+    use crate::kafka::testing::{log_manager, temp_root};
+    use std::io::Cursor;
+
+    fn topic(name: &str, configs: &[(&str, &str)]) -> NewTopic {
+        NewTopic {
+            name: name.to_string(),
+            num_partitions: 2,
+            replication_factor: 1,
+            configs: configs
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    // a request the way a client lays it out for version
+    fn encode(version: u16, topics: &[NewTopic], validate_only: bool) -> Vec<u8> {
+        let flexible = version >= 5;
+        let out = &mut vec![];
+        writer::write_array_len(out, topics.len(), flexible).unwrap();
+        for t in topics {
+            writer::write_string(out, t.name.as_bytes(), flexible).unwrap();
+            writer::write_bytes(out, &t.num_partitions).unwrap();
+            writer::write_bytes(out, &t.replication_factor).unwrap();
+            writer::write_array_len(out, t.assignments.len(), flexible).unwrap();
+            for (partition, broker_ids) in &t.assignments {
+                writer::write_bytes(out, partition).unwrap();
+                writer::write_array_len(out, broker_ids.len(), flexible).unwrap();
+                broker_ids
+                    .iter()
+                    .for_each(|id| writer::write_bytes(out, id).unwrap());
+                writer::write_tagged_fields(out, flexible).unwrap();
+            }
+            writer::write_array_len(out, t.configs.len(), flexible).unwrap();
+            for (key, value) in &t.configs {
+                writer::write_string(out, key.as_bytes(), flexible).unwrap();
+                writer::write_nullable_string(out, Some(value.as_bytes()), flexible).unwrap();
+                writer::write_tagged_fields(out, flexible).unwrap();
+            }
+            writer::write_tagged_fields(out, flexible).unwrap();
+        }
+        writer::write_bytes(out, &30_000_i32).unwrap(); // timeout
+        if version >= 1 {
+            writer::write_bool(out, validate_only).unwrap();
+        }
+        writer::write_tagged_fields(out, flexible).unwrap();
+        out.clone()
+    }
+
+    // what a client reads back of a topic, None for fields the version
+    // doesn't have
+    #[derive(Debug, Default, PartialEq)]
+    struct Decoded {
+        name: Vec<u8>,
+        topic_id: Option<u128>,
+        error_code: i16,
+        error_message: Option<Option<Vec<u8>>>,
+        num_partitions: Option<i32>,
+        replication_factor: Option<i16>,
+        configs: Option<Option<Vec<String>>>, // name=value
+    }
+
+    fn decode(data: &[u8], version: u16) -> Vec<Decoded> {
+        let flexible = version >= 5;
+        let r = &mut Cursor::new(data);
+        if version >= 2 {
+            assert_eq!(parser::read_int(r).unwrap(), 0); // throttle time
+        }
+        let mut topics = vec![];
+        for _ in 0..parser::read_array_len(r, flexible).unwrap().unwrap() {
+            let mut t = Decoded {
+                name: parser::read_string(r, flexible).unwrap(),
+                ..Default::default()
+            };
+            if version >= 7 {
+                t.topic_id = Some(parser::read_u128(r).unwrap());
+            }
+            t.error_code = parser::read_short(r).unwrap();
+            if version >= 1 {
+                t.error_message = Some(parser::read_nullable_string(r, flexible).unwrap());
+            }
+            if version >= 5 {
+                t.num_partitions = Some(parser::read_int(r).unwrap());
+                t.replication_factor = Some(parser::read_short(r).unwrap());
+                let len = parser::read_array_len(r, flexible).unwrap();
+                let mut configs = vec![];
+                for _ in 0..len.unwrap_or_default() {
+                    let name = parser::read_string(r, flexible).unwrap();
+                    let value = parser::read_nullable_string(r, flexible).unwrap();
+                    assert_eq!(parser::read_byte(r).unwrap(), 0); // read only
+                    assert_eq!(parser::read_byte(r).unwrap(), DYNAMIC_TOPIC_CONFIG);
+                    assert_eq!(parser::read_byte(r).unwrap(), 0); // sensitive
+                    parser::skip_tagged_fields(r).unwrap();
+                    configs.push(format!(
+                        "{}={}",
+                        String::from_utf8(name).unwrap(),
+                        String::from_utf8(value.unwrap()).unwrap()
+                    ));
+                }
+                t.configs = Some(len.map(|_| configs));
+            }
+            if flexible {
+                parser::skip_tagged_fields(r).unwrap();
+            }
+            topics.push(t);
+        }
+        if flexible {
+            parser::skip_tagged_fields(r).unwrap();
+        }
+        assert_eq!(r.position() as usize, data.len(), "trailing bytes");
+        topics
+    }
+
+    #[test]
+    fn test_create_topics_versions() {
+        let root = temp_root("create-topics-test");
+        let metadata = Arc::new(MetadataCache::default());
+        let controller = Arc::new(controller::open_single_voter(&root.join("meta"), &metadata));
+        let logs = log_manager(&root, &["logs"]);
+        let create = |version: u16, topics: &[NewTopic], validate_only: bool| {
+            let body = encode(version, topics, validate_only);
+            let request = CreateTopicsRequest::new(&mut &body[..], version, version >= 5).unwrap();
+            assert_eq!(request.topics.len(), topics.len());
+            assert_eq!(request.validate_only, validate_only);
+            let mut out = vec![];
+            CreateTopicsResponse::new(&request, &logs, &controller)
+                .serialize(&mut out, version >= 5)
+                .unwrap();
+            decode(&out, version)
+        };
+        let exists = |name: &str| metadata.image().topic_by_name(name).is_some();
+
+        // v0: the name and error code alone, the logs open right away
+        let response = create(0, &[topic("orders", &[])], false);
+        assert_eq!(
+            response,
+            vec![Decoded {
+                name: b"orders".to_vec(),
+                ..Default::default()
+            }]
+        );
+        assert!(exists("orders"));
+        assert!(logs
+            .with_existing_log("orders", 1, |_| Ok(()))
+            .unwrap()
+            .is_some());
+
+        // v1: validate_only and error messages
+        let response = create(1, &[topic("events", &[])], true);
+        assert_eq!(
+            (response[0].error_code, &response[0].error_message),
+            (0, &Some(None))
+        );
+        assert!(!exists("events"));
+        let response = create(1, &[topic("orders", &[])], false);
+        assert_eq!(response[0].error_code, controller::TOPIC_ALREADY_EXISTS);
+        assert!(response[0].error_message.clone().flatten().is_some());
+        let response = create(1, &[topic("twice", &[]), topic("twice", &[])], false);
+        assert!(response
+            .iter()
+            .all(|t| t.error_code == raft::INVALID_REQUEST));
+        assert!(!exists("twice"));
+
+        // v5: flexible, with the partition count, replication factor and
+        // configs of the topic, and configs checked
+        let compacted = topic("compacted", &[("cleanup.policy", "compact")]);
+        let response = create(5, &[compacted], false);
+        assert_eq!(
+            response[0],
+            Decoded {
+                name: b"compacted".to_vec(),
+                error_message: Some(None),
+                num_partitions: Some(2),
+                replication_factor: Some(1),
+                configs: Some(Some(vec!["cleanup.policy=compact".to_string()])),
+                ..Default::default()
+            }
+        );
+        assert!(
+            metadata
+                .image()
+                .topic_config("compacted")
+                .cleanup_policy
+                .compact
+        );
+        let invalid = [
+            topic("bad-value", &[("retention.ms", "abc")]),
+            topic("bad-key", &[("retention.mins", "5")]),
+            topic("bad-ratio", &[("min.cleanable.dirty.ratio", "2")]),
+        ];
+        for t in create(5, &invalid, false) {
+            assert_eq!(t.error_code, controller::INVALID_CONFIG, "{t:?}");
+            assert_eq!(
+                (t.num_partitions, t.replication_factor, t.configs),
+                (Some(-1), Some(-1), Some(None))
+            );
+        }
+        assert!(invalid.iter().all(|t| !exists(&t.name)));
+
+        // v7: the id of the new topic, none for one only validated
+        let response = create(7, &[topic("with-id", &[])], false);
+        assert_eq!(
+            response[0].topic_id,
+            Some(metadata.image().topic_by_name("with-id").unwrap().id)
+        );
+        assert_ne!(response[0].topic_id, Some(0));
+        let response = create(7, &[topic("validated", &[])], true);
+        assert_eq!((response[0].error_code, response[0].topic_id), (0, Some(0)));
+        let _ = std::fs::remove_dir_all(&root);
+    }
+}
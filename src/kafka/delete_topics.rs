@@ -0,0 +1,288 @@
+// DeleteTopics (key 20): topics removed by the active controller, by name
+// or, from v6 on, by id, each reported on its own. The logs this node holds
+// for them go right away.
+//
+// https://kafka.apache.org/protocol.html#The_Messages_DeleteTopics
+use crate::kafka::{controller, errors, log, metadata, parser, writer};
+use std::io::{Read, Write};
+use std::sync::Arc;
+
+const UNKNOWN_TOPIC_ID: i16 = 100;
+
+// a topic to delete, by name or with a null name by id
+#[derive(Debug, Clone, Default)]
+pub struct DeleteTopicsRequestTopic {
+    pub name: Option<String>,
+    pub topic_id: u128, // v6+
+}
+
+#[derive(Debug, Clone)]
+pub struct DeleteTopicsRequest {
+    pub version: u16,
+    pub topics: Vec<DeleteTopicsRequestTopic>,
+}
+
+impl DeleteTopicsRequest {
+    pub fn new<R: Read>(req: &mut R, version: u16, flexible: bool) -> errors::Result<Self> {
+        let mut topics = vec![];
+        for _ in 0..parser::read_array_len(req, flexible)?.unwrap_or_default() {
+            // up to v5 just the names
+            if version < 6 {
+                let name = String::from_utf8(parser::read_string(req, flexible)?)?;
+                topics.push(DeleteTopicsRequestTopic {
+                    name: Some(name),
+                    topic_id: 0,
+                });
+                continue;
+            }
+            let name = parser::read_nullable_string(req, flexible)?
+                .map(String::from_utf8)
+                .transpose()?;
+            let topic_id = parser::read_u128(req)?;
+            parser::skip_tagged_fields(req)?;
+            topics.push(DeleteTopicsRequestTopic { name, topic_id });
+        }
+        // the timeout: topics are deleted before the controller returns
+        parser::read_int(req)?;
+        if flexible {
+            parser::skip_tagged_fields(req)?;
+        }
+        Ok(Self { version, topics })
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct DeleteTopicsResponseTopic {
+    pub name: Option<String>,
+    pub topic_id: u128, // v6+
+    pub error_code: i16,
+    pub error_message: Option<String>, // v5+
+}
+
+#[derive(Debug, Clone)]
+pub struct DeleteTopicsResponse {
+    pub version: u16,
+    pub throttle_time_ms: i32, // v1+
+    pub topics: Vec<DeleteTopicsResponseTopic>,
+}
+
+impl DeleteTopicsResponse {
+    pub fn new(
+        request: &DeleteTopicsRequest,
+        metadata: &Arc<metadata::MetadataCache>,
+        logs: &Arc<log::LogManager>,
+        controller: &Arc<controller::Controller>,
+    ) -> Self {
+        let topics = request
+            .topics
+            .iter()
+            .map(|topic| {
+                let image = metadata.image();
+                let name = match &topic.name {
+                    Some(name) => name.clone(),
+                    None => match image.topic_name(topic.topic_id) {
+                        Some(name) => name.to_string(),
+                        None => {
+                            return failed(
+                                topic,
+                                UNKNOWN_TOPIC_ID,
+                                format!("topic id {:032x} doesn't exist", topic.topic_id),
+                            )
+                        }
+                    },
+                };
+                let partitions = image
+                    .topic_by_name(&name)
+                    .map(|t| t.partitions.keys().copied().collect::<Vec<_>>())
+                    .unwrap_or_default();
+                match controller.delete_topic(&name) {
+                    Ok(topic_id) => {
+                        delete_logs(&name, &partitions, logs);
+                        DeleteTopicsResponseTopic {
+                            name: Some(name),
+                            topic_id,
+                            ..Default::default()
+                        }
+                    }
+                    Err(e) => {
+                        println!("DeleteTopics - {name}: {e}");
+                        failed(topic, controller::error_code(&e), e.to_string())
+                    }
+                }
+            })
+            .collect();
+        Self {
+            version: request.version,
+            throttle_time_ms: 0,
+            topics,
+        }
+    }
+
+    pub fn serialize<W: Write>(&self, resp: &mut W, flexible: bool) -> errors::Result<()> {
+        if self.version >= 1 {
+            writer::write_bytes(resp, &self.throttle_time_ms)?;
+        }
+        writer::write_array_len(resp, self.topics.len(), flexible)?;
+        for topic in &self.topics {
+            let name = topic.name.as_ref().map(String::as_bytes);
+            if self.version >= 6 {
+                writer::write_nullable_string(resp, name, flexible)?;
+                writer::write_bytes(resp, &topic.topic_id)?;
+            } else {
+                writer::write_string(resp, name.unwrap_or_default(), flexible)?;
+            }
+            writer::write_bytes(resp, &topic.error_code)?;
+            if self.version >= 5 {
+                writer::write_nullable_string(
+                    resp,
+                    topic.error_message.as_ref().map(String::as_bytes),
+                    flexible,
+                )?;
+            }
+            writer::write_tagged_fields(resp, flexible)?;
+        }
+        writer::write_tagged_fields(resp, flexible)
+    }
+}
+
+fn failed(
+    topic: &DeleteTopicsRequestTopic,
+    error_code: i16,
+    message: String,
+) -> DeleteTopicsResponseTopic {
+    DeleteTopicsResponseTopic {
+        name: topic.name.clone(),
+        topic_id: topic.topic_id,
+        error_code,
+        error_message: Some(message),
+    }
+}
+
+// drops the logs of the partitions hosted here, the other brokers do the
+// same for theirs
+fn delete_logs(topic: &str, partitions: &[i32], logs: &Arc<log::LogManager>) {
+    for partition in partitions {
+        if let Err(e) = logs.delete_log(topic, *partition) {
+            println!("DeleteTopics - {topic}-{partition}: {e}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kafka::controller::NewTopic;
+    use crate::kafka::testing::{log_manager, temp_root};
+    use std::io::Cursor;
+
+    // a request the way a client lays it out for version, names up to v5
+    // and (name, id) pairs from v6 on
+    fn encode(version: u16, topics: &[(Option<&str>, u128)]) -> Vec<u8> {
+        let flexible = version >= 4;
+        let out = &mut vec![];
+        writer::write_array_len(out, topics.len(), flexible).unwrap();
+        for (name, id) in topics {
+            if version >= 6 {
+                writer::write_nullable_string(out, name.map(str::as_bytes), flexible).unwrap();
+                writer::write_bytes(out, id).unwrap();
+                writer::write_tagged_fields(out, flexible).unwrap();
+            } else {
+                writer::write_string(out, name.unwrap().as_bytes(), flexible).unwrap();
+            }
+        }
+        writer::write_bytes(out, &30_000_i32).unwrap(); // timeout
+        writer::write_tagged_fields(out, flexible).unwrap();
+        out.clone()
+    }
+
+    // (name, id, error code) of every topic as a client reads them back
+    fn decode(data: &[u8], version: u16) -> Vec<(Option<Vec<u8>>, u128, i16)> {
+        let flexible = version >= 4;
+        let r = &mut Cursor::new(data);
+        if version >= 1 {
+            assert_eq!(parser::read_int(r).unwrap(), 0); // throttle time
+        }
+        let mut topics = vec![];
+        for _ in 0..parser::read_array_len(r, flexible).unwrap().unwrap() {
+            let (name, id) = if version >= 6 {
+                let name = parser::read_nullable_string(r, flexible).unwrap();
+                (name, parser::read_u128(r).unwrap())
+            } else {
+                (Some(parser::read_string(r, flexible).unwrap()), 0)
+            };
+            let error_code = parser::read_short(r).unwrap();
+            if version >= 5 {
+                parser::read_nullable_string(r, flexible).unwrap();
+            }
+            if flexible {
+                parser::skip_tagged_fields(r).unwrap();
+            }
+            topics.push((name, id, error_code));
+        }
+        if flexible {
+            parser::skip_tagged_fields(r).unwrap();
+        }
+        assert_eq!(r.position() as usize, data.len());
+        topics
+    }
+
+    #[test]
+    fn test_delete_topics_versions() {
+        let root = temp_root("delete-topics-test");
+        let metadata = Arc::new(metadata::MetadataCache::default());
+        let controller = Arc::new(controller::open_single_voter(&root.join("meta"), &metadata));
+        let logs = log_manager(&root, &["logs"]);
+        let create = |name: &str| {
+            let topic = NewTopic {
+                name: name.to_string(),
+                num_partitions: 2,
+                replication_factor: 1,
+                ..Default::default()
+            };
+            let (id, _) = controller.create_topic(&topic, false).unwrap();
+            logs.get_or_open(name, 0).unwrap();
+            id
+        };
+        let delete = |version: u16, topics: &[(Option<&str>, u128)]| {
+            let request = DeleteTopicsRequest::new(
+                &mut Cursor::new(encode(version, topics)),
+                version,
+                version >= 4,
+            )
+            .unwrap();
+            let mut out = vec![];
+            DeleteTopicsResponse::new(&request, &metadata, &logs, &controller)
+                .serialize(&mut out, version >= 4)
+                .unwrap();
+            decode(&out, version)
+        };
+
+        // by name, the logs here go along
+        create("orders");
+        let dir = logs.get("orders", 0).unwrap().lock().unwrap().dir.clone();
+        for version in [0, 4, 5] {
+            let deleted = delete(version, &[(Some("orders"), 0)]);
+            let expected = if version == 0 {
+                0
+            } else {
+                controller::UNKNOWN_TOPIC_OR_PARTITION
+            };
+            assert_eq!(deleted, vec![(Some(b"orders".to_vec()), 0, expected)]);
+        }
+        assert!(metadata.image().topic_by_name("orders").is_none());
+        assert!(logs.get("orders", 0).is_none());
+        assert!(!dir.exists());
+
+        // by id from v6 on
+        let id = create("events");
+        assert_eq!(
+            delete(6, &[(None, id), (None, id)]),
+            vec![
+                (Some(b"events".to_vec()), id, 0),
+                (None, id, UNKNOWN_TOPIC_ID)
+            ]
+        );
+        assert!(metadata.image().topics.is_empty());
+        let _ = std::fs::remove_dir_all(&root);
+    }
+}
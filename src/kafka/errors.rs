@@ -10,6 +10,18 @@ pub enum KafkaErrors {
     InvalidApiKey(String),
     #[error("Invalid Writer Argument: {0}")]
     InvalidWriterArg(String),
+    #[error("Invalid Checkpoint: {0}")]
+    InvalidCheckpoint(String),
+    #[error("Corrupt Record: {0}")]
+    CorruptRecord(String),
+    #[error("Offset Out Of Range: {0}")]
+    OffsetOutOfRange(String),
+    #[error("Storage Error: {0}")]
+    StorageError(String),
+    #[error("Log Dir Not Found: {0}")]
+    LogDirNotFound(String),
+    #[error("Request Failed ({0}): {1}")]
+    RequestFailed(i16, String),
     #[error("IO error: {0}")]
     IoError(#[from] std::io::Error),
 }
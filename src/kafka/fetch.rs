@@ -1,33 +1,96 @@
-use crate::kafka::{errors, metadata, parser, writer};
+use crate::kafka::quorum::{self, CurrentLeader, DivergingEpoch, SnapshotId};
+use crate::kafka::zerocopy::{FileRegion, RegionWrite};
+use crate::kafka::{errors, legacy, log, metadata, parser, raft, writer};
 use std::io::{Read, Write};
-use std::sync::{Arc, Mutex};
-
-use super::records;
+use std::sync::Arc;
 
+const FETCH_RESPONSE_UNKNOWN_SERVER_ERROR: u16 = u16::MAX; // -1
+const FETCH_RESPONSE_OFFSET_OUT_OF_RANGE: u16 = 1;
+const FETCH_RESPONSE_UNKNOWN_TOPIC_OR_PARTITION: u16 = 3;
+const FETCH_RESPONSE_NOT_LEADER_OR_FOLLOWER: u16 = 6;
+const FETCH_RESPONSE_KAFKA_STORAGE_ERROR: u16 = 56;
 const FETCH_RESPONSE_UNKNOWN_TOPIC: u16 = 100;
 
+// first version that names topics by id instead of by name
+const FETCH_TOPIC_ID_VERSION: u16 = 13;
+// first version with the replica id in a tagged field
+const FETCH_REPLICA_STATE_VERSION: u16 = 15;
+// tag of the replica state in the request
+const FETCH_TAG_REPLICA_STATE: u32 = 1;
+// last version that can't read v2 record batches
+const FETCH_LAST_LEGACY_VERSION: u16 = 3;
+
+// message format a legacy fetch version understands
+fn legacy_magic(version: u16) -> Option<i8> {
+    match version {
+        0..=1 => Some(0),
+        2..=FETCH_LAST_LEGACY_VERSION => Some(1),
+        _ => None,
+    }
+}
+
+// a topic as the request names it: by name up to v12, by id from v13 on
+#[derive(Debug, Clone)]
+enum FetchTopicRef {
+    Name(String),
+    Id(u128),
+}
+
+impl FetchTopicRef {
+    fn new<R: Read>(req: &mut R, version: u16, flexible: bool) -> errors::Result<Self> {
+        if version >= FETCH_TOPIC_ID_VERSION {
+            Ok(Self::Id(parser::read_u128(req)?))
+        } else {
+            Ok(Self::Name(String::from_utf8(parser::read_string(
+                req, flexible,
+            )?)?))
+        }
+    }
+
+    fn serialize<W: Write>(&self, resp: &mut W, flexible: bool) -> errors::Result<()> {
+        match self {
+            Self::Name(name) => writer::write_string(resp, name.as_bytes(), flexible),
+            Self::Id(id) => writer::write_bytes(resp, id),
+        }
+    }
+
+    // the raft replicated __cluster_metadata partition
+    fn is_metadata(&self) -> bool {
+        match self {
+            Self::Name(name) => name == log::METADATA_TOPIC,
+            Self::Id(id) => *id == quorum::METADATA_TOPIC_ID,
+        }
+    }
+}
+
+impl std::fmt::Display for FetchTopicRef {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Name(name) => write!(f, "{name}"),
+            Self::Id(id) => write!(f, "{id:032x}"),
+        }
+    }
+}
+
 #[allow(dead_code)]
 #[derive(Debug, Clone)]
 struct FetchRequestForgottenTopic {
-    topic_id: u128,
+    topic: FetchTopicRef,
     partitions: Vec<u32>,
-    tag_buffer: u8,
 }
 impl FetchRequestForgottenTopic {
-    fn new<R: Read>(req: &mut R) -> errors::Result<Self> {
-        let topic_id = parser::read_u128(req)?;
-        let num_partitions = parser::read_byte(req)? as u8;
+    fn new<R: Read>(req: &mut R, version: u16, flexible: bool) -> errors::Result<Self> {
+        let topic = FetchTopicRef::new(req, version, flexible)?;
+        let num_partitions = parser::read_array_len(req, flexible)?.unwrap_or_default();
         let mut partitions = vec![];
         for _i in 0..num_partitions {
             let p = parser::read_int(req)? as u32;
             partitions.push(p);
         }
-        let tag_buffer = parser::read_byte(req)? as u8;
-        Ok(Self {
-            topic_id,
-            partitions,
-            tag_buffer,
-        })
+        if flexible {
+            parser::skip_tagged_fields(req)?;
+        }
+        Ok(Self { topic, partitions })
     }
 }
 
@@ -35,23 +98,42 @@ impl FetchRequestForgottenTopic {
 #[derive(Debug, Clone)]
 struct FetchPartition {
     partition: u32,
-    current_leader_epoch: u32,
+    current_leader_epoch: i32,
     fetch_offset: u64,
-    last_fetched_epoch: u32,
-    log_start_offset: u64,
+    last_fetched_epoch: i32,
+    log_start_offset: i64,
     partition_max_bytes: u32,
-    tag_buffer: u8,
+    replica_directory_id: [u8; 16], // tagged, v17+
 }
 
 impl FetchPartition {
-    fn new<R: Read>(req: &mut R) -> errors::Result<Self> {
+    fn new<R: Read>(req: &mut R, version: u16, flexible: bool) -> errors::Result<Self> {
         let partition = parser::read_int(req)? as u32;
-        let current_leader_epoch = parser::read_int(req)? as u32;
+        let current_leader_epoch = if version >= 9 {
+            parser::read_int(req)?
+        } else {
+            -1
+        };
         let fetch_offset = parser::read_u64(req)?;
-        let last_fetched_epoch = parser::read_int(req)? as u32;
-        let log_start_offset = parser::read_u64(req)?;
+        let last_fetched_epoch = if version >= 12 {
+            parser::read_int(req)?
+        } else {
+            -1
+        };
+        let log_start_offset = if version >= 5 {
+            parser::read_u64(req)? as i64
+        } else {
+            -1
+        };
         let partition_max_bytes = parser::read_int(req)? as u32;
-        let tag_buffer = parser::read_byte(req)? as u8;
+        let mut replica_directory_id = [0; 16];
+        if flexible {
+            for (tag, data) in parser::read_tagged_fields(req)? {
+                if tag == quorum::TAG_REPLICA_DIRECTORY_ID {
+                    replica_directory_id = parser::read_u128(&mut &data[..])?.to_be_bytes();
+                }
+            }
+        }
 
         Ok(Self {
             partition,
@@ -60,7 +142,7 @@ impl FetchPartition {
             last_fetched_epoch,
             log_start_offset,
             partition_max_bytes,
-            tag_buffer,
+            replica_directory_id,
         })
     }
 }
@@ -78,23 +160,20 @@ impl std::fmt::Display for FetchPartition {
 #[allow(dead_code)]
 #[derive(Debug, Clone)]
 pub(crate) struct FetchTopic {
-    topic_id: u128,
+    topic: FetchTopicRef,
     partitions: Vec<FetchPartition>,
-    tag_buffer: u8,
 }
 
 impl std::fmt::Display for FetchTopic {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let mut output = String::new();
-        // conv ert u128 into individual bytes
-        let name = if let Ok(n) = String::from_utf8(self.topic_id.to_be_bytes().to_vec()) {
-            n
-        } else {
-            "unable-to-convert".into()
-        };
         let _ = std::fmt::write(
             &mut output,
-            format_args!("topic: {}, partitions: {}", name, self.partitions.len()),
+            format_args!(
+                "topic: {}, partitions: {}",
+                self.topic,
+                self.partitions.len()
+            ),
         );
         self.partitions.iter().for_each(|p| {
             let _ = std::fmt::write(&mut output, format_args!("Partition info: {}", p));
@@ -104,30 +183,28 @@ impl std::fmt::Display for FetchTopic {
 }
 
 impl FetchTopic {
-    fn new<R: Read>(req: &mut R) -> errors::Result<Self> {
-        let topic_id = parser::read_u128(req)?;
-        let num_partitions = parser::read_byte(req)? as u8;
+    fn new<R: Read>(req: &mut R, version: u16, flexible: bool) -> errors::Result<Self> {
+        let topic = FetchTopicRef::new(req, version, flexible)?;
+        let num_partitions = parser::read_array_len(req, flexible)?.unwrap_or_default();
         let mut partitions = vec![];
-        for _i in 0..num_partitions - 1 {
-            let p = FetchPartition::new(req)?;
+        for _i in 0..num_partitions {
+            let p = FetchPartition::new(req, version, flexible)?;
             partitions.push(p);
         }
-        // tag buffer
-        let tag_buffer = parser::read_byte(req)? as u8;
-        Ok(Self {
-            topic_id,
-            partitions,
-            tag_buffer,
-        })
+        if flexible {
+            parser::skip_tagged_fields(req)?;
+        }
+        Ok(Self { topic, partitions })
     }
 }
 
-impl FetchTopic {}
-
-// Version 16
+// Versions 0 to 17
 #[allow(dead_code)]
 #[derive(Debug, Clone)]
 pub(crate) struct FetchRequest {
+    version: u16,
+    flexible: bool,
+    replica_id: i32,
     max_wait_ms: u32,
     min_bytes: u32,
     max_bytes: u32,
@@ -137,7 +214,7 @@ pub(crate) struct FetchRequest {
     topics: Vec<FetchTopic>,
     forgotten_topics_data: Vec<FetchRequestForgottenTopic>,
     rack_id: Vec<u8>,
-    tag_buffer: u8,
+    cluster_id: Option<String>, // tagged, v12+
 }
 
 impl std::fmt::Display for FetchRequest {
@@ -146,8 +223,8 @@ impl std::fmt::Display for FetchRequest {
         let _ = std::fmt::write(
             &mut output,
             format_args!(
-                "Fetch Request: \nsession id: {}, max_wait_ms: {}, min_bytes: {}, topics: \n",
-                self.session_id, self.max_wait_ms, self.min_bytes
+                "Fetch Request v{}: \nsession id: {}, max_wait_ms: {}, min_bytes: {}, topics: \n",
+                self.version, self.session_id, self.max_wait_ms, self.min_bytes
             ),
         );
         self.topics.iter().for_each(|t| {
@@ -157,30 +234,66 @@ impl std::fmt::Display for FetchRequest {
     }
 }
 impl FetchRequest {
-    pub fn new<R: Read>(req: &mut R) -> errors::Result<Self> {
+    pub fn new<R: Read>(req: &mut R, version: u16, flexible: bool) -> errors::Result<Self> {
+        // from v15 on the replica id travels in a tagged field
+        let mut replica_id = if version < FETCH_REPLICA_STATE_VERSION {
+            parser::read_int(req)?
+        } else {
+            -1
+        };
         let max_wait_ms = parser::read_int(req)? as u32;
         let min_bytes = parser::read_int(req)? as u32;
-        let max_bytes = parser::read_int(req)? as u32;
-        let isolation_level = parser::read_byte(req)? as u8;
-        let session_id = parser::read_int(req)? as u32;
-        let session_epoch = parser::read_int(req)? as u32;
+        let max_bytes = if version >= 3 {
+            parser::read_int(req)? as u32
+        } else {
+            i32::MAX as u32
+        };
+        let isolation_level = if version >= 4 {
+            parser::read_byte(req)? as u8
+        } else {
+            0
+        };
+        let (session_id, session_epoch) = if version >= 7 {
+            (parser::read_int(req)? as u32, parser::read_int(req)? as u32)
+        } else {
+            (0, u32::MAX)
+        };
 
-        let num_topics = parser::read_byte(req)? as u8;
+        let num_topics = parser::read_array_len(req, flexible)?.unwrap_or_default();
         let mut topics = vec![];
-        for _i in 0..num_topics - 1 {
-            let p = FetchTopic::new(req)?;
+        for _i in 0..num_topics {
+            let p = FetchTopic::new(req, version, flexible)?;
             topics.push(p);
         }
-        let num_forgotten_topics = parser::read_byte(req)? as u8;
         let mut forgotten_topics_data = vec![];
-        for _i in 0..num_forgotten_topics - 1 {
-            let p = FetchRequestForgottenTopic::new(req)?;
-            forgotten_topics_data.push(p);
+        if version >= 7 {
+            let num_forgotten_topics = parser::read_array_len(req, flexible)?.unwrap_or_default();
+            for _i in 0..num_forgotten_topics {
+                let p = FetchRequestForgottenTopic::new(req, version, flexible)?;
+                forgotten_topics_data.push(p);
+            }
+        }
+        let rack_id = if version >= 11 {
+            parser::read_string(req, flexible)?
+        } else {
+            vec![]
+        };
+        let mut cluster_id = None;
+        if flexible {
+            let fields = parser::read_tagged_fields(req)?;
+            cluster_id = quorum::fetch_cluster_id(&fields)?;
+            if let Some((_, state)) = fields
+                .iter()
+                .find(|(tag, _)| *tag == FETCH_TAG_REPLICA_STATE)
+            {
+                replica_id = parser::read_int(&mut &state[..])?;
+            }
         }
-        let rack_id = parser::read_compact_string(req)?;
-        let tag_buffer = parser::read_byte(req)? as u8;
 
         Ok(Self {
+            version,
+            flexible,
+            replica_id,
             max_wait_ms,
             min_bytes,
             max_bytes,
@@ -190,7 +303,7 @@ impl FetchRequest {
             topics,
             forgotten_topics_data,
             rack_id,
-            tag_buffer,
+            cluster_id,
         })
     }
 }
@@ -200,25 +313,34 @@ impl FetchRequest {
 struct FetchResponseAbortedTransaction {
     producer_id: u64,
     first_offset: u64,
-    tag_buffer: u8,
 }
 
 impl FetchResponseAbortedTransaction {
-    #[allow(dead_code)]
-    fn new(_req: &FetchRequest) -> Self {
-        Self {
-            ..Default::default()
-        }
-    }
-
-    fn serialize<W: Write>(&self, resp: &mut W) -> errors::Result<()> {
+    fn serialize<W: Write>(&self, resp: &mut W, flexible: bool) -> errors::Result<()> {
         writer::write_bytes(resp, &self.producer_id)?;
         writer::write_bytes(resp, &self.first_offset)?;
-        writer::write_bytes(resp, &self.tag_buffer)?;
+        writer::write_tagged_fields(resp, flexible)?;
         Ok(())
     }
 }
 
+// record data for one partition: straight from the segment file, or
+// rewritten in memory for clients that need an older message format
+#[derive(Debug, Clone)]
+enum FetchedRecords {
+    Region(FileRegion),
+    Converted(Vec<u8>),
+}
+
+impl FetchedRecords {
+    fn len(&self) -> u64 {
+        match self {
+            Self::Region(r) => r.length,
+            Self::Converted(b) => b.len() as u64,
+        }
+    }
+}
+
 #[allow(dead_code)]
 #[derive(Debug, Clone, Default)]
 struct FetchResponsePartition {
@@ -229,58 +351,162 @@ struct FetchResponsePartition {
     log_start_offset: u64,
     aborted_transactions: Vec<FetchResponseAbortedTransaction>,
     preferred_read_replica: u32,
-    records: Vec<u8>,
-    tag_buffer: u8,
+    records: Option<FetchedRecords>,
+    // tagged, only for fetches of the metadata partition
+    diverging_epoch: Option<DivergingEpoch>,
+    current_leader: Option<CurrentLeader>,
+    snapshot_id: Option<SnapshotId>,
 }
 
 impl FetchResponsePartition {
-    fn new(topic_meta: &metadata::TopicMetadata, partition: u32) -> Self {
-        let aborted_transactions: Vec<FetchResponseAbortedTransaction> = vec![];
-        let log_file_name = format!(
-            "/tmp/kraft-combined-logs/{}-{}/00000000000000000000.log",
-            topic_meta.topic_name, partition
-        );
-
-        let records = std::fs::read(&log_file_name).unwrap();
+    fn new(
+        topic_name: &str,
+        part: &FetchPartition,
+        logs: &Arc<log::LogManager>,
+        version: u16,
+    ) -> Self {
+        // out of range still reports the log bounds, so the consumer can reset
+        let read = logs.with_existing_log(topic_name, part.partition as i32, |log| {
+            let next_offset = log.next_offset();
+            let log_start_offset = logs.log_start_offset(log);
+            match logs.read(log, part.fetch_offset, part.partition_max_bytes as u64) {
+                Ok(records) => Ok((0, records, next_offset, log_start_offset)),
+                Err(e) => match e.downcast_ref::<errors::KafkaErrors>() {
+                    Some(errors::KafkaErrors::OffsetOutOfRange(_)) => Ok((
+                        FETCH_RESPONSE_OFFSET_OUT_OF_RANGE,
+                        None,
+                        next_offset,
+                        log_start_offset,
+                    )),
+                    _ => Err(e),
+                },
+            }
+        });
+        let (mut error_code, region, next_offset, log_start_offset) = match read {
+            Ok(Some(read)) => read,
+            // no replica of the partition on this broker
+            Ok(None) => {
+                return Self {
+                    partiton_index: part.partition,
+                    ..Self::new_with_error(FETCH_RESPONSE_NOT_LEADER_OR_FOLLOWER)
+                };
+            }
+            Err(e) => {
+                println!("Fetch - read failed: {e}");
+                return Self {
+                    partiton_index: part.partition,
+                    ..Self::new_with_error(FETCH_RESPONSE_KAFKA_STORAGE_ERROR)
+                };
+            }
+        };
+        let records = match (region, legacy_magic(version)) {
+            (Some(region), Some(magic)) => {
+                match region
+                    .read_to_vec()
+                    .and_then(|data| legacy::down_convert(&data, magic))
+                {
+                    Ok(converted) => Some(FetchedRecords::Converted(converted)),
+                    Err(e) => {
+                        println!("Fetch - down-conversion to magic {magic} failed: {e}");
+                        error_code = FETCH_RESPONSE_UNKNOWN_SERVER_ERROR;
+                        None
+                    }
+                }
+            }
+            (region, _) => region.map(FetchedRecords::Region),
+        };
         Self {
-            partiton_index: partition,
-            aborted_transactions,
-            error_code: 0,
+            partiton_index: part.partition,
+            error_code,
+            high_watermark: next_offset,
+            last_stable_offset: next_offset,
+            log_start_offset,
+            aborted_transactions: vec![],
+            preferred_read_replica: u32::MAX, // -1, no preference
             records,
             ..Default::default()
         }
     }
 
+    // the metadata partition is served by raft rather than the log manager
+    fn from_raft(req: &FetchRequest, part: &FetchPartition, raft: &Arc<raft::RaftClient>) -> Self {
+        let fetch = raft.handle_fetch(
+            req.cluster_id.as_deref(),
+            quorum::ReplicaKey {
+                id: req.replica_id,
+                directory_id: part.replica_directory_id,
+            },
+            part.current_leader_epoch,
+            part.fetch_offset,
+            part.last_fetched_epoch,
+            part.partition_max_bytes as u64,
+        );
+        Self {
+            partiton_index: part.partition,
+            error_code: fetch.error_code as u16,
+            high_watermark: fetch.high_watermark as u64,
+            last_stable_offset: fetch.high_watermark as u64,
+            log_start_offset: fetch.log_start_offset as u64,
+            aborted_transactions: vec![],
+            preferred_read_replica: u32::MAX,
+            records: fetch.records.map(FetchedRecords::Region),
+            diverging_epoch: fetch.diverging_epoch,
+            current_leader: Some(fetch.current_leader),
+            snapshot_id: fetch.snapshot_id,
+        }
+    }
+
     fn new_with_error(ec: u16) -> Self {
-        let aborted_transactions = vec![];
         Self {
-            aborted_transactions,
             error_code: ec,
             ..Default::default()
         }
     }
 
-    fn serialize<W: Write>(&self, resp: &mut W) -> errors::Result<()> {
+    fn serialize<W: RegionWrite>(
+        &self,
+        resp: &mut W,
+        version: u16,
+        flexible: bool,
+    ) -> errors::Result<()> {
         writer::write_bytes(resp, &self.partiton_index)?;
         writer::write_bytes(resp, &self.error_code)?;
         writer::write_bytes(resp, &self.high_watermark)?;
-        writer::write_bytes(resp, &self.last_stable_offset)?;
-        writer::write_bytes(resp, &self.log_start_offset)?;
-        writer::write_bytes(resp, &(self.aborted_transactions.len() as u8))?;
-        self.aborted_transactions
-            .iter()
-            .try_for_each(|t| t.serialize(resp))?;
-        writer::write_bytes(resp, &self.preferred_read_replica)?;
+        if version >= 4 {
+            writer::write_bytes(resp, &self.last_stable_offset)?;
+        }
+        if version >= 5 {
+            writer::write_bytes(resp, &self.log_start_offset)?;
+        }
+        if version >= 4 {
+            writer::write_array_len(resp, self.aborted_transactions.len(), flexible)?;
+            self.aborted_transactions
+                .iter()
+                .try_for_each(|t| t.serialize(resp, flexible))?;
+        }
+        if version >= 11 {
+            writer::write_bytes(resp, &self.preferred_read_replica)?;
+        }
 
-        if false {
-            //writer::write_bytes(resp, &(self.records.len() as u8 + 1))?;
-            //self.records.iter().try_for_each(|t| t.serialize(resp))?;
+        let records_len = self.records.as_ref().map_or(0, FetchedRecords::len);
+        if flexible {
+            writer::write_uvarint(resp, 1 + records_len as i32)?;
         } else {
-            writer::write_uvarint(resp, 1 + self.records.len() as i32)?;
-            //writer::write_varint_main(resp, 1 + self.records.len() as i32)?;
-            resp.write_all(&self.records)?;
+            writer::write_bytes(resp, &(records_len as i32))?;
+        }
+        match &self.records {
+            Some(FetchedRecords::Region(region)) => resp.write_region(region)?,
+            Some(FetchedRecords::Converted(data)) => resp.write_all(data)?,
+            None => (),
+        }
+        if flexible {
+            let fields = quorum::fetch_partition_tags(
+                self.diverging_epoch,
+                self.current_leader,
+                self.snapshot_id,
+            )?;
+            writer::write_tagged_fields_with(resp, &fields)?;
         }
-        writer::write_bytes(resp, &self.tag_buffer)?;
         Ok(())
     }
 }
@@ -288,39 +514,84 @@ impl FetchResponsePartition {
 #[allow(dead_code)]
 #[derive(Debug, Clone)]
 struct FetchResponseTopic {
-    topic_id: u128,
+    topic: FetchTopicRef,
     partitions: Vec<FetchResponsePartition>,
-    tag_buffer: u8,
 }
 
 impl FetchResponseTopic {
-    pub fn new(topic: &FetchTopic, metadata: &Arc<Mutex<metadata::Metadata>>) -> Self {
-        let metadata = metadata.lock().unwrap();
-        let topic_meta = metadata.get_topic(topic.topic_id);
-
-        let partitions = if let Some(ppm) = topic_meta {
-            topic.partitions.iter().fold(vec![], |mut acc, part| {
-                acc.push(FetchResponsePartition::new(ppm, part.partition));
-                acc
-            })
-        } else {
-            vec![FetchResponsePartition::new_with_error(
-                FETCH_RESPONSE_UNKNOWN_TOPIC,
-            )]
+    pub fn new(
+        topic: &FetchTopic,
+        version: u16,
+        image: &metadata::MetadataImage,
+        logs: &Arc<log::LogManager>,
+    ) -> Self {
+        let (topic_id, unknown) = match &topic.topic {
+            FetchTopicRef::Id(id) => (Some(*id), FETCH_RESPONSE_UNKNOWN_TOPIC),
+            FetchTopicRef::Name(name) => (
+                image.topic_id(name),
+                FETCH_RESPONSE_UNKNOWN_TOPIC_OR_PARTITION,
+            ),
         };
+        let topic_name = topic_id.and_then(|id| image.topic_name(id));
+
+        let partitions = topic.partitions.iter().fold(vec![], |mut acc, part| {
+            let known = topic_id
+                .and_then(|id| image.partition_by_id(id, part.partition as i32))
+                .and(topic_name);
+            acc.push(match known {
+                Some(name) => FetchResponsePartition::new(name, part, logs, version),
+                None => FetchResponsePartition {
+                    partiton_index: part.partition,
+                    ..FetchResponsePartition::new_with_error(if topic_name.is_some() {
+                        FETCH_RESPONSE_UNKNOWN_TOPIC_OR_PARTITION
+                    } else {
+                        unknown
+                    })
+                },
+            });
+            acc
+        });
 
         Self {
-            topic_id: topic.topic_id,
+            topic: topic.topic.clone(),
             partitions,
-            tag_buffer: topic.tag_buffer,
         }
     }
 
-    fn serialize<W: Write>(&self, resp: &mut W) -> errors::Result<()> {
-        writer::write_bytes(resp, &self.topic_id)?;
-        writer::write_bytes(resp, &(self.partitions.len() as u8 + 1))?;
-        self.partitions.iter().try_for_each(|p| p.serialize(resp))?;
-        writer::write_bytes(resp, &self.tag_buffer)?;
+    fn from_raft(topic: &FetchTopic, req: &FetchRequest, raft: &Arc<raft::RaftClient>) -> Self {
+        Self {
+            topic: topic.topic.clone(),
+            partitions: topic
+                .partitions
+                .iter()
+                .map(|part| {
+                    if part.partition == 0 {
+                        FetchResponsePartition::from_raft(req, part, raft)
+                    } else {
+                        FetchResponsePartition {
+                            partiton_index: part.partition,
+                            ..FetchResponsePartition::new_with_error(
+                                FETCH_RESPONSE_UNKNOWN_TOPIC_OR_PARTITION,
+                            )
+                        }
+                    }
+                })
+                .collect(),
+        }
+    }
+
+    fn serialize<W: RegionWrite>(
+        &self,
+        resp: &mut W,
+        version: u16,
+        flexible: bool,
+    ) -> errors::Result<()> {
+        self.topic.serialize(resp, flexible)?;
+        writer::write_array_len(resp, self.partitions.len(), flexible)?;
+        self.partitions
+            .iter()
+            .try_for_each(|p| p.serialize(resp, version, flexible))?;
+        writer::write_tagged_fields(resp, flexible)?;
         Ok(())
     }
 }
@@ -328,35 +599,143 @@ impl FetchResponseTopic {
 #[allow(dead_code)]
 #[derive(Debug, Clone)]
 pub(crate) struct FetchResponse {
+    version: u16,
+    flexible: bool,
     throttle_time_ms: u32,
     error_code: u16,
     session_id: u32,
     responses: Vec<FetchResponseTopic>,
-    tag_buffer: u8,
 }
 
 impl FetchResponse {
-    pub fn new(req: &FetchRequest, metadata: &Arc<Mutex<metadata::Metadata>>) -> Self {
+    pub fn new(
+        req: &FetchRequest,
+        metadata: &Arc<metadata::MetadataCache>,
+        logs: &Arc<log::LogManager>,
+        raft: &Arc<raft::RaftClient>,
+    ) -> Self {
+        let image = metadata.image();
         let responses = req.topics.iter().fold(vec![], |mut acc, t| {
-            acc.push(FetchResponseTopic::new(t, metadata));
+            acc.push(if t.topic.is_metadata() {
+                FetchResponseTopic::from_raft(t, req, raft)
+            } else {
+                FetchResponseTopic::new(t, req.version, &image, logs)
+            });
             acc
         });
         Self {
+            version: req.version,
+            flexible: req.flexible,
             throttle_time_ms: 0,
             error_code: 0,
             session_id: req.session_id,
             responses,
-            tag_buffer: req.tag_buffer,
         }
     }
 
-    pub fn serialize<W: Write>(&self, resp: &mut W) -> errors::Result<()> {
-        writer::write_bytes(resp, &self.throttle_time_ms)?;
-        writer::write_bytes(resp, &self.error_code)?;
-        writer::write_bytes(resp, &self.session_id)?;
-        writer::write_bytes(resp, &(self.responses.len() as u8 + 1))?;
-        self.responses.iter().try_for_each(|r| r.serialize(resp))?;
-        writer::write_bytes(resp, &self.tag_buffer)?;
+    pub fn serialize<W: RegionWrite>(&self, resp: &mut W) -> errors::Result<()> {
+        if self.version >= 1 {
+            writer::write_bytes(resp, &self.throttle_time_ms)?;
+        }
+        if self.version >= 7 {
+            writer::write_bytes(resp, &self.error_code)?;
+            writer::write_bytes(resp, &self.session_id)?;
+        }
+        writer::write_array_len(resp, self.responses.len(), self.flexible)?;
+        self.responses
+            .iter()
+            .try_for_each(|r| r.serialize(resp, self.version, self.flexible))?;
+        writer::write_tagged_fields(resp, self.flexible)?;
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kafka::records::BatchHeader;
+    use crate::kafka::testing::{log_manager, record, records_batch, temp_root};
+    use crate::kafka::{config, records};
+
+    #[test]
+    fn test_headers_fetched_and_dropped_on_down_conversion() {
+        let root = temp_root("fetch-headers");
+        let logs = log_manager(&root, &["a"]);
+        let headers = vec![records::KafkaRecordHeader {
+            key: b"trace-id".to_vec(),
+            value: Some(b"abc".to_vec()),
+        }];
+        let batch = records_batch(vec![records::KafkaRecord {
+            key: Some(b"k".to_vec()),
+            headers: headers.clone(),
+            ..record(0)
+        }]);
+        logs.with_log("events", 0, |l| {
+            l.append(&batch.to_bytes()?, &config::TopicConfig::default())
+        })
+        .unwrap();
+        let part = FetchPartition {
+            partition: 0,
+            current_leader_epoch: -1,
+            fetch_offset: 0,
+            last_fetched_epoch: -1,
+            log_start_offset: -1,
+            partition_max_bytes: 1024,
+            replica_directory_id: [0; 16],
+        };
+        let fetched = |version| {
+            let response = FetchResponsePartition::new("events", &part, &logs, version);
+            assert_eq!(response.error_code, 0);
+            let data = match response.records.unwrap() {
+                FetchedRecords::Region(region) => region.read_to_vec().unwrap(),
+                FetchedRecords::Converted(data) => data,
+            };
+            let batches = records::RawBatch::split(&data).unwrap();
+            assert_eq!(batches.len(), 1);
+            (batches[0].magic(), batches[0].records().unwrap())
+        };
+
+        // v4 and later get the stored batch, headers and all
+        let (magic, fetched_records) = fetched(4);
+        assert_eq!(magic, 2);
+        assert_eq!(fetched_records[0].headers, headers);
+
+        // older formats have nowhere to put them, the rest of the record
+        // still comes through
+        for (version, expected) in [(0, 0), (1, 0), (2, 1), (3, 1)] {
+            let (magic, fetched_records) = fetched(version);
+            assert_eq!(magic, expected);
+            assert_eq!(fetched_records.len(), 1);
+            assert_eq!(fetched_records[0].key.as_deref(), Some(&b"k"[..]));
+            assert_eq!(fetched_records[0].value.as_deref(), Some(&b"v"[..]));
+            assert!(fetched_records[0].headers.is_empty());
+        }
+        let _ = std::fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn test_metadata_fetch_request() {
+        let request = quorum::MetadataFetchRequest {
+            cluster_id: "test".to_string(),
+            replica_id: 2,
+            replica_directory_id: [7; 16],
+            current_leader_epoch: 3,
+            fetch_offset: 10,
+            last_fetched_epoch: 2,
+            max_bytes: 1024,
+        };
+        let mut body = vec![];
+        request.serialize(&mut body).unwrap();
+        let req = FetchRequest::new(&mut &body[..], quorum::METADATA_FETCH_VERSION, true).unwrap();
+        assert_eq!(req.replica_id, 2);
+        assert_eq!(req.cluster_id.as_deref(), Some("test"));
+        assert_eq!(req.topics.len(), 1);
+        let p = &req.topics[0].partitions[0];
+        assert_eq!(
+            (p.current_leader_epoch, p.fetch_offset, p.last_fetched_epoch),
+            (3, 10, 2)
+        );
+        assert_eq!(p.partition_max_bytes, 1024);
+        assert_eq!(p.replica_directory_id, [7; 16]);
+    }
+}
@@ -0,0 +1,115 @@
+// background flusher: applies flush.ms to every log and moves the recovery
+// point checkpoint forward once the flushed data is on disk
+use crate::kafka::log::{self, LogManager};
+use crate::kafka::{errors, metadata};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+pub struct LogFlusher {
+    logs: Arc<LogManager>,
+    metadata: Arc<metadata::MetadataCache>,
+}
+
+impl LogFlusher {
+    pub fn new(logs: Arc<LogManager>, metadata: Arc<metadata::MetadataCache>) -> Self {
+        Self { logs, metadata }
+    }
+
+    pub fn start(self, interval_ms: u64) -> thread::JoinHandle<()> {
+        thread::spawn(move || loop {
+            thread::sleep(Duration::from_millis(interval_ms));
+            if let Err(e) = self.flush_due() {
+                println!("log flusher pass failed: {e}");
+            }
+        })
+    }
+
+    pub fn flush_due(&self) -> errors::Result<()> {
+        let now = log::now_ms();
+        for log in self.logs.all_logs() {
+            let mut log = log.lock().unwrap();
+            let config = self.metadata.image().topic_config(&log.topic);
+            if log.needs_time_flush(&config, now) {
+                if let Err(e) = log.flush() {
+                    println!("flush of {}-{} failed: {e}", log.topic, log.partition);
+                    let dir = log.log_dir.clone();
+                    drop(log);
+                    self.logs.mark_dir_offline(&dir);
+                }
+            }
+        }
+        // also covers flushes done inline by appends and acks=-1 produces
+        self.logs.checkpoint_recovery_points()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kafka::checkpoint::OffsetCheckpoint;
+    use crate::kafka::config;
+    use crate::kafka::metadata::{MetadataCache, MetadataImage};
+    use crate::kafka::metadata_records::CONFIG_RESOURCE_TOPIC;
+    use crate::kafka::testing::{batch, log_manager, temp_root};
+    use std::collections::HashMap;
+    use std::fs;
+
+    #[test]
+    fn test_flush_messages_moves_recovery_point_after_fsync() {
+        let root = temp_root("flush-messages");
+        let mut log = log::PartitionLog::open(&root, "events", 0, 0).unwrap();
+        let config = config::TopicConfig {
+            flush_messages: 3,
+            ..Default::default()
+        };
+
+        log.append(&batch(2), &config).unwrap();
+        assert_eq!((log.recovery_point, log.unflushed_messages), (0, 2));
+        log.append(&batch(1), &config).unwrap();
+        assert_eq!((log.recovery_point, log.unflushed_messages), (3, 0));
+
+        // a failed fsync leaves the recovery point where it was
+        fs::remove_file(log.active_segment().index_path()).unwrap();
+        assert!(log.append(&batch(3), &config).is_err());
+        assert_eq!(log.recovery_point, 3);
+        let _ = fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn test_flush_ms_flushes_and_checkpoints_once() {
+        let root = temp_root("flush-ms");
+        let logs = log_manager(&root, &["a"]);
+        let metadata = Arc::new(MetadataCache::default());
+        let overrides = HashMap::from([(config::FLUSH_MS_CONFIG.to_string(), "0".to_string())]);
+        metadata.publish(MetadataImage {
+            configs: HashMap::from([((CONFIG_RESOURCE_TOPIC, "events".to_string()), overrides)]),
+            ..Default::default()
+        });
+        logs.with_log("events", 0, |l| {
+            l.append(&batch(2), &config::TopicConfig::default())
+        })
+        .unwrap();
+        let recovery_point = || {
+            logs.get("events", 0)
+                .unwrap()
+                .lock()
+                .unwrap()
+                .recovery_point
+        };
+        assert_eq!(recovery_point(), 0);
+
+        let flusher = LogFlusher::new(Arc::clone(&logs), metadata);
+        flusher.flush_due().unwrap();
+        assert_eq!(recovery_point(), 2);
+        let checkpoint = root.join("a").join(log::RECOVERY_POINT_CHECKPOINT_FILE);
+        let points = OffsetCheckpoint::new(&checkpoint).read().unwrap();
+        assert_eq!(points.get(&("events".to_string(), 0)), Some(&2));
+
+        // nothing moved, so the checkpoint isn't written again
+        fs::remove_file(&checkpoint).unwrap();
+        flusher.flush_due().unwrap();
+        assert!(!checkpoint.exists());
+        let _ = fs::remove_dir_all(&root);
+    }
+}
@@ -29,7 +29,8 @@ impl RequestHeader {
         let mut client_id: Option<String> = None;
         let mut client_id_length_data = [0_u8; 2];
         req.read_exact(&mut client_id_length_data)?;
-        let client_id_length = u16::from_be_bytes(client_id_length_data);
+        // nullable string, -1 is null
+        let client_id_length = i16::from_be_bytes(client_id_length_data);
         if client_id_length > 0 {
             let mut client_id_data = vec![0_u8; client_id_length as usize];
             req.read_exact(&mut client_id_data)?;
@@ -37,7 +38,9 @@ impl RequestHeader {
                 client_id.get_or_insert(ss);
             }
         }
-        parser::tag_buffer(req)?;
+        if api_key.is_flexible(api_ver) {
+            parser::skip_tagged_fields(req)?;
+        }
 
         Ok(Self {
             api_key,
@@ -63,6 +66,10 @@ impl RequestHeader {
         self.api_ver
     }
 
+    pub fn is_flexible(&self) -> bool {
+        self.api_key.is_flexible(self.api_ver)
+    }
+
     pub fn get_client_id(&self) -> Option<String> {
         todo!()
     }
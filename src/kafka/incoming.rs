@@ -1,10 +1,14 @@
 #[allow(dead_code)]
 use super::{ErrorCodes, MAX_SUPPORTED_API_VERSION, MIN_SUPPORTED_API_VERSION};
-use crate::kafka::{apikey, body, errors, fetch, header, metadata, partitions, produce, writer};
+use crate::kafka::{
+    alter_configs, apikey, body, cluster, config, controller, create_topics, delete_topics, errors,
+    fetch, header, log, logdirs, metadata, metadata_api, partitions, produce, quorum, raft,
+    registration, voters, writer, zerocopy,
+};
 use std::fmt;
 use std::fs::metadata;
 use std::io::{self, Read, Write};
-use std::sync::{Arc, Mutex};
+use std::sync::Arc;
 
 // incoming request parser/handler
 //
@@ -37,10 +41,13 @@ impl Request {
         Ok(Self { header, body })
     }
 
-    pub fn process<W: Write>(
+    pub fn process<W: zerocopy::RegionWrite>(
         &self,
         response: &mut W,
-        metadata: &Arc<Mutex<metadata::Metadata>>,
+        roles: config::ListenerRoles,
+        metadata: &Arc<metadata::MetadataCache>,
+        logs: &Arc<log::LogManager>,
+        controller: &Arc<controller::Controller>,
     ) -> errors::Result<()> {
         // fill in the correlation id
         let _ = response.write(&self.header.get_correlation_id().to_be_bytes());
@@ -49,25 +56,34 @@ impl Request {
         let api_ver = self.header.get_api_ver();
         match &self.body {
             body::RequestBody::Fetch(fetcher) => {
-                // tag buffer is the first after corelation ID
-                writer::write_bytes(response, &0_u8)?;
-                let fetch_resp = fetch::FetchResponse::new(fetcher, metadata);
+                writer::write_tagged_fields(response, self.header.is_flexible())?;
+                let fetch_resp =
+                    fetch::FetchResponse::new(fetcher, metadata, logs, controller.raft());
                 if let Err(e) = fetch_resp.serialize(response) {
                     println!("there's error serializing data: {e:?}");
                 }
                 println!("Fetch response serialized!!!!!");
             }
+            body::RequestBody::Metadata(req) => {
+                let flexible = self.header.is_flexible();
+                writer::write_tagged_fields(response, flexible)?;
+                metadata_api::MetadataResponse::new(req, metadata, controller)
+                    .serialize(response, flexible)?;
+            }
             body::RequestBody::ApiVersions(_throttle, _tbuf) => {
-                if api_ver < super::MIN_SUPPORTED_API_VERSION || api_ver > MAX_SUPPORTED_API_VERSION
-                {
+                if !(MIN_SUPPORTED_API_VERSION..=MAX_SUPPORTED_API_VERSION).contains(&api_ver) {
                     let ec = u16::from(ErrorCodes::UnsupportedAPIVersion);
                     let _ = response.write(&ec.to_be_bytes());
                 } else {
                     let _ = response.write(&0_i16.to_be_bytes());
                 }
+                let keys = apikey::SUPPORTED_APIKEYS
+                    .iter()
+                    .filter(|sk| roles.controller || !sk.controller_only())
+                    .collect::<Vec<_>>();
                 // TODO - clean it up.. need +1 keys
-                let _ = response.write(&[apikey::SUPPORTED_APIKEYS.len() as u8 + 1]);
-                apikey::SUPPORTED_APIKEYS.iter().for_each(|sk| {
+                let _ = response.write(&[keys.len() as u8 + 1]);
+                keys.iter().for_each(|sk| {
                     let _ = response.write(&sk.key.to_be_bytes());
                     let _ = response.write(&sk.min.to_be_bytes());
                     let _ = response.write(&sk.max.to_be_bytes());
@@ -84,7 +100,7 @@ impl Request {
                 // tag buffer is first (immediately after correlation id) as per the test
                 writer::write_bytes(response, &0_u8)?;
 
-                let metadata = metadata.lock().unwrap();
+                let image = metadata.image();
                 let mut partitions_included = 0;
                 let _topics_length = p.topics.len();
                 let pr = partitions::PartitionsResponse {
@@ -93,9 +109,9 @@ impl Request {
                         .topics
                         .iter()
                         .map(|topic_name| {
-                            let topic = metadata.get_topic_by_name(&topic_name.name);
-                            let uuid = topic.map(|tt| tt.uuid_u128).unwrap_or(0);
-                            let partition = metadata.partition_map.get(&uuid);
+                            let topic = image.topic_by_name(&topic_name.name);
+                            let uuid = topic.map(|tt| tt.id).unwrap_or(0);
+                            let partition = topic.map(|tt| &tt.partitions);
                             partitions::Topic {
                                 error_code: if topic.is_some() { 0 } else { 3 },
                                 name: topic_name.name.clone(),
@@ -107,17 +123,19 @@ impl Request {
                                         p.response_partition_limit as usize - partitions_included,
                                     );
                                     partitions_included += pps_to_include;
-                                    for i in 0..pps_to_include {
+                                    for (idx, ppm) in pp.iter().take(pps_to_include) {
                                         //p.response_partition_limit
                                         ps.push(partitions::Partition {
                                             error_code: 0,
-                                            partition_index: pp[i].partition_id,
-                                            leader_id: 0,
-                                            leader_epoch: 0,
-                                            replica_nodes: vec![],
-                                            isr_nodes: vec![],
-                                            eligible_leader_repilcas: vec![],
-                                            last_known_elr: vec![],
+                                            partition_index: *idx,
+                                            leader_id: ppm.leader,
+                                            leader_epoch: ppm.leader_epoch,
+                                            replica_nodes: ppm.replicas.clone(),
+                                            isr_nodes: ppm.isr.clone(),
+                                            eligible_leader_repilcas: ppm
+                                                .eligible_leader_replicas
+                                                .clone(),
+                                            last_known_elr: ppm.last_known_elr.clone(),
                                             offline_replicas: vec![],
                                             tagged_field: 0,
                                         });
@@ -138,12 +156,396 @@ impl Request {
                 println!("======================= its Produce ====================");
                 // tag buffer is first (immediately after correlation id) as per the test
                 writer::write_bytes(response, &0_u8)?;
-                let prod_resp = produce::ProduceResponse::new(prod, metadata);
+                let prod_resp = produce::ProduceResponse::new(prod, metadata, logs);
                 if let Err(e) = prod_resp.serialize(response) {
                     println!("there's error serializing produce response: {e:?}");
                 }
             }
+            body::RequestBody::CreateTopics(req) => {
+                let flexible = self.header.is_flexible();
+                writer::write_tagged_fields(response, flexible)?;
+                create_topics::CreateTopicsResponse::new(req, logs, controller)
+                    .serialize(response, flexible)?;
+            }
+            body::RequestBody::DeleteTopics(req) => {
+                let flexible = self.header.is_flexible();
+                writer::write_tagged_fields(response, flexible)?;
+                delete_topics::DeleteTopicsResponse::new(req, metadata, logs, controller)
+                    .serialize(response, flexible)?;
+            }
+            body::RequestBody::IncrementalAlterConfigs(req) => {
+                let flexible = self.header.is_flexible();
+                writer::write_tagged_fields(response, flexible)?;
+                alter_configs::IncrementalAlterConfigsResponse::new(req, controller)
+                    .serialize(response, flexible)?;
+            }
+            body::RequestBody::DescribeLogDirs(req) => {
+                let flexible = self.header.is_flexible();
+                writer::write_tagged_fields(response, flexible)?;
+                logdirs::DescribeLogDirsResponse::new(req, logs).serialize(response, flexible)?;
+            }
+            body::RequestBody::DescribeCluster(req) => {
+                writer::write_tagged_fields(response, true)?;
+                cluster::DescribeClusterResponse::new(req, roles, metadata, controller)
+                    .serialize(response)?;
+            }
+            // the voters only talk to each other on controller listeners
+            body::RequestBody::Vote(req) => {
+                writer::write_tagged_fields(response, true)?;
+                if roles.controller {
+                    controller.raft().handle_vote(req)?
+                } else {
+                    quorum::VoteResponse {
+                        error_code: raft::INVALID_REQUEST,
+                        ..Default::default()
+                    }
+                }
+                .serialize(response)?;
+            }
+            body::RequestBody::BeginQuorumEpoch(req) => {
+                let flexible = self.header.is_flexible();
+                writer::write_tagged_fields(response, flexible)?;
+                if roles.controller {
+                    controller.raft().handle_begin_quorum_epoch(req)?
+                } else {
+                    quorum::QuorumEpochResponse {
+                        error_code: raft::INVALID_REQUEST,
+                        ..Default::default()
+                    }
+                }
+                .serialize(response, flexible)?;
+            }
+            body::RequestBody::EndQuorumEpoch(req) => {
+                let flexible = self.header.is_flexible();
+                writer::write_tagged_fields(response, flexible)?;
+                if roles.controller {
+                    controller.raft().handle_end_quorum_epoch(req)?
+                } else {
+                    quorum::QuorumEpochResponse {
+                        error_code: raft::INVALID_REQUEST,
+                        ..Default::default()
+                    }
+                }
+                .serialize(response, flexible)?;
+            }
+            body::RequestBody::DescribeQuorum(req) => {
+                writer::write_tagged_fields(response, true)?;
+                controller
+                    .raft()
+                    .handle_describe_quorum(req)
+                    .serialize(response)?;
+            }
+            body::RequestBody::AddRaftVoter(req) => {
+                writer::write_tagged_fields(response, true)?;
+                if roles.controller {
+                    controller.raft().handle_add_voter(req)
+                } else {
+                    voters::RaftVoterResponse {
+                        error_code: raft::INVALID_REQUEST,
+                        ..Default::default()
+                    }
+                }
+                .serialize(response)?;
+            }
+            body::RequestBody::RemoveRaftVoter(req) => {
+                writer::write_tagged_fields(response, true)?;
+                if roles.controller {
+                    controller.raft().handle_remove_voter(req)
+                } else {
+                    voters::RaftVoterResponse {
+                        error_code: raft::INVALID_REQUEST,
+                        ..Default::default()
+                    }
+                }
+                .serialize(response)?;
+            }
+            body::RequestBody::UpdateRaftVoter(req) => {
+                writer::write_tagged_fields(response, true)?;
+                if roles.controller {
+                    controller.raft().handle_update_voter(req)
+                } else {
+                    voters::UpdateRaftVoterResponse {
+                        error_code: raft::INVALID_REQUEST,
+                        ..Default::default()
+                    }
+                }
+                .serialize(response)?;
+            }
+            body::RequestBody::FetchSnapshot(req) => {
+                writer::write_tagged_fields(response, true)?;
+                if roles.controller {
+                    controller.raft().handle_fetch_snapshot(req)
+                } else {
+                    quorum::FetchSnapshotResponse {
+                        error_code: raft::INVALID_REQUEST,
+                        ..Default::default()
+                    }
+                }
+                .serialize(response)?;
+            }
+            body::RequestBody::BrokerRegistration(req) => {
+                writer::write_tagged_fields(response, true)?;
+                registration::BrokerRegistrationResponse::new(req, controller)
+                    .serialize(response)?;
+            }
+            body::RequestBody::AlterReplicaLogDirs(req) => {
+                let flexible = self.header.is_flexible();
+                writer::write_tagged_fields(response, flexible)?;
+                logdirs::AlterReplicaLogDirsResponse::new(req, metadata, logs)
+                    .serialize(response, flexible)?;
+            }
         }
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kafka::parser;
+    use crate::kafka::testing::{log_manager, temp_root};
+    use std::io::Cursor;
+    use std::path::Path;
+
+    const BROKER: config::ListenerRoles = config::ListenerRoles {
+        broker: true,
+        controller: false,
+    };
+    const QUORUM: config::ListenerRoles = config::ListenerRoles {
+        broker: false,
+        controller: true,
+    };
+
+    // what a request is processed against: a single voter quorum and the
+    // logs of the broker next to it
+    struct Node {
+        metadata: Arc<metadata::MetadataCache>,
+        logs: Arc<log::LogManager>,
+        controller: Arc<controller::Controller>,
+    }
+
+    fn node(root: &Path) -> Node {
+        let metadata = Arc::new(metadata::MetadataCache::default());
+        let controller = Arc::new(controller::open_single_voter(&root.join("meta"), &metadata));
+        Node {
+            metadata,
+            logs: log_manager(root, &["logs"]),
+            controller,
+        }
+    }
+
+    // a request the way it comes off the wire, its header for api_key at
+    // version ahead of the body
+    fn request(api_key: apikey::ApiKey, version: u16, body: body::RequestBody) -> Request {
+        let data = &mut vec![];
+        writer::write_bytes(data, &(api_key as u16)).unwrap();
+        writer::write_bytes(data, &version).unwrap();
+        writer::write_bytes(data, &7_i32).unwrap(); // correlation id
+        writer::write_bytes(data, &-1_i16).unwrap(); // no client id
+        writer::write_tagged_fields(data, api_key.is_flexible(version)).unwrap();
+        Request {
+            header: header::RequestHeader::new(&mut Cursor::new(data)).unwrap(),
+            body,
+        }
+    }
+
+    // the response to request on a listener with roles, past the
+    // correlation id
+    fn respond(request: &Request, roles: config::ListenerRoles, node: &Node) -> Cursor<Vec<u8>> {
+        let mut out = vec![];
+        request
+            .process(
+                &mut out,
+                roles,
+                &node.metadata,
+                &node.logs,
+                &node.controller,
+            )
+            .unwrap();
+        let mut r = Cursor::new(out);
+        assert_eq!(parser::read_int(&mut r).unwrap(), 7);
+        r
+    }
+
+    #[test]
+    fn test_raft_requests_only_on_controller_listeners() {
+        let root = temp_root("incoming-raft-test");
+        let node = node(&root);
+        // the top level error code of each response to the raft requests
+        let error_codes = |roles: config::ListenerRoles| {
+            let requests = [
+                request(
+                    apikey::ApiKey::Vote,
+                    1,
+                    body::RequestBody::Vote(quorum::VoteRequest {
+                        version: 1,
+                        cluster_id: None,
+                        voter_id: -1,
+                        partitions: vec![],
+                    }),
+                ),
+                request(
+                    apikey::ApiKey::BeginQuorumEpoch,
+                    1,
+                    body::RequestBody::BeginQuorumEpoch(quorum::BeginQuorumEpochRequest {
+                        version: 1,
+                        cluster_id: None,
+                        voter_id: -1,
+                        partitions: vec![],
+                        leader_endpoints: vec![],
+                    }),
+                ),
+                request(
+                    apikey::ApiKey::EndQuorumEpoch,
+                    1,
+                    body::RequestBody::EndQuorumEpoch(quorum::EndQuorumEpochRequest {
+                        version: 1,
+                        cluster_id: None,
+                        partitions: vec![],
+                        leader_endpoints: vec![],
+                    }),
+                ),
+                request(
+                    apikey::ApiKey::FetchSnapshot,
+                    1,
+                    body::RequestBody::FetchSnapshot(quorum::FetchSnapshotRequest {
+                        cluster_id: None,
+                        replica_id: 2,
+                        max_bytes: 1024,
+                        partitions: vec![],
+                    }),
+                ),
+            ];
+            requests
+                .iter()
+                .map(|request| {
+                    let r = &mut respond(request, roles, &node);
+                    parser::skip_tagged_fields(r).unwrap();
+                    match request.body {
+                        body::RequestBody::Vote(_) => {
+                            quorum::VoteResponse::read(r).unwrap().error_code
+                        }
+                        body::RequestBody::FetchSnapshot(_) => {
+                            quorum::FetchSnapshotResponse::read(r).unwrap().error_code
+                        }
+                        _ => {
+                            quorum::QuorumEpochResponse::read(r, true)
+                                .unwrap()
+                                .error_code
+                        }
+                    }
+                })
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(error_codes(BROKER), vec![raft::INVALID_REQUEST; 4]);
+        assert_eq!(error_codes(QUORUM), vec![0; 4]);
+        let _ = std::fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn test_voter_changes_only_on_controller_listeners() {
+        let root = temp_root("incoming-voters-test");
+        let node = node(&root);
+        let listeners = vec![config::Listener {
+            name: "CONTROLLER".to_string(),
+            host: "localhost".to_string(),
+            port: 9093,
+        }];
+        let requests = [
+            request(
+                apikey::ApiKey::AddRaftVoter,
+                1,
+                body::RequestBody::AddRaftVoter(voters::AddRaftVoterRequest {
+                    cluster_id: None,
+                    timeout_ms: 0,
+                    voter_id: 2,
+                    voter_directory_id: [2; 16],
+                    listeners: listeners.clone(),
+                    ack_when_committed: true,
+                }),
+            ),
+            request(
+                apikey::ApiKey::RemoveRaftVoter,
+                0,
+                body::RequestBody::RemoveRaftVoter(voters::RemoveRaftVoterRequest {
+                    cluster_id: None,
+                    voter_id: 2,
+                    voter_directory_id: [2; 16],
+                }),
+            ),
+            request(
+                apikey::ApiKey::UpdateRaftVoter,
+                0,
+                body::RequestBody::UpdateRaftVoter(voters::UpdateRaftVoterRequest {
+                    cluster_id: None,
+                    current_leader_epoch: 1,
+                    voter_id: 2,
+                    voter_directory_id: [2; 16],
+                    listeners,
+                    min_kraft_version: 0,
+                    max_kraft_version: 1,
+                }),
+            ),
+        ];
+        for request in &requests {
+            // throttle time ahead of the error code in all three
+            let r = &mut respond(request, BROKER, &node);
+            parser::skip_tagged_fields(r).unwrap();
+            assert_eq!(parser::read_int(r).unwrap(), 0);
+            assert_eq!(parser::read_short(r).unwrap(), raft::INVALID_REQUEST);
+        }
+        let _ = std::fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn test_api_versions_by_listener() {
+        let root = temp_root("incoming-api-versions-test");
+        let node = node(&root);
+        let api_keys = |roles: config::ListenerRoles| {
+            let request = request(
+                apikey::ApiKey::ApiVersions,
+                MAX_SUPPORTED_API_VERSION,
+                body::RequestBody::ApiVersions(0, 0),
+            );
+            let r = &mut respond(&request, roles, &node);
+            assert_eq!(parser::read_short(r).unwrap(), 0);
+            let count = parser::read_byte(r).unwrap() - 1;
+            let keys = (0..count)
+                .map(|_| {
+                    let key = parser::read_short(r).unwrap() as u16;
+                    parser::read_short(r).unwrap(); // min
+                    parser::read_short(r).unwrap(); // max
+                    parser::skip_tagged_fields(r).unwrap();
+                    key
+                })
+                .collect::<Vec<_>>();
+            assert_eq!(parser::read_int(r).unwrap(), 0); // throttle time
+            parser::skip_tagged_fields(r).unwrap();
+            assert_eq!(r.position() as usize, r.get_ref().len());
+            keys
+        };
+        let raft_keys = [
+            apikey::ApiKey::Vote,
+            apikey::ApiKey::BeginQuorumEpoch,
+            apikey::ApiKey::EndQuorumEpoch,
+            apikey::ApiKey::FetchSnapshot,
+            apikey::ApiKey::AddRaftVoter,
+            apikey::ApiKey::RemoveRaftVoter,
+            apikey::ApiKey::UpdateRaftVoter,
+        ]
+        .map(|k| k as u16);
+
+        let broker = api_keys(BROKER);
+        assert_eq!(
+            broker.len(),
+            apikey::SUPPORTED_APIKEYS.len() - raft_keys.len()
+        );
+        assert!(raft_keys.iter().all(|k| !broker.contains(k)));
+        assert!(broker.contains(&(apikey::ApiKey::DescribeQuorum as u16)));
+        let quorum = api_keys(QUORUM);
+        assert_eq!(quorum.len(), apikey::SUPPORTED_APIKEYS.len());
+        assert!(raft_keys.iter().all(|k| quorum.contains(k)));
+        let _ = std::fs::remove_dir_all(&root);
+    }
+}
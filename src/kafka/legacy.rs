@@ -0,0 +1,333 @@
+// legacy message sets, the magic 0 and 1 formats that came before v2 record
+// batches
+//
+// entry layout:
+//   offset(8) message_size(4) crc(4) magic(1) attributes(1)
+//   [timestamp(8), magic 1 only] key(4 + n) value(4 + n)
+//
+// The crc is a plain CRC32 over magic up to the end of the value. Compressed
+// messages are wrappers whose value is a compressed message set of inner
+// messages. With magic 1 the inner offsets are relative (0, 1, ...) and the
+// wrapper carries the absolute offset of the last inner message, with magic
+// 0 the inner offsets are absolute already.
+use crate::kafka::compression::CompressionType;
+use crate::kafka::records::BatchHeader;
+use crate::kafka::{errors, parser, records, writer};
+use std::io::{Cursor, Read};
+
+pub const LEGACY_ENTRY_OVERHEAD: usize = 12; // offset + message_size
+pub const LEGACY_CRC_OFFSET: usize = 12;
+pub const LEGACY_MAGIC_OFFSET: usize = 16;
+pub const LEGACY_ATTRIBUTES_OFFSET: usize = 17;
+pub const LEGACY_TIMESTAMP_OFFSET: usize = 18; // magic 1 only
+
+const LEGACY_COMPRESSION_CODEC_MASK: i8 = 0x07;
+const LEGACY_TIMESTAMP_TYPE_MASK: i8 = 0x08; // magic 1 LogAppendTime
+const NO_TIMESTAMP: i64 = -1;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LegacyMessage {
+    pub offset: u64,
+    pub magic: i8,
+    pub attributes: i8,
+    pub timestamp: i64, // -1 for magic 0
+    pub key: Option<Vec<u8>>,
+    pub value: Option<Vec<u8>>,
+}
+
+impl LegacyMessage {
+    // decodes one message set entry, offset and size included
+    pub fn decode(entry: &[u8]) -> errors::Result<Self> {
+        let mut input = Cursor::new(entry);
+        let offset = parser::read_u64(&mut input)?;
+        let size = parser::read_int(&mut input)?;
+        if size < 0 || entry.len() != LEGACY_ENTRY_OVERHEAD + size as usize {
+            return Err(
+                errors::KafkaErrors::CorruptRecord(format!("invalid message size {size}")).into(),
+            );
+        }
+        let crc = parser::read_int(&mut input)? as u32;
+        let computed = crc32(&entry[LEGACY_MAGIC_OFFSET..]);
+        if crc != computed {
+            return Err(errors::KafkaErrors::CorruptRecord(format!(
+                "message crc mismatch: stored {crc:0x}, computed {computed:0x}"
+            ))
+            .into());
+        }
+        let magic = parser::read_byte(&mut input)?;
+        if !(0..=1).contains(&magic) {
+            return Err(
+                errors::KafkaErrors::CorruptRecord(format!("not a legacy magic {magic}")).into(),
+            );
+        }
+        let attributes = parser::read_byte(&mut input)?;
+        let timestamp = if magic == 1 {
+            parser::read_u64(&mut input)? as i64
+        } else {
+            NO_TIMESTAMP
+        };
+        let key = read_bytes(&mut input)?;
+        let value = read_bytes(&mut input)?;
+        Ok(Self {
+            offset,
+            magic,
+            attributes,
+            timestamp,
+            key,
+            value,
+        })
+    }
+
+    // the whole entry, with a fresh size and crc
+    pub fn encode(&self) -> errors::Result<Vec<u8>> {
+        let mut message = vec![];
+        writer::write_bytes(&mut message, &self.magic)?;
+        writer::write_bytes(&mut message, &self.attributes)?;
+        if self.magic == 1 {
+            writer::write_bytes(&mut message, &self.timestamp)?;
+        }
+        write_bytes(&mut message, self.key.as_deref())?;
+        write_bytes(&mut message, self.value.as_deref())?;
+
+        let mut entry = Vec::with_capacity(LEGACY_ENTRY_OVERHEAD + 4 + message.len());
+        writer::write_bytes(&mut entry, &self.offset)?;
+        writer::write_bytes(&mut entry, &((message.len() + 4) as i32))?;
+        writer::write_bytes(&mut entry, &crc32(&message))?;
+        entry.extend_from_slice(&message);
+        Ok(entry)
+    }
+
+    pub fn compression(&self) -> errors::Result<CompressionType> {
+        CompressionType::from_id((self.attributes & LEGACY_COMPRESSION_CODEC_MASK) as i16)
+    }
+
+    pub fn is_log_append_time(&self) -> bool {
+        self.magic == 1 && self.attributes & LEGACY_TIMESTAMP_TYPE_MASK != 0
+    }
+
+    // the messages a wrapper holds, with absolute offsets and, for
+    // LogAppendTime wrappers, the wrapper's timestamp
+    fn unwrap(&self) -> errors::Result<Vec<Self>> {
+        let codec = self.compression()?;
+        if codec == CompressionType::None {
+            return Ok(vec![self.clone()]);
+        }
+        let inner_set = codec.decompress(self.value.as_deref().unwrap_or_default())?;
+        let mut inner = vec![];
+        for entry in split_entries(&inner_set)? {
+            let message = Self::decode(entry)?;
+            if message.compression()? != CompressionType::None {
+                return Err(errors::KafkaErrors::CorruptRecord(
+                    "nested compressed message".to_string(),
+                )
+                .into());
+            }
+            inner.push(message);
+        }
+        if self.magic == 1 {
+            let last_relative = inner.last().map_or(0, |m| m.offset);
+            let first_absolute = self.offset.checked_sub(last_relative).ok_or_else(|| {
+                errors::KafkaErrors::CorruptRecord("invalid inner offsets".to_string())
+            })?;
+            inner.iter_mut().for_each(|m| {
+                m.offset += first_absolute;
+                if self.is_log_append_time() {
+                    m.timestamp = self.timestamp;
+                }
+            });
+        }
+        Ok(inner)
+    }
+}
+
+// splits a message set into its entries. A truncated last entry, which
+// fetches of old may end with, is dropped.
+fn split_entries(data: &[u8]) -> errors::Result<Vec<&[u8]>> {
+    let mut entries = vec![];
+    let mut rest = data;
+    while rest.len() >= LEGACY_ENTRY_OVERHEAD {
+        let size = i32::from_be_bytes(rest[8..12].try_into()?);
+        if size < 0 {
+            return Err(
+                errors::KafkaErrors::CorruptRecord(format!("invalid message size {size}")).into(),
+            );
+        }
+        let len = LEGACY_ENTRY_OVERHEAD + size as usize;
+        if len > rest.len() {
+            break;
+        }
+        entries.push(&rest[..len]);
+        rest = &rest[len..];
+    }
+    Ok(entries)
+}
+
+// decodes a message set into its messages, compressed wrappers unpacked
+pub fn read_message_set(data: &[u8]) -> errors::Result<Vec<LegacyMessage>> {
+    let mut messages = vec![];
+    for entry in split_entries(data)? {
+        messages.extend(LegacyMessage::decode(entry)?.unwrap()?);
+    }
+    Ok(messages)
+}
+
+// up-converts legacy messages into a single v2 batch. v0 messages have no
+// timestamp and end up with -1.
+pub fn to_batch(messages: &[LegacyMessage]) -> errors::Result<records::RecordsBatch> {
+    let (Some(first), Some(last)) = (messages.first(), messages.last()) else {
+        return Err(errors::KafkaErrors::CorruptRecord("empty message set".to_string()).into());
+    };
+    let mut batch = records::RecordsBatch::new();
+    batch.base_offset = first.offset;
+    batch.last_offset_delta = (last.offset - first.offset) as i32;
+    batch.base_timestamp = first.timestamp;
+    batch.max_timestamp = messages
+        .iter()
+        .map(|m| m.timestamp)
+        .max()
+        .unwrap_or(NO_TIMESTAMP);
+    if first.is_log_append_time() {
+        batch.attributes |= records::TIMESTAMP_TYPE_MASK;
+    }
+    batch.records = messages
+        .iter()
+        .map(|m| records::KafkaRecord {
+            offset_delta: (m.offset - first.offset) as i32,
+            timestamp_delta: m.timestamp - first.timestamp,
+            key: m.key.clone(),
+            value: m.value.clone(),
+            ..Default::default()
+        })
+        .collect();
+    Ok(batch)
+}
+
+// rewrites the batches of a fetch for clients that can't read v2: every
+// record becomes an uncompressed message of the given magic. Headers have no
+// legacy counterpart and are dropped, as are control batches.
+pub fn down_convert(data: &[u8], magic: i8) -> errors::Result<Vec<u8>> {
+    let mut out = vec![];
+    let mut input = Cursor::new(data);
+    while let Some(batch) = records::RawBatch::read(&mut input)? {
+        if batch.magic() < 2 {
+            for mut message in read_message_set(&batch.data)? {
+                message.magic = magic;
+                message.attributes &= if magic == 0 {
+                    0
+                } else {
+                    LEGACY_TIMESTAMP_TYPE_MASK
+                };
+                out.extend_from_slice(&message.encode()?);
+            }
+            continue;
+        }
+        if batch.is_control() {
+            continue;
+        }
+        let log_append_time = batch.is_log_append_time();
+        for record in batch.records()? {
+            let timestamp = if log_append_time {
+                batch.max_timestamp()
+            } else {
+                batch.base_timestamp() + record.timestamp_delta
+            };
+            let message = LegacyMessage {
+                offset: batch.base_offset() + record.offset_delta as u64,
+                magic,
+                attributes: if magic == 1 && log_append_time {
+                    LEGACY_TIMESTAMP_TYPE_MASK
+                } else {
+                    0
+                },
+                timestamp: if magic == 0 { NO_TIMESTAMP } else { timestamp },
+                key: record.key,
+                value: record.value,
+            };
+            out.extend_from_slice(&message.encode()?);
+        }
+    }
+    Ok(out)
+}
+
+fn read_bytes<R: Read>(input: &mut R) -> errors::Result<Option<Vec<u8>>> {
+    let len = parser::read_int(input)?;
+    if len < 0 {
+        return Ok(None);
+    }
+    let mut data = vec![0_u8; len as usize];
+    input.read_exact(&mut data)?;
+    Ok(Some(data))
+}
+
+fn write_bytes(out: &mut Vec<u8>, val: Option<&[u8]>) -> errors::Result<()> {
+    match val {
+        Some(v) => {
+            writer::write_bytes(out, &(v.len() as i32))?;
+            out.extend_from_slice(v);
+        }
+        None => writer::write_bytes(out, &-1_i32)?,
+    }
+    Ok(())
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = flate2::Crc::new();
+    crc.update(data);
+    crc.sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(offset: u64, magic: i8, value: &[u8]) -> LegacyMessage {
+        LegacyMessage {
+            offset,
+            magic,
+            attributes: 0,
+            timestamp: if magic == 1 {
+                1_000 + offset as i64
+            } else {
+                -1
+            },
+            key: Some(b"k".to_vec()),
+            value: Some(value.to_vec()),
+        }
+    }
+
+    #[test]
+    fn test_legacy_message_sets() {
+        // a gzip wrapper with relative inner offsets, last one at 11
+        let inner = [message(0, 1, b"a"), message(1, 1, b"b")]
+            .iter()
+            .map(|m| m.encode().unwrap())
+            .collect::<Vec<_>>()
+            .concat();
+        let wrapper = LegacyMessage {
+            attributes: 1,
+            key: None,
+            value: Some(CompressionType::Gzip.compress(&inner).unwrap()),
+            ..message(11, 1, b"")
+        };
+        let mut set = message(9, 0, b"plain").encode().unwrap();
+        set.extend_from_slice(&wrapper.encode().unwrap());
+
+        let messages = read_message_set(&set).unwrap();
+        let offsets: Vec<u64> = messages.iter().map(|m| m.offset).collect();
+        assert_eq!(offsets, vec![9, 10, 11]);
+        assert_eq!(messages[2].value, Some(b"b".to_vec()));
+
+        let batch = to_batch(&messages).unwrap();
+        assert_eq!(batch.base_offset, 9);
+        assert_eq!(batch.last_offset_delta, 2);
+        assert_eq!(batch.records[1].offset_delta, 1);
+
+        // and back down from v2, for an old fetch
+        let v2 = batch.to_bytes().unwrap();
+        let down = read_message_set(&down_convert(&v2, 0).unwrap()).unwrap();
+        assert_eq!(down.len(), 3);
+        assert!(down.iter().all(|m| m.magic == 0 && m.timestamp == -1));
+        assert_eq!(down[1].offset, 10);
+        assert_eq!(down[1].value, Some(b"a".to_vec()));
+    }
+}
@@ -0,0 +1,1384 @@
+// on-disk partition logs
+//
+// every partition lives in <log dir>/<topic>-<partition>/ as a sequence of
+// segment files named after the first offset they hold, e.g.
+// 00000000000000000000.log, 00000000000000001042.log, ...
+// The last segment is the active one, all appends go there.
+//
+// Appends only reach the page cache. Data becomes durable when the log is
+// flushed, either because flush.messages/flush.ms say so or because the
+// broker is set to sync before acknowledging acks=-1. The recovery point is
+// the offset up to which everything is known to be on disk, only segments
+// past it get their batches re-validated on startup.
+//
+// Each segment also keeps a sparse offset index (<base>.index, pairs of
+// relative offset and file position every INDEX_INTERVAL_BYTES) so a fetch
+// can find its starting position without reading the whole segment.
+//
+// The broker may be given several log dirs (JBOD). New partitions go to the
+// least used one. An I/O error takes the whole dir offline: its partitions
+// stop being served (KAFKA_STORAGE_ERROR) while the rest carry on.
+use crate::kafka::checkpoint::{OffsetCheckpoint, TopicPartition};
+use crate::kafka::records::BatchHeader;
+use crate::kafka::zerocopy::FileRegion;
+use crate::kafka::{config, errors, records, remote};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufReader, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, UNIX_EPOCH};
+
+pub const LOG_FILE_SUFFIX: &str = "log";
+pub const INDEX_FILE_SUFFIX: &str = "index";
+pub const INDEX_INTERVAL_BYTES: u64 = 4096;
+pub const CLEANED_FILE_SUFFIX: &str = "cleaned";
+pub const METADATA_TOPIC: &str = "__cluster_metadata";
+pub const RECOVERY_POINT_CHECKPOINT_FILE: &str = "recovery-point-offset-checkpoint";
+// replica moves between log dirs: the copy is built next to the live one
+// under a -future name, the old one is renamed -delete before removal
+pub const FUTURE_DIR_SUFFIX: &str = "-future";
+pub const DELETE_DIR_SUFFIX: &str = "-delete";
+
+pub fn segment_file_name(base_offset: u64) -> String {
+    format!("{base_offset:020}.{LOG_FILE_SUFFIX}")
+}
+
+pub fn partition_dir_name(topic: &str, partition: i32) -> String {
+    format!("{topic}-{partition}")
+}
+
+// reverse of partition_dir_name, topics may contain '-' themselves
+pub fn parse_partition_dir_name(name: &str) -> Option<TopicPartition> {
+    let (topic, partition) = name.rsplit_once('-')?;
+    if topic.is_empty() {
+        return None;
+    }
+    Some((topic.to_string(), partition.parse().ok()?))
+}
+
+// what an append did: the offset given to the first batch, and the time the
+// batches were stamped with on LogAppendTime topics
+#[derive(Debug, Clone, Copy)]
+pub struct LogAppendInfo {
+    pub base_offset: u64,
+    pub log_append_time: Option<i64>,
+}
+
+pub fn now_ms() -> i64 {
+    std::time::SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as i64)
+        .unwrap_or_default()
+}
+
+// file position of the last index entry at or below target
+pub fn index_floor(index: &[(u64, u64)], target: u64) -> u64 {
+    index
+        .iter()
+        .take_while(|(offset, _)| *offset <= target)
+        .last()
+        .map_or(0, |(_, position)| *position)
+}
+
+// decodes an index file back into (offset, position) entries
+pub fn decode_index(base_offset: u64, data: &[u8]) -> Vec<(u64, u64)> {
+    data.chunks_exact(8)
+        .map(|entry| {
+            let relative = u32::from_be_bytes(entry[..4].try_into().unwrap());
+            let position = u32::from_be_bytes(entry[4..].try_into().unwrap());
+            (base_offset + relative as u64, position as u64)
+        })
+        .collect()
+}
+
+// walks the batch headers of a segment file from start, looking for the
+// first batch holding target or anything after it
+pub fn find_batch_position(
+    f: &File,
+    start: u64,
+    size: u64,
+    target: u64,
+) -> errors::Result<Option<u64>> {
+    let mut position = start;
+    let mut header = [0_u8; records::LAST_OFFSET_DELTA_OFFSET + 4];
+    while position < size {
+        f.read_exact_at(&mut header, position)?;
+        let base_offset = u64::from_be_bytes(header[..8].try_into()?);
+        let length = i32::from_be_bytes(header[8..12].try_into()?) as u64;
+        let last_offset_delta =
+            i32::from_be_bytes(header[records::LAST_OFFSET_DELTA_OFFSET..].try_into()?);
+        if base_offset + last_offset_delta as u64 >= target {
+            return Ok(Some(position));
+        }
+        position += records::BATCH_OVERHEAD as u64 + length;
+    }
+    Ok(None)
+}
+
+// region of whole batches starting at position, at most max_bytes unless
+// the first batch alone is bigger
+pub fn batches_region(
+    f: Arc<File>,
+    position: u64,
+    size: u64,
+    max_bytes: u64,
+) -> errors::Result<FileRegion> {
+    let mut end = position;
+    let mut length_field = [0_u8; 4];
+    while end < size {
+        f.read_exact_at(&mut length_field, end + records::BATCH_LENGTH_OFFSET as u64)?;
+        let batch_size = records::BATCH_OVERHEAD as u64 + i32::from_be_bytes(length_field) as u64;
+        if end > position && end - position + batch_size > max_bytes {
+            break;
+        }
+        end += batch_size;
+    }
+    Ok(FileRegion {
+        file: f,
+        position,
+        length: end - position,
+    })
+}
+
+#[derive(Debug, Clone)]
+pub struct LogSegment {
+    pub base_offset: u64,
+    pub path: PathBuf,
+    pub size: u64,
+    pub max_timestamp: i64,
+    pub next_offset: u64, // offset following the last batch, base_offset if empty
+    pub index: Vec<(u64, u64)>, // (batch base offset, file position)
+    bytes_since_index: u64,
+}
+
+impl LogSegment {
+    fn empty(path: PathBuf, base_offset: u64) -> Self {
+        Self {
+            base_offset,
+            path,
+            size: 0,
+            max_timestamp: -1,
+            next_offset: base_offset,
+            index: vec![],
+            bytes_since_index: 0,
+        }
+    }
+
+    pub fn create(dir: &Path, base_offset: u64) -> errors::Result<Self> {
+        let segment = Self::empty(dir.join(segment_file_name(base_offset)), base_offset);
+        File::create(&segment.path)?;
+        File::create(segment.index_path())?;
+        Ok(segment)
+    }
+
+    pub fn index_path(&self) -> PathBuf {
+        self.path.with_extension(INDEX_FILE_SUFFIX)
+    }
+
+    // opens an existing segment. With recover set every batch crc is checked
+    // and any torn or corrupt tail left behind by a crash gets truncated.
+    pub fn open(path: PathBuf, base_offset: u64, recover: bool) -> errors::Result<Self> {
+        let mut segment = Self::empty(path, base_offset);
+        let file_len = fs::metadata(&segment.path)?.len();
+        let mut reader = BufReader::new(File::open(&segment.path)?);
+        loop {
+            match records::RawBatch::read(&mut reader) {
+                Ok(Some(batch)) if recover && batch.crc() != batch.compute_crc() => {
+                    println!(
+                        "{}: crc mismatch for batch at offset {}",
+                        segment.path.display(),
+                        batch.base_offset()
+                    );
+                    break;
+                }
+                Ok(Some(batch)) => {
+                    segment.track(&batch);
+                }
+                Ok(None) => break,
+                Err(e) => {
+                    println!(
+                        "{}: corrupt batch at {}: {e}",
+                        segment.path.display(),
+                        segment.size
+                    );
+                    break;
+                }
+            }
+        }
+        if segment.size < file_len {
+            println!(
+                "{}: truncating {} trailing bytes",
+                segment.path.display(),
+                file_len - segment.size
+            );
+            OpenOptions::new()
+                .write(true)
+                .open(&segment.path)?
+                .set_len(segment.size)?;
+        }
+        // the scan above rebuilt the index, the file may be stale or missing
+        segment.write_index()?;
+        Ok(segment)
+    }
+
+    // returns true when the batch got an index entry
+    fn track(&mut self, batch: &records::RawBatch) -> bool {
+        let indexed = self.index.is_empty() || self.bytes_since_index >= INDEX_INTERVAL_BYTES;
+        if indexed {
+            self.index.push((batch.base_offset(), self.size));
+            self.bytes_since_index = 0;
+        }
+        self.bytes_since_index += batch.size() as u64;
+        self.size += batch.size() as u64;
+        self.max_timestamp = self.max_timestamp.max(batch.max_timestamp());
+        self.next_offset = self.next_offset.max(batch.next_offset());
+        indexed
+    }
+
+    fn encode_index_entry(&self, (offset, position): (u64, u64)) -> [u8; 8] {
+        let mut entry = [0_u8; 8];
+        entry[..4].copy_from_slice(&((offset - self.base_offset) as u32).to_be_bytes());
+        entry[4..].copy_from_slice(&(position as u32).to_be_bytes());
+        entry
+    }
+
+    pub fn write_index(&self) -> errors::Result<()> {
+        let mut f = File::create(self.index_path())?;
+        for entry in &self.index {
+            f.write_all(&self.encode_index_entry(*entry))?;
+        }
+        Ok(())
+    }
+
+    // position of the first batch holding target or anything after it
+    pub fn find_position(&self, target: u64) -> errors::Result<Option<u64>> {
+        let start = index_floor(&self.index, target);
+        find_batch_position(&File::open(&self.path)?, start, self.size, target)
+    }
+
+    // whole batches from position up to max_bytes, but always at least one
+    // so a batch larger than the limit can't stall a consumer
+    pub fn region_from(&self, position: u64, max_bytes: u64) -> errors::Result<FileRegion> {
+        batches_region(
+            Arc::new(File::open(&self.path)?),
+            position,
+            self.size,
+            max_bytes,
+        )
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.size == 0
+    }
+
+    // the batches holding offset and everything after it, read one header at
+    // a time. Records are only read when a view is asked for them, so
+    // scanning a segment takes the memory of a single batch.
+    pub fn batches_from(&self, offset: u64) -> errors::Result<SegmentBatches<'_>> {
+        let file = File::open(&self.path)?;
+        let start = index_floor(&self.index, offset);
+        let position = find_batch_position(&file, start, self.size, offset)?.unwrap_or(self.size);
+        Ok(SegmentBatches {
+            segment: self,
+            file: Arc::new(file),
+            position,
+        })
+    }
+
+    pub fn append(&mut self, batch: &records::RawBatch) -> errors::Result<()> {
+        let mut f = OpenOptions::new().append(true).open(&self.path)?;
+        f.write_all(&batch.data)?;
+        if self.track(batch) {
+            let entry = self.encode_index_entry(*self.index.last().unwrap());
+            OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(self.index_path())?
+                .write_all(&entry)?;
+        }
+        Ok(())
+    }
+
+    pub fn flush(&self) -> errors::Result<()> {
+        File::open(&self.path)?.sync_all()?;
+        File::open(self.index_path())?.sync_all()?;
+        Ok(())
+    }
+
+    pub fn delete(&self) -> errors::Result<()> {
+        fs::remove_file(&self.path)?;
+        let _ = fs::remove_file(self.index_path());
+        Ok(())
+    }
+
+    pub fn last_modified_ms(&self) -> i64 {
+        fs::metadata(&self.path)
+            .and_then(|m| m.modified())
+            .ok()
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map(|d| d.as_millis() as i64)
+            .unwrap_or_default()
+    }
+
+    pub fn set_last_modified_ms(&self, ms: i64) -> errors::Result<()> {
+        let time = UNIX_EPOCH + Duration::from_millis(ms.max(0) as u64);
+        OpenOptions::new()
+            .write(true)
+            .open(&self.path)?
+            .set_modified(time)?;
+        Ok(())
+    }
+}
+
+pub struct SegmentBatches<'a> {
+    segment: &'a LogSegment,
+    file: Arc<File>,
+    position: u64,
+}
+
+impl Iterator for SegmentBatches<'_> {
+    type Item = errors::Result<BatchView>;
+
+    // a torn tail ends the scan like the end of the file does, as in
+    // RawBatch::read
+    fn next(&mut self) -> Option<Self::Item> {
+        let remaining = self.segment.size.saturating_sub(self.position);
+        if remaining < records::BATCH_OVERHEAD as u64 {
+            return None;
+        }
+        let region = FileRegion {
+            file: self.file.clone(),
+            position: self.position,
+            length: remaining.min(records::BATCH_HEADER_LEN as u64),
+        };
+        let mut header = match region.read_to_vec() {
+            Ok(header) => header,
+            Err(e) => {
+                self.position = self.segment.size;
+                return Some(Err(e));
+            }
+        };
+        let batch_length = i32::from_be_bytes(
+            header[records::BATCH_LENGTH_OFFSET..records::BATCH_OVERHEAD]
+                .try_into()
+                .unwrap(),
+        );
+        if batch_length < records::MIN_ENTRY_LENGTH {
+            self.position = self.segment.size;
+            return Some(Err(errors::KafkaErrors::CorruptRecord(format!(
+                "invalid batch length {batch_length}"
+            ))
+            .into()));
+        }
+        let size = (records::BATCH_OVERHEAD + batch_length as usize) as u64;
+        if size > remaining {
+            return None;
+        }
+        if header[records::MAGIC_OFFSET] as i8 >= 2 && size < records::BATCH_HEADER_LEN as u64 {
+            self.position = self.segment.size;
+            return Some(Err(errors::KafkaErrors::CorruptRecord(format!(
+                "invalid batch length {batch_length}"
+            ))
+            .into()));
+        }
+        header.truncate(size as usize);
+        let view = BatchView {
+            file: self.file.clone(),
+            position: self.position,
+            header,
+        };
+        self.position += size;
+        Some(Ok(view))
+    }
+}
+
+// a batch in a segment file with only its header read
+#[derive(Debug, Clone)]
+pub struct BatchView {
+    file: Arc<File>,
+    pub position: u64,
+    header: Vec<u8>,
+}
+
+impl records::BatchHeader for BatchView {
+    fn header_bytes(&self) -> &[u8] {
+        &self.header
+    }
+}
+
+impl BatchView {
+    fn region(&self, skip: usize) -> FileRegion {
+        FileRegion {
+            file: self.file.clone(),
+            position: self.position + skip as u64,
+            length: (self.size() - skip) as u64,
+        }
+    }
+
+    pub fn records(&self) -> errors::Result<records::RecordIter> {
+        if self.is_legacy() {
+            return records::RecordIter::legacy(self, &self.region(0).read_to_vec()?);
+        }
+        records::RecordIter::new(
+            self.compression()?,
+            self.region(records::BATCH_HEADER_LEN).read_to_vec()?,
+            self.records_count(),
+        )
+    }
+
+    // the whole batch, for callers that need to copy or rewrite it
+    pub fn load(&self) -> errors::Result<records::RawBatch> {
+        Ok(records::RawBatch {
+            data: self.region(0).read_to_vec()?,
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct PartitionLog {
+    pub topic: String,
+    pub partition: i32,
+    pub log_dir: PathBuf, // the JBOD dir this partition lives in
+    pub dir: PathBuf,
+    pub segments: BTreeMap<u64, LogSegment>,
+    pub log_start_offset: u64,
+    pub recovery_point: u64,
+    pub unflushed_messages: u64,
+    pub last_flush_ms: i64,
+}
+
+impl PartitionLog {
+    pub fn open(
+        root: &Path,
+        topic: &str,
+        partition: i32,
+        recovery_point: u64,
+    ) -> errors::Result<Self> {
+        let dir = root.join(partition_dir_name(topic, partition));
+        fs::create_dir_all(&dir)?;
+
+        let mut segments = BTreeMap::new();
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            let ext = path
+                .extension()
+                .and_then(|e| e.to_str())
+                .unwrap_or_default();
+            if ext == CLEANED_FILE_SUFFIX {
+                // the cleaner died before swapping this in, the original is intact
+                fs::remove_file(&path)?;
+                continue;
+            }
+            if ext != LOG_FILE_SUFFIX {
+                continue;
+            }
+            let Some(base_offset) = path
+                .file_stem()
+                .and_then(|s| s.to_str())
+                .and_then(|s| s.parse::<u64>().ok())
+            else {
+                continue;
+            };
+            segments.insert(base_offset, path);
+        }
+        // a segment needs recovery if it may hold anything past the recovery
+        // point, i.e. the one containing it and everything after
+        let first_to_recover = segments
+            .keys()
+            .take_while(|base| **base <= recovery_point)
+            .last()
+            .copied()
+            .unwrap_or(0);
+        let mut segments = segments
+            .into_iter()
+            .map(|(base_offset, path)| {
+                let recover = base_offset >= first_to_recover;
+                LogSegment::open(path, base_offset, recover).map(|s| (base_offset, s))
+            })
+            .collect::<errors::Result<BTreeMap<u64, LogSegment>>>()?;
+        if segments.is_empty() {
+            segments.insert(0, LogSegment::create(&dir, 0)?);
+        }
+        let log_start_offset = *segments.keys().next().unwrap_or(&0);
+
+        let mut log = Self {
+            topic: topic.to_string(),
+            partition,
+            log_dir: root.to_path_buf(),
+            dir,
+            segments,
+            log_start_offset,
+            recovery_point: 0,
+            unflushed_messages: 0,
+            last_flush_ms: now_ms(),
+        };
+        log.recovery_point = recovery_point.min(log.next_offset());
+        Ok(log)
+    }
+
+    pub fn topic_partition(&self) -> TopicPartition {
+        (self.topic.clone(), self.partition)
+    }
+
+    pub fn active_segment(&self) -> &LogSegment {
+        self.segments
+            .values()
+            .next_back()
+            .expect("a partition log always has an active segment")
+    }
+
+    fn active_segment_mut(&mut self) -> &mut LogSegment {
+        self.segments
+            .values_mut()
+            .next_back()
+            .expect("a partition log always has an active segment")
+    }
+
+    pub fn next_offset(&self) -> u64 {
+        self.active_segment().next_offset
+    }
+
+    // bytes on disk across all segments
+    pub fn size(&self) -> u64 {
+        self.segments.values().map(|s| s.size).sum()
+    }
+
+    // appends a produce payload, assigning offsets to every batch in it.
+    // Batches are expected to have been through the validator already, they
+    // are recompressed when the topic's compression.type asks for a codec
+    // other than the producer's, and stamped with the broker's clock on
+    // LogAppendTime topics.
+    pub fn append(
+        &mut self,
+        payload: &[u8],
+        config: &config::TopicConfig,
+    ) -> errors::Result<LogAppendInfo> {
+        let mut batches = records::RawBatch::split(payload)?
+            .into_iter()
+            .map(|batch| batch.recompressed(config.compression_type.target(batch.compression()?)))
+            .collect::<errors::Result<Vec<_>>>()?;
+        let log_append_time =
+            (config.message_timestamp_type == config::TimestampType::LogAppendTime).then(now_ms);
+        if let Some(now) = log_append_time {
+            batches
+                .iter_mut()
+                .for_each(|batch| batch.set_log_append_time(now));
+        }
+        let size: usize = batches.iter().map(|b| b.size()).sum();
+        let base_offset = self.next_offset();
+        if !self.active_segment().is_empty()
+            && self.active_segment().size + size as u64 > config.segment_bytes
+        {
+            self.roll()?;
+        }
+        for mut batch in batches {
+            let offset = self.next_offset();
+            batch.set_base_offset(offset);
+            self.active_segment_mut().append(&batch)?;
+            self.unflushed_messages += batch.records_count().max(0) as u64;
+        }
+        if self.unflushed_messages >= config.flush_messages {
+            self.flush()?;
+        }
+        Ok(LogAppendInfo {
+            base_offset,
+            log_append_time,
+        })
+    }
+
+    // fsyncs every segment that may hold data past the recovery point, which
+    // then moves up to the log end
+    pub fn flush(&mut self) -> errors::Result<()> {
+        let next_offset = self.next_offset();
+        if self.recovery_point >= next_offset {
+            return Ok(());
+        }
+        let recovery_point = self.recovery_point;
+        self.segments
+            .values()
+            .filter(|s| s.next_offset > recovery_point || s.base_offset >= recovery_point)
+            .try_for_each(|s| s.flush())?;
+        self.recovery_point = next_offset;
+        self.unflushed_messages = 0;
+        self.last_flush_ms = now_ms();
+        Ok(())
+    }
+
+    pub fn needs_time_flush(&self, config: &config::TopicConfig, now: i64) -> bool {
+        self.recovery_point < self.next_offset()
+            && now.saturating_sub(self.last_flush_ms) >= config.flush_ms
+    }
+
+    pub fn roll(&mut self) -> errors::Result<()> {
+        // the closed segment won't be appended to again, get it to disk
+        self.active_segment().flush()?;
+        let next_offset = self.next_offset();
+        let segment = LogSegment::create(&self.dir, next_offset)?;
+        println!(
+            "{}-{}: rolled new segment at {next_offset}",
+            self.topic, self.partition
+        );
+        self.segments.insert(next_offset, segment);
+        Ok(())
+    }
+
+    // locates the data a fetch at fetch_offset should get. None means the
+    // consumer is caught up.
+    pub fn read(&self, fetch_offset: u64, max_bytes: u64) -> errors::Result<Option<FileRegion>> {
+        if fetch_offset < self.log_start_offset || fetch_offset > self.next_offset() {
+            return Err(errors::KafkaErrors::OffsetOutOfRange(format!(
+                "{fetch_offset} not in [{}, {}] for {}-{}",
+                self.log_start_offset,
+                self.next_offset(),
+                self.topic,
+                self.partition
+            ))
+            .into());
+        }
+        // the offset may fall into a gap left by the cleaner, in which case
+        // the data starts in a later segment
+        let first = self
+            .segments
+            .range(..=fetch_offset)
+            .next_back()
+            .map_or(self.log_start_offset, |(base, _)| *base);
+        for segment in self.segments.range(first..).map(|(_, s)| s) {
+            if let Some(position) = segment.find_position(fetch_offset)? {
+                return Ok(Some(segment.region_from(position, max_bytes)?));
+            }
+        }
+        Ok(None)
+    }
+
+    // drops everything from offset on, the batch holding it included. Only
+    // the metadata log needs this, when a raft follower finds its log
+    // diverged from the leader's.
+    pub fn truncate_to(&mut self, offset: u64) -> errors::Result<()> {
+        if offset >= self.next_offset() {
+            return Ok(());
+        }
+        let dropped = self
+            .segments
+            .range(offset..)
+            .map(|(base, _)| *base)
+            .collect::<Vec<_>>();
+        for base in dropped {
+            if let Some(segment) = self.segments.remove(&base) {
+                segment.delete()?;
+            }
+        }
+        if let Some((&base, segment)) = self.segments.iter().next_back() {
+            if let Some(position) = segment.find_position(offset)? {
+                let path = segment.path.clone();
+                OpenOptions::new()
+                    .write(true)
+                    .open(&path)?
+                    .set_len(position)?;
+                let truncated = LogSegment::open(path, base, false)?;
+                self.segments.insert(base, truncated);
+            }
+        } else {
+            self.segments
+                .insert(offset, LogSegment::create(&self.dir, offset)?);
+            self.log_start_offset = offset;
+        }
+        self.recovery_point = self.recovery_point.min(self.next_offset());
+        println!(
+            "{}-{}: truncated to offset {}",
+            self.topic,
+            self.partition,
+            self.next_offset()
+        );
+        Ok(())
+    }
+
+    // drops the whole log and starts it over, empty, at offset. Only the
+    // metadata log needs this, when a raft follower takes a snapshot from
+    // the leader in place of a log it's too far behind on.
+    pub fn truncate_fully_and_start_at(&mut self, offset: u64) -> errors::Result<()> {
+        for segment in std::mem::take(&mut self.segments).into_values() {
+            segment.delete()?;
+        }
+        self.segments
+            .insert(offset, LogSegment::create(&self.dir, offset)?);
+        self.log_start_offset = offset;
+        self.recovery_point = offset;
+        println!(
+            "{}-{}: restarted empty at offset {offset}",
+            self.topic, self.partition
+        );
+        Ok(())
+    }
+
+    // atomically swaps the contents of a segment for a rewritten set of
+    // batches. Offsets are not touched, so the segment keeps its name.
+    pub fn replace_segment(
+        &mut self,
+        base_offset: u64,
+        batches: &[records::RawBatch],
+    ) -> errors::Result<()> {
+        let Some(old) = self.segments.get(&base_offset) else {
+            return Err(errors::KafkaErrors::StorageError(format!(
+                "no segment at offset {base_offset}"
+            ))
+            .into());
+        };
+        let cleaned_path = old.path.with_extension(CLEANED_FILE_SUFFIX);
+        let mut f = File::create(&cleaned_path)?;
+        let mut replacement = LogSegment::empty(old.path.clone(), base_offset);
+        replacement.next_offset = old.next_offset;
+        for batch in batches {
+            f.write_all(&batch.data)?;
+            replacement.track(batch);
+        }
+        f.sync_all()?;
+        fs::rename(&cleaned_path, &replacement.path)?;
+        replacement.write_index()?;
+        self.segments.insert(base_offset, replacement);
+        Ok(())
+    }
+
+    // drops a whole non-active segment, advancing the log start offset when
+    // it was the oldest one
+    pub fn delete_segment(&mut self, base_offset: u64) -> errors::Result<()> {
+        if base_offset == self.active_segment().base_offset {
+            return Ok(());
+        }
+        if let Some(segment) = self.segments.remove(&base_offset) {
+            segment.delete()?;
+        }
+        self.log_start_offset = *self.segments.keys().next().unwrap_or(&0);
+        Ok(())
+    }
+}
+
+// true for failures that mean the disk itself is in trouble. Short reads and
+// bad bytes come from decoding corrupt input, and a file that is gone was
+// raced by a delete or move, neither of which is the disk's fault.
+pub fn is_storage_error(e: &anyhow::Error) -> bool {
+    if let Some(e) = e.downcast_ref::<std::io::Error>() {
+        return !matches!(
+            e.kind(),
+            std::io::ErrorKind::UnexpectedEof
+                | std::io::ErrorKind::InvalidData
+                | std::io::ErrorKind::NotFound
+        );
+    }
+    matches!(
+        e.downcast_ref::<errors::KafkaErrors>(),
+        Some(errors::KafkaErrors::StorageError(_))
+    )
+}
+
+fn dir_usage_bytes(dir: &Path) -> u64 {
+    fs::read_dir(dir)
+        .map(|entries| {
+            entries
+                .filter_map(|e| e.ok())
+                .map(|e| {
+                    let path = e.path();
+                    if path.is_dir() {
+                        dir_usage_bytes(&path)
+                    } else {
+                        e.metadata().map(|m| m.len()).unwrap_or_default()
+                    }
+                })
+                .sum()
+        })
+        .unwrap_or_default()
+}
+
+// partition directories in a log dir, moves and deletes in flight included
+fn dir_partition_count(dir: &Path) -> usize {
+    fs::read_dir(dir)
+        .map(|entries| {
+            entries
+                .filter_map(|e| e.ok())
+                .filter(|e| e.file_type().is_ok_and(|t| t.is_dir()))
+                .filter(|e| !e.file_name().to_string_lossy().starts_with(METADATA_TOPIC))
+                .count()
+        })
+        .unwrap_or_default()
+}
+
+#[derive(Debug)]
+pub struct LogDirInfo {
+    pub path: PathBuf,
+    pub online: bool,
+    pub logs: Vec<Arc<Mutex<PartitionLog>>>,
+}
+
+#[derive(Debug)]
+pub struct LogManager {
+    dirs: Vec<PathBuf>,
+    offline_dirs: Mutex<HashSet<PathBuf>>,
+    offline_partitions: Mutex<HashSet<TopicPartition>>,
+    logs: Mutex<HashMap<TopicPartition, Arc<Mutex<PartitionLog>>>>,
+    // recovery points as last written to each dir's checkpoint
+    checkpointed: Mutex<HashMap<PathBuf, HashMap<TopicPartition, u64>>>,
+    flush_on_acks_all: bool,
+    remote: Option<remote::RemoteLogManager>,
+}
+
+impl LogManager {
+    // loads every partition directory already present under the log dirs. A
+    // dir that can't be read starts offline rather than failing the broker.
+    pub fn open(config: &config::BrokerConfig) -> errors::Result<Self> {
+        let manager = Self {
+            dirs: config.log_dirs.iter().map(PathBuf::from).collect(),
+            offline_dirs: Mutex::new(HashSet::new()),
+            offline_partitions: Mutex::new(HashSet::new()),
+            logs: Mutex::new(HashMap::new()),
+            checkpointed: Mutex::new(HashMap::new()),
+            flush_on_acks_all: config.log_flush_on_acks_all,
+            remote: if config.remote_log_storage_system_enable {
+                Some(remote::RemoteLogManager::new(config)?)
+            } else {
+                None
+            },
+        };
+        for dir in &manager.dirs {
+            if let Err(e) = manager.load_dir(dir) {
+                println!("log dir {} failed to load: {e}", dir.display());
+                manager.mark_dir_offline(dir);
+            }
+        }
+        Ok(manager)
+    }
+
+    fn load_dir(&self, dir: &Path) -> errors::Result<()> {
+        fs::create_dir_all(dir)?;
+        let recovery_points =
+            OffsetCheckpoint::new(dir.join(RECOVERY_POINT_CHECKPOINT_FILE)).read()?;
+        self.checkpointed
+            .lock()
+            .unwrap()
+            .insert(dir.to_path_buf(), recovery_points.clone());
+        let mut futures = vec![];
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            if !entry.file_type()?.is_dir() {
+                continue;
+            }
+            let name = entry.file_name().to_string_lossy().to_string();
+            if name.starts_with(METADATA_TOPIC) {
+                continue;
+            }
+            if name.ends_with(DELETE_DIR_SUFFIX) {
+                fs::remove_dir_all(entry.path())?;
+                continue;
+            }
+            if let Some(live) = name.strip_suffix(FUTURE_DIR_SUFFIX) {
+                futures.push((entry.path(), live.to_string()));
+                continue;
+            }
+            let Some(key) = parse_partition_dir_name(&name) else {
+                continue;
+            };
+            let recovery_point = recovery_points.get(&key).copied().unwrap_or(0);
+            let log = PartitionLog::open(dir, &key.0, key.1, recovery_point)?;
+            self.logs
+                .lock()
+                .unwrap()
+                .insert(key, Arc::new(Mutex::new(log)));
+        }
+        // a move interrupted after the old copy was dropped but before the
+        // new one got its final name
+        for (path, live) in futures {
+            match parse_partition_dir_name(&live) {
+                Some(key) if !self.logs.lock().unwrap().contains_key(&key) => {
+                    fs::rename(&path, dir.join(&live))?;
+                    let log = PartitionLog::open(dir, &key.0, key.1, 0)?;
+                    self.logs
+                        .lock()
+                        .unwrap()
+                        .insert(key, Arc::new(Mutex::new(log)));
+                }
+                _ => fs::remove_dir_all(&path)?,
+            }
+        }
+        Ok(())
+    }
+
+    pub fn is_online(&self, dir: &Path) -> bool {
+        !self.offline_dirs.lock().unwrap().contains(dir)
+    }
+
+    pub fn online_dirs(&self) -> Vec<PathBuf> {
+        self.dirs
+            .iter()
+            .filter(|d| self.is_online(d))
+            .cloned()
+            .collect()
+    }
+
+    pub fn flush_on_acks_all(&self) -> bool {
+        self.flush_on_acks_all
+    }
+
+    // the remote tier, when remote.log.storage.system.enable is set
+    pub fn remote(&self) -> Option<&remote::RemoteLogManager> {
+        self.remote.as_ref()
+    }
+
+    // earliest offset still available, local or remote
+    pub fn log_start_offset(&self, log: &PartitionLog) -> u64 {
+        self.remote
+            .as_ref()
+            .and_then(|r| r.log_start_offset(&log.topic_partition()))
+            .map_or(log.log_start_offset, |remote| {
+                remote.min(log.log_start_offset)
+            })
+    }
+
+    // like PartitionLog::read, falling back to the remote tier for offsets
+    // that were already dropped locally
+    pub fn read(
+        &self,
+        log: &PartitionLog,
+        fetch_offset: u64,
+        max_bytes: u64,
+    ) -> errors::Result<Option<FileRegion>> {
+        match &self.remote {
+            Some(remote) if fetch_offset < log.log_start_offset => {
+                remote.read(&log.topic_partition(), fetch_offset, max_bytes)
+            }
+            _ => log.read(fetch_offset, max_bytes),
+        }
+    }
+
+    // takes a dir and all partitions in it offline
+    pub fn mark_dir_offline(&self, dir: &Path) {
+        if !self.offline_dirs.lock().unwrap().insert(dir.to_path_buf()) {
+            return;
+        }
+        let mut logs = self.logs.lock().unwrap();
+        let mut offline = self.offline_partitions.lock().unwrap();
+        // callers must not hold any partition lock here
+        logs.retain(|tp, log| {
+            let in_dir = log.lock().unwrap().log_dir == dir;
+            if in_dir {
+                offline.insert(tp.clone());
+            }
+            !in_dir
+        });
+        println!(
+            "log dir {} is offline, partitions affected: {:?}",
+            dir.display(),
+            offline
+        );
+    }
+
+    // runs f against a partition log, creating it if this broker has none
+    // yet. I/O failures take the log's dir offline and come back as a
+    // storage error.
+    pub fn with_log<T, F>(&self, topic: &str, partition: i32, f: F) -> errors::Result<T>
+    where
+        F: FnOnce(&mut PartitionLog) -> errors::Result<T>,
+    {
+        let log = self.get_or_open(topic, partition)?;
+        self.run_on(&log, topic, partition, f)
+    }
+
+    // like with_log for readers: None when the partition has no log here,
+    // nothing is created on disk
+    pub fn with_existing_log<T, F>(
+        &self,
+        topic: &str,
+        partition: i32,
+        f: F,
+    ) -> errors::Result<Option<T>>
+    where
+        F: FnOnce(&mut PartitionLog) -> errors::Result<T>,
+    {
+        self.check_online(topic, partition)?;
+        match self.get(topic, partition) {
+            Some(log) => self.run_on(&log, topic, partition, f).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn run_on<T, F>(
+        &self,
+        log: &Mutex<PartitionLog>,
+        topic: &str,
+        partition: i32,
+        f: F,
+    ) -> errors::Result<T>
+    where
+        F: FnOnce(&mut PartitionLog) -> errors::Result<T>,
+    {
+        let mut log = log.lock().unwrap();
+        let result = f(&mut log);
+        if let Err(e) = &result {
+            if is_storage_error(e) {
+                let dir = log.log_dir.clone();
+                drop(log);
+                self.mark_dir_offline(&dir);
+                return Err(errors::KafkaErrors::StorageError(format!(
+                    "{topic}-{partition} in {}: {e}",
+                    dir.display()
+                ))
+                .into());
+            }
+        }
+        result
+    }
+
+    fn check_online(&self, topic: &str, partition: i32) -> errors::Result<()> {
+        if self
+            .offline_partitions
+            .lock()
+            .unwrap()
+            .contains(&(topic.to_string(), partition))
+        {
+            return Err(errors::KafkaErrors::StorageError(format!(
+                "{topic}-{partition} is offline"
+            ))
+            .into());
+        }
+        Ok(())
+    }
+
+    pub fn get(&self, topic: &str, partition: i32) -> Option<Arc<Mutex<PartitionLog>>> {
+        self.logs
+            .lock()
+            .unwrap()
+            .get(&(topic.to_string(), partition))
+            .cloned()
+    }
+
+    pub fn get_or_open(
+        &self,
+        topic: &str,
+        partition: i32,
+    ) -> errors::Result<Arc<Mutex<PartitionLog>>> {
+        self.check_online(topic, partition)?;
+        let key = (topic.to_string(), partition);
+        let mut logs = self.logs.lock().unwrap();
+        if let Some(log) = logs.get(&key) {
+            return Ok(Arc::clone(log));
+        }
+        let dir = self.pick_dir()?;
+        let log = match PartitionLog::open(&dir, topic, partition, 0) {
+            Ok(log) => log,
+            Err(e) => {
+                drop(logs);
+                if is_storage_error(&e) {
+                    self.mark_dir_offline(&dir);
+                }
+                return Err(e);
+            }
+        };
+        println!("created log for {topic}-{partition} in {}", dir.display());
+        let log = Arc::new(Mutex::new(log));
+        logs.insert(key, Arc::clone(&log));
+        Ok(log)
+    }
+
+    // least loaded online dir: fewest partitions on disk, then fewest bytes
+    fn pick_dir(&self) -> errors::Result<PathBuf> {
+        self.online_dirs()
+            .into_iter()
+            .min_by_key(|d| (dir_partition_count(d), dir_usage_bytes(d)))
+            .ok_or_else(|| {
+                errors::KafkaErrors::StorageError("no online log dirs".to_string()).into()
+            })
+    }
+
+    pub fn all_logs(&self) -> Vec<Arc<Mutex<PartitionLog>>> {
+        self.logs.lock().unwrap().values().cloned().collect()
+    }
+
+    pub fn logs_in(&self, dir: &Path) -> Vec<Arc<Mutex<PartitionLog>>> {
+        self.all_logs()
+            .into_iter()
+            .filter(|l| l.lock().unwrap().log_dir == dir)
+            .collect()
+    }
+
+    pub fn describe(&self) -> Vec<LogDirInfo> {
+        self.dirs
+            .iter()
+            .map(|d| LogDirInfo {
+                path: d.clone(),
+                online: self.is_online(d),
+                logs: self.logs_in(d),
+            })
+            .collect()
+    }
+
+    // moves a partition to another log dir: copy next to the destination,
+    // then swap. Appends to the partition wait for the duration.
+    pub fn move_log(&self, topic: &str, partition: i32, dest: &Path) -> errors::Result<()> {
+        if !self.dirs.iter().any(|d| d == dest) {
+            return Err(errors::KafkaErrors::LogDirNotFound(dest.display().to_string()).into());
+        }
+        if !self.is_online(dest) {
+            return Err(errors::KafkaErrors::StorageError(format!(
+                "{} is offline",
+                dest.display()
+            ))
+            .into());
+        }
+        let Some(log) = self.get(topic, partition) else {
+            // not hosted yet: just make sure it gets created there
+            let log = PartitionLog::open(dest, topic, partition, 0)?;
+            self.logs
+                .lock()
+                .unwrap()
+                .insert((topic.to_string(), partition), Arc::new(Mutex::new(log)));
+            return Ok(());
+        };
+        let mut log = log.lock().unwrap();
+        if log.log_dir == dest {
+            return Ok(());
+        }
+        log.flush()?;
+
+        let name = partition_dir_name(topic, partition);
+        let future = dest.join(format!("{name}{FUTURE_DIR_SUFFIX}"));
+        let _ = fs::remove_dir_all(&future);
+        fs::create_dir_all(&future)?;
+        for entry in fs::read_dir(&log.dir)? {
+            let entry = entry?;
+            if entry.file_type()?.is_file() {
+                fs::copy(entry.path(), future.join(entry.file_name()))?;
+            }
+        }
+        let old_dir = log.dir.clone();
+        let doomed = log
+            .log_dir
+            .join(format!("{name}.{}{DELETE_DIR_SUFFIX}", now_ms()));
+        fs::rename(&old_dir, &doomed)?;
+        fs::rename(&future, dest.join(&name))?;
+        *log = PartitionLog::open(dest, topic, partition, log.recovery_point)?;
+        fs::remove_dir_all(&doomed)?;
+        println!(
+            "moved {topic}-{partition} from {} to {}",
+            old_dir.display(),
+            dest.display()
+        );
+        Ok(())
+    }
+
+    // removes a partition's log altogether, once its topic is deleted. The
+    // dir is renamed first so a crash midway can't bring the log back.
+    pub fn delete_log(&self, topic: &str, partition: i32) -> errors::Result<()> {
+        let Some(log) = self
+            .logs
+            .lock()
+            .unwrap()
+            .remove(&(topic.to_string(), partition))
+        else {
+            return Ok(());
+        };
+        let log = log.lock().unwrap();
+        let doomed = log.log_dir.join(format!(
+            "{}.{}{DELETE_DIR_SUFFIX}",
+            partition_dir_name(topic, partition),
+            now_ms()
+        ));
+        fs::rename(&log.dir, &doomed)?;
+        fs::remove_dir_all(&doomed)?;
+        println!("deleted log for {topic}-{partition}");
+        Ok(())
+    }
+
+    // persists the recovery point of every log, one file per log dir. Only
+    // ever called after the flushes that moved them, so the files never
+    // claim more than is on disk.
+    pub fn checkpoint_recovery_points(&self) -> errors::Result<()> {
+        for dir in self.online_dirs() {
+            let points = self
+                .logs_in(&dir)
+                .iter()
+                .map(|l| {
+                    let l = l.lock().unwrap();
+                    (l.topic_partition(), l.recovery_point)
+                })
+                .collect::<HashMap<_, _>>();
+            // nothing was flushed or moved since the last write
+            if self.checkpointed.lock().unwrap().get(&dir) == Some(&points) {
+                continue;
+            }
+            match OffsetCheckpoint::new(dir.join(RECOVERY_POINT_CHECKPOINT_FILE)).write(&points) {
+                Ok(()) => {
+                    self.checkpointed.lock().unwrap().insert(dir, points);
+                }
+                Err(e) => {
+                    println!("unable to checkpoint {}: {e}", dir.display());
+                    self.mark_dir_offline(&dir);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kafka::testing::{batch, log_manager, temp_root};
+
+    #[test]
+    fn test_with_existing_log_creates_nothing() {
+        let root = temp_root("log-existing");
+        let logs = log_manager(&root, &["a"]);
+
+        let read = logs.with_existing_log("events", 0, |l| Ok(l.next_offset()));
+        assert_eq!(read.unwrap(), None);
+        assert!(logs.get("events", 0).is_none());
+        assert!(!root
+            .join("a")
+            .join(partition_dir_name("events", 0))
+            .exists());
+
+        logs.with_log("events", 0, |l| {
+            l.append(&batch(2), &config::TopicConfig::default())
+        })
+        .unwrap();
+        let read = logs.with_existing_log("events", 0, |l| Ok(l.next_offset()));
+        assert_eq!(read.unwrap(), Some(2));
+        let _ = fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn test_new_logs_spread_over_dirs() {
+        let root = temp_root("log-pick-dir");
+        let logs = log_manager(&root, &["a", "b"]);
+
+        for p in 0..4 {
+            logs.get_or_open("events", p).unwrap();
+        }
+        for dir in ["a", "b"] {
+            assert_eq!(logs.logs_in(&root.join(dir)).len(), 2);
+        }
+        let _ = fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn test_move_log_keeps_data() {
+        let root = temp_root("log-move");
+        let logs = log_manager(&root, &["a", "b"]);
+        let (a, b) = (root.join("a"), root.join("b"));
+        let name = partition_dir_name("events", 0);
+
+        logs.move_log("events", 0, &a).unwrap();
+        logs.with_log("events", 0, |l| {
+            l.append(&batch(3), &config::TopicConfig::default())
+        })
+        .unwrap();
+
+        logs.move_log("events", 0, &b).unwrap();
+        assert!(!a.join(&name).exists());
+        assert!(b.join(&name).exists());
+        let log = logs.get("events", 0).unwrap();
+        let log = log.lock().unwrap();
+        assert_eq!(log.log_dir, b);
+        assert_eq!(log.next_offset(), 3);
+        let data = log
+            .read(0, u64::MAX)
+            .unwrap()
+            .unwrap()
+            .read_to_vec()
+            .unwrap();
+        assert_eq!(data, batch(3));
+        drop(log);
+
+        // the moved log is found again after a restart
+        let logs = log_manager(&root, &["a", "b"]);
+        assert_eq!(logs.logs_in(&b).len(), 1);
+
+        let missing = logs.move_log("events", 0, &root.join("c")).unwrap_err();
+        assert!(matches!(
+            missing.downcast_ref::<errors::KafkaErrors>(),
+            Some(errors::KafkaErrors::LogDirNotFound(_))
+        ));
+        let _ = fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn test_short_v2_batch_in_segment_is_corrupt() {
+        let root = temp_root("log-short-batch");
+        fs::create_dir_all(&root).unwrap();
+        let mut segment = LogSegment::create(&root, 0).unwrap();
+        segment
+            .append(&records::RawBatch { data: batch(1) })
+            .unwrap();
+
+        // a v2 batch whose length passes the legacy minimum but is too
+        // short for the v2 header
+        let mut short = batch(1)[..40].to_vec();
+        short[records::BATCH_LENGTH_OFFSET..records::BATCH_OVERHEAD]
+            .copy_from_slice(&(40 - records::BATCH_OVERHEAD as i32).to_be_bytes());
+        OpenOptions::new()
+            .append(true)
+            .open(&segment.path)
+            .unwrap()
+            .write_all(&short)
+            .unwrap();
+        segment.size += short.len() as u64;
+
+        let mut batches = segment.batches_from(0).unwrap();
+        assert_eq!(batches.next().unwrap().unwrap().records_count(), 1);
+        let corrupt = batches.next().unwrap().unwrap_err();
+        assert!(matches!(
+            corrupt.downcast_ref::<errors::KafkaErrors>(),
+            Some(errors::KafkaErrors::CorruptRecord(_))
+        ));
+        assert!(batches.next().is_none());
+        let _ = fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn test_io_error_takes_dir_offline() {
+        let root = temp_root("log-offline");
+        let logs = log_manager(&root, &["a", "b"]);
+        let (a, b) = (root.join("a"), root.join("b"));
+        logs.move_log("events", 0, &a).unwrap();
+        logs.move_log("events", 1, &b).unwrap();
+
+        let failed = logs.with_log("events", 0, |_| -> errors::Result<()> {
+            Err(std::io::Error::other("disk gone").into())
+        });
+        assert!(is_storage_error(&failed.unwrap_err()));
+        assert!(!logs.is_online(&a));
+        assert_eq!(logs.online_dirs(), vec![b.clone()]);
+
+        // the partitions in it stay offline, the others keep working
+        let offline = logs.with_log("events", 0, |l| Ok(l.next_offset()));
+        assert!(is_storage_error(&offline.unwrap_err()));
+        assert!(logs.with_existing_log("events", 0, |_| Ok(())).is_err());
+        assert!(logs.with_log("events", 1, |_| Ok(())).is_ok());
+        // new logs only go to online dirs
+        logs.get_or_open("events", 2).unwrap();
+        assert_eq!(logs.logs_in(&b).len(), 2);
+
+        // errors that aren't about the disk leave the dir alone
+        let failed = logs.with_log("events", 1, |_| -> errors::Result<()> {
+            Err(errors::KafkaErrors::CorruptRecord("bad".to_string()).into())
+        });
+        assert!(failed.is_err());
+        assert!(logs.is_online(&b));
+        // nor do decoding a truncated batch or a segment deleted under us
+        let failed = logs.with_log("events", 1, |_| -> errors::Result<()> {
+            let mut data = batch(1);
+            data.truncate(data.len() - 1);
+            records::RawBatch { data }.records()?;
+            Ok(())
+        });
+        let failed = failed.unwrap_err();
+        assert!(failed.downcast_ref::<std::io::Error>().is_some());
+        assert!(!is_storage_error(&failed));
+        let failed = logs.with_log("events", 1, |_| -> errors::Result<()> {
+            File::open(root.join("gone.log"))?;
+            Ok(())
+        });
+        assert!(!is_storage_error(&failed.unwrap_err()));
+        assert!(logs.is_online(&b));
+        let _ = fs::remove_dir_all(&root);
+    }
+}
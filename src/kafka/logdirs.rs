@@ -0,0 +1,445 @@
+// DescribeLogDirs (key 35) and AlterReplicaLogDirs (key 34)
+//
+// https://kafka.apache.org/protocol.html#The_Messages_DescribeLogDirs
+// https://kafka.apache.org/protocol.html#The_Messages_AlterReplicaLogDirs
+use crate::kafka::{errors, log, metadata, parser, writer};
+use std::collections::BTreeMap;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+const UNKNOWN_TOPIC_OR_PARTITION: u16 = 3;
+const KAFKA_STORAGE_ERROR: u16 = 56;
+const LOG_DIR_NOT_FOUND: u16 = 57;
+
+#[derive(Debug, Clone)]
+pub struct TopicPartitions {
+    pub topic: String,
+    pub partitions: Vec<i32>,
+}
+
+impl TopicPartitions {
+    fn new<R: Read>(req: &mut R, flexible: bool) -> errors::Result<Self> {
+        let topic = String::from_utf8(parser::read_string(req, flexible)?)?;
+        let len = parser::read_array_len(req, flexible)?.unwrap_or(0);
+        let partitions = (0..len)
+            .map(|_| parser::read_int(req))
+            .collect::<errors::Result<Vec<_>>>()?;
+        if flexible {
+            parser::skip_tagged_fields(req)?;
+        }
+        Ok(Self { topic, partitions })
+    }
+}
+
+fn read_topics<R: Read>(
+    req: &mut R,
+    flexible: bool,
+) -> errors::Result<Option<Vec<TopicPartitions>>> {
+    parser::read_array_len(req, flexible)?
+        .map(|len| {
+            (0..len)
+                .map(|_| TopicPartitions::new(req, flexible))
+                .collect()
+        })
+        .transpose()
+}
+
+#[derive(Debug, Clone)]
+pub struct DescribeLogDirsRequest {
+    pub version: u16,
+    // None asks for every partition
+    pub topics: Option<Vec<TopicPartitions>>,
+}
+
+impl DescribeLogDirsRequest {
+    pub fn new<R: Read>(req: &mut R, version: u16, flexible: bool) -> errors::Result<Self> {
+        let topics = read_topics(req, flexible)?;
+        if flexible {
+            parser::skip_tagged_fields(req)?;
+        }
+        Ok(Self { version, topics })
+    }
+
+    fn wants(&self, topic: &str, partition: i32) -> bool {
+        match &self.topics {
+            None => true,
+            Some(topics) => topics
+                .iter()
+                .any(|t| t.topic == topic && t.partitions.contains(&partition)),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct DescribeLogDirsPartition {
+    pub partition_index: i32,
+    pub partition_size: i64,
+    pub offset_lag: i64,
+    pub is_future_key: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct DescribeLogDirsResult {
+    pub error_code: u16,
+    pub log_dir: String,
+    pub topics: BTreeMap<String, Vec<DescribeLogDirsPartition>>,
+    pub total_bytes: i64,
+    pub usable_bytes: i64,
+}
+
+impl DescribeLogDirsResult {
+    fn serialize<W: Write>(
+        &self,
+        resp: &mut W,
+        version: u16,
+        flexible: bool,
+    ) -> errors::Result<()> {
+        writer::write_bytes(resp, &self.error_code)?;
+        writer::write_string(resp, self.log_dir.as_bytes(), flexible)?;
+        writer::write_array_len(resp, self.topics.len(), flexible)?;
+        for (name, partitions) in &self.topics {
+            writer::write_string(resp, name.as_bytes(), flexible)?;
+            writer::write_array_len(resp, partitions.len(), flexible)?;
+            for p in partitions {
+                writer::write_bytes(resp, &p.partition_index)?;
+                writer::write_bytes(resp, &p.partition_size)?;
+                writer::write_bytes(resp, &p.offset_lag)?;
+                writer::write_bytes(resp, &p.is_future_key)?;
+                writer::write_tagged_fields(resp, flexible)?;
+            }
+            writer::write_tagged_fields(resp, flexible)?;
+        }
+        if version >= 4 {
+            writer::write_bytes(resp, &self.total_bytes)?;
+            writer::write_bytes(resp, &self.usable_bytes)?;
+        }
+        writer::write_tagged_fields(resp, flexible)?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct DescribeLogDirsResponse {
+    pub version: u16,
+    pub throttle_time_ms: i32,
+    pub error_code: u16,
+    pub results: Vec<DescribeLogDirsResult>,
+}
+
+impl DescribeLogDirsResponse {
+    pub fn new(request: &DescribeLogDirsRequest, logs: &Arc<log::LogManager>) -> Self {
+        let results = logs
+            .describe()
+            .into_iter()
+            .map(|dir| {
+                let (total_bytes, usable_bytes) = disk_space(&dir.path);
+                let mut topics: BTreeMap<String, Vec<DescribeLogDirsPartition>> = BTreeMap::new();
+                if dir.online {
+                    for log in &dir.logs {
+                        let log = log.lock().unwrap();
+                        if !request.wants(&log.topic, log.partition) {
+                            continue;
+                        }
+                        topics.entry(log.topic.clone()).or_default().push(
+                            DescribeLogDirsPartition {
+                                partition_index: log.partition,
+                                partition_size: log.size() as i64,
+                                offset_lag: 0,
+                                is_future_key: false,
+                            },
+                        );
+                    }
+                    topics
+                        .values_mut()
+                        .for_each(|p| p.sort_by_key(|p| p.partition_index));
+                }
+                DescribeLogDirsResult {
+                    error_code: if dir.online { 0 } else { KAFKA_STORAGE_ERROR },
+                    log_dir: dir.path.display().to_string(),
+                    topics,
+                    total_bytes,
+                    usable_bytes,
+                }
+            })
+            .collect();
+        Self {
+            version: request.version,
+            throttle_time_ms: 0,
+            error_code: 0,
+            results,
+        }
+    }
+
+    pub fn serialize<W: Write>(&self, resp: &mut W, flexible: bool) -> errors::Result<()> {
+        writer::write_bytes(resp, &self.throttle_time_ms)?;
+        if self.version >= 3 {
+            writer::write_bytes(resp, &self.error_code)?;
+        }
+        writer::write_array_len(resp, self.results.len(), flexible)?;
+        self.results
+            .iter()
+            .try_for_each(|r| r.serialize(resp, self.version, flexible))?;
+        writer::write_tagged_fields(resp, flexible)?;
+        Ok(())
+    }
+}
+
+// total and usable bytes of the filesystem holding a log dir, -1 if unknown
+#[cfg(unix)]
+fn disk_space(path: &Path) -> (i64, i64) {
+    use std::os::unix::ffi::OsStrExt;
+
+    let Ok(c_path) = std::ffi::CString::new(path.as_os_str().as_bytes()) else {
+        return (-1, -1);
+    };
+    // SAFETY: c_path is a valid NUL terminated string and stat is a plain
+    // struct the call fills in
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(c_path.as_ptr(), &mut stat) } != 0 {
+        return (-1, -1);
+    }
+    let frsize = stat.f_frsize as i64;
+    (stat.f_blocks as i64 * frsize, stat.f_bavail as i64 * frsize)
+}
+
+#[cfg(not(unix))]
+fn disk_space(_path: &Path) -> (i64, i64) {
+    (-1, -1)
+}
+
+#[derive(Debug, Clone)]
+pub struct AlterReplicaLogDir {
+    pub path: String,
+    pub topics: Vec<TopicPartitions>,
+}
+
+#[derive(Debug, Clone)]
+pub struct AlterReplicaLogDirsRequest {
+    pub dirs: Vec<AlterReplicaLogDir>,
+}
+
+impl AlterReplicaLogDirsRequest {
+    pub fn new<R: Read>(req: &mut R, flexible: bool) -> errors::Result<Self> {
+        let len = parser::read_array_len(req, flexible)?.unwrap_or(0);
+        let mut dirs = Vec::with_capacity(len);
+        for _ in 0..len {
+            let path = String::from_utf8(parser::read_string(req, flexible)?)?;
+            let topics = read_topics(req, flexible)?.unwrap_or_default();
+            if flexible {
+                parser::skip_tagged_fields(req)?;
+            }
+            dirs.push(AlterReplicaLogDir { path, topics });
+        }
+        if flexible {
+            parser::skip_tagged_fields(req)?;
+        }
+        Ok(Self { dirs })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct AlterReplicaLogDirsResponse {
+    pub throttle_time_ms: i32,
+    // topic -> (partition, error code)
+    pub results: BTreeMap<String, Vec<(i32, u16)>>,
+}
+
+impl AlterReplicaLogDirsResponse {
+    // moves are done inline: the response only goes out once the partition
+    // lives in its new dir
+    pub fn new(
+        request: &AlterReplicaLogDirsRequest,
+        metadata: &Arc<metadata::MetadataCache>,
+        logs: &Arc<log::LogManager>,
+    ) -> Self {
+        let mut results: BTreeMap<String, Vec<(i32, u16)>> = BTreeMap::new();
+        for dir in &request.dirs {
+            let dest = PathBuf::from(&dir.path);
+            for topic in &dir.topics {
+                for &partition in &topic.partitions {
+                    let known = metadata
+                        .image()
+                        .partition(&topic.topic, partition)
+                        .is_some();
+                    let error_code = if !known {
+                        UNKNOWN_TOPIC_OR_PARTITION
+                    } else {
+                        match logs.move_log(&topic.topic, partition, &dest) {
+                            Ok(()) => 0,
+                            Err(e) => {
+                                println!("AlterReplicaLogDirs - {}-{partition}: {e}", topic.topic);
+                                match e.downcast_ref::<errors::KafkaErrors>() {
+                                    Some(errors::KafkaErrors::LogDirNotFound(_)) => {
+                                        LOG_DIR_NOT_FOUND
+                                    }
+                                    _ => KAFKA_STORAGE_ERROR,
+                                }
+                            }
+                        }
+                    };
+                    results
+                        .entry(topic.topic.clone())
+                        .or_default()
+                        .push((partition, error_code));
+                }
+            }
+        }
+        Self {
+            throttle_time_ms: 0,
+            results,
+        }
+    }
+
+    pub fn serialize<W: Write>(&self, resp: &mut W, flexible: bool) -> errors::Result<()> {
+        writer::write_bytes(resp, &self.throttle_time_ms)?;
+        writer::write_array_len(resp, self.results.len(), flexible)?;
+        for (topic, partitions) in &self.results {
+            writer::write_string(resp, topic.as_bytes(), flexible)?;
+            writer::write_array_len(resp, partitions.len(), flexible)?;
+            for (partition, error_code) in partitions {
+                writer::write_bytes(resp, partition)?;
+                writer::write_bytes(resp, error_code)?;
+                writer::write_tagged_fields(resp, flexible)?;
+            }
+            writer::write_tagged_fields(resp, flexible)?;
+        }
+        writer::write_tagged_fields(resp, flexible)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kafka::config;
+    use crate::kafka::testing::{batch, log_manager, temp_root};
+    use metadata::{MetadataCache, MetadataImage, PartitionImage, TopicImage};
+    use std::collections::HashMap;
+    use std::fs;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_describe_log_dirs() {
+        let root = temp_root("describe-log-dirs");
+        let logs = log_manager(&root, &["a", "b"]);
+        let (a, b) = (root.join("a"), root.join("b"));
+        logs.move_log("events", 1, &a).unwrap();
+        logs.move_log("events", 0, &a).unwrap();
+        logs.move_log("other", 0, &b).unwrap();
+        logs.with_log("events", 0, |l| {
+            l.append(&batch(1), &config::TopicConfig::default())
+        })
+        .unwrap();
+
+        // v4, flexible: only events-0 and events-1
+        let mut req = vec![];
+        writer::write_array_len(&mut req, 1, true).unwrap();
+        writer::write_string(&mut req, b"events", true).unwrap();
+        writer::write_array_len(&mut req, 2, true).unwrap();
+        writer::write_bytes(&mut req, &0_i32).unwrap();
+        writer::write_bytes(&mut req, &1_i32).unwrap();
+        writer::write_tagged_fields(&mut req, true).unwrap();
+        writer::write_tagged_fields(&mut req, true).unwrap();
+        let request = DescribeLogDirsRequest::new(&mut Cursor::new(req), 4, true).unwrap();
+
+        let response = DescribeLogDirsResponse::new(&request, &logs);
+        assert_eq!(response.results.len(), 2);
+        let dir_a = &response.results[0];
+        assert_eq!(dir_a.log_dir, a.display().to_string());
+        let partitions = &dir_a.topics["events"];
+        assert_eq!(
+            partitions
+                .iter()
+                .map(|p| (p.partition_index, p.partition_size))
+                .collect::<Vec<_>>(),
+            vec![(0, batch(1).len() as i64), (1, 0)]
+        );
+        assert!(response.results[1].topics.is_empty());
+
+        let mut resp = vec![];
+        response.serialize(&mut resp, true).unwrap();
+        let mut resp = Cursor::new(resp);
+        assert_eq!(parser::read_int(&mut resp).unwrap(), 0); // throttle
+        assert_eq!(parser::read_short(&mut resp).unwrap(), 0);
+        assert_eq!(parser::read_array_len(&mut resp, true).unwrap(), Some(2));
+        assert_eq!(parser::read_short(&mut resp).unwrap(), 0);
+        let path = parser::read_string(&mut resp, true).unwrap();
+        assert_eq!(path, a.display().to_string().into_bytes());
+        assert_eq!(parser::read_array_len(&mut resp, true).unwrap(), Some(1));
+        assert_eq!(parser::read_string(&mut resp, true).unwrap(), b"events");
+        assert_eq!(parser::read_array_len(&mut resp, true).unwrap(), Some(2));
+        assert_eq!(parser::read_int(&mut resp).unwrap(), 0);
+        assert_eq!(parser::read_u64(&mut resp).unwrap(), batch(1).len() as u64);
+
+        // an offline dir is reported with a storage error and no partitions
+        logs.mark_dir_offline(&a);
+        let response = DescribeLogDirsResponse::new(&request, &logs);
+        assert_eq!(response.results[0].error_code, KAFKA_STORAGE_ERROR);
+        assert!(response.results[0].topics.is_empty());
+        assert_eq!(response.results[1].error_code, 0);
+        let _ = fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn test_alter_replica_log_dirs() {
+        let root = temp_root("alter-log-dirs");
+        let logs = log_manager(&root, &["a", "b"]);
+        let (a, b) = (root.join("a"), root.join("b"));
+        logs.move_log("events", 0, &a).unwrap();
+
+        let metadata = Arc::new(MetadataCache::default());
+        let topic = TopicImage {
+            id: 1,
+            name: "events".to_string(),
+            partitions: BTreeMap::from([(0, PartitionImage::default())]),
+        };
+        metadata.publish(MetadataImage {
+            topics: HashMap::from([(1, Arc::new(topic))]),
+            topic_ids: HashMap::from([("events".to_string(), 1)]),
+            ..Default::default()
+        });
+
+        let mut req = vec![];
+        writer::write_array_len(&mut req, 2, true).unwrap();
+        for (dir, topic, partitions) in [
+            (b.display().to_string(), "events", vec![0_i32, 7]),
+            (root.join("c").display().to_string(), "events", vec![0]),
+        ] {
+            writer::write_string(&mut req, dir.as_bytes(), true).unwrap();
+            writer::write_array_len(&mut req, 1, true).unwrap();
+            writer::write_string(&mut req, topic.as_bytes(), true).unwrap();
+            writer::write_array_len(&mut req, partitions.len(), true).unwrap();
+            for p in partitions {
+                writer::write_bytes(&mut req, &p).unwrap();
+            }
+            writer::write_tagged_fields(&mut req, true).unwrap();
+            writer::write_tagged_fields(&mut req, true).unwrap();
+        }
+        writer::write_tagged_fields(&mut req, true).unwrap();
+        let request = AlterReplicaLogDirsRequest::new(&mut Cursor::new(req), true).unwrap();
+
+        let response = AlterReplicaLogDirsResponse::new(&request, &metadata, &logs);
+        assert_eq!(
+            response.results["events"],
+            vec![
+                (0, 0),
+                (7, UNKNOWN_TOPIC_OR_PARTITION),
+                (0, LOG_DIR_NOT_FOUND)
+            ]
+        );
+        assert_eq!(logs.logs_in(&b).len(), 1);
+        assert!(logs.logs_in(&a).is_empty());
+
+        let mut resp = vec![];
+        response.serialize(&mut resp, true).unwrap();
+        let mut resp = Cursor::new(resp);
+        assert_eq!(parser::read_int(&mut resp).unwrap(), 0);
+        assert_eq!(parser::read_array_len(&mut resp, true).unwrap(), Some(1));
+        assert_eq!(parser::read_string(&mut resp, true).unwrap(), b"events");
+        assert_eq!(parser::read_array_len(&mut resp, true).unwrap(), Some(3));
+        assert_eq!(parser::read_int(&mut resp).unwrap(), 0);
+        assert_eq!(parser::read_short(&mut resp).unwrap(), 0);
+        let _ = fs::remove_dir_all(&root);
+    }
+}
@@ -1,138 +1,623 @@
-use crate::kafka::{self, errors};
-use std::collections::HashMap;
-use std::fs::File;
-use std::io::{BufReader, Read};
+// cluster metadata as replayed from the __cluster_metadata log
+//
+// A MetadataImage is an immutable view of the cluster as of some offset in
+// the metadata log. Records are replayed into a MetadataDelta on top of an
+// image, and applying the delta gives the next image, which the
+// MetadataCache then publishes. Handlers take the current image once per
+// request and read everything from it, so they never see a half applied
+// batch nor hold a lock while doing IO.
+use super::metadata_records::{
+    BrokerEndpoint, BrokerFeature, KafkaRecordAccessControlEntryRecord,
+    KafkaRecordClientQuotaRecord, KafkaRecordConfigRecord, KafkaRecordFeature,
+    KafkaRecordPartitionChangeRecord, KafkaRecordPartitionRecord, KafkaRecordProducerIdsRecord,
+    KafkaRecordRegisterBrokerRecord, KafkaRecordTopicRecord, KafkaRecordValue, QuotaEntity,
+    CONFIG_RESOURCE_TOPIC, NO_LEADER_CHANGE,
+};
+use super::records::BatchHeader;
+use super::{config, errors, records};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::sync::{Arc, RwLock};
 
-use crate::kafka::parser;
+// a config resource: its type (CONFIG_RESOURCE_TOPIC, ...) and name
+pub type ConfigResource = (i8, String);
 
-use super::{records, ErrorCodes};
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MetadataImage {
+    pub offset: Option<u64>, // last metadata log offset applied
+    pub epoch: i32,          // leader epoch of the batch holding it
+    pub features: BTreeMap<String, i16>,
+    pub brokers: BTreeMap<i32, BrokerRegistration>,
+    pub topics: HashMap<u128, Arc<TopicImage>>,
+    pub topic_ids: HashMap<String, u128>, // topics by name
+    pub configs: HashMap<ConfigResource, HashMap<String, String>>,
+    pub client_quotas: HashMap<Vec<QuotaEntity>, BTreeMap<String, f64>>,
+    pub acls: HashMap<[u8; 16], KafkaRecordAccessControlEntryRecord>,
+    pub next_producer_id: i64,
+}
+
+impl MetadataImage {
+    pub fn topic(&self, id: u128) -> Option<&TopicImage> {
+        self.topics.get(&id).map(|t| t.as_ref())
+    }
+
+    pub fn topic_by_name(&self, name: &str) -> Option<&TopicImage> {
+        self.topic(*self.topic_ids.get(name)?)
+    }
+
+    pub fn topic_id(&self, name: &str) -> Option<u128> {
+        self.topic_ids.get(name).copied()
+    }
 
-pub type LogBatchRecords = Vec<records::RecordsBatch>;
+    pub fn topic_name(&self, id: u128) -> Option<&str> {
+        self.topic(id).map(|t| t.name.as_str())
+    }
+
+    pub fn partition(&self, topic_name: &str, partition: i32) -> Option<&PartitionImage> {
+        self.topic_by_name(topic_name)?.partitions.get(&partition)
+    }
+
+    pub fn partition_by_id(&self, topic_id: u128, partition: i32) -> Option<&PartitionImage> {
+        self.topic(topic_id)?.partitions.get(&partition)
+    }
+
+    // effective config for a topic: stored overrides on top of the defaults
+    pub fn topic_config(&self, topic_name: &str) -> config::TopicConfig {
+        self.configs
+            .get(&(CONFIG_RESOURCE_TOPIC, topic_name.to_string()))
+            .map(config::TopicConfig::from_props)
+            .unwrap_or_default()
+    }
 
-#[allow(dead_code)]
-#[derive(Debug, Clone, Default)]
-pub struct TopicMetadata {
-    pub uuid: [u8; 16],
-    pub uuid_u128: u128,
-    pub record_id1: usize,
-    pub record_id2: usize,
-    pub topic_name: String,
+    // the records that rebuild this image from nothing, as written to a
+    // snapshot: features first, then brokers, topics and the rest
+    pub fn records(&self) -> Vec<KafkaRecordValue> {
+        let mut out = vec![];
+        for (name, level) in &self.features {
+            out.push(KafkaRecordValue::KafkaRecordFeatureType(
+                KafkaRecordFeature {
+                    version: 0,
+                    name: name.as_bytes().to_vec(),
+                    feature_level: *level,
+                },
+            ));
+        }
+        for broker in self.brokers.values() {
+            out.push(KafkaRecordValue::KafkaRecordRegisterBrokerType(
+                broker.to_record(),
+            ));
+        }
+        let mut topics = self.topics.values().collect::<Vec<_>>();
+        topics.sort_by(|a, b| a.name.cmp(&b.name));
+        for topic in topics {
+            let topic_uuid = topic.id.to_be_bytes();
+            out.push(KafkaRecordValue::KafkaRecordTopicRecordType(
+                KafkaRecordTopicRecord {
+                    version: 0,
+                    topic_name: topic.name.as_bytes().to_vec(),
+                    topic_uuid,
+                },
+            ));
+            for (id, partition) in &topic.partitions {
+                out.push(KafkaRecordValue::KafkaRecordPartitionType(
+                    partition.to_record(*id, topic_uuid),
+                ));
+            }
+        }
+        let mut configs = self.configs.iter().collect::<Vec<_>>();
+        configs.sort_by(|a, b| a.0.cmp(b.0));
+        for ((resource_type, resource_name), props) in configs {
+            let mut props = props.iter().collect::<Vec<_>>();
+            props.sort();
+            for (name, value) in props {
+                out.push(KafkaRecordValue::KafkaRecordConfigType(
+                    KafkaRecordConfigRecord {
+                        version: 0,
+                        resource_type: *resource_type,
+                        resource_name: resource_name.as_bytes().to_vec(),
+                        name: name.as_bytes().to_vec(),
+                        value: Some(value.as_bytes().to_vec()),
+                    },
+                ));
+            }
+        }
+        for (entity, quotas) in &self.client_quotas {
+            for (key, value) in quotas {
+                out.push(KafkaRecordValue::KafkaRecordClientQuotaType(
+                    KafkaRecordClientQuotaRecord {
+                        version: 0,
+                        entity: entity.clone(),
+                        key: key.as_bytes().to_vec(),
+                        value: *value,
+                        remove: false,
+                    },
+                ));
+            }
+        }
+        if self.next_producer_id > 0 {
+            // which broker took the last block doesn't survive a snapshot
+            out.push(KafkaRecordValue::KafkaRecordProducerIdsType(
+                KafkaRecordProducerIdsRecord {
+                    version: 0,
+                    broker_id: -1,
+                    broker_epoch: -1,
+                    next_producer_id: self.next_producer_id,
+                },
+            ));
+        }
+        for acl in self.acls.values() {
+            out.push(KafkaRecordValue::KafkaRecordAccessControlEntryType(
+                acl.clone(),
+            ));
+        }
+        out
+    }
 }
 
-#[allow(dead_code)]
-#[derive(Debug, Clone, Default)]
-pub struct PartitionMetadata {
-    pub partition_id: i32,
-    pub record_id1: usize,
-    pub record_id2: usize,
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TopicImage {
+    pub id: u128,
+    pub name: String,
+    pub partitions: BTreeMap<i32, PartitionImage>,
 }
 
-#[allow(dead_code)]
-#[derive(Debug, Clone, Default)]
-pub struct Metadata {
-    pub topic_map: HashMap<u128, TopicMetadata>,
-    pub partition_map: HashMap<u128, Vec<PartitionMetadata>>,
-    pub records: LogBatchRecords,
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PartitionImage {
+    pub replicas: Vec<i32>,
+    pub isr: Vec<i32>,
+    pub removing_replicas: Vec<i32>,
+    pub adding_replicas: Vec<i32>,
+    pub leader: i32,
+    pub leader_recovery_state: i8,
+    pub leader_epoch: i32,
+    pub partition_epoch: i32,
+    pub directories: Vec<[u8; 16]>,
+    pub eligible_leader_replicas: Vec<i32>,
+    pub last_known_elr: Vec<i32>,
 }
 
-impl Metadata {
-    pub fn new(filename: &str) -> errors::Result<Self> {
-        if let Ok(f) = File::open(filename) {
-            let mut reader = BufReader::new(f);
-            Self::decode(&mut reader)
-        } else {
-            println!("File not found!! - generating dummy Metadata!");
-            Ok(Self {
-                ..Default::default()
-            })
+impl PartitionImage {
+    fn new(rec: &KafkaRecordPartitionRecord) -> Self {
+        Self {
+            replicas: rec.replicas.clone(),
+            isr: rec.isr.clone(),
+            removing_replicas: rec.removing_replicas.clone(),
+            adding_replicas: rec.adding_replicas.clone(),
+            leader: rec.leader,
+            leader_recovery_state: rec.leader_recovery_state,
+            leader_epoch: rec.leader_epoch,
+            partition_epoch: rec.partition_epoch,
+            directories: rec.directories.clone(),
+            eligible_leader_replicas: rec.eligible_leader_replicas.clone().unwrap_or_default(),
+            last_known_elr: rec.last_known_elr.clone().unwrap_or_default(),
         }
     }
 
-    fn decode<R: Read>(buffer: &mut R) -> errors::Result<Self> {
-        let mut topic_map: HashMap<u128, TopicMetadata> = HashMap::new();
-        let mut partition_map: HashMap<u128, Vec<PartitionMetadata>> = HashMap::new();
-        let mut records: Vec<records::RecordsBatch> = vec![];
+    // v1 is needed for directories, v2 for the eligible leader replicas
+    fn to_record(&self, partition_id: i32, topic_uuid: [u8; 16]) -> KafkaRecordPartitionRecord {
+        let elr = !self.eligible_leader_replicas.is_empty() || !self.last_known_elr.is_empty();
+        KafkaRecordPartitionRecord {
+            version: match (elr, self.directories.is_empty()) {
+                (true, _) => 2,
+                (false, false) => 1,
+                (false, true) => 0,
+            },
+            partition_id,
+            topic_uuid,
+            replicas: self.replicas.clone(),
+            isr: self.isr.clone(),
+            removing_replicas: self.removing_replicas.clone(),
+            adding_replicas: self.adding_replicas.clone(),
+            leader: self.leader,
+            leader_recovery_state: self.leader_recovery_state,
+            leader_epoch: self.leader_epoch,
+            partition_epoch: self.partition_epoch,
+            directories: self.directories.clone(),
+            eligible_leader_replicas: elr.then(|| self.eligible_leader_replicas.clone()),
+            last_known_elr: elr.then(|| self.last_known_elr.clone()),
+        }
+    }
 
-        loop {
-            match records::RecordsBatch::deserialize(buffer) {
-                Ok(record) => {
-                    if record.batch_length == 0 {
-                        // Likely an empty read at the end of the file
-                        break;
-                    }
-                    records.push(record)
+    // fields a change record leaves out stay as they are. A new leader
+    // starts a new leader epoch, any change a new partition epoch.
+    fn merge(&mut self, change: &KafkaRecordPartitionChangeRecord) {
+        let lists = [
+            (&mut self.isr, &change.isr),
+            (&mut self.replicas, &change.replicas),
+            (&mut self.removing_replicas, &change.removing_replicas),
+            (&mut self.adding_replicas, &change.adding_replicas),
+            (
+                &mut self.eligible_leader_replicas,
+                &change.eligible_leader_replicas,
+            ),
+            (&mut self.last_known_elr, &change.last_known_elr),
+        ];
+        for (current, changed) in lists {
+            if let Some(changed) = changed {
+                current.clone_from(changed);
+            }
+        }
+        if let Some(dirs) = &change.directories {
+            self.directories.clone_from(dirs);
+        }
+        if change.leader != NO_LEADER_CHANGE {
+            self.leader = change.leader;
+            self.leader_epoch += 1;
+        }
+        if change.leader_recovery_state >= 0 {
+            self.leader_recovery_state = change.leader_recovery_state;
+        }
+        self.partition_epoch += 1;
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct BrokerRegistration {
+    pub id: i32,
+    pub epoch: i64,
+    pub incarnation_id: [u8; 16],
+    pub end_points: Vec<BrokerEndpoint>,
+    pub features: Vec<BrokerFeature>,
+    pub rack: Option<String>,
+    pub fenced: bool,
+    pub in_controlled_shutdown: bool,
+    pub log_dirs: Vec<[u8; 16]>,
+}
+
+impl BrokerRegistration {
+    fn new(rec: &KafkaRecordRegisterBrokerRecord) -> Self {
+        Self {
+            id: rec.broker_id,
+            epoch: rec.broker_epoch,
+            incarnation_id: rec.incarnation_id,
+            end_points: rec.end_points.clone(),
+            features: rec.features.clone(),
+            rack: rec
+                .rack
+                .as_ref()
+                .map(|r| String::from_utf8_lossy(r).to_string()),
+            fenced: rec.fenced,
+            in_controlled_shutdown: rec.in_controlled_shutdown,
+            log_dirs: rec.log_dirs.clone(),
+        }
+    }
+
+    fn to_record(&self) -> KafkaRecordRegisterBrokerRecord {
+        KafkaRecordRegisterBrokerRecord {
+            version: if self.log_dirs.is_empty() { 1 } else { 3 },
+            broker_id: self.id,
+            is_migrating_zk_broker: false,
+            incarnation_id: self.incarnation_id,
+            broker_epoch: self.epoch,
+            end_points: self.end_points.clone(),
+            features: self.features.clone(),
+            rack: self.rack.as_ref().map(|r| r.as_bytes().to_vec()),
+            fenced: self.fenced,
+            in_controlled_shutdown: self.in_controlled_shutdown,
+            log_dirs: self.log_dirs.clone(),
+        }
+    }
+}
+
+// the changes of some records on top of a base image. Topics are shared
+// with the base and only copied once a record touches them.
+#[derive(Debug, Clone)]
+pub struct MetadataDelta {
+    image: MetadataImage,
+}
+
+impl MetadataDelta {
+    pub fn new(base: &MetadataImage) -> Self {
+        Self {
+            image: base.clone(),
+        }
+    }
+
+    // the image as of the last replayed record
+    pub fn image(&self) -> &MetadataImage {
+        &self.image
+    }
+
+    // replays the records of a batch past what the image already has.
+    // Control batches only move the offset along. A record that doesn't
+    // decode or apply is skipped, so one bad record can't wedge the log.
+    pub fn replay_batch(&mut self, batch: &records::RawBatch) -> errors::Result<bool> {
+        if self.image.offset.is_some_and(|o| batch.last_offset() <= o) {
+            return Ok(false);
+        }
+        if !batch.is_control() {
+            for rec in batch.iter_records()? {
+                let rec = rec?;
+                let offset = batch.base_offset() + rec.offset_delta as u64;
+                if self.image.offset.is_some_and(|o| offset <= o) {
+                    continue;
                 }
-                Err(e) => {
-                    if let Some(io_err) = e.downcast_ref::<std::io::Error>() {
-                        if io_err.kind() == std::io::ErrorKind::UnexpectedEof {
-                            // Clean end of file
-                            break;
-                        }
-                    }
-                    // For any other error, we propagate it.
-                    return Err(e);
+                let value = rec.metadata_value().unwrap_or_else(|e| {
+                    println!("skipping undecodable metadata record at {offset}: {e}");
+                    KafkaRecordValue::Invalid
+                });
+                if let Err(e) = self.replay(offset, &value) {
+                    println!("skipping metadata record at {offset}: {e}");
                 }
             }
         }
+        self.image.offset = Some(batch.last_offset());
+        self.image.epoch = batch.partition_leader_epoch();
+        Ok(true)
+    }
 
-        records.iter().enumerate().for_each(|(i, batch)| {
-            batch
-                .records
-                .iter()
-                .enumerate()
-                .for_each(|(r2, rec)| match &rec.value {
-                    kafka::records::KafkaRecordValue::KafkaRecordTopicRecordType(v) => {
-                        let uuid = u128::from_be_bytes(v.topic_uuid);
-                        let meta = TopicMetadata {
-                            uuid: v.topic_uuid,
-                            uuid_u128: uuid,
-                            record_id1: i,
-                            record_id2: r2,
-                            topic_name: String::from_utf8(v.topic_name.clone()).unwrap(),
-                        };
-                        topic_map
-                            .entry(uuid)
-                            .and_modify(|v| *v = meta.clone())
-                            .or_insert(meta);
+    pub fn replay(&mut self, offset: u64, value: &KafkaRecordValue) -> errors::Result<()> {
+        self.image.offset = Some(offset);
+        let image = &mut self.image;
+        match value {
+            KafkaRecordValue::KafkaRecordTopicRecordType(v) => {
+                let id = u128::from_be_bytes(v.topic_uuid);
+                let topic = TopicImage {
+                    id,
+                    name: String::from_utf8_lossy(&v.topic_name).to_string(),
+                    partitions: BTreeMap::new(),
+                };
+                if image
+                    .topic_ids
+                    .get(&topic.name)
+                    .is_some_and(|old| *old != id)
+                {
+                    return Err(errors::KafkaErrors::CorruptRecord(format!(
+                        "topic '{}' already exists with another id",
+                        topic.name
+                    ))
+                    .into());
+                }
+                image.topic_ids.insert(topic.name.clone(), id);
+                image.topics.insert(id, Arc::new(topic));
+            }
+            KafkaRecordValue::KafkaRecordPartitionType(v) => {
+                let topic = self.topic_mut(&v.topic_uuid)?;
+                topic
+                    .partitions
+                    .insert(v.partition_id, PartitionImage::new(v));
+            }
+            KafkaRecordValue::KafkaRecordPartitionChangeType(v) => {
+                let topic = self.topic_mut(&v.topic_uuid)?;
+                let Some(partition) = topic.partitions.get_mut(&v.partition_id) else {
+                    return Err(errors::KafkaErrors::CorruptRecord(format!(
+                        "change for unknown partition {}-{}",
+                        topic.name, v.partition_id
+                    ))
+                    .into());
+                };
+                partition.merge(v);
+            }
+            KafkaRecordValue::KafkaRecordRemoveTopicType(v) => {
+                let id = u128::from_be_bytes(v.topic_uuid);
+                let Some(topic) = image.topics.remove(&id) else {
+                    return Err(unknown_topic(&v.topic_uuid));
+                };
+                image.topic_ids.remove(&topic.name);
+                // the topic's config overrides go along with it
+                image
+                    .configs
+                    .remove(&(CONFIG_RESOURCE_TOPIC, topic.name.clone()));
+            }
+            KafkaRecordValue::KafkaRecordConfigType(v) => {
+                let resource = (
+                    v.resource_type,
+                    String::from_utf8_lossy(&v.resource_name).to_string(),
+                );
+                let name = String::from_utf8_lossy(&v.name).to_string();
+                let props = image.configs.entry(resource.clone()).or_default();
+                match &v.value {
+                    Some(value) => {
+                        props.insert(name, String::from_utf8_lossy(value).to_string());
                     }
-                    kafka::records::KafkaRecordValue::KafkaRecordPartitionType(v) => {
-                        let meta = PartitionMetadata {
-                            partition_id: v.partition_id,
-                            record_id1: i,
-                            record_id2: r2,
-                        };
-                        let uuid = u128::from_be_bytes(v.topic_uuid);
-                        partition_map.entry(uuid).or_default().push(meta);
+                    None => {
+                        props.remove(&name);
                     }
+                }
+                if props.is_empty() {
+                    image.configs.remove(&resource);
+                }
+            }
+            KafkaRecordValue::KafkaRecordRegisterBrokerType(v) => {
+                image
+                    .brokers
+                    .insert(v.broker_id, BrokerRegistration::new(v));
+            }
+            KafkaRecordValue::KafkaRecordUnregisterBrokerType(v) => {
+                // a stale unregistration mustn't remove a newer incarnation
+                if image
+                    .brokers
+                    .get(&v.broker_id)
+                    .is_some_and(|b| b.epoch == v.broker_epoch)
+                {
+                    image.brokers.remove(&v.broker_id);
+                }
+            }
+            KafkaRecordValue::KafkaRecordFenceBrokerType(v)
+            | KafkaRecordValue::KafkaRecordUnfenceBrokerType(v) => {
+                let fenced = matches!(value, KafkaRecordValue::KafkaRecordFenceBrokerType(_));
+                let broker = broker_mut(image, v.id, v.epoch)?;
+                broker.fenced = fenced;
+            }
+            KafkaRecordValue::KafkaRecordBrokerRegistrationChangeType(v) => {
+                let broker = broker_mut(image, v.broker_id, v.broker_epoch)?;
+                match v.fenced {
+                    1 => broker.fenced = true,
+                    -1 => broker.fenced = false,
                     _ => (),
-                });
-            println!("======================= batch {i} =======================\n");
-            println!("{}", batch);
-            println!("Topic Map: {:?}", topic_map);
-            println!("Partition Map: {:?}", partition_map);
-            println!("==========================================================\n");
-        });
-
-        Ok(Metadata {
-            topic_map,
-            partition_map,
-            records,
-        })
+                }
+                match v.in_controlled_shutdown {
+                    1 => broker.in_controlled_shutdown = true,
+                    -1 => broker.in_controlled_shutdown = false,
+                    _ => (),
+                }
+                if !v.log_dirs.is_empty() {
+                    broker.log_dirs.clone_from(&v.log_dirs);
+                }
+            }
+            KafkaRecordValue::KafkaRecordFeatureType(v) => {
+                let name = String::from_utf8_lossy(&v.name).to_string();
+                // level 0 turns the feature off
+                if v.feature_level == 0 {
+                    image.features.remove(&name);
+                } else {
+                    image.features.insert(name, v.feature_level);
+                }
+            }
+            KafkaRecordValue::KafkaRecordClientQuotaType(v) => {
+                let key = String::from_utf8_lossy(&v.key).to_string();
+                let quotas = image.client_quotas.entry(v.entity.clone()).or_default();
+                if v.remove {
+                    quotas.remove(&key);
+                } else {
+                    quotas.insert(key, v.value);
+                }
+                if quotas.is_empty() {
+                    image.client_quotas.remove(&v.entity);
+                }
+            }
+            KafkaRecordValue::KafkaRecordProducerIdsType(v) => {
+                image.next_producer_id = v.next_producer_id;
+            }
+            KafkaRecordValue::KafkaRecordAccessControlEntryType(v) => {
+                image.acls.insert(v.id, v.clone());
+            }
+            KafkaRecordValue::KafkaRecordRemoveAccessControlEntryType(v) => {
+                image.acls.remove(&v.id);
+            }
+            // nothing in the image for these, the offset still moves
+            KafkaRecordValue::KafkaRecordNoOpType(_)
+            | KafkaRecordValue::KafkaRecordZkMigrationStateType(_)
+            | KafkaRecordValue::Unknown(_)
+            | KafkaRecordValue::Invalid => (),
+        }
+        Ok(())
     }
 
-    #[allow(dead_code)]
-    pub fn get_topic(&self, topic: u128) -> Option<&TopicMetadata> {
-        self.topic_map.get(&topic)
+    pub fn apply(self) -> MetadataImage {
+        self.image
+    }
+
+    fn topic_mut(&mut self, uuid: &[u8; 16]) -> errors::Result<&mut TopicImage> {
+        let id = u128::from_be_bytes(*uuid);
+        let topic = self
+            .image
+            .topics
+            .get_mut(&id)
+            .ok_or_else(|| unknown_topic(uuid))?;
+        Ok(Arc::make_mut(topic))
+    }
+}
+
+fn broker_mut(
+    image: &mut MetadataImage,
+    id: i32,
+    epoch: i64,
+) -> errors::Result<&mut BrokerRegistration> {
+    image
+        .brokers
+        .get_mut(&id)
+        .filter(|b| b.epoch == epoch)
+        .ok_or_else(|| {
+            errors::KafkaErrors::CorruptRecord(format!(
+                "no registration for broker {id} with epoch {epoch}"
+            ))
+            .into()
+        })
+}
+
+fn unknown_topic(uuid: &[u8; 16]) -> anyhow::Error {
+    errors::KafkaErrors::CorruptRecord(format!(
+        "unknown topic id {:032x}",
+        u128::from_be_bytes(*uuid)
+    ))
+    .into()
+}
+
+// the image handlers read from, swapped whole when a new one is published
+#[derive(Debug, Default)]
+pub struct MetadataCache {
+    image: RwLock<Arc<MetadataImage>>,
+}
+
+impl MetadataCache {
+    pub fn image(&self) -> Arc<MetadataImage> {
+        Arc::clone(&self.image.read().unwrap())
     }
 
-    #[allow(dead_code)]
-    pub fn get_topic_by_name(&self, topic_name: &str) -> Option<&TopicMetadata> {
-        self.topic_map.values().find(|t| t.topic_name == topic_name)
+    pub fn publish(&self, image: MetadataImage) {
+        *self.image.write().unwrap() = Arc::new(image);
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kafka::metadata_records::{
+        KafkaRecordConfigRecord, KafkaRecordRemoveTopicRecord, KafkaRecordTopicRecord,
+    };
+
+    #[test]
+    fn test_delta_replay() {
+        let uuid = [7; 16];
+        let records = [
+            KafkaRecordValue::KafkaRecordTopicRecordType(KafkaRecordTopicRecord {
+                topic_name: b"foo".to_vec(),
+                topic_uuid: uuid,
+                ..Default::default()
+            }),
+            KafkaRecordValue::KafkaRecordPartitionType(KafkaRecordPartitionRecord {
+                topic_uuid: uuid,
+                replicas: vec![1, 2],
+                isr: vec![1, 2],
+                leader: 1,
+                leader_epoch: 0,
+                partition_epoch: 0,
+                ..Default::default()
+            }),
+            KafkaRecordValue::KafkaRecordConfigType(KafkaRecordConfigRecord {
+                resource_type: CONFIG_RESOURCE_TOPIC,
+                resource_name: b"foo".to_vec(),
+                name: b"cleanup.policy".to_vec(),
+                value: Some(b"compact".to_vec()),
+                ..Default::default()
+            }),
+            KafkaRecordValue::KafkaRecordPartitionChangeType(KafkaRecordPartitionChangeRecord {
+                topic_uuid: uuid,
+                leader: 2,
+                isr: Some(vec![2]),
+                ..Default::default()
+            }),
+        ];
+        let mut delta = MetadataDelta::new(&MetadataImage::default());
+        for (offset, rec) in records.iter().enumerate() {
+            delta.replay(offset as u64, rec).unwrap();
+        }
+        let image = delta.apply();
+        let p = image.partition("foo", 0).unwrap();
+        let id = u128::from_be_bytes(uuid);
+        assert_eq!(image.partition_by_id(id, 0), Some(p));
+        assert_eq!(image.topic_id("foo"), Some(id));
+        assert_eq!(image.topic_name(id), Some("foo"));
+        assert_eq!((p.leader, p.leader_epoch, p.partition_epoch), (2, 1, 1));
+        assert_eq!(p.isr, vec![2]);
+        assert_eq!(image.configs.len(), 1);
+        assert_eq!(image.offset, Some(3));
 
-    #[allow(dead_code)]
-    pub fn get_partition(&self, topic_uuid: &[u8]) -> Option<&Vec<PartitionMetadata>> {
-        println!("------------- {topic_uuid:?} based partition lookup not implemented!!");
-        todo!()
+        // the old image stays as it was
+        let mut delta = MetadataDelta::new(&image);
+        delta
+            .replay(
+                4,
+                &KafkaRecordValue::KafkaRecordRemoveTopicType(KafkaRecordRemoveTopicRecord {
+                    topic_uuid: uuid,
+                    ..Default::default()
+                }),
+            )
+            .unwrap();
+        let next = delta.apply();
+        assert!(next.topic_by_name("foo").is_none());
+        assert!(next.topic_ids.is_empty());
+        assert!(next.configs.is_empty());
+        assert!(image.topic_by_name("foo").is_some());
     }
 }
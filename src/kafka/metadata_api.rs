@@ -0,0 +1,587 @@
+// Metadata (key 3): the brokers, the cluster and the partitions of the
+// topics asked for, as of the current metadata image
+//
+// https://kafka.apache.org/protocol.html#The_Messages_Metadata
+use crate::kafka::{controller, errors, metadata, parser, writer};
+use std::io::{Read, Write};
+use std::sync::Arc;
+
+const UNKNOWN_TOPIC_OR_PARTITION: i16 = 3;
+const LEADER_NOT_AVAILABLE: i16 = 5;
+const UNKNOWN_TOPIC_ID: i16 = 100;
+
+const NO_LEADER: i32 = -1;
+// authorized operations of a request that didn't ask for them
+pub const AUTHORIZED_OPERATIONS_OMITTED: i32 = i32::MIN;
+// there's no authorizer, so everything a resource supports is allowed.
+// Topics: read, write, create, delete, alter, describe, describe and alter
+// configs. Cluster: create, alter, describe, cluster action, describe and
+// alter configs, idempotent write.
+const TOPIC_OPERATIONS: i32 = 0b1101_1111_1000;
+pub const CLUSTER_OPERATIONS: i32 = 0b1_1111_1010_0000;
+
+const INTERNAL_TOPICS: &[&str] = &["__consumer_offsets", "__transaction_state"];
+// the listener clients get pointed at
+const LISTENER_NAME: &[u8] = b"PLAINTEXT";
+
+#[derive(Debug, Clone)]
+pub struct MetadataRequestTopic {
+    pub topic_id: u128,       // v10+, 0 when asking by name
+    pub name: Option<String>, // None when asking by id (v10+)
+}
+
+#[derive(Debug, Clone)]
+pub struct MetadataRequest {
+    pub version: u16,
+    // None asks for every topic
+    pub topics: Option<Vec<MetadataRequestTopic>>,
+    pub allow_auto_topic_creation: bool,
+    pub include_cluster_authorized_operations: bool,
+    pub include_topic_authorized_operations: bool,
+}
+
+impl MetadataRequest {
+    pub fn new<R: Read>(req: &mut R, version: u16, flexible: bool) -> errors::Result<Self> {
+        let topics = parser::read_array_len(req, flexible)?
+            .map(|len| {
+                (0..len)
+                    .map(|_| {
+                        let topic_id = if version >= 10 {
+                            parser::read_u128(req)?
+                        } else {
+                            0
+                        };
+                        let name = parser::read_nullable_string(req, flexible)?
+                            .map(String::from_utf8)
+                            .transpose()?;
+                        if flexible {
+                            parser::skip_tagged_fields(req)?;
+                        }
+                        Ok(MetadataRequestTopic { topic_id, name })
+                    })
+                    .collect::<errors::Result<Vec<_>>>()
+            })
+            .transpose()?
+            // v0 has no null array, an empty one means every topic
+            .filter(|topics| version > 0 || !topics.is_empty());
+        let allow_auto_topic_creation = version < 4 || parser::read_byte(req)? != 0;
+        let include_cluster_authorized_operations =
+            (8..=10).contains(&version) && parser::read_byte(req)? != 0;
+        let include_topic_authorized_operations = version >= 8 && parser::read_byte(req)? != 0;
+        if flexible {
+            parser::skip_tagged_fields(req)?;
+        }
+        Ok(Self {
+            version,
+            topics,
+            allow_auto_topic_creation,
+            include_cluster_authorized_operations,
+            include_topic_authorized_operations,
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MetadataResponseBroker {
+    pub node_id: i32,
+    pub host: Vec<u8>,
+    pub port: i32,
+    pub rack: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct MetadataResponsePartition {
+    pub error_code: i16,
+    pub partition_index: i32,
+    pub leader_id: i32,
+    pub leader_epoch: i32,
+    pub replica_nodes: Vec<i32>,
+    pub isr_nodes: Vec<i32>,
+    pub offline_replicas: Vec<i32>,
+}
+
+impl MetadataResponsePartition {
+    fn new(
+        partition_index: i32,
+        partition: &metadata::PartitionImage,
+        image: &metadata::MetadataImage,
+    ) -> Self {
+        let alive = |id: &i32| image.brokers.get(id).is_some_and(|b| !b.fenced);
+        let (error_code, leader_id) = if alive(&partition.leader) {
+            (0, partition.leader)
+        } else {
+            (LEADER_NOT_AVAILABLE, NO_LEADER)
+        };
+        Self {
+            error_code,
+            partition_index,
+            leader_id,
+            leader_epoch: partition.leader_epoch,
+            replica_nodes: partition.replicas.clone(),
+            isr_nodes: partition.isr.clone(),
+            offline_replicas: partition
+                .replicas
+                .iter()
+                .filter(|r| !alive(r))
+                .copied()
+                .collect(),
+        }
+    }
+
+    fn serialize<W: Write>(
+        &self,
+        resp: &mut W,
+        version: u16,
+        flexible: bool,
+    ) -> errors::Result<()> {
+        writer::write_bytes(resp, &self.error_code)?;
+        writer::write_bytes(resp, &self.partition_index)?;
+        writer::write_bytes(resp, &self.leader_id)?;
+        if version >= 7 {
+            writer::write_bytes(resp, &self.leader_epoch)?;
+        }
+        write_nodes(resp, &self.replica_nodes, flexible)?;
+        write_nodes(resp, &self.isr_nodes, flexible)?;
+        if version >= 5 {
+            write_nodes(resp, &self.offline_replicas, flexible)?;
+        }
+        writer::write_tagged_fields(resp, flexible)?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MetadataResponseTopic {
+    pub error_code: i16,
+    pub name: Option<String>,
+    pub topic_id: u128,
+    pub is_internal: bool,
+    pub partitions: Vec<MetadataResponsePartition>,
+    pub topic_authorized_operations: i32,
+}
+
+impl MetadataResponseTopic {
+    fn new(topic: &metadata::TopicImage, image: &metadata::MetadataImage) -> Self {
+        Self {
+            error_code: 0,
+            name: Some(topic.name.clone()),
+            topic_id: topic.id,
+            is_internal: INTERNAL_TOPICS.contains(&topic.name.as_str()),
+            partitions: topic
+                .partitions
+                .iter()
+                .map(|(idx, p)| MetadataResponsePartition::new(*idx, p, image))
+                .collect(),
+            topic_authorized_operations: AUTHORIZED_OPERATIONS_OMITTED,
+        }
+    }
+
+    fn new_with_error(error_code: i16, name: Option<String>, topic_id: u128) -> Self {
+        Self {
+            error_code,
+            name,
+            topic_id,
+            is_internal: false,
+            partitions: vec![],
+            topic_authorized_operations: AUTHORIZED_OPERATIONS_OMITTED,
+        }
+    }
+
+    fn serialize<W: Write>(
+        &self,
+        resp: &mut W,
+        version: u16,
+        flexible: bool,
+    ) -> errors::Result<()> {
+        writer::write_bytes(resp, &self.error_code)?;
+        let name = self.name.as_ref().map(String::as_bytes);
+        if version >= 12 {
+            writer::write_nullable_string(resp, name, flexible)?;
+        } else {
+            writer::write_string(resp, name.unwrap_or_default(), flexible)?;
+        }
+        if version >= 10 {
+            writer::write_bytes(resp, &self.topic_id)?;
+        }
+        if version >= 1 {
+            writer::write_bytes(resp, &self.is_internal)?;
+        }
+        writer::write_array_len(resp, self.partitions.len(), flexible)?;
+        self.partitions
+            .iter()
+            .try_for_each(|p| p.serialize(resp, version, flexible))?;
+        if version >= 8 {
+            writer::write_bytes(resp, &self.topic_authorized_operations)?;
+        }
+        writer::write_tagged_fields(resp, flexible)?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MetadataResponse {
+    pub version: u16,
+    pub throttle_time_ms: i32,
+    pub brokers: Vec<MetadataResponseBroker>,
+    pub cluster_id: Option<String>,
+    pub controller_id: i32,
+    pub topics: Vec<MetadataResponseTopic>,
+    pub cluster_authorized_operations: i32,
+}
+
+impl MetadataResponse {
+    pub fn new(
+        request: &MetadataRequest,
+        metadata: &Arc<metadata::MetadataCache>,
+        controller: &Arc<controller::Controller>,
+    ) -> Self {
+        let mut topics = vec![];
+        match &request.topics {
+            None => {
+                let image = metadata.image();
+                let mut all = image.topics.values().collect::<Vec<_>>();
+                all.sort_by(|a, b| a.name.cmp(&b.name));
+                topics.extend(
+                    all.into_iter()
+                        .map(|t| MetadataResponseTopic::new(t, &image)),
+                );
+            }
+            Some(wanted) => {
+                for t in wanted {
+                    topics.push(describe_topic(t, request, metadata, controller));
+                }
+            }
+        }
+        if request.include_topic_authorized_operations {
+            topics
+                .iter_mut()
+                .filter(|t| t.error_code == 0)
+                .for_each(|t| t.topic_authorized_operations = TOPIC_OPERATIONS);
+        }
+
+        // taken last so auto created topics see their leader alive
+        let brokers = live_brokers(&metadata.image());
+        Self {
+            version: request.version,
+            throttle_time_ms: 0,
+            brokers,
+            cluster_id: Some(controller.cluster_id().to_string()),
+            controller_id: controller.controller_id(),
+            topics,
+            cluster_authorized_operations: if request.include_cluster_authorized_operations {
+                CLUSTER_OPERATIONS
+            } else {
+                AUTHORIZED_OPERATIONS_OMITTED
+            },
+        }
+    }
+
+    pub fn serialize<W: Write>(&self, resp: &mut W, flexible: bool) -> errors::Result<()> {
+        let version = self.version;
+        if version >= 3 {
+            writer::write_bytes(resp, &self.throttle_time_ms)?;
+        }
+        writer::write_array_len(resp, self.brokers.len(), flexible)?;
+        for b in &self.brokers {
+            writer::write_bytes(resp, &b.node_id)?;
+            writer::write_string(resp, &b.host, flexible)?;
+            writer::write_bytes(resp, &b.port)?;
+            if version >= 1 {
+                writer::write_nullable_string(
+                    resp,
+                    b.rack.as_ref().map(String::as_bytes),
+                    flexible,
+                )?;
+            }
+            writer::write_tagged_fields(resp, flexible)?;
+        }
+        if version >= 2 {
+            writer::write_nullable_string(
+                resp,
+                self.cluster_id.as_ref().map(String::as_bytes),
+                flexible,
+            )?;
+        }
+        if version >= 1 {
+            writer::write_bytes(resp, &self.controller_id)?;
+        }
+        writer::write_array_len(resp, self.topics.len(), flexible)?;
+        self.topics
+            .iter()
+            .try_for_each(|t| t.serialize(resp, version, flexible))?;
+        if (8..=10).contains(&version) {
+            writer::write_bytes(resp, &self.cluster_authorized_operations)?;
+        }
+        writer::write_tagged_fields(resp, flexible)?;
+        Ok(())
+    }
+}
+
+// the unfenced brokers, with the endpoint of the listener clients use
+pub fn live_brokers(image: &metadata::MetadataImage) -> Vec<MetadataResponseBroker> {
+    image
+        .brokers
+        .values()
+        .filter(|b| !b.fenced)
+        .filter_map(|b| {
+            let endpoint = b
+                .end_points
+                .iter()
+                .find(|e| e.name == LISTENER_NAME)
+                .or(b.end_points.first())?;
+            Some(MetadataResponseBroker {
+                node_id: b.id,
+                host: endpoint.host.clone(),
+                port: endpoint.port as i32,
+                rack: b.rack.clone(),
+            })
+        })
+        .collect()
+}
+
+// a topic asked for by name or id, created first when it's missing and
+// the client allows it
+fn describe_topic(
+    wanted: &MetadataRequestTopic,
+    request: &MetadataRequest,
+    metadata: &Arc<metadata::MetadataCache>,
+    controller: &Arc<controller::Controller>,
+) -> MetadataResponseTopic {
+    let image = metadata.image();
+    let Some(name) = &wanted.name else {
+        return match image.topic(wanted.topic_id) {
+            Some(topic) => MetadataResponseTopic::new(topic, &image),
+            None => MetadataResponseTopic::new_with_error(UNKNOWN_TOPIC_ID, None, wanted.topic_id),
+        };
+    };
+    if let Some(topic) = image.topic_by_name(name) {
+        return MetadataResponseTopic::new(topic, &image);
+    }
+    let unknown =
+        || MetadataResponseTopic::new_with_error(UNKNOWN_TOPIC_OR_PARTITION, Some(name.clone()), 0);
+    if !request.allow_auto_topic_creation {
+        return unknown();
+    }
+    match controller.auto_create_topic(name) {
+        Ok(Some(id)) => {
+            let image = metadata.image();
+            match image.topic(id) {
+                Some(topic) => MetadataResponseTopic::new(topic, &image),
+                None => unknown(),
+            }
+        }
+        Ok(None) => unknown(),
+        Err(e) => {
+            println!("Metadata - auto creating {name}: {e}");
+            MetadataResponseTopic::new_with_error(controller::error_code(&e), Some(name.clone()), 0)
+        }
+    }
+}
+
+fn write_nodes<W: Write>(resp: &mut W, nodes: &[i32], flexible: bool) -> errors::Result<()> {
+    writer::write_array_len(resp, nodes.len(), flexible)?;
+    nodes.iter().try_for_each(|n| writer::write_bytes(resp, n))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kafka::config;
+    use crate::kafka::metadata::MetadataCache;
+    use crate::kafka::testing::temp_root;
+    use std::io::Cursor;
+
+    // what a client reads back, None for fields the version doesn't have
+    #[derive(Debug, Default, PartialEq)]
+    struct Decoded {
+        rack: Option<Option<Vec<u8>>>,
+        cluster_id: Option<Option<Vec<u8>>>,
+        controller_id: Option<i32>,
+        name: Option<Vec<u8>>,
+        topic_id: Option<u128>,
+        leader_epoch: Option<i32>,
+        offline_replicas: Option<Vec<i32>>,
+        topic_operations: Option<i32>,
+        cluster_operations: Option<i32>,
+    }
+
+    // reads a response the way the protocol spec lays out each version
+    fn decode(data: &[u8], version: u16) -> Decoded {
+        let flexible = version >= 9;
+        let r = &mut Cursor::new(data);
+        let tags = |r: &mut Cursor<&[u8]>| {
+            if flexible {
+                parser::skip_tagged_fields(r).unwrap();
+            }
+        };
+        let nodes = |r: &mut Cursor<&[u8]>| {
+            (0..parser::read_array_len(r, flexible).unwrap().unwrap())
+                .map(|_| parser::read_int(r).unwrap())
+                .collect::<Vec<_>>()
+        };
+        let mut out = Decoded::default();
+        if version >= 3 {
+            parser::read_int(r).unwrap();
+        }
+        assert_eq!(parser::read_array_len(r, flexible).unwrap(), Some(1));
+        assert_eq!(parser::read_int(r).unwrap(), 1);
+        assert_eq!(parser::read_string(r, flexible).unwrap(), b"localhost");
+        assert_eq!(parser::read_int(r).unwrap(), 9092);
+        if version >= 1 {
+            out.rack = Some(parser::read_nullable_string(r, flexible).unwrap());
+        }
+        tags(r);
+        if version >= 2 {
+            out.cluster_id = Some(parser::read_nullable_string(r, flexible).unwrap());
+        }
+        if version >= 1 {
+            out.controller_id = Some(parser::read_int(r).unwrap());
+        }
+        assert_eq!(parser::read_array_len(r, flexible).unwrap(), Some(1));
+        assert_eq!(parser::read_short(r).unwrap(), 0);
+        out.name = parser::read_nullable_string(r, flexible).unwrap();
+        if version >= 10 {
+            out.topic_id = Some(parser::read_u128(r).unwrap());
+        }
+        if version >= 1 {
+            assert_eq!(parser::read_byte(r).unwrap(), 0); // is_internal
+        }
+        assert_eq!(parser::read_array_len(r, flexible).unwrap(), Some(1));
+        assert_eq!(parser::read_short(r).unwrap(), 0);
+        assert_eq!(parser::read_int(r).unwrap(), 0); // partition index
+        assert_eq!(parser::read_int(r).unwrap(), 1); // leader
+        if version >= 7 {
+            out.leader_epoch = Some(parser::read_int(r).unwrap());
+        }
+        assert_eq!(nodes(r), vec![1, 2]);
+        assert_eq!(nodes(r), vec![1]);
+        if version >= 5 {
+            out.offline_replicas = Some(nodes(r));
+        }
+        tags(r);
+        if version >= 8 {
+            out.topic_operations = Some(parser::read_int(r).unwrap());
+        }
+        tags(r);
+        if (8..=10).contains(&version) {
+            out.cluster_operations = Some(parser::read_int(r).unwrap());
+        }
+        tags(r);
+        assert_eq!(
+            r.position() as usize,
+            data.len(),
+            "v{version} trailing bytes"
+        );
+        out
+    }
+
+    fn response(version: u16, name: Option<&str>) -> MetadataResponse {
+        MetadataResponse {
+            version,
+            throttle_time_ms: 0,
+            brokers: vec![MetadataResponseBroker {
+                node_id: 1,
+                host: b"localhost".to_vec(),
+                port: 9092,
+                rack: Some("r1".to_string()),
+            }],
+            cluster_id: Some("cluster".to_string()),
+            controller_id: 3,
+            topics: vec![MetadataResponseTopic {
+                error_code: 0,
+                name: name.map(str::to_string),
+                topic_id: 42,
+                is_internal: false,
+                partitions: vec![MetadataResponsePartition {
+                    error_code: 0,
+                    partition_index: 0,
+                    leader_id: 1,
+                    leader_epoch: 5,
+                    replica_nodes: vec![1, 2],
+                    isr_nodes: vec![1],
+                    offline_replicas: vec![2],
+                }],
+                topic_authorized_operations: TOPIC_OPERATIONS,
+            }],
+            cluster_authorized_operations: CLUSTER_OPERATIONS,
+        }
+    }
+
+    #[test]
+    fn test_response_versions() {
+        for version in 0..=12 {
+            let mut data = vec![];
+            response(version, Some("events"))
+                .serialize(&mut data, version >= 9)
+                .unwrap();
+            let decoded = decode(&data, version);
+            let since = |v: u16| version >= v;
+            assert_eq!(decoded.rack, since(1).then(|| Some(b"r1".to_vec())));
+            assert_eq!(decoded.controller_id, since(1).then_some(3));
+            assert_eq!(
+                decoded.cluster_id,
+                since(2).then(|| Some(b"cluster".to_vec()))
+            );
+            assert_eq!(decoded.offline_replicas, since(5).then(|| vec![2]));
+            assert_eq!(decoded.leader_epoch, since(7).then_some(5));
+            assert_eq!(
+                decoded.topic_operations,
+                since(8).then_some(TOPIC_OPERATIONS)
+            );
+            assert_eq!(
+                decoded.cluster_operations,
+                (8..=10).contains(&version).then_some(CLUSTER_OPERATIONS)
+            );
+            assert_eq!(decoded.topic_id, since(10).then_some(42));
+            assert_eq!(decoded.name, Some(b"events".to_vec()));
+        }
+
+        // a topic asked for by an unknown id has no name: null from v12,
+        // an empty string before
+        for (version, name) in [(10, Some(vec![])), (11, Some(vec![])), (12, None)] {
+            let mut data = vec![];
+            response(version, None).serialize(&mut data, true).unwrap();
+            assert_eq!(decode(&data, version).name, name);
+        }
+    }
+
+    #[test]
+    fn test_auto_topic_creation() {
+        let root = temp_root("metadata-api-test");
+        let metadata = Arc::new(MetadataCache::default());
+        let controller = Arc::new(controller::open_single_voter(&root, &metadata));
+        let config = config::BrokerConfig::default();
+        let request = |allow_auto_topic_creation: bool| MetadataRequest {
+            version: 12,
+            topics: Some(vec![MetadataRequestTopic {
+                topic_id: 0,
+                name: Some("events".to_string()),
+            }]),
+            allow_auto_topic_creation,
+            include_cluster_authorized_operations: false,
+            include_topic_authorized_operations: false,
+        };
+
+        let response = MetadataResponse::new(&request(false), &metadata, &controller);
+        assert_eq!(response.topics[0].error_code, UNKNOWN_TOPIC_OR_PARTITION);
+        assert!(metadata.image().topic_by_name("events").is_none());
+
+        let response = MetadataResponse::new(&request(true), &metadata, &controller);
+        let topic = &response.topics[0];
+        let image = metadata.image();
+        let created = image.topic_by_name("events").unwrap();
+        assert_eq!(topic.name.as_deref(), Some("events"));
+        assert_eq!(topic.topic_id, created.id);
+        assert_eq!(topic.partitions.len(), config.num_partitions as usize);
+        assert_eq!(
+            created.partitions.keys().copied().collect::<Vec<_>>(),
+            (0..config.num_partitions).collect::<Vec<_>>()
+        );
+
+        // a topic that exists is described without creating anything
+        let response = MetadataResponse::new(&request(false), &metadata, &controller);
+        assert_eq!(response.topics[0].topic_id, created.id);
+        assert_eq!(metadata.image().topics.len(), 1);
+        let _ = std::fs::remove_dir_all(&root);
+    }
+}
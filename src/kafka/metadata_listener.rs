@@ -0,0 +1,161 @@
+// follows the __cluster_metadata log as it grows. Each poll replays the
+// batches appended since the last one into a delta on top of the current
+// image and publishes the result in one go, so handlers see either all of a
+// poll's records or none. The first poll catches up from the latest
+// snapshot; later ones pick up where the previous stopped, moving on to the
+// next segment once the log rolls. Only batches below the raft high
+// watermark are applied, the rest may still be truncated away. A log that
+// now starts past the image, after raft swapped it for a snapshot fetched
+// from the leader, means starting over from that snapshot.
+use crate::kafka::metadata::{MetadataCache, MetadataDelta};
+use crate::kafka::records::{self, BatchHeader};
+use crate::kafka::{errors, log, snapshot};
+use std::fs::{self, File};
+use std::io::{BufReader, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+// how often the log is checked for new batches
+pub const METADATA_LISTENER_POLL_MS: u64 = 100;
+
+pub struct MetadataListener {
+    dir: PathBuf,
+    metadata: Arc<MetadataCache>,
+    snapshots: snapshot::SnapshotGenerator,
+    high_watermark: Arc<AtomicU64>,
+    // the segment being followed and how much of it was read
+    segment: Option<u64>,
+    position: u64,
+}
+
+impl MetadataListener {
+    pub fn new(
+        dir: &Path,
+        metadata: Arc<MetadataCache>,
+        snapshots: snapshot::SnapshotGenerator,
+        high_watermark: Arc<AtomicU64>,
+    ) -> Self {
+        Self {
+            dir: dir.to_path_buf(),
+            metadata,
+            snapshots,
+            high_watermark,
+            segment: None,
+            position: 0,
+        }
+    }
+
+    // shared with the controller, which polls right after its own appends
+    pub fn start(listener: Arc<Mutex<Self>>, interval_ms: u64) -> thread::JoinHandle<()> {
+        thread::spawn(move || loop {
+            thread::sleep(Duration::from_millis(interval_ms));
+            if let Err(e) = listener.lock().unwrap().poll() {
+                println!("metadata listener poll failed: {e}");
+            }
+        })
+    }
+
+    // replays whatever was appended since the last poll, the number of
+    // batches applied
+    pub fn poll(&mut self) -> errors::Result<usize> {
+        if !self.dir.is_dir() {
+            return Ok(0);
+        }
+        let mut base = self.metadata.image();
+        let mut changed = false;
+        let segments = segment_paths(&self.dir)?;
+        // only kept once published, a failed poll starts over from here
+        let (mut segment, mut position) = (self.segment, self.position);
+        let log_start = segments.first().map_or(0, |(base_offset, _)| *base_offset);
+        if (segment.is_none() && base.offset.is_none())
+            || base.offset.map_or(0, |o| o + 1) < log_start
+        {
+            base = Arc::new(snapshot::load_latest(&self.dir)?);
+            changed = base.offset.is_some();
+            (segment, position) = (None, 0);
+            self.snapshots
+                .snapshot_loaded(base.offset.map(|o| snapshot::SnapshotId {
+                    end_offset: o + 1,
+                    epoch: base.epoch,
+                }));
+        }
+        let mut delta = MetadataDelta::new(&base);
+        let mut batches = 0;
+        let start = match segment {
+            Some(current) => current,
+            None => base.offset.map_or(0, |o| o + 1),
+        };
+        // the segment holding start, or the first one if the log begins later
+        let first = segments
+            .iter()
+            .rposition(|(base_offset, _)| *base_offset <= start)
+            .unwrap_or(0);
+        let high_watermark = self.high_watermark.load(Ordering::SeqCst);
+        'segments: for (base_offset, path) in &segments[first..] {
+            if segment != Some(*base_offset) {
+                segment = Some(*base_offset);
+                position = 0;
+            }
+            let mut file = File::open(path)?;
+            // truncated under us, batches already applied are skipped anyway
+            if file.metadata()?.len() < position {
+                position = 0;
+            }
+            file.seek(SeekFrom::Start(position))?;
+            let mut reader = BufReader::new(file);
+            // a torn tail reads as the end, it's picked up once complete
+            while let Some(batch) = records::RawBatch::read(&mut reader)? {
+                // not committed yet, picked up by a later poll
+                if batch.last_offset() >= high_watermark {
+                    break 'segments;
+                }
+                position += batch.size() as u64;
+                if delta.replay_batch(&batch)? {
+                    batches += 1;
+                    self.snapshots.batch_applied(&batch, delta.image());
+                }
+            }
+        }
+        if batches > 0 || changed {
+            let image = delta.apply();
+            println!(
+                "metadata at offset {:?}: {batches} new batches, {} topics, {} partitions, {} brokers",
+                image.offset,
+                image.topics.len(),
+                image
+                    .topics
+                    .values()
+                    .map(|t| t.partitions.len())
+                    .sum::<usize>(),
+                image.brokers.len()
+            );
+            self.metadata.publish(image);
+        }
+        self.segment = segment;
+        self.position = position;
+        Ok(batches)
+    }
+}
+
+// the segments of the metadata log, by base offset
+fn segment_paths(dir: &Path) -> errors::Result<Vec<(u64, PathBuf)>> {
+    let mut segments = vec![];
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().and_then(|e| e.to_str()) != Some(log::LOG_FILE_SUFFIX) {
+            continue;
+        }
+        if let Some(base_offset) = path
+            .file_stem()
+            .and_then(|s| s.to_str())
+            .and_then(|s| s.parse::<u64>().ok())
+        {
+            segments.push((base_offset, path));
+        }
+    }
+    segments.sort();
+    Ok(segments)
+}
@@ -0,0 +1,1183 @@
+// payloads of the records in the __cluster_metadata log
+//
+// value framing:
+//   frame_version(uvarint) type(uvarint) version(uvarint) data
+//
+// data is encoded like a flexible request body: compact strings and arrays,
+// and a tagged field section closing every struct. Record types, or versions
+// of them, this broker doesn't know are kept as raw bytes so a log written by
+// a newer controller still loads.
+use crate::kafka::{errors, parser, writer};
+use crate::kafka::{
+    KAFKA_RECORDTYPE_ACCESS_CONTROL_ENTRY, KAFKA_RECORDTYPE_BROKER_REGISTRATION_CHANGE,
+    KAFKA_RECORDTYPE_CLIENT_QUOTA, KAFKA_RECORDTYPE_CONFIG, KAFKA_RECORDTYPE_FEATURE,
+    KAFKA_RECORDTYPE_FENCE_BROKER, KAFKA_RECORDTYPE_NO_OP, KAFKA_RECORDTYPE_PARTITION,
+    KAFKA_RECORDTYPE_PARTITION_CHANGE, KAFKA_RECORDTYPE_PRODUCER_IDS,
+    KAFKA_RECORDTYPE_REGISTER_BROKER, KAFKA_RECORDTYPE_REMOVE_ACCESS_CONTROL_ENTRY,
+    KAFKA_RECORDTYPE_REMOVE_TOPIC, KAFKA_RECORDTYPE_TOPIC, KAFKA_RECORDTYPE_UNFENCE_BROKER,
+    KAFKA_RECORDTYPE_UNREGISTER_BROKER, KAFKA_RECORDTYPE_ZK_MIGRATION_STATE,
+};
+use std::io::{Cursor, Read, Write};
+
+pub const METADATA_FRAME_VERSION: u32 = 1;
+
+// resource types a ConfigRecord can target
+pub const CONFIG_RESOURCE_TOPIC: i8 = 2;
+
+// PartitionChangeRecord defaults, meaning "unchanged"
+pub const NO_LEADER_CHANGE: i32 = -2;
+const NO_LEADER_RECOVERY_STATE_CHANGE: i8 = -1;
+
+type Uuid = [u8; 16];
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub enum KafkaRecordValue {
+    #[default]
+    Invalid,
+    KafkaRecordRegisterBrokerType(KafkaRecordRegisterBrokerRecord),
+    KafkaRecordUnregisterBrokerType(KafkaRecordUnregisterBrokerRecord),
+    KafkaRecordTopicRecordType(KafkaRecordTopicRecord),
+    KafkaRecordPartitionType(KafkaRecordPartitionRecord),
+    KafkaRecordConfigType(KafkaRecordConfigRecord),
+    KafkaRecordPartitionChangeType(KafkaRecordPartitionChangeRecord),
+    KafkaRecordAccessControlEntryType(KafkaRecordAccessControlEntryRecord),
+    KafkaRecordFenceBrokerType(KafkaRecordBrokerEpochRecord),
+    KafkaRecordUnfenceBrokerType(KafkaRecordBrokerEpochRecord),
+    KafkaRecordRemoveTopicType(KafkaRecordRemoveTopicRecord),
+    KafkaRecordFeatureType(KafkaRecordFeature),
+    KafkaRecordClientQuotaType(KafkaRecordClientQuotaRecord),
+    KafkaRecordProducerIdsType(KafkaRecordProducerIdsRecord),
+    KafkaRecordBrokerRegistrationChangeType(KafkaRecordBrokerRegistrationChangeRecord),
+    KafkaRecordRemoveAccessControlEntryType(KafkaRecordRemoveAccessControlEntryRecord),
+    KafkaRecordNoOpType(KafkaRecordNoOpRecord),
+    KafkaRecordZkMigrationStateType(KafkaRecordZkMigrationStateRecord),
+    Unknown(KafkaRecordUnknown),
+}
+
+impl std::fmt::Display for KafkaRecordValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Invalid => write!(f, "Invalid!"),
+            Self::KafkaRecordRegisterBrokerType(v) => write!(f, "{v:?}"),
+            Self::KafkaRecordUnregisterBrokerType(v) => write!(f, "{v:?}"),
+            Self::KafkaRecordTopicRecordType(v) => write!(f, "{v:?}"),
+            Self::KafkaRecordPartitionType(v) => write!(f, "{v:?}"),
+            Self::KafkaRecordConfigType(v) => write!(f, "{v:?}"),
+            Self::KafkaRecordPartitionChangeType(v) => write!(f, "{v:?}"),
+            Self::KafkaRecordAccessControlEntryType(v) => write!(f, "{v:?}"),
+            Self::KafkaRecordFenceBrokerType(v) => write!(f, "fence {v:?}"),
+            Self::KafkaRecordUnfenceBrokerType(v) => write!(f, "unfence {v:?}"),
+            Self::KafkaRecordRemoveTopicType(v) => write!(f, "{v:?}"),
+            Self::KafkaRecordFeatureType(v) => write!(f, "{v:?}"),
+            Self::KafkaRecordClientQuotaType(v) => write!(f, "{v:?}"),
+            Self::KafkaRecordProducerIdsType(v) => write!(f, "{v:?}"),
+            Self::KafkaRecordBrokerRegistrationChangeType(v) => write!(f, "{v:?}"),
+            Self::KafkaRecordRemoveAccessControlEntryType(v) => write!(f, "{v:?}"),
+            Self::KafkaRecordNoOpType(v) => write!(f, "{v:?}"),
+            Self::KafkaRecordZkMigrationStateType(v) => write!(f, "{v:?}"),
+            Self::Unknown(v) => write!(
+                f,
+                "unknown record type {} version {}, {} bytes",
+                v.frame_type,
+                v.version,
+                v.data.len()
+            ),
+        }
+    }
+}
+
+#[allow(dead_code)]
+impl KafkaRecordValue {
+    // decodes a whole record value, frame included
+    pub fn deserialize<R: Read>(buffer: &mut R) -> errors::Result<KafkaRecordValue> {
+        let frame_version = parser::read_uvarint(buffer)?;
+        if frame_version != METADATA_FRAME_VERSION {
+            return Err(errors::KafkaErrors::CorruptRecord(format!(
+                "unsupported metadata frame version {frame_version}"
+            ))
+            .into());
+        }
+        let frame_type = parser::read_uvarint(buffer)? as i16;
+        let version = parser::read_uvarint(buffer)? as i16;
+        let mut data = vec![];
+        buffer.read_to_end(&mut data)?;
+
+        let input = &mut Cursor::new(&data[..]);
+        let value = match (frame_type, version) {
+            (KAFKA_RECORDTYPE_REGISTER_BROKER, 0..=3) => Self::KafkaRecordRegisterBrokerType(
+                KafkaRecordRegisterBrokerRecord::deserialize(input, version)?,
+            ),
+            (KAFKA_RECORDTYPE_UNREGISTER_BROKER, 0) => Self::KafkaRecordUnregisterBrokerType(
+                KafkaRecordUnregisterBrokerRecord::deserialize(input, version)?,
+            ),
+            (KAFKA_RECORDTYPE_TOPIC, 0) => Self::KafkaRecordTopicRecordType(
+                KafkaRecordTopicRecord::deserialize(input, version)?,
+            ),
+            (KAFKA_RECORDTYPE_PARTITION, 0..=2) => Self::KafkaRecordPartitionType(
+                KafkaRecordPartitionRecord::deserialize(input, version)?,
+            ),
+            (KAFKA_RECORDTYPE_CONFIG, 0) => {
+                Self::KafkaRecordConfigType(KafkaRecordConfigRecord::deserialize(input, version)?)
+            }
+            (KAFKA_RECORDTYPE_PARTITION_CHANGE, 0..=2) => Self::KafkaRecordPartitionChangeType(
+                KafkaRecordPartitionChangeRecord::deserialize(input, version)?,
+            ),
+            (KAFKA_RECORDTYPE_ACCESS_CONTROL_ENTRY, 0) => Self::KafkaRecordAccessControlEntryType(
+                KafkaRecordAccessControlEntryRecord::deserialize(input, version)?,
+            ),
+            (KAFKA_RECORDTYPE_FENCE_BROKER, 0) => Self::KafkaRecordFenceBrokerType(
+                KafkaRecordBrokerEpochRecord::deserialize(input, version)?,
+            ),
+            (KAFKA_RECORDTYPE_UNFENCE_BROKER, 0) => Self::KafkaRecordUnfenceBrokerType(
+                KafkaRecordBrokerEpochRecord::deserialize(input, version)?,
+            ),
+            (KAFKA_RECORDTYPE_REMOVE_TOPIC, 0) => Self::KafkaRecordRemoveTopicType(
+                KafkaRecordRemoveTopicRecord::deserialize(input, version)?,
+            ),
+            (KAFKA_RECORDTYPE_FEATURE, 0) => {
+                Self::KafkaRecordFeatureType(KafkaRecordFeature::deserialize(input, version)?)
+            }
+            (KAFKA_RECORDTYPE_CLIENT_QUOTA, 0) => Self::KafkaRecordClientQuotaType(
+                KafkaRecordClientQuotaRecord::deserialize(input, version)?,
+            ),
+            (KAFKA_RECORDTYPE_PRODUCER_IDS, 0) => Self::KafkaRecordProducerIdsType(
+                KafkaRecordProducerIdsRecord::deserialize(input, version)?,
+            ),
+            (KAFKA_RECORDTYPE_BROKER_REGISTRATION_CHANGE, 0..=2) => {
+                Self::KafkaRecordBrokerRegistrationChangeType(
+                    KafkaRecordBrokerRegistrationChangeRecord::deserialize(input, version)?,
+                )
+            }
+            (KAFKA_RECORDTYPE_REMOVE_ACCESS_CONTROL_ENTRY, 0) => {
+                Self::KafkaRecordRemoveAccessControlEntryType(
+                    KafkaRecordRemoveAccessControlEntryRecord::deserialize(input, version)?,
+                )
+            }
+            (KAFKA_RECORDTYPE_NO_OP, 0) => {
+                Self::KafkaRecordNoOpType(KafkaRecordNoOpRecord::deserialize(input, version)?)
+            }
+            (KAFKA_RECORDTYPE_ZK_MIGRATION_STATE, 0) => Self::KafkaRecordZkMigrationStateType(
+                KafkaRecordZkMigrationStateRecord::deserialize(input, version)?,
+            ),
+            _ => {
+                return Ok(Self::Unknown(KafkaRecordUnknown {
+                    frame_type,
+                    version,
+                    data,
+                }))
+            }
+        };
+        if (input.position() as usize) < data.len() {
+            return Err(errors::KafkaErrors::CorruptRecord(format!(
+                "metadata record type {frame_type} version {version} has trailing bytes"
+            ))
+            .into());
+        }
+        Ok(value)
+    }
+
+    pub fn frame_type(&self) -> Option<i16> {
+        Some(match self {
+            Self::Invalid => return None,
+            Self::KafkaRecordRegisterBrokerType(_) => KAFKA_RECORDTYPE_REGISTER_BROKER,
+            Self::KafkaRecordUnregisterBrokerType(_) => KAFKA_RECORDTYPE_UNREGISTER_BROKER,
+            Self::KafkaRecordTopicRecordType(_) => KAFKA_RECORDTYPE_TOPIC,
+            Self::KafkaRecordPartitionType(_) => KAFKA_RECORDTYPE_PARTITION,
+            Self::KafkaRecordConfigType(_) => KAFKA_RECORDTYPE_CONFIG,
+            Self::KafkaRecordPartitionChangeType(_) => KAFKA_RECORDTYPE_PARTITION_CHANGE,
+            Self::KafkaRecordAccessControlEntryType(_) => KAFKA_RECORDTYPE_ACCESS_CONTROL_ENTRY,
+            Self::KafkaRecordFenceBrokerType(_) => KAFKA_RECORDTYPE_FENCE_BROKER,
+            Self::KafkaRecordUnfenceBrokerType(_) => KAFKA_RECORDTYPE_UNFENCE_BROKER,
+            Self::KafkaRecordRemoveTopicType(_) => KAFKA_RECORDTYPE_REMOVE_TOPIC,
+            Self::KafkaRecordFeatureType(_) => KAFKA_RECORDTYPE_FEATURE,
+            Self::KafkaRecordClientQuotaType(_) => KAFKA_RECORDTYPE_CLIENT_QUOTA,
+            Self::KafkaRecordProducerIdsType(_) => KAFKA_RECORDTYPE_PRODUCER_IDS,
+            Self::KafkaRecordBrokerRegistrationChangeType(_) => {
+                KAFKA_RECORDTYPE_BROKER_REGISTRATION_CHANGE
+            }
+            Self::KafkaRecordRemoveAccessControlEntryType(_) => {
+                KAFKA_RECORDTYPE_REMOVE_ACCESS_CONTROL_ENTRY
+            }
+            Self::KafkaRecordNoOpType(_) => KAFKA_RECORDTYPE_NO_OP,
+            Self::KafkaRecordZkMigrationStateType(_) => KAFKA_RECORDTYPE_ZK_MIGRATION_STATE,
+            Self::Unknown(v) => v.frame_type,
+        })
+    }
+
+    pub fn version(&self) -> i16 {
+        match self {
+            Self::Invalid => 0,
+            Self::KafkaRecordRegisterBrokerType(v) => v.version,
+            Self::KafkaRecordUnregisterBrokerType(v) => v.version,
+            Self::KafkaRecordTopicRecordType(v) => v.version,
+            Self::KafkaRecordPartitionType(v) => v.version,
+            Self::KafkaRecordConfigType(v) => v.version,
+            Self::KafkaRecordPartitionChangeType(v) => v.version,
+            Self::KafkaRecordAccessControlEntryType(v) => v.version,
+            Self::KafkaRecordFenceBrokerType(v) | Self::KafkaRecordUnfenceBrokerType(v) => {
+                v.version
+            }
+            Self::KafkaRecordRemoveTopicType(v) => v.version,
+            Self::KafkaRecordFeatureType(v) => v.version,
+            Self::KafkaRecordClientQuotaType(v) => v.version,
+            Self::KafkaRecordProducerIdsType(v) => v.version,
+            Self::KafkaRecordBrokerRegistrationChangeType(v) => v.version,
+            Self::KafkaRecordRemoveAccessControlEntryType(v) => v.version,
+            Self::KafkaRecordNoOpType(v) => v.version,
+            Self::KafkaRecordZkMigrationStateType(v) => v.version,
+            Self::Unknown(v) => v.version,
+        }
+    }
+
+    // writes the record value, frame included. Invalid has no frame and
+    // writes nothing.
+    pub fn serialize<W: Write>(&self, resp: &mut W) -> errors::Result<()> {
+        let Some(frame_type) = self.frame_type() else {
+            return Ok(());
+        };
+        writer::write_uvarint(resp, METADATA_FRAME_VERSION as i32)?;
+        writer::write_uvarint(resp, frame_type as i32)?;
+        writer::write_uvarint(resp, self.version() as i32)?;
+        match self {
+            Self::Invalid => Ok(()),
+            Self::KafkaRecordRegisterBrokerType(v) => v.serialize(resp),
+            Self::KafkaRecordUnregisterBrokerType(v) => v.serialize(resp),
+            Self::KafkaRecordTopicRecordType(v) => v.serialize(resp),
+            Self::KafkaRecordPartitionType(v) => v.serialize(resp),
+            Self::KafkaRecordConfigType(v) => v.serialize(resp),
+            Self::KafkaRecordPartitionChangeType(v) => v.serialize(resp),
+            Self::KafkaRecordAccessControlEntryType(v) => v.serialize(resp),
+            Self::KafkaRecordFenceBrokerType(v) | Self::KafkaRecordUnfenceBrokerType(v) => {
+                v.serialize(resp)
+            }
+            Self::KafkaRecordRemoveTopicType(v) => v.serialize(resp),
+            Self::KafkaRecordFeatureType(v) => v.serialize(resp),
+            Self::KafkaRecordClientQuotaType(v) => v.serialize(resp),
+            Self::KafkaRecordProducerIdsType(v) => v.serialize(resp),
+            Self::KafkaRecordBrokerRegistrationChangeType(v) => v.serialize(resp),
+            Self::KafkaRecordRemoveAccessControlEntryType(v) => v.serialize(resp),
+            Self::KafkaRecordNoOpType(v) => v.serialize(resp),
+            Self::KafkaRecordZkMigrationStateType(v) => v.serialize(resp),
+            Self::Unknown(v) => Ok(resp.write_all(&v.data)?),
+        }
+    }
+
+    pub fn to_bytes(&self) -> errors::Result<Vec<u8>> {
+        let mut buf = vec![];
+        self.serialize(&mut buf)?;
+        Ok(buf)
+    }
+}
+
+// a record type or version without a decoder, data being everything after
+// the frame
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct KafkaRecordUnknown {
+    pub frame_type: i16,
+    pub version: i16,
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct BrokerEndpoint {
+    pub name: Vec<u8>,
+    pub host: Vec<u8>,
+    pub port: u16,
+    pub security_protocol: i16,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct BrokerFeature {
+    pub name: Vec<u8>,
+    pub min_supported_version: i16,
+    pub max_supported_version: i16,
+}
+
+// v1 adds in_controlled_shutdown, v2 is_migrating_zk_broker, v3 log_dirs
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct KafkaRecordRegisterBrokerRecord {
+    pub version: i16,
+    pub broker_id: i32,
+    pub is_migrating_zk_broker: bool,
+    pub incarnation_id: Uuid,
+    pub broker_epoch: i64,
+    pub end_points: Vec<BrokerEndpoint>,
+    pub features: Vec<BrokerFeature>,
+    pub rack: Option<Vec<u8>>,
+    pub fenced: bool,
+    pub in_controlled_shutdown: bool,
+    pub log_dirs: Vec<Uuid>,
+}
+
+#[allow(dead_code)]
+impl KafkaRecordRegisterBrokerRecord {
+    pub fn deserialize<R: Read>(buffer: &mut R, version: i16) -> errors::Result<Self> {
+        let mut rec = Self {
+            version,
+            broker_id: parser::read_int(buffer)?,
+            ..Default::default()
+        };
+        if version >= 2 {
+            rec.is_migrating_zk_broker = read_bool(buffer)?;
+        }
+        rec.incarnation_id = read_uuid(buffer)?;
+        rec.broker_epoch = parser::read_u64(buffer)? as i64;
+        rec.end_points = read_array(buffer, |b| {
+            let endpoint = BrokerEndpoint {
+                name: parser::read_string(b, true)?,
+                host: parser::read_string(b, true)?,
+                port: parser::read_short(b)? as u16,
+                security_protocol: parser::read_short(b)?,
+            };
+            parser::skip_tagged_fields(b)?;
+            Ok(endpoint)
+        })?
+        .unwrap_or_default();
+        rec.features = read_array(buffer, |b| {
+            let feature = BrokerFeature {
+                name: parser::read_string(b, true)?,
+                min_supported_version: parser::read_short(b)?,
+                max_supported_version: parser::read_short(b)?,
+            };
+            parser::skip_tagged_fields(b)?;
+            Ok(feature)
+        })?
+        .unwrap_or_default();
+        rec.rack = parser::read_nullable_string(buffer, true)?;
+        rec.fenced = read_bool(buffer)?;
+        if version >= 1 {
+            rec.in_controlled_shutdown = read_bool(buffer)?;
+        }
+        if version >= 3 {
+            rec.log_dirs = read_array(buffer, read_uuid)?.unwrap_or_default();
+        }
+        parser::skip_tagged_fields(buffer)?;
+        Ok(rec)
+    }
+
+    pub fn serialize<W: Write>(&self, resp: &mut W) -> errors::Result<()> {
+        writer::write_bytes(resp, &self.broker_id)?;
+        if self.version >= 2 {
+            writer::write_bool(resp, self.is_migrating_zk_broker)?;
+        }
+        resp.write_all(&self.incarnation_id)?;
+        writer::write_bytes(resp, &self.broker_epoch)?;
+        write_array(resp, Some(&self.end_points), |r, e| {
+            writer::write_string(r, &e.name, true)?;
+            writer::write_string(r, &e.host, true)?;
+            writer::write_bytes(r, &e.port)?;
+            writer::write_bytes(r, &e.security_protocol)?;
+            writer::write_tagged_fields(r, true)
+        })?;
+        write_array(resp, Some(&self.features), |r, f| {
+            writer::write_string(r, &f.name, true)?;
+            writer::write_bytes(r, &f.min_supported_version)?;
+            writer::write_bytes(r, &f.max_supported_version)?;
+            writer::write_tagged_fields(r, true)
+        })?;
+        writer::write_nullable_string(resp, self.rack.as_deref(), true)?;
+        writer::write_bool(resp, self.fenced)?;
+        if self.version >= 1 {
+            writer::write_bool(resp, self.in_controlled_shutdown)?;
+        }
+        if self.version >= 3 {
+            write_array(resp, Some(&self.log_dirs), write_uuid)?;
+        }
+        writer::write_tagged_fields(resp, true)
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct KafkaRecordUnregisterBrokerRecord {
+    pub version: i16,
+    pub broker_id: i32,
+    pub broker_epoch: i64,
+}
+
+#[allow(dead_code)]
+impl KafkaRecordUnregisterBrokerRecord {
+    pub fn deserialize<R: Read>(buffer: &mut R, version: i16) -> errors::Result<Self> {
+        let rec = Self {
+            version,
+            broker_id: parser::read_int(buffer)?,
+            broker_epoch: parser::read_u64(buffer)? as i64,
+        };
+        parser::skip_tagged_fields(buffer)?;
+        Ok(rec)
+    }
+
+    pub fn serialize<W: Write>(&self, resp: &mut W) -> errors::Result<()> {
+        writer::write_bytes(resp, &self.broker_id)?;
+        writer::write_bytes(resp, &self.broker_epoch)?;
+        writer::write_tagged_fields(resp, true)
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct KafkaRecordTopicRecord {
+    pub version: i16,
+    pub topic_name: Vec<u8>,
+    pub topic_uuid: Uuid,
+}
+
+#[allow(dead_code)]
+impl KafkaRecordTopicRecord {
+    pub fn deserialize<R: Read>(buffer: &mut R, version: i16) -> errors::Result<Self> {
+        let rec = Self {
+            version,
+            topic_name: parser::read_string(buffer, true)?,
+            topic_uuid: read_uuid(buffer)?,
+        };
+        parser::skip_tagged_fields(buffer)?;
+        Ok(rec)
+    }
+
+    pub fn serialize<W: Write>(&self, resp: &mut W) -> errors::Result<()> {
+        writer::write_string(resp, &self.topic_name, true)?;
+        resp.write_all(&self.topic_uuid)?;
+        writer::write_tagged_fields(resp, true)
+    }
+}
+
+// v1 adds directories, v2 the eligible leader replica fields (tagged)
+#[derive(Debug, Clone, PartialEq)]
+pub struct KafkaRecordPartitionRecord {
+    pub version: i16,
+    pub partition_id: i32,
+    pub topic_uuid: Uuid,
+    pub replicas: Vec<i32>,
+    pub isr: Vec<i32>,
+    pub removing_replicas: Vec<i32>,
+    pub adding_replicas: Vec<i32>,
+    pub leader: i32,
+    pub leader_recovery_state: i8, // tag 0
+    pub leader_epoch: i32,
+    pub partition_epoch: i32,
+    pub directories: Vec<Uuid>,
+    pub eligible_leader_replicas: Option<Vec<i32>>, // tag 1
+    pub last_known_elr: Option<Vec<i32>>,           // tag 2
+}
+
+impl Default for KafkaRecordPartitionRecord {
+    fn default() -> Self {
+        Self {
+            version: 0,
+            partition_id: 0,
+            topic_uuid: Uuid::default(),
+            replicas: vec![],
+            isr: vec![],
+            removing_replicas: vec![],
+            adding_replicas: vec![],
+            leader: -1,
+            leader_recovery_state: 0,
+            leader_epoch: -1,
+            partition_epoch: -1,
+            directories: vec![],
+            eligible_leader_replicas: None,
+            last_known_elr: None,
+        }
+    }
+}
+
+#[allow(dead_code)]
+impl KafkaRecordPartitionRecord {
+    pub fn deserialize<R: Read>(buffer: &mut R, version: i16) -> errors::Result<Self> {
+        let mut rec = Self {
+            version,
+            partition_id: parser::read_int(buffer)?,
+            topic_uuid: read_uuid(buffer)?,
+            replicas: read_array(buffer, parser::read_int)?.unwrap_or_default(),
+            isr: read_array(buffer, parser::read_int)?.unwrap_or_default(),
+            removing_replicas: read_array(buffer, parser::read_int)?.unwrap_or_default(),
+            adding_replicas: read_array(buffer, parser::read_int)?.unwrap_or_default(),
+            leader: parser::read_int(buffer)?,
+            leader_epoch: parser::read_int(buffer)?,
+            partition_epoch: parser::read_int(buffer)?,
+            ..Default::default()
+        };
+        if version >= 1 {
+            rec.directories = read_array(buffer, read_uuid)?.unwrap_or_default();
+        }
+        for (tag, data) in read_tagged_fields(buffer)? {
+            let input = &mut Cursor::new(data);
+            match tag {
+                0 => rec.leader_recovery_state = parser::read_byte(input)?,
+                1 if version >= 2 => {
+                    rec.eligible_leader_replicas = read_array(input, parser::read_int)?
+                }
+                2 if version >= 2 => rec.last_known_elr = read_array(input, parser::read_int)?,
+                _ => (),
+            }
+        }
+        Ok(rec)
+    }
+
+    pub fn serialize<W: Write>(&self, resp: &mut W) -> errors::Result<()> {
+        writer::write_bytes(resp, &self.partition_id)?;
+        resp.write_all(&self.topic_uuid)?;
+        write_array(resp, Some(&self.replicas), write_int)?;
+        write_array(resp, Some(&self.isr), write_int)?;
+        write_array(resp, Some(&self.removing_replicas), write_int)?;
+        write_array(resp, Some(&self.adding_replicas), write_int)?;
+        writer::write_bytes(resp, &self.leader)?;
+        writer::write_bytes(resp, &self.leader_epoch)?;
+        writer::write_bytes(resp, &self.partition_epoch)?;
+        if self.version >= 1 {
+            write_array(resp, Some(&self.directories), write_uuid)?;
+        }
+        let mut tagged = TaggedFields::default();
+        if self.leader_recovery_state != 0 {
+            tagged.add(0, |b| writer::write_bytes(b, &self.leader_recovery_state))?;
+        }
+        if self.version >= 2 {
+            if let Some(elr) = &self.eligible_leader_replicas {
+                tagged.add(1, |b| write_array(b, Some(elr), write_int))?;
+            }
+            if let Some(elr) = &self.last_known_elr {
+                tagged.add(2, |b| write_array(b, Some(elr), write_int))?;
+            }
+        }
+        tagged.serialize(resp)
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct KafkaRecordConfigRecord {
+    pub version: i16,
+    pub resource_type: i8,
+    pub resource_name: Vec<u8>,
+    pub name: Vec<u8>,
+    pub value: Option<Vec<u8>>, // null removes the override
+}
+
+#[allow(dead_code)]
+impl KafkaRecordConfigRecord {
+    pub fn deserialize<R: Read>(buffer: &mut R, version: i16) -> errors::Result<Self> {
+        let rec = Self {
+            version,
+            resource_type: parser::read_byte(buffer)?,
+            resource_name: parser::read_string(buffer, true)?,
+            name: parser::read_string(buffer, true)?,
+            value: parser::read_nullable_string(buffer, true)?,
+        };
+        parser::skip_tagged_fields(buffer)?;
+        Ok(rec)
+    }
+
+    pub fn serialize<W: Write>(&self, resp: &mut W) -> errors::Result<()> {
+        writer::write_bytes(resp, &self.resource_type)?;
+        writer::write_string(resp, &self.resource_name, true)?;
+        writer::write_string(resp, &self.name, true)?;
+        writer::write_nullable_string(resp, self.value.as_deref(), true)?;
+        writer::write_tagged_fields(resp, true)
+    }
+}
+
+// everything but the partition and topic is tagged, and absent when it
+// didn't change. v1 adds directories, v2 the eligible leader replica fields.
+#[derive(Debug, Clone, PartialEq)]
+pub struct KafkaRecordPartitionChangeRecord {
+    pub version: i16,
+    pub partition_id: i32,
+    pub topic_uuid: Uuid,
+    pub isr: Option<Vec<i32>>,                      // tag 0
+    pub leader: i32,                                // tag 1, NO_LEADER_CHANGE if unchanged
+    pub replicas: Option<Vec<i32>>,                 // tag 2
+    pub removing_replicas: Option<Vec<i32>>,        // tag 3
+    pub adding_replicas: Option<Vec<i32>>,          // tag 4
+    pub leader_recovery_state: i8,                  // tag 5, -1 if unchanged
+    pub directories: Option<Vec<Uuid>>,             // tag 6
+    pub eligible_leader_replicas: Option<Vec<i32>>, // tag 7
+    pub last_known_elr: Option<Vec<i32>>,           // tag 8
+}
+
+impl Default for KafkaRecordPartitionChangeRecord {
+    fn default() -> Self {
+        Self {
+            version: 0,
+            partition_id: 0,
+            topic_uuid: Uuid::default(),
+            isr: None,
+            leader: NO_LEADER_CHANGE,
+            replicas: None,
+            removing_replicas: None,
+            adding_replicas: None,
+            leader_recovery_state: NO_LEADER_RECOVERY_STATE_CHANGE,
+            directories: None,
+            eligible_leader_replicas: None,
+            last_known_elr: None,
+        }
+    }
+}
+
+#[allow(dead_code)]
+impl KafkaRecordPartitionChangeRecord {
+    pub fn deserialize<R: Read>(buffer: &mut R, version: i16) -> errors::Result<Self> {
+        let mut rec = Self {
+            version,
+            partition_id: parser::read_int(buffer)?,
+            topic_uuid: read_uuid(buffer)?,
+            ..Default::default()
+        };
+        for (tag, data) in read_tagged_fields(buffer)? {
+            let input = &mut Cursor::new(data);
+            match tag {
+                0 => rec.isr = read_array(input, parser::read_int)?,
+                1 => rec.leader = parser::read_int(input)?,
+                2 => rec.replicas = read_array(input, parser::read_int)?,
+                3 => rec.removing_replicas = read_array(input, parser::read_int)?,
+                4 => rec.adding_replicas = read_array(input, parser::read_int)?,
+                5 => rec.leader_recovery_state = parser::read_byte(input)?,
+                6 if version >= 1 => rec.directories = read_array(input, read_uuid)?,
+                7 if version >= 2 => {
+                    rec.eligible_leader_replicas = read_array(input, parser::read_int)?
+                }
+                8 if version >= 2 => rec.last_known_elr = read_array(input, parser::read_int)?,
+                _ => (),
+            }
+        }
+        Ok(rec)
+    }
+
+    pub fn serialize<W: Write>(&self, resp: &mut W) -> errors::Result<()> {
+        writer::write_bytes(resp, &self.partition_id)?;
+        resp.write_all(&self.topic_uuid)?;
+        let mut tagged = TaggedFields::default();
+        let replica_lists = [
+            (0, &self.isr),
+            (2, &self.replicas),
+            (3, &self.removing_replicas),
+            (4, &self.adding_replicas),
+        ];
+        for (tag, list) in replica_lists {
+            if let Some(list) = list {
+                tagged.add(tag, |b| write_array(b, Some(list), write_int))?;
+            }
+        }
+        if self.leader != NO_LEADER_CHANGE {
+            tagged.add(1, |b| writer::write_bytes(b, &self.leader))?;
+        }
+        if self.leader_recovery_state != NO_LEADER_RECOVERY_STATE_CHANGE {
+            tagged.add(5, |b| writer::write_bytes(b, &self.leader_recovery_state))?;
+        }
+        if let (1.., Some(dirs)) = (self.version, &self.directories) {
+            tagged.add(6, |b| write_array(b, Some(dirs), write_uuid))?;
+        }
+        if self.version >= 2 {
+            if let Some(elr) = &self.eligible_leader_replicas {
+                tagged.add(7, |b| write_array(b, Some(elr), write_int))?;
+            }
+            if let Some(elr) = &self.last_known_elr {
+                tagged.add(8, |b| write_array(b, Some(elr), write_int))?;
+            }
+        }
+        tagged.serialize(resp)
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct KafkaRecordAccessControlEntryRecord {
+    pub version: i16,
+    pub id: Uuid,
+    pub resource_type: i8,
+    pub resource_name: Vec<u8>,
+    pub pattern_type: i8,
+    pub principal: Vec<u8>,
+    pub host: Vec<u8>,
+    pub operation: i8,
+    pub permission_type: i8,
+}
+
+#[allow(dead_code)]
+impl KafkaRecordAccessControlEntryRecord {
+    pub fn deserialize<R: Read>(buffer: &mut R, version: i16) -> errors::Result<Self> {
+        let rec = Self {
+            version,
+            id: read_uuid(buffer)?,
+            resource_type: parser::read_byte(buffer)?,
+            resource_name: parser::read_string(buffer, true)?,
+            pattern_type: parser::read_byte(buffer)?,
+            principal: parser::read_string(buffer, true)?,
+            host: parser::read_string(buffer, true)?,
+            operation: parser::read_byte(buffer)?,
+            permission_type: parser::read_byte(buffer)?,
+        };
+        parser::skip_tagged_fields(buffer)?;
+        Ok(rec)
+    }
+
+    pub fn serialize<W: Write>(&self, resp: &mut W) -> errors::Result<()> {
+        resp.write_all(&self.id)?;
+        writer::write_bytes(resp, &self.resource_type)?;
+        writer::write_string(resp, &self.resource_name, true)?;
+        writer::write_bytes(resp, &self.pattern_type)?;
+        writer::write_string(resp, &self.principal, true)?;
+        writer::write_string(resp, &self.host, true)?;
+        writer::write_bytes(resp, &self.operation)?;
+        writer::write_bytes(resp, &self.permission_type)?;
+        writer::write_tagged_fields(resp, true)
+    }
+}
+
+// FenceBrokerRecord and UnfenceBrokerRecord share this layout
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct KafkaRecordBrokerEpochRecord {
+    pub version: i16,
+    pub id: i32,
+    pub epoch: i64,
+}
+
+#[allow(dead_code)]
+impl KafkaRecordBrokerEpochRecord {
+    pub fn deserialize<R: Read>(buffer: &mut R, version: i16) -> errors::Result<Self> {
+        let rec = Self {
+            version,
+            id: parser::read_int(buffer)?,
+            epoch: parser::read_u64(buffer)? as i64,
+        };
+        parser::skip_tagged_fields(buffer)?;
+        Ok(rec)
+    }
+
+    pub fn serialize<W: Write>(&self, resp: &mut W) -> errors::Result<()> {
+        writer::write_bytes(resp, &self.id)?;
+        writer::write_bytes(resp, &self.epoch)?;
+        writer::write_tagged_fields(resp, true)
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct KafkaRecordRemoveTopicRecord {
+    pub version: i16,
+    pub topic_uuid: Uuid,
+}
+
+#[allow(dead_code)]
+impl KafkaRecordRemoveTopicRecord {
+    pub fn deserialize<R: Read>(buffer: &mut R, version: i16) -> errors::Result<Self> {
+        let rec = Self {
+            version,
+            topic_uuid: read_uuid(buffer)?,
+        };
+        parser::skip_tagged_fields(buffer)?;
+        Ok(rec)
+    }
+
+    pub fn serialize<W: Write>(&self, resp: &mut W) -> errors::Result<()> {
+        resp.write_all(&self.topic_uuid)?;
+        writer::write_tagged_fields(resp, true)
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct KafkaRecordFeature {
+    pub version: i16,
+    pub name: Vec<u8>,
+    pub feature_level: i16,
+}
+
+#[allow(dead_code)]
+impl KafkaRecordFeature {
+    pub fn deserialize<R: Read>(buffer: &mut R, version: i16) -> errors::Result<Self> {
+        let rec = Self {
+            version,
+            name: parser::read_string(buffer, true)?,
+            feature_level: parser::read_short(buffer)?,
+        };
+        parser::skip_tagged_fields(buffer)?;
+        Ok(rec)
+    }
+
+    pub fn serialize<W: Write>(&self, resp: &mut W) -> errors::Result<()> {
+        writer::write_string(resp, &self.name, true)?;
+        writer::write_bytes(resp, &self.feature_level)?;
+        writer::write_tagged_fields(resp, true)
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct QuotaEntity {
+    pub entity_type: Vec<u8>,
+    pub entity_name: Option<Vec<u8>>, // null for the default entity
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct KafkaRecordClientQuotaRecord {
+    pub version: i16,
+    pub entity: Vec<QuotaEntity>,
+    pub key: Vec<u8>,
+    pub value: f64,
+    pub remove: bool,
+}
+
+#[allow(dead_code)]
+impl KafkaRecordClientQuotaRecord {
+    pub fn deserialize<R: Read>(buffer: &mut R, version: i16) -> errors::Result<Self> {
+        let rec = Self {
+            version,
+            entity: read_array(buffer, |b| {
+                let entity = QuotaEntity {
+                    entity_type: parser::read_string(b, true)?,
+                    entity_name: parser::read_nullable_string(b, true)?,
+                };
+                parser::skip_tagged_fields(b)?;
+                Ok(entity)
+            })?
+            .unwrap_or_default(),
+            key: parser::read_string(buffer, true)?,
+            value: f64::from_bits(parser::read_u64(buffer)?),
+            remove: read_bool(buffer)?,
+        };
+        parser::skip_tagged_fields(buffer)?;
+        Ok(rec)
+    }
+
+    pub fn serialize<W: Write>(&self, resp: &mut W) -> errors::Result<()> {
+        write_array(resp, Some(&self.entity), |r, e| {
+            writer::write_string(r, &e.entity_type, true)?;
+            writer::write_nullable_string(r, e.entity_name.as_deref(), true)?;
+            writer::write_tagged_fields(r, true)
+        })?;
+        writer::write_string(resp, &self.key, true)?;
+        writer::write_bytes(resp, &self.value.to_bits())?;
+        writer::write_bool(resp, self.remove)?;
+        writer::write_tagged_fields(resp, true)
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct KafkaRecordProducerIdsRecord {
+    pub version: i16,
+    pub broker_id: i32,
+    pub broker_epoch: i64,
+    pub next_producer_id: i64,
+}
+
+#[allow(dead_code)]
+impl KafkaRecordProducerIdsRecord {
+    pub fn deserialize<R: Read>(buffer: &mut R, version: i16) -> errors::Result<Self> {
+        let rec = Self {
+            version,
+            broker_id: parser::read_int(buffer)?,
+            broker_epoch: parser::read_u64(buffer)? as i64,
+            next_producer_id: parser::read_u64(buffer)? as i64,
+        };
+        parser::skip_tagged_fields(buffer)?;
+        Ok(rec)
+    }
+
+    pub fn serialize<W: Write>(&self, resp: &mut W) -> errors::Result<()> {
+        writer::write_bytes(resp, &self.broker_id)?;
+        writer::write_bytes(resp, &self.broker_epoch)?;
+        writer::write_bytes(resp, &self.next_producer_id)?;
+        writer::write_tagged_fields(resp, true)
+    }
+}
+
+// fenced and in_controlled_shutdown are -1 (no), 0 (unchanged) or 1 (yes).
+// v1 adds in_controlled_shutdown, v2 log_dirs, all of them tagged.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct KafkaRecordBrokerRegistrationChangeRecord {
+    pub version: i16,
+    pub broker_id: i32,
+    pub broker_epoch: i64,
+    pub fenced: i8,                 // tag 0
+    pub in_controlled_shutdown: i8, // tag 1
+    pub log_dirs: Vec<Uuid>,        // tag 2
+}
+
+#[allow(dead_code)]
+impl KafkaRecordBrokerRegistrationChangeRecord {
+    pub fn deserialize<R: Read>(buffer: &mut R, version: i16) -> errors::Result<Self> {
+        let mut rec = Self {
+            version,
+            broker_id: parser::read_int(buffer)?,
+            broker_epoch: parser::read_u64(buffer)? as i64,
+            ..Default::default()
+        };
+        for (tag, data) in read_tagged_fields(buffer)? {
+            let input = &mut Cursor::new(data);
+            match tag {
+                0 => rec.fenced = parser::read_byte(input)?,
+                1 if version >= 1 => rec.in_controlled_shutdown = parser::read_byte(input)?,
+                2 if version >= 2 => {
+                    rec.log_dirs = read_array(input, read_uuid)?.unwrap_or_default()
+                }
+                _ => (),
+            }
+        }
+        Ok(rec)
+    }
+
+    pub fn serialize<W: Write>(&self, resp: &mut W) -> errors::Result<()> {
+        writer::write_bytes(resp, &self.broker_id)?;
+        writer::write_bytes(resp, &self.broker_epoch)?;
+        let mut tagged = TaggedFields::default();
+        if self.fenced != 0 {
+            tagged.add(0, |b| writer::write_bytes(b, &self.fenced))?;
+        }
+        if self.version >= 1 && self.in_controlled_shutdown != 0 {
+            tagged.add(1, |b| writer::write_bytes(b, &self.in_controlled_shutdown))?;
+        }
+        if self.version >= 2 && !self.log_dirs.is_empty() {
+            tagged.add(2, |b| write_array(b, Some(&self.log_dirs), write_uuid))?;
+        }
+        tagged.serialize(resp)
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct KafkaRecordRemoveAccessControlEntryRecord {
+    pub version: i16,
+    pub id: Uuid,
+}
+
+#[allow(dead_code)]
+impl KafkaRecordRemoveAccessControlEntryRecord {
+    pub fn deserialize<R: Read>(buffer: &mut R, version: i16) -> errors::Result<Self> {
+        let rec = Self {
+            version,
+            id: read_uuid(buffer)?,
+        };
+        parser::skip_tagged_fields(buffer)?;
+        Ok(rec)
+    }
+
+    pub fn serialize<W: Write>(&self, resp: &mut W) -> errors::Result<()> {
+        resp.write_all(&self.id)?;
+        writer::write_tagged_fields(resp, true)
+    }
+}
+
+// written by the controller on every election to advance the high watermark
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct KafkaRecordNoOpRecord {
+    pub version: i16,
+}
+
+#[allow(dead_code)]
+impl KafkaRecordNoOpRecord {
+    pub fn deserialize<R: Read>(buffer: &mut R, version: i16) -> errors::Result<Self> {
+        parser::skip_tagged_fields(buffer)?;
+        Ok(Self { version })
+    }
+
+    pub fn serialize<W: Write>(&self, resp: &mut W) -> errors::Result<()> {
+        writer::write_tagged_fields(resp, true)
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct KafkaRecordZkMigrationStateRecord {
+    pub version: i16,
+    pub zk_migration_state: i8,
+}
+
+#[allow(dead_code)]
+impl KafkaRecordZkMigrationStateRecord {
+    pub fn deserialize<R: Read>(buffer: &mut R, version: i16) -> errors::Result<Self> {
+        let rec = Self {
+            version,
+            zk_migration_state: parser::read_byte(buffer)?,
+        };
+        parser::skip_tagged_fields(buffer)?;
+        Ok(rec)
+    }
+
+    pub fn serialize<W: Write>(&self, resp: &mut W) -> errors::Result<()> {
+        writer::write_bytes(resp, &self.zk_migration_state)?;
+        writer::write_tagged_fields(resp, true)
+    }
+}
+
+fn read_uuid<R: Read>(buffer: &mut R) -> errors::Result<Uuid> {
+    Ok(parser::read_u128(buffer)?.to_be_bytes())
+}
+
+fn write_uuid<W: Write>(resp: &mut W, uuid: &Uuid) -> errors::Result<()> {
+    Ok(resp.write_all(uuid)?)
+}
+
+fn write_int<W: Write>(resp: &mut W, val: &i32) -> errors::Result<()> {
+    writer::write_bytes(resp, val)
+}
+
+fn read_bool<R: Read>(buffer: &mut R) -> errors::Result<bool> {
+    Ok(parser::read_byte(buffer)? != 0)
+}
+
+// a compact array, None when null
+fn read_array<R: Read, T>(
+    buffer: &mut R,
+    mut read: impl FnMut(&mut R) -> errors::Result<T>,
+) -> errors::Result<Option<Vec<T>>> {
+    let Some(len) = parser::read_array_len(buffer, true)? else {
+        return Ok(None);
+    };
+    (0..len)
+        .map(|_| read(buffer))
+        .collect::<errors::Result<_>>()
+        .map(Some)
+}
+
+fn write_array<W: Write, T>(
+    resp: &mut W,
+    items: Option<&[T]>,
+    write: impl Fn(&mut W, &T) -> errors::Result<()>,
+) -> errors::Result<()> {
+    let Some(items) = items else {
+        return writer::write_uvarint(resp, 0);
+    };
+    writer::write_array_len(resp, items.len(), true)?;
+    items.iter().try_for_each(|item| write(resp, item))
+}
+
+// a tagged field section, each field as its tag and raw bytes
+fn read_tagged_fields<R: Read>(buffer: &mut R) -> errors::Result<Vec<(u32, Vec<u8>)>> {
+    let count = parser::read_uvarint(buffer)?;
+    (0..count)
+        .map(|_| {
+            let tag = parser::read_uvarint(buffer)?;
+            let mut data = vec![0_u8; parser::read_uvarint(buffer)? as usize];
+            buffer.read_exact(&mut data)?;
+            Ok((tag, data))
+        })
+        .collect()
+}
+
+// tagged fields being written, added in ascending tag order
+#[derive(Default)]
+struct TaggedFields {
+    fields: Vec<(u32, Vec<u8>)>,
+}
+
+impl TaggedFields {
+    fn add(
+        &mut self,
+        tag: u32,
+        write: impl FnOnce(&mut Vec<u8>) -> errors::Result<()>,
+    ) -> errors::Result<()> {
+        let mut data = vec![];
+        write(&mut data)?;
+        self.fields.push((tag, data));
+        Ok(())
+    }
+
+    fn serialize<W: Write>(mut self, resp: &mut W) -> errors::Result<()> {
+        self.fields.sort_by_key(|(tag, _)| *tag);
+        writer::write_uvarint(resp, self.fields.len() as i32)?;
+        for (tag, data) in &self.fields {
+            writer::write_uvarint(resp, *tag as i32)?;
+            writer::write_uvarint(resp, data.len() as i32)?;
+            resp.write_all(data)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(value: KafkaRecordValue) {
+        let bytes = value.to_bytes().unwrap();
+        let decoded = KafkaRecordValue::deserialize(&mut Cursor::new(&bytes)).unwrap();
+        assert_eq!(decoded, value);
+    }
+
+    #[test]
+    fn test_partition_record_layout() {
+        // a v1 PartitionRecord as the kafka-storage format tool writes it
+        let bytes = [
+            vec![0x01, 0x03, 0x01, 0x00, 0x00, 0x00, 0x01],
+            vec![0xab; 16],
+            vec![0x02, 0x00, 0x00, 0x00, 0x01, 0x02, 0x00, 0x00, 0x00, 0x01],
+            vec![0x01, 0x01, 0x00, 0x00, 0x00, 0x01],
+            vec![0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02],
+            vec![0xcd; 16],
+            vec![0x00],
+        ]
+        .concat();
+        let value = KafkaRecordValue::deserialize(&mut Cursor::new(&bytes)).unwrap();
+        let KafkaRecordValue::KafkaRecordPartitionType(p) = &value else {
+            panic!("not a partition record: {value}");
+        };
+        assert_eq!(p.partition_id, 1);
+        assert_eq!(p.replicas, vec![1]);
+        assert_eq!(p.leader, 1);
+        assert_eq!(p.directories, vec![[0xcd; 16]]);
+        assert_eq!(value.to_bytes().unwrap(), bytes);
+    }
+
+    #[test]
+    fn test_metadata_record_round_trips() {
+        round_trip(KafkaRecordValue::KafkaRecordRegisterBrokerType(
+            KafkaRecordRegisterBrokerRecord {
+                version: 3,
+                broker_id: 1,
+                incarnation_id: [7; 16],
+                broker_epoch: 12,
+                end_points: vec![BrokerEndpoint {
+                    name: b"PLAINTEXT".to_vec(),
+                    host: b"localhost".to_vec(),
+                    port: 9092,
+                    security_protocol: 0,
+                }],
+                features: vec![BrokerFeature {
+                    name: b"metadata.version".to_vec(),
+                    min_supported_version: 1,
+                    max_supported_version: 20,
+                }],
+                fenced: true,
+                log_dirs: vec![[9; 16]],
+                ..Default::default()
+            },
+        ));
+        round_trip(KafkaRecordValue::KafkaRecordPartitionChangeType(
+            KafkaRecordPartitionChangeRecord {
+                version: 2,
+                partition_id: 3,
+                isr: Some(vec![1, 2]),
+                leader: 2,
+                eligible_leader_replicas: Some(vec![]),
+                ..Default::default()
+            },
+        ));
+        round_trip(KafkaRecordValue::KafkaRecordPartitionType(
+            KafkaRecordPartitionRecord {
+                version: 2,
+                leader_recovery_state: 1,
+                last_known_elr: Some(vec![3]),
+                ..Default::default()
+            },
+        ));
+        round_trip(KafkaRecordValue::KafkaRecordClientQuotaType(
+            KafkaRecordClientQuotaRecord {
+                entity: vec![QuotaEntity {
+                    entity_type: b"user".to_vec(),
+                    entity_name: None,
+                }],
+                key: b"producer_byte_rate".to_vec(),
+                value: 1024.5,
+                ..Default::default()
+            },
+        ));
+        round_trip(KafkaRecordValue::KafkaRecordBrokerRegistrationChangeType(
+            KafkaRecordBrokerRegistrationChangeRecord {
+                version: 1,
+                fenced: -1,
+                in_controlled_shutdown: 1,
+                ..Default::default()
+            },
+        ));
+        round_trip(KafkaRecordValue::KafkaRecordNoOpType(
+            KafkaRecordNoOpRecord::default(),
+        ));
+
+        // a type from a newer controller is carried along as is
+        let bytes = [0x01, 0x63, 0x00, 0x01, 0x02, 0x03];
+        let value = KafkaRecordValue::deserialize(&mut Cursor::new(&bytes)).unwrap();
+        assert!(matches!(&value, KafkaRecordValue::Unknown(u) if u.frame_type == 99));
+        assert_eq!(value.to_bytes().unwrap(), bytes);
+    }
+}
@@ -1,16 +1,44 @@
+pub mod alter_configs;
 pub mod apikey;
 pub mod basics;
 pub mod body;
+pub mod checkpoint;
+pub mod cleaner;
+pub mod client;
+pub mod cluster;
+pub mod compression;
+pub mod config;
+pub mod controller;
+pub mod create_topics;
+pub mod delete_topics;
 pub mod errors;
 pub mod fetch;
+pub mod flusher;
 pub mod header;
 pub mod incoming;
+pub mod legacy;
+pub mod log;
+pub mod logdirs;
 pub mod metadata;
+pub mod metadata_api;
+pub mod metadata_listener;
+pub mod metadata_records;
 pub mod parser;
 pub mod partitions;
 pub mod produce;
+pub mod quorum;
+pub mod raft;
 pub mod records;
+pub mod registration;
+pub mod remote;
+pub mod snapshot;
+pub mod storage;
+#[cfg(test)]
+pub mod testing;
+pub mod validator;
+pub mod voters;
 pub mod writer;
+pub mod zerocopy;
 
 // supports version 0 through 4
 pub const MIN_SUPPORTED_API_VERSION: u16 = 0;
@@ -19,15 +47,72 @@ pub const MIN_SUPPORTED_DESCRIBE_PARTITION_VER: u16 = 0;
 pub const MAX_SUPPORTED_DESCRIBE_PARTITION_VER: u16 = 0;
 
 pub const MIN_SUPPORTED_FETCH_VER: u16 = 0;
-pub const MAX_SUPPORTED_FETCH_VER: u16 = 16;
+pub const MAX_SUPPORTED_FETCH_VER: u16 = 17;
 
-pub const KAFKA_RECORDTYPE_FEATURE: i8 = 12;
-pub const KAFKA_RECORDTYPE_TOPIC: i8 = 2;
-pub const KAFKA_RECORDTYPE_PARTITION: i8 = 3;
+pub const MIN_SUPPORTED_METADATA_VER: u16 = 0;
+pub const MAX_SUPPORTED_METADATA_VER: u16 = 12;
+
+// metadata log record types
+pub const KAFKA_RECORDTYPE_REGISTER_BROKER: i16 = 0;
+pub const KAFKA_RECORDTYPE_UNREGISTER_BROKER: i16 = 1;
+pub const KAFKA_RECORDTYPE_TOPIC: i16 = 2;
+pub const KAFKA_RECORDTYPE_PARTITION: i16 = 3;
+pub const KAFKA_RECORDTYPE_CONFIG: i16 = 4;
+pub const KAFKA_RECORDTYPE_PARTITION_CHANGE: i16 = 5;
+pub const KAFKA_RECORDTYPE_ACCESS_CONTROL_ENTRY: i16 = 6;
+pub const KAFKA_RECORDTYPE_FENCE_BROKER: i16 = 7;
+pub const KAFKA_RECORDTYPE_UNFENCE_BROKER: i16 = 8;
+pub const KAFKA_RECORDTYPE_REMOVE_TOPIC: i16 = 9;
+pub const KAFKA_RECORDTYPE_FEATURE: i16 = 12;
+pub const KAFKA_RECORDTYPE_CLIENT_QUOTA: i16 = 14;
+pub const KAFKA_RECORDTYPE_PRODUCER_IDS: i16 = 15;
+pub const KAFKA_RECORDTYPE_BROKER_REGISTRATION_CHANGE: i16 = 17;
+pub const KAFKA_RECORDTYPE_REMOVE_ACCESS_CONTROL_ENTRY: i16 = 18;
+pub const KAFKA_RECORDTYPE_NO_OP: i16 = 20;
+pub const KAFKA_RECORDTYPE_ZK_MIGRATION_STATE: i16 = 21;
 
 pub const MIN_SUPPORTED_PRODUCE_VERSION: u16 = 0;
 pub const MAX_SUPPORTED_PRODUCE_VERSION: u16 = 11;
 
+pub const MIN_SUPPORTED_ALTER_REPLICA_LOG_DIRS_VER: u16 = 0;
+pub const MAX_SUPPORTED_ALTER_REPLICA_LOG_DIRS_VER: u16 = 2;
+pub const MIN_SUPPORTED_DESCRIBE_LOG_DIRS_VER: u16 = 0;
+pub const MAX_SUPPORTED_DESCRIBE_LOG_DIRS_VER: u16 = 4;
+
+pub const MIN_SUPPORTED_DESCRIBE_CLUSTER_VER: u16 = 0;
+pub const MAX_SUPPORTED_DESCRIBE_CLUSTER_VER: u16 = 1;
+
+pub const MIN_SUPPORTED_CREATE_TOPICS_VER: u16 = 0;
+pub const MAX_SUPPORTED_CREATE_TOPICS_VER: u16 = 7;
+pub const MIN_SUPPORTED_DELETE_TOPICS_VER: u16 = 0;
+pub const MAX_SUPPORTED_DELETE_TOPICS_VER: u16 = 6;
+pub const MIN_SUPPORTED_INCREMENTAL_ALTER_CONFIGS_VER: u16 = 0;
+pub const MAX_SUPPORTED_INCREMENTAL_ALTER_CONFIGS_VER: u16 = 1;
+
+// raft between the controllers
+pub const MIN_SUPPORTED_VOTE_VER: u16 = 0;
+pub const MAX_SUPPORTED_VOTE_VER: u16 = 1;
+pub const MIN_SUPPORTED_BEGIN_QUORUM_EPOCH_VER: u16 = 0;
+pub const MAX_SUPPORTED_BEGIN_QUORUM_EPOCH_VER: u16 = 1;
+pub const MIN_SUPPORTED_END_QUORUM_EPOCH_VER: u16 = 0;
+pub const MAX_SUPPORTED_END_QUORUM_EPOCH_VER: u16 = 1;
+pub const MIN_SUPPORTED_DESCRIBE_QUORUM_VER: u16 = 0;
+pub const MAX_SUPPORTED_DESCRIBE_QUORUM_VER: u16 = 2;
+pub const MIN_SUPPORTED_FETCH_SNAPSHOT_VER: u16 = 0;
+pub const MAX_SUPPORTED_FETCH_SNAPSHOT_VER: u16 = 1;
+pub const MIN_SUPPORTED_ADD_RAFT_VOTER_VER: u16 = 0;
+pub const MAX_SUPPORTED_ADD_RAFT_VOTER_VER: u16 = 1;
+pub const MIN_SUPPORTED_REMOVE_RAFT_VOTER_VER: u16 = 0;
+pub const MAX_SUPPORTED_REMOVE_RAFT_VOTER_VER: u16 = 0;
+pub const MIN_SUPPORTED_UPDATE_RAFT_VOTER_VER: u16 = 0;
+pub const MAX_SUPPORTED_UPDATE_RAFT_VOTER_VER: u16 = 0;
+
+pub const MIN_SUPPORTED_BROKER_REGISTRATION_VER: u16 = 0;
+pub const MAX_SUPPORTED_BROKER_REGISTRATION_VER: u16 = 3;
+
+// root of all partition directories
+pub const LOG_DIR: &str = "/tmp/kraft-combined-logs";
+
 #[repr(u16)]
 #[derive(Debug)]
 pub enum ErrorCodes {
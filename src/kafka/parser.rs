@@ -17,7 +17,9 @@ pub fn read_uvarint<R: Read>(req: &mut R) -> errors::Result<u32> {
             break;
         }
         if i >= 5 {
-            return Err(errors::KafkaErrors::InvalidWriterArg("UVarInt is too long".to_string()).into());
+            return Err(
+                errors::KafkaErrors::InvalidWriterArg("UVarInt is too long".to_string()).into(),
+            );
         }
     }
     Ok(res)
@@ -77,6 +79,7 @@ pub fn read_byte<R: Read>(req: &mut R) -> errors::Result<i8> {
     req.read_exact(&mut data)?;
     Ok(data[0] as i8)
 }
+#[allow(dead_code)]
 pub fn tag_buffer<R: Read>(req: &mut R) -> errors::Result<()> {
     let _v = read_byte(req)?;
     Ok(())
@@ -97,8 +100,91 @@ pub fn read_varint<R: Read>(req: &mut R) -> errors::Result<i32> {
             break;
         }
         if i >= 5 {
-            return Err(errors::KafkaErrors::InvalidWriterArg("Varint is too long".to_string()).into());
+            return Err(
+                errors::KafkaErrors::InvalidWriterArg("Varint is too long".to_string()).into(),
+            );
         }
     }
     Ok(((res >> 1) as i32) ^ -((res & 1) as i32)) // zigzag decode
 }
+
+pub fn read_varlong<R: Read>(req: &mut R) -> errors::Result<i64> {
+    let mut res: u64 = 0;
+    let mut shift = 0;
+    let mut i = 0;
+    loop {
+        let mut buf = [0; 1];
+        req.read_exact(&mut buf)?;
+        let b = buf[0];
+        res |= ((b & 0x7f) as u64) << shift;
+        shift += 7;
+        i += 1;
+        if (b & 0x80) == 0 {
+            break;
+        }
+        if i >= 10 {
+            return Err(
+                errors::KafkaErrors::InvalidWriterArg("Varlong is too long".to_string()).into(),
+            );
+        }
+    }
+    Ok(((res >> 1) as i64) ^ -((res & 1) as i64)) // zigzag decode
+}
+
+// version aware helpers: flexible versions use compact (uvarint length + 1)
+// strings and arrays, older ones int16/int32 lengths with -1 for null
+pub fn read_nullable_string<R: Read>(
+    req: &mut R,
+    flexible: bool,
+) -> errors::Result<Option<Vec<u8>>> {
+    let len = if flexible {
+        read_uvarint(req)? as i64 - 1
+    } else {
+        read_short(req)? as i64
+    };
+    if len < 0 {
+        return Ok(None);
+    }
+    let mut data = vec![0_u8; len as usize];
+    req.read_exact(&mut data)?;
+    Ok(Some(data))
+}
+
+pub fn read_string<R: Read>(req: &mut R, flexible: bool) -> errors::Result<Vec<u8>> {
+    Ok(read_nullable_string(req, flexible)?.unwrap_or_default())
+}
+
+// None for a null array
+pub fn read_array_len<R: Read>(req: &mut R, flexible: bool) -> errors::Result<Option<usize>> {
+    let len = if flexible {
+        read_uvarint(req)? as i64 - 1
+    } else {
+        read_int(req)? as i64
+    };
+    Ok((len >= 0).then_some(len as usize))
+}
+
+// skips a tagged field section, whatever fields it carries
+pub fn skip_tagged_fields<R: Read>(req: &mut R) -> errors::Result<()> {
+    let count = read_uvarint(req)?;
+    for _ in 0..count {
+        let _tag = read_uvarint(req)?;
+        let size = read_uvarint(req)?;
+        std::io::copy(&mut req.take(size as u64), &mut std::io::sink())?;
+    }
+    Ok(())
+}
+
+// reads a tagged field section, the raw bytes of each field by tag
+pub fn read_tagged_fields<R: Read>(req: &mut R) -> errors::Result<Vec<(u32, Vec<u8>)>> {
+    let count = read_uvarint(req)?;
+    let mut fields = vec![];
+    for _ in 0..count {
+        let tag = read_uvarint(req)?;
+        let size = read_uvarint(req)?;
+        let mut data = vec![0_u8; size as usize];
+        req.read_exact(&mut data)?;
+        fields.push((tag, data));
+    }
+    Ok(fields)
+}
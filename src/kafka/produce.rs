@@ -1,9 +1,14 @@
-use crate::kafka::{basics, errors, metadata, parser, records, writer};
+use crate::kafka::{config, errors, log, metadata, parser, records, validator, writer};
 use std::io::{Read, Write};
 use std::ptr::write_bytes;
-use std::sync::{Arc, Mutex};
+use std::sync::Arc;
 
+const PRODUCE_RESPONSE_CORRUPT_MESSAGE: u16 = 2;
 const PRODUCE_RESPONSE_UNKNOWN_TOPIC_OR_PARTITION: u16 = 3;
+const PRODUCE_RESPONSE_MESSAGE_TOO_LARGE: u16 = 10;
+const PRODUCE_RESPONSE_KAFKA_STORAGE_ERROR: u16 = 56;
+const PRODUCE_RESPONSE_INVALID_RECORD: u16 = 87;
+const PRODUCE_ACKS_ALL: i16 = -1;
 
 #[allow(dead_code)]
 #[derive(Debug, Clone)]
@@ -131,7 +136,8 @@ impl std::fmt::Display for ProduceRequestTopicPartition {
 impl ProduceRequestTopicPartition {
     pub fn new<R: Read>(req: &mut R) -> errors::Result<Self> {
         let partition_idx = parser::read_int(req)? as u32;
-        let record_batch_length = parser::read_byte(req)? as usize - 1;
+        // compact nullable records, null reads as no batches at all
+        let record_batch_length = (parser::read_uvarint(req)? as usize).saturating_sub(1);
         let mut record_batches = vec![0_u8; record_batch_length];
         req.read_exact(&mut record_batches)?;
         let tag_buffer = parser::read_byte(req)? as u8;
@@ -158,9 +164,19 @@ pub struct ProduceResponse {
 }
 
 impl ProduceResponse {
-    pub fn new(request: &ProduceRequest, metadata: &Arc<Mutex<metadata::Metadata>>) -> Self {
+    pub fn new(
+        request: &ProduceRequest,
+        metadata: &Arc<metadata::MetadataCache>,
+        logs: &Arc<log::LogManager>,
+    ) -> Self {
+        let image = metadata.image();
         let topics = request.topics.iter().fold(vec![], |mut acc, topic| {
-            acc.push(ProduceResponseTopic::new(topic, metadata));
+            acc.push(ProduceResponseTopic::new(
+                topic,
+                request.required_acks as i16,
+                &image,
+                logs,
+            ));
             acc
         });
         Self {
@@ -190,16 +206,36 @@ pub struct ProduceResponseTopic {
 }
 
 impl ProduceResponseTopic {
-    pub fn new(request: &ProduceRequestTopic, metadata: &Arc<Mutex<metadata::Metadata>>) -> Self {
+    pub fn new(
+        request: &ProduceRequestTopic,
+        acks: i16,
+        image: &metadata::MetadataImage,
+        logs: &Arc<log::LogManager>,
+    ) -> Self {
         Self {
             topic_name: request.topic_name.clone(),
             partitions: request.partitions.iter().fold(vec![], |mut acc, part| {
                 let topic_name = String::from_utf8(request.topic_name.to_vec())
                     .expect("Able to convert topic name UUID to string");
-                let pp = ProduceResponseTopicPartition::new(part, metadata, &topic_name);
+                let topic_id = image.topic_id(&topic_name);
+                let mut pp = ProduceResponseTopicPartition::new(part, image, topic_id);
                 if pp.error_code == 0 {
-                    if let Err(e) = pp.persist(part, metadata, &topic_name) {
+                    let config = image.topic_config(&topic_name);
+                    if let Err(e) =
+                        validator::validate(&part.record_batches, &config, log::now_ms())
+                    {
+                        println!("Produce API - rejecting batches for {topic_name}: {e}");
+                        pp.reject(&e);
+                    } else if let Err(e) = pp.persist(part, acks, &config, logs, &topic_name) {
                         println!("Produce API - failed to persist metadata on disk!!: {e}");
+                        pp.error_code = match e.downcast_ref::<errors::KafkaErrors>() {
+                            Some(errors::KafkaErrors::CorruptRecord(_)) => {
+                                PRODUCE_RESPONSE_CORRUPT_MESSAGE
+                            }
+                            _ => PRODUCE_RESPONSE_KAFKA_STORAGE_ERROR,
+                        };
+                        pp.base_offset = u64::MAX;
+                        pp.log_start_offset = u64::MAX;
                     }
                 }
 
@@ -228,44 +264,39 @@ pub struct ProduceResponseTopicPartition {
     partition_id: u32,
     error_code: u16,
     base_offset: u64,
-    log_append_time: u64,
+    log_append_time: i64,
     log_start_offset: u64,
     errors: Vec<ProduceResponseTopicPartitionError>,
-    error_message: basics::CompactNullableString,
+    error_message: Option<String>,
     tag_buffer: u8,
 }
 
 impl ProduceResponseTopicPartition {
     pub fn new(
         request: &ProduceRequestTopicPartition,
-        metadata: &Arc<Mutex<metadata::Metadata>>,
-        topic_name: &str,
+        image: &metadata::MetadataImage,
+        topic_id: Option<u128>,
     ) -> Self {
-        let metadata = metadata.lock().unwrap();
         let mut error_code = PRODUCE_RESPONSE_UNKNOWN_TOPIC_OR_PARTITION;
         let mut base_offset = 0xFFFFFFFFFFFFFFFF;
         let mut log_start_offset = 0xFFFFFFFFFFFFFFFF;
-        if let Some(topic) = metadata.get_topic_by_name(topic_name) {
-            if let Some(partitions) = metadata.partition_map.get(&topic.uuid_u128) {
-                if let Some(_pp) = partitions
-                    .iter()
-                    .find(|p| p.partition_id as u32 == request.partition_idx)
-                {
-                    error_code = 0;
-                    base_offset = 0;
-                    log_start_offset = 0;
-                }
-            }
+        if topic_id
+            .and_then(|id| image.partition_by_id(id, request.partition_idx as i32))
+            .is_some()
+        {
+            error_code = 0;
+            base_offset = 0;
+            log_start_offset = 0;
         }
 
         Self {
             partition_id: request.partition_idx,
             error_code,
             base_offset,
-            log_append_time: 0xFFFFFFFFFFFFFFFF,
+            log_append_time: -1,
             log_start_offset,
             errors: vec![],
-            error_message: basics::CompactNullableString::from(vec![]),
+            error_message: None,
             tag_buffer: request.tag_buffer,
         }
     }
@@ -278,39 +309,223 @@ impl ProduceResponseTopicPartition {
         writer::write_bytes(resp, &self.log_start_offset)?;
         writer::write_varint(resp, 1 + self.errors.len())?;
         self.errors.iter().try_for_each(|e| e.serialize(resp))?;
-        self.error_message.serialize(resp)?;
+        writer::write_nullable_string(
+            resp,
+            self.error_message.as_deref().map(str::as_bytes),
+            true,
+        )?;
         writer::write_bytes(resp, &self.tag_buffer)?;
         Ok(())
     }
 
+    // a produce the validator turned down: nothing gets appended, the
+    // records at fault are listed in record_errors
+    pub fn reject(&mut self, error: &validator::ValidationError) {
+        self.error_code = match error {
+            validator::ValidationError::CorruptMessage(_) => PRODUCE_RESPONSE_CORRUPT_MESSAGE,
+            validator::ValidationError::InvalidRecord { .. } => PRODUCE_RESPONSE_INVALID_RECORD,
+            validator::ValidationError::MessageTooLarge(_) => PRODUCE_RESPONSE_MESSAGE_TOO_LARGE,
+        };
+        if let validator::ValidationError::InvalidRecord { record_errors, .. } = error {
+            self.errors = record_errors
+                .iter()
+                .map(ProduceResponseTopicPartitionError::new)
+                .collect();
+        }
+        self.error_message = Some(error.to_string());
+        self.base_offset = u64::MAX;
+        self.log_start_offset = u64::MAX;
+    }
+
+    // appends the batches to the partition log, the offset they were given
+    // becomes the base offset of the response, along with the append time on
+    // LogAppendTime topics. acks=-1 may have to wait for
+    // the fsync, depending on the broker's durability setting.
     pub fn persist(
-        &self,
+        &mut self,
         request: &ProduceRequestTopicPartition,
-        _metadata: &Arc<Mutex<metadata::Metadata>>,
+        acks: i16,
+        config: &config::TopicConfig,
+        logs: &Arc<log::LogManager>,
         topic_name: &str,
     ) -> errors::Result<()> {
-        let log_file_name = format!(
-            "/tmp/kraft-combined-logs/{}-{}/00000000000000000000.log",
-            topic_name, request.partition_idx,
-        );
-        std::fs::write(&log_file_name, &request.record_batches)?;
+        let flush = acks == PRODUCE_ACKS_ALL && logs.flush_on_acks_all();
+        let (info, log_start_offset) =
+            logs.with_log(topic_name, request.partition_idx as i32, |log| {
+                let info = log.append(&request.record_batches, config)?;
+                if flush {
+                    log.flush()?;
+                }
+                Ok((info, logs.log_start_offset(log)))
+            })?;
+        self.base_offset = info.base_offset;
+        self.log_start_offset = log_start_offset;
+        // -1 unless the topic uses LogAppendTime
+        self.log_append_time = info.log_append_time.unwrap_or(-1);
         Ok(())
     }
 }
 
-#[allow(dead_code)]
 #[derive(Debug, Clone)]
 pub struct ProduceResponseTopicPartitionError {
-    length: u8,
+    batch_index: i32,
+    batch_index_error_message: Option<String>,
 }
 
 impl ProduceResponseTopicPartitionError {
-    #[allow(dead_code)]
-    pub fn new(_request: &ProduceRequest, _metadata: &Arc<Mutex<metadata::Metadata>>) -> Self {
-        todo!()
+    pub fn new(error: &validator::RecordError) -> Self {
+        Self {
+            batch_index: error.batch_index,
+            batch_index_error_message: Some(error.message.clone()),
+        }
     }
 
     pub fn serialize<W: Write>(&self, resp: &mut W) -> errors::Result<()> {
-        writer::write_bytes(resp, &self.length)
+        writer::write_bytes(resp, &self.batch_index)?;
+        writer::write_nullable_string(
+            resp,
+            self.batch_index_error_message.as_deref().map(str::as_bytes),
+            true,
+        )?;
+        writer::write_tagged_fields(resp, true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kafka::testing::{log_manager, record, records_batch, temp_root};
+    use std::io::Cursor;
+
+    fn partition(record_batches: Vec<u8>) -> ProduceRequestTopicPartition {
+        ProduceRequestTopicPartition {
+            partition_idx: 0,
+            record_batches,
+            tag_buffer: 0,
+        }
+    }
+
+    fn batch(offset_deltas: &[i32]) -> Vec<u8> {
+        records_batch(offset_deltas.iter().copied().map(record).collect())
+            .to_bytes()
+            .unwrap()
+    }
+
+    fn rejected(payload: Vec<u8>, config: &config::TopicConfig) -> ProduceResponseTopicPartition {
+        let request = partition(payload);
+        let mut response = ProduceResponseTopicPartition::new(&request, &Default::default(), None);
+        let error = validator::validate(&request.record_batches, config, 0).unwrap_err();
+        response.reject(&error);
+        response
+    }
+
+    #[test]
+    fn test_rejections_map_to_error_codes() {
+        let config = config::TopicConfig::default();
+
+        let mut corrupt = batch(&[0]);
+        let last = corrupt.len() - 1;
+        corrupt[last] ^= 0xff;
+        assert_eq!(
+            rejected(corrupt, &config).error_code,
+            PRODUCE_RESPONSE_CORRUPT_MESSAGE
+        );
+
+        let too_large = config::TopicConfig {
+            max_message_bytes: 1,
+            ..Default::default()
+        };
+        assert_eq!(
+            rejected(batch(&[0]), &too_large).error_code,
+            PRODUCE_RESPONSE_MESSAGE_TOO_LARGE
+        );
+
+        let invalid = rejected(batch(&[0, 0]), &config);
+        assert_eq!(invalid.error_code, PRODUCE_RESPONSE_INVALID_RECORD);
+        assert_eq!(invalid.errors.len(), 1);
+        assert_eq!(invalid.errors[0].batch_index, 1);
+        assert_eq!(invalid.base_offset, u64::MAX);
+    }
+
+    #[test]
+    fn test_log_append_time_in_response() {
+        let root = temp_root("produce-append-time");
+        let logs = log_manager(&root, &["a"]);
+        let request = partition(batch(&[0]));
+        let append_time = |config: &config::TopicConfig| {
+            let mut response =
+                ProduceResponseTopicPartition::new(&request, &Default::default(), None);
+            response
+                .persist(&request, 1, config, &logs, "events")
+                .unwrap();
+            let mut out = vec![];
+            response.serialize(&mut out).unwrap();
+            let mut out = Cursor::new(&out[14..]);
+            (
+                response.log_append_time,
+                parser::read_u64(&mut out).unwrap(),
+            )
+        };
+
+        // -1 on CreateTime topics, the broker's clock on LogAppendTime ones
+        assert_eq!(append_time(&config::TopicConfig::default()), (-1, u64::MAX));
+        let (time, encoded) = append_time(&config::TopicConfig {
+            message_timestamp_type: config::TimestampType::LogAppendTime,
+            ..Default::default()
+        });
+        assert!(time > 0);
+        assert_eq!(encoded, time as u64);
+        let _ = std::fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn test_headers_read_back() {
+        let root = temp_root("produce-headers");
+        let logs = log_manager(&root, &["a"]);
+        // header keys are taken as given, utf-8 or not
+        let headers = vec![
+            records::KafkaRecordHeader {
+                key: b"trace-id".to_vec(),
+                value: Some(b"abc".to_vec()),
+            },
+            records::KafkaRecordHeader {
+                key: vec![0xff, 0xfe],
+                value: None,
+            },
+        ];
+        let payload = records::RecordsBatch {
+            records: vec![records::KafkaRecord {
+                key: Some(b"k".to_vec()),
+                value: Some(b"v".to_vec()),
+                headers: headers.clone(),
+                ..Default::default()
+            }],
+            ..records::RecordsBatch::new()
+        }
+        .to_bytes()
+        .unwrap();
+        let config = config::TopicConfig::default();
+        let request = partition(payload);
+        validator::validate(&request.record_batches, &config, log::now_ms()).unwrap();
+        let mut response = ProduceResponseTopicPartition::new(&request, &Default::default(), None);
+        response
+            .persist(&request, 1, &config, &logs, "events")
+            .unwrap();
+
+        let log = logs.get("events", 0).unwrap();
+        let data = log
+            .lock()
+            .unwrap()
+            .read(0, u64::MAX)
+            .unwrap()
+            .unwrap()
+            .read_to_vec()
+            .unwrap();
+        let batches = records::RawBatch::split(&data).unwrap();
+        let records = batches[0].records().unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].key.as_deref(), Some(&b"k"[..]));
+        assert_eq!(records[0].headers, headers);
+        let _ = std::fs::remove_dir_all(&root);
     }
 }
@@ -0,0 +1,1003 @@
+// the raft apis of KIP-595: Vote (key 52), BeginQuorumEpoch (53),
+// EndQuorumEpoch (54) and DescribeQuorum (55), plus the Fetch a follower
+// sends for the metadata partition and FetchSnapshot (59). Controllers
+// are both the client and the server of these, so each message can be
+// read and written. Version 1 of Vote, BeginQuorumEpoch and
+// EndQuorumEpoch and Fetch v17 are the KIP-853 ones, where replicas are
+// told apart by the directory id of their metadata log dir too.
+//
+// https://cwiki.apache.org/confluence/display/KAFKA/KIP-595%3A+A+Raft+Protocol+for+the+Metadata+Quorum
+use crate::kafka::config::Listener;
+use crate::kafka::{errors, parser, voters, writer};
+use std::io::{Read, Write};
+
+// the id the metadata partition is fetched by, AAAAAAAAAAAAAAAAAAAAAQ
+pub const METADATA_TOPIC_ID: u128 = 1;
+
+// the versions controllers send each other: the first with directory ids
+pub const VOTE_VERSION: u16 = 1;
+pub const QUORUM_EPOCH_VERSION: u16 = 1;
+pub const METADATA_FETCH_VERSION: u16 = 17;
+
+// tags of the fetch partition response
+const TAG_DIVERGING_EPOCH: u32 = 0;
+const TAG_CURRENT_LEADER: u32 = 1;
+const TAG_SNAPSHOT_ID: u32 = 2;
+// tags of the cluster id and, from v15, the replica state in a fetch request
+const TAG_CLUSTER_ID: u32 = 0;
+const TAG_REPLICA_STATE: u32 = 1;
+// tag of the replica's directory id in a fetch request partition, v17+
+pub const TAG_REPLICA_DIRECTORY_ID: u32 = 0;
+
+// a replica as KIP-853 knows it: its id and the directory id of its
+// metadata log dir, zero when the request didn't carry one
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ReplicaKey {
+    pub id: i32,
+    pub directory_id: [u8; 16],
+}
+
+fn read_uuid<R: Read>(req: &mut R) -> errors::Result<[u8; 16]> {
+    Ok(parser::read_u128(req)?.to_be_bytes())
+}
+
+// reads the topics array every raft message has, flattened into one entry
+// per partition
+fn read_partitions<R: Read, T>(
+    req: &mut R,
+    flexible: bool,
+    mut read: impl FnMut(&mut R, String) -> errors::Result<T>,
+) -> errors::Result<Vec<T>> {
+    let mut partitions = vec![];
+    for _ in 0..parser::read_array_len(req, flexible)?.unwrap_or_default() {
+        let topic = String::from_utf8(parser::read_string(req, flexible)?)?;
+        for _ in 0..parser::read_array_len(req, flexible)?.unwrap_or_default() {
+            partitions.push(read(req, topic.clone())?);
+            if flexible {
+                parser::skip_tagged_fields(req)?;
+            }
+        }
+        if flexible {
+            parser::skip_tagged_fields(req)?;
+        }
+    }
+    Ok(partitions)
+}
+
+// the reverse, consecutive partitions of a topic go under one entry
+fn write_partitions<W: Write, T>(
+    resp: &mut W,
+    partitions: &[T],
+    topic: impl Fn(&T) -> &str,
+    flexible: bool,
+    write: impl Fn(&mut W, &T) -> errors::Result<()>,
+) -> errors::Result<()> {
+    let mut groups: Vec<&[T]> = vec![];
+    let mut rest = partitions;
+    while let Some(first) = rest.first() {
+        let len = rest.iter().take_while(|p| topic(p) == topic(first)).count();
+        groups.push(&rest[..len]);
+        rest = &rest[len..];
+    }
+    writer::write_array_len(resp, groups.len(), flexible)?;
+    for group in groups {
+        writer::write_string(resp, topic(&group[0]).as_bytes(), flexible)?;
+        writer::write_array_len(resp, group.len(), flexible)?;
+        for p in group {
+            write(resp, p)?;
+            writer::write_tagged_fields(resp, flexible)?;
+        }
+        writer::write_tagged_fields(resp, flexible)?;
+    }
+    Ok(())
+}
+
+fn read_cluster_id<R: Read>(req: &mut R, flexible: bool) -> errors::Result<Option<String>> {
+    Ok(parser::read_nullable_string(req, flexible)?
+        .map(String::from_utf8)
+        .transpose()?)
+}
+
+fn write_cluster_id<W: Write>(
+    resp: &mut W,
+    cluster_id: &Option<String>,
+    flexible: bool,
+) -> errors::Result<()> {
+    writer::write_nullable_string(resp, cluster_id.as_ref().map(String::as_bytes), flexible)
+}
+
+// Vote, versions 0 and 1 and always flexible
+#[derive(Debug, Clone)]
+pub struct VoteRequest {
+    pub version: u16,
+    pub cluster_id: Option<String>,
+    pub voter_id: i32, // v1+, the voter asked
+    pub partitions: Vec<VotePartition>,
+}
+
+#[derive(Debug, Clone)]
+pub struct VotePartition {
+    pub topic: String,
+    pub partition_index: i32,
+    pub candidate_epoch: i32,
+    pub candidate_id: i32,
+    pub candidate_directory_id: [u8; 16], // v1+
+    pub voter_directory_id: [u8; 16],     // v1+
+    pub last_offset_epoch: i32,
+    pub last_offset: i64,
+}
+
+impl VoteRequest {
+    pub fn new<R: Read>(req: &mut R, version: u16) -> errors::Result<Self> {
+        let cluster_id = read_cluster_id(req, true)?;
+        let voter_id = if version >= 1 {
+            parser::read_int(req)?
+        } else {
+            -1
+        };
+        let partitions = read_partitions(req, true, |req, topic| {
+            let partition_index = parser::read_int(req)?;
+            let candidate_epoch = parser::read_int(req)?;
+            let candidate_id = parser::read_int(req)?;
+            let (candidate_directory_id, voter_directory_id) = if version >= 1 {
+                (read_uuid(req)?, read_uuid(req)?)
+            } else {
+                ([0; 16], [0; 16])
+            };
+            Ok(VotePartition {
+                topic,
+                partition_index,
+                candidate_epoch,
+                candidate_id,
+                candidate_directory_id,
+                voter_directory_id,
+                last_offset_epoch: parser::read_int(req)?,
+                last_offset: parser::read_u64(req)? as i64,
+            })
+        })?;
+        parser::skip_tagged_fields(req)?;
+        Ok(Self {
+            version,
+            cluster_id,
+            voter_id,
+            partitions,
+        })
+    }
+
+    pub fn serialize<W: Write>(&self, resp: &mut W) -> errors::Result<()> {
+        write_cluster_id(resp, &self.cluster_id, true)?;
+        if self.version >= 1 {
+            writer::write_bytes(resp, &self.voter_id)?;
+        }
+        write_partitions(
+            resp,
+            &self.partitions,
+            |p| &p.topic,
+            true,
+            |resp, p| {
+                writer::write_bytes(resp, &p.partition_index)?;
+                writer::write_bytes(resp, &p.candidate_epoch)?;
+                writer::write_bytes(resp, &p.candidate_id)?;
+                if self.version >= 1 {
+                    resp.write_all(&p.candidate_directory_id)?;
+                    resp.write_all(&p.voter_directory_id)?;
+                }
+                writer::write_bytes(resp, &p.last_offset_epoch)?;
+                writer::write_bytes(resp, &p.last_offset)
+            },
+        )?;
+        writer::write_tagged_fields(resp, true)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct VoteResponse {
+    pub error_code: i16,
+    pub partitions: Vec<VoteResponsePartition>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct VoteResponsePartition {
+    pub topic: String,
+    pub partition_index: i32,
+    pub error_code: i16,
+    pub leader_id: i32,
+    pub leader_epoch: i32,
+    pub vote_granted: bool,
+}
+
+impl VoteResponse {
+    pub fn read<R: Read>(resp: &mut R) -> errors::Result<Self> {
+        let error_code = parser::read_short(resp)?;
+        let partitions = read_partitions(resp, true, |resp, topic| {
+            Ok(VoteResponsePartition {
+                topic,
+                partition_index: parser::read_int(resp)?,
+                error_code: parser::read_short(resp)?,
+                leader_id: parser::read_int(resp)?,
+                leader_epoch: parser::read_int(resp)?,
+                vote_granted: parser::read_byte(resp)? != 0,
+            })
+        })?;
+        parser::skip_tagged_fields(resp)?;
+        Ok(Self {
+            error_code,
+            partitions,
+        })
+    }
+
+    pub fn serialize<W: Write>(&self, resp: &mut W) -> errors::Result<()> {
+        writer::write_bytes(resp, &self.error_code)?;
+        write_partitions(
+            resp,
+            &self.partitions,
+            |p| &p.topic,
+            true,
+            |resp, p| {
+                writer::write_bytes(resp, &p.partition_index)?;
+                writer::write_bytes(resp, &p.error_code)?;
+                writer::write_bytes(resp, &p.leader_id)?;
+                writer::write_bytes(resp, &p.leader_epoch)?;
+                writer::write_bytes(resp, &p.vote_granted)
+            },
+        )?;
+        writer::write_tagged_fields(resp, true)
+    }
+}
+
+// BeginQuorumEpoch, versions 0 and 1, flexible from 1
+#[derive(Debug, Clone)]
+pub struct BeginQuorumEpochRequest {
+    pub version: u16,
+    pub cluster_id: Option<String>,
+    pub voter_id: i32, // v1+, the voter told
+    pub partitions: Vec<QuorumEpochPartition>,
+    pub leader_endpoints: Vec<Listener>, // v1+
+}
+
+#[derive(Debug, Clone)]
+pub struct QuorumEpochPartition {
+    pub topic: String,
+    pub partition_index: i32,
+    pub voter_directory_id: [u8; 16], // v1+
+    pub leader_id: i32,
+    pub leader_epoch: i32,
+}
+
+impl BeginQuorumEpochRequest {
+    pub fn new<R: Read>(req: &mut R, version: u16, flexible: bool) -> errors::Result<Self> {
+        let cluster_id = read_cluster_id(req, flexible)?;
+        let voter_id = if version >= 1 {
+            parser::read_int(req)?
+        } else {
+            -1
+        };
+        let partitions = read_partitions(req, flexible, |req, topic| {
+            let partition_index = parser::read_int(req)?;
+            let voter_directory_id = if version >= 1 {
+                read_uuid(req)?
+            } else {
+                [0; 16]
+            };
+            Ok(QuorumEpochPartition {
+                topic,
+                partition_index,
+                voter_directory_id,
+                leader_id: parser::read_int(req)?,
+                leader_epoch: parser::read_int(req)?,
+            })
+        })?;
+        let leader_endpoints = if version >= 1 {
+            voters::read_endpoints(req)?
+        } else {
+            vec![]
+        };
+        if flexible {
+            parser::skip_tagged_fields(req)?;
+        }
+        Ok(Self {
+            version,
+            cluster_id,
+            voter_id,
+            partitions,
+            leader_endpoints,
+        })
+    }
+
+    pub fn serialize<W: Write>(&self, resp: &mut W, flexible: bool) -> errors::Result<()> {
+        write_cluster_id(resp, &self.cluster_id, flexible)?;
+        if self.version >= 1 {
+            writer::write_bytes(resp, &self.voter_id)?;
+        }
+        write_partitions(
+            resp,
+            &self.partitions,
+            |p| &p.topic,
+            flexible,
+            |resp, p| {
+                writer::write_bytes(resp, &p.partition_index)?;
+                if self.version >= 1 {
+                    resp.write_all(&p.voter_directory_id)?;
+                }
+                writer::write_bytes(resp, &p.leader_id)?;
+                writer::write_bytes(resp, &p.leader_epoch)
+            },
+        )?;
+        if self.version >= 1 {
+            voters::write_endpoints(resp, &self.leader_endpoints)?;
+        }
+        writer::write_tagged_fields(resp, flexible)
+    }
+}
+
+// EndQuorumEpoch, versions 0 and 1, flexible from 1
+#[derive(Debug, Clone)]
+pub struct EndQuorumEpochRequest {
+    pub version: u16,
+    pub cluster_id: Option<String>,
+    pub partitions: Vec<EndQuorumEpochPartition>,
+    pub leader_endpoints: Vec<Listener>, // v1+
+}
+
+#[derive(Debug, Clone)]
+pub struct EndQuorumEpochPartition {
+    pub topic: String,
+    pub partition_index: i32,
+    pub leader_id: i32,
+    pub leader_epoch: i32,
+    // the voters best placed to take over, most caught up first. Only v1+
+    // has their directory ids.
+    pub preferred_candidates: Vec<ReplicaKey>,
+}
+
+impl EndQuorumEpochRequest {
+    pub fn new<R: Read>(req: &mut R, version: u16, flexible: bool) -> errors::Result<Self> {
+        let cluster_id = read_cluster_id(req, flexible)?;
+        let partitions = read_partitions(req, flexible, |req, topic| {
+            let partition_index = parser::read_int(req)?;
+            let leader_id = parser::read_int(req)?;
+            let leader_epoch = parser::read_int(req)?;
+            let count = parser::read_array_len(req, flexible)?.unwrap_or_default();
+            let mut preferred_candidates = vec![];
+            for _ in 0..count {
+                let id = parser::read_int(req)?;
+                let directory_id = if version >= 1 {
+                    let directory_id = read_uuid(req)?;
+                    parser::skip_tagged_fields(req)?;
+                    directory_id
+                } else {
+                    [0; 16]
+                };
+                preferred_candidates.push(ReplicaKey { id, directory_id });
+            }
+            Ok(EndQuorumEpochPartition {
+                topic,
+                partition_index,
+                leader_id,
+                leader_epoch,
+                preferred_candidates,
+            })
+        })?;
+        let leader_endpoints = if version >= 1 {
+            voters::read_endpoints(req)?
+        } else {
+            vec![]
+        };
+        if flexible {
+            parser::skip_tagged_fields(req)?;
+        }
+        Ok(Self {
+            version,
+            cluster_id,
+            partitions,
+            leader_endpoints,
+        })
+    }
+
+    pub fn serialize<W: Write>(&self, resp: &mut W, flexible: bool) -> errors::Result<()> {
+        write_cluster_id(resp, &self.cluster_id, flexible)?;
+        write_partitions(
+            resp,
+            &self.partitions,
+            |p| &p.topic,
+            flexible,
+            |resp, p| {
+                writer::write_bytes(resp, &p.partition_index)?;
+                writer::write_bytes(resp, &p.leader_id)?;
+                writer::write_bytes(resp, &p.leader_epoch)?;
+                writer::write_array_len(resp, p.preferred_candidates.len(), flexible)?;
+                p.preferred_candidates.iter().try_for_each(|c| {
+                    writer::write_bytes(resp, &c.id)?;
+                    if self.version >= 1 {
+                        resp.write_all(&c.directory_id)?;
+                        writer::write_tagged_fields(resp, flexible)?;
+                    }
+                    Ok(())
+                })
+            },
+        )?;
+        if self.version >= 1 {
+            voters::write_endpoints(resp, &self.leader_endpoints)?;
+        }
+        writer::write_tagged_fields(resp, flexible)
+    }
+}
+
+// the response of both BeginQuorumEpoch and EndQuorumEpoch
+#[derive(Debug, Clone, Default)]
+pub struct QuorumEpochResponse {
+    pub error_code: i16,
+    pub partitions: Vec<QuorumEpochResponsePartition>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct QuorumEpochResponsePartition {
+    pub topic: String,
+    pub partition_index: i32,
+    pub error_code: i16,
+    pub leader_id: i32,
+    pub leader_epoch: i32,
+}
+
+impl QuorumEpochResponse {
+    pub fn read<R: Read>(resp: &mut R, flexible: bool) -> errors::Result<Self> {
+        let error_code = parser::read_short(resp)?;
+        let partitions = read_partitions(resp, flexible, |resp, topic| {
+            Ok(QuorumEpochResponsePartition {
+                topic,
+                partition_index: parser::read_int(resp)?,
+                error_code: parser::read_short(resp)?,
+                leader_id: parser::read_int(resp)?,
+                leader_epoch: parser::read_int(resp)?,
+            })
+        })?;
+        if flexible {
+            parser::skip_tagged_fields(resp)?;
+        }
+        Ok(Self {
+            error_code,
+            partitions,
+        })
+    }
+
+    pub fn serialize<W: Write>(&self, resp: &mut W, flexible: bool) -> errors::Result<()> {
+        writer::write_bytes(resp, &self.error_code)?;
+        write_partitions(
+            resp,
+            &self.partitions,
+            |p| &p.topic,
+            flexible,
+            |resp, p| {
+                writer::write_bytes(resp, &p.partition_index)?;
+                writer::write_bytes(resp, &p.error_code)?;
+                writer::write_bytes(resp, &p.leader_id)?;
+                writer::write_bytes(resp, &p.leader_epoch)
+            },
+        )?;
+        writer::write_tagged_fields(resp, flexible)
+    }
+}
+
+// DescribeQuorum, versions 0 to 2, always flexible
+#[derive(Debug, Clone)]
+pub struct DescribeQuorumRequest {
+    pub version: u16,
+    pub partitions: Vec<(String, i32)>,
+}
+
+impl DescribeQuorumRequest {
+    pub fn new<R: Read>(req: &mut R, version: u16) -> errors::Result<Self> {
+        let partitions =
+            read_partitions(req, true, |req, topic| Ok((topic, parser::read_int(req)?)))?;
+        parser::skip_tagged_fields(req)?;
+        Ok(Self {
+            version,
+            partitions,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct DescribeQuorumResponse {
+    pub version: u16,
+    pub error_code: i16,
+    pub partitions: Vec<DescribeQuorumPartition>,
+    pub nodes: Vec<(i32, Vec<Listener>)>, // v2+, the voters' endpoints
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct DescribeQuorumPartition {
+    pub topic: String,
+    pub partition_index: i32,
+    pub error_code: i16,
+    pub leader_id: i32,
+    pub leader_epoch: i32,
+    pub high_watermark: i64,
+    pub current_voters: Vec<ReplicaState>,
+    pub observers: Vec<ReplicaState>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ReplicaState {
+    pub replica_id: i32,
+    pub replica_directory_id: [u8; 16], // v2+
+    pub log_end_offset: i64,
+    pub last_fetch_timestamp: i64,     // v1+
+    pub last_caught_up_timestamp: i64, // v1+
+}
+
+impl DescribeQuorumResponse {
+    pub fn serialize<W: Write>(&self, resp: &mut W) -> errors::Result<()> {
+        let write_replicas = |resp: &mut W, replicas: &[ReplicaState]| {
+            writer::write_array_len(resp, replicas.len(), true)?;
+            replicas.iter().try_for_each(|r| {
+                writer::write_bytes(resp, &r.replica_id)?;
+                if self.version >= 2 {
+                    resp.write_all(&r.replica_directory_id)?;
+                }
+                writer::write_bytes(resp, &r.log_end_offset)?;
+                if self.version >= 1 {
+                    writer::write_bytes(resp, &r.last_fetch_timestamp)?;
+                    writer::write_bytes(resp, &r.last_caught_up_timestamp)?;
+                }
+                writer::write_tagged_fields(resp, true)
+            })
+        };
+        writer::write_bytes(resp, &self.error_code)?;
+        if self.version >= 2 {
+            writer::write_nullable_string(resp, None, true)?; // error message
+        }
+        write_partitions(
+            resp,
+            &self.partitions,
+            |p| &p.topic,
+            true,
+            |resp, p| {
+                writer::write_bytes(resp, &p.partition_index)?;
+                writer::write_bytes(resp, &p.error_code)?;
+                if self.version >= 2 {
+                    writer::write_nullable_string(resp, None, true)?;
+                }
+                writer::write_bytes(resp, &p.leader_id)?;
+                writer::write_bytes(resp, &p.leader_epoch)?;
+                writer::write_bytes(resp, &p.high_watermark)?;
+                write_replicas(resp, &p.current_voters)?;
+                write_replicas(resp, &p.observers)
+            },
+        )?;
+        if self.version >= 2 {
+            writer::write_array_len(resp, self.nodes.len(), true)?;
+            for (node_id, endpoints) in &self.nodes {
+                writer::write_bytes(resp, node_id)?;
+                voters::write_endpoints(resp, endpoints)?;
+                writer::write_tagged_fields(resp, true)?;
+            }
+        }
+        writer::write_tagged_fields(resp, true)
+    }
+}
+
+// where a follower's log stops matching the leader's: the largest epoch
+// the leader has up to the one the follower last fetched, and its end
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DivergingEpoch {
+    pub epoch: i32,
+    pub end_offset: i64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CurrentLeader {
+    pub leader_id: i32,
+    pub leader_epoch: i32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SnapshotId {
+    pub end_offset: i64,
+    pub epoch: i32,
+}
+
+impl Default for SnapshotId {
+    fn default() -> Self {
+        Self {
+            end_offset: -1,
+            epoch: -1,
+        }
+    }
+}
+
+// the raft specific tagged fields of a fetch partition response
+pub fn fetch_partition_tags(
+    diverging_epoch: Option<DivergingEpoch>,
+    current_leader: Option<CurrentLeader>,
+    snapshot_id: Option<SnapshotId>,
+) -> errors::Result<Vec<(u32, Vec<u8>)>> {
+    let mut fields = vec![];
+    if let Some(d) = diverging_epoch {
+        let mut data = vec![];
+        writer::write_bytes(&mut data, &d.epoch)?;
+        writer::write_bytes(&mut data, &d.end_offset)?;
+        writer::write_tagged_fields(&mut data, true)?;
+        fields.push((TAG_DIVERGING_EPOCH, data));
+    }
+    if let Some(l) = current_leader {
+        let mut data = vec![];
+        writer::write_bytes(&mut data, &l.leader_id)?;
+        writer::write_bytes(&mut data, &l.leader_epoch)?;
+        writer::write_tagged_fields(&mut data, true)?;
+        fields.push((TAG_CURRENT_LEADER, data));
+    }
+    if let Some(s) = snapshot_id {
+        let mut data = vec![];
+        writer::write_bytes(&mut data, &s.end_offset)?;
+        writer::write_bytes(&mut data, &s.epoch)?;
+        writer::write_tagged_fields(&mut data, true)?;
+        fields.push((TAG_SNAPSHOT_ID, data));
+    }
+    Ok(fields)
+}
+
+// the cluster id tagged onto a fetch request, if any
+pub fn fetch_cluster_id(fields: &[(u32, Vec<u8>)]) -> errors::Result<Option<String>> {
+    match fields.iter().find(|(tag, _)| *tag == TAG_CLUSTER_ID) {
+        Some((_, data)) => read_cluster_id(&mut &data[..], true),
+        None => Ok(None),
+    }
+}
+
+// the fetch a follower sends the leader for the metadata partition
+#[derive(Debug, Clone)]
+pub struct MetadataFetchRequest {
+    pub cluster_id: String,
+    pub replica_id: i32,
+    pub replica_directory_id: [u8; 16],
+    pub current_leader_epoch: i32,
+    pub fetch_offset: i64,
+    pub last_fetched_epoch: i32,
+    pub max_bytes: i32,
+}
+
+impl MetadataFetchRequest {
+    pub fn serialize<W: Write>(&self, resp: &mut W) -> errors::Result<()> {
+        writer::write_bytes(resp, &0_i32)?; // max wait, answered right away
+        writer::write_bytes(resp, &0_i32)?; // min bytes
+        writer::write_bytes(resp, &self.max_bytes)?;
+        writer::write_bytes(resp, &0_i8)?; // isolation level
+        writer::write_bytes(resp, &0_i32)?; // no fetch session
+        writer::write_bytes(resp, &-1_i32)?;
+        writer::write_array_len(resp, 1, true)?;
+        writer::write_bytes(resp, &METADATA_TOPIC_ID)?;
+        writer::write_array_len(resp, 1, true)?;
+        writer::write_bytes(resp, &0_i32)?;
+        writer::write_bytes(resp, &self.current_leader_epoch)?;
+        writer::write_bytes(resp, &self.fetch_offset)?;
+        writer::write_bytes(resp, &self.last_fetched_epoch)?;
+        writer::write_bytes(resp, &-1_i64)?; // log start offset
+        writer::write_bytes(resp, &self.max_bytes)?;
+        writer::write_tagged_fields_with(
+            resp,
+            &[(TAG_REPLICA_DIRECTORY_ID, self.replica_directory_id.to_vec())],
+        )?;
+        writer::write_tagged_fields(resp, true)?;
+        writer::write_array_len(resp, 0, true)?; // forgotten topics
+        writer::write_string(resp, b"", true)?; // rack id
+        let mut cluster_id = vec![];
+        write_cluster_id(&mut cluster_id, &Some(self.cluster_id.clone()), true)?;
+        let mut replica_state = vec![];
+        writer::write_bytes(&mut replica_state, &self.replica_id)?;
+        writer::write_bytes(&mut replica_state, &-1_i64)?; // broker epoch
+        writer::write_tagged_fields(&mut replica_state, true)?;
+        writer::write_tagged_fields_with(
+            resp,
+            &[
+                (TAG_CLUSTER_ID, cluster_id),
+                (TAG_REPLICA_STATE, replica_state),
+            ],
+        )
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct MetadataFetchResponse {
+    pub error_code: i16,
+    pub partition_error_code: i16,
+    pub high_watermark: i64,
+    pub log_start_offset: i64,
+    pub records: Vec<u8>,
+    pub diverging_epoch: Option<DivergingEpoch>,
+    pub current_leader: Option<CurrentLeader>,
+    pub snapshot_id: Option<SnapshotId>,
+}
+
+impl MetadataFetchResponse {
+    // reads the response to a MetadataFetchRequest, whose only partition
+    // is the metadata one
+    pub fn read<R: Read>(resp: &mut R) -> errors::Result<Self> {
+        let _throttle_time_ms = parser::read_int(resp)?;
+        let mut response = Self {
+            error_code: parser::read_short(resp)?,
+            ..Default::default()
+        };
+        let _session_id = parser::read_int(resp)?;
+        for _ in 0..parser::read_array_len(resp, true)?.unwrap_or_default() {
+            let _topic_id = parser::read_u128(resp)?;
+            for _ in 0..parser::read_array_len(resp, true)?.unwrap_or_default() {
+                let _partition_index = parser::read_int(resp)?;
+                response.partition_error_code = parser::read_short(resp)?;
+                response.high_watermark = parser::read_u64(resp)? as i64;
+                let _last_stable_offset = parser::read_u64(resp)?;
+                response.log_start_offset = parser::read_u64(resp)? as i64;
+                let aborted = parser::read_array_len(resp, true)?.unwrap_or_default();
+                for _ in 0..aborted {
+                    let _producer_id = parser::read_u64(resp)?;
+                    let _first_offset = parser::read_u64(resp)?;
+                    parser::skip_tagged_fields(resp)?;
+                }
+                let _preferred_read_replica = parser::read_int(resp)?;
+                response.records = parser::read_nullable_string(resp, true)?.unwrap_or_default();
+                for (tag, data) in parser::read_tagged_fields(resp)? {
+                    let data = &mut &data[..];
+                    match tag {
+                        TAG_DIVERGING_EPOCH => {
+                            response.diverging_epoch = Some(DivergingEpoch {
+                                epoch: parser::read_int(data)?,
+                                end_offset: parser::read_u64(data)? as i64,
+                            })
+                        }
+                        TAG_CURRENT_LEADER => {
+                            response.current_leader = Some(CurrentLeader {
+                                leader_id: parser::read_int(data)?,
+                                leader_epoch: parser::read_int(data)?,
+                            })
+                        }
+                        TAG_SNAPSHOT_ID => {
+                            response.snapshot_id = Some(SnapshotId {
+                                end_offset: parser::read_u64(data)? as i64,
+                                epoch: parser::read_int(data)?,
+                            })
+                        }
+                        _ => (),
+                    }
+                }
+            }
+            parser::skip_tagged_fields(resp)?;
+        }
+        parser::skip_tagged_fields(resp)?;
+        Ok(response)
+    }
+}
+
+// FetchSnapshot (59, KIP-630), versions 0 and 1 and always flexible: a
+// replica whose fetch offset is below the leader's log start offset pulls
+// the snapshot the leader pointed it to, a chunk at a time
+#[derive(Debug, Clone)]
+pub struct FetchSnapshotRequest {
+    pub cluster_id: Option<String>, // tagged
+    pub replica_id: i32,
+    pub max_bytes: i32,
+    pub partitions: Vec<FetchSnapshotPartition>,
+}
+
+#[derive(Debug, Clone)]
+pub struct FetchSnapshotPartition {
+    pub topic: String,
+    pub partition: i32,
+    pub current_leader_epoch: i32,
+    pub snapshot_id: SnapshotId,
+    pub position: i64,
+}
+
+fn read_snapshot_id<R: Read>(req: &mut R) -> errors::Result<SnapshotId> {
+    let id = SnapshotId {
+        end_offset: parser::read_u64(req)? as i64,
+        epoch: parser::read_int(req)?,
+    };
+    parser::skip_tagged_fields(req)?;
+    Ok(id)
+}
+
+fn write_snapshot_id<W: Write>(resp: &mut W, id: &SnapshotId) -> errors::Result<()> {
+    writer::write_bytes(resp, &id.end_offset)?;
+    writer::write_bytes(resp, &id.epoch)?;
+    writer::write_tagged_fields(resp, true)
+}
+
+impl FetchSnapshotRequest {
+    pub fn new<R: Read>(req: &mut R) -> errors::Result<Self> {
+        let replica_id = parser::read_int(req)?;
+        let max_bytes = parser::read_int(req)?;
+        let partitions = read_partitions(req, true, |req, topic| {
+            Ok(FetchSnapshotPartition {
+                topic,
+                partition: parser::read_int(req)?,
+                current_leader_epoch: parser::read_int(req)?,
+                snapshot_id: read_snapshot_id(req)?,
+                position: parser::read_u64(req)? as i64,
+            })
+        })?;
+        let cluster_id = fetch_cluster_id(&parser::read_tagged_fields(req)?)?;
+        Ok(Self {
+            cluster_id,
+            replica_id,
+            max_bytes,
+            partitions,
+        })
+    }
+
+    pub fn serialize<W: Write>(&self, resp: &mut W) -> errors::Result<()> {
+        writer::write_bytes(resp, &self.replica_id)?;
+        writer::write_bytes(resp, &self.max_bytes)?;
+        write_partitions(
+            resp,
+            &self.partitions,
+            |p| &p.topic,
+            true,
+            |resp, p| {
+                writer::write_bytes(resp, &p.partition)?;
+                writer::write_bytes(resp, &p.current_leader_epoch)?;
+                write_snapshot_id(resp, &p.snapshot_id)?;
+                writer::write_bytes(resp, &p.position)
+            },
+        )?;
+        let mut fields = vec![];
+        if self.cluster_id.is_some() {
+            let mut cluster_id = vec![];
+            write_cluster_id(&mut cluster_id, &self.cluster_id, true)?;
+            fields.push((TAG_CLUSTER_ID, cluster_id));
+        }
+        writer::write_tagged_fields_with(resp, &fields)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct FetchSnapshotResponse {
+    pub error_code: i16,
+    pub partitions: Vec<FetchSnapshotResponsePartition>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct FetchSnapshotResponsePartition {
+    pub topic: String,
+    pub index: i32,
+    pub error_code: i16,
+    pub snapshot_id: SnapshotId,
+    pub size: i64, // of the whole snapshot
+    pub position: i64,
+    pub unaligned_records: Vec<u8>,
+}
+
+impl FetchSnapshotResponse {
+    pub fn read<R: Read>(resp: &mut R) -> errors::Result<Self> {
+        let _throttle_time_ms = parser::read_int(resp)?;
+        let error_code = parser::read_short(resp)?;
+        let partitions = read_partitions(resp, true, |resp, topic| {
+            Ok(FetchSnapshotResponsePartition {
+                topic,
+                index: parser::read_int(resp)?,
+                error_code: parser::read_short(resp)?,
+                snapshot_id: read_snapshot_id(resp)?,
+                size: parser::read_u64(resp)? as i64,
+                position: parser::read_u64(resp)? as i64,
+                unaligned_records: parser::read_nullable_string(resp, true)?.unwrap_or_default(),
+            })
+        })?;
+        parser::skip_tagged_fields(resp)?;
+        Ok(Self {
+            error_code,
+            partitions,
+        })
+    }
+
+    pub fn serialize<W: Write>(&self, resp: &mut W) -> errors::Result<()> {
+        writer::write_bytes(resp, &0_i32)?; // throttle time
+        writer::write_bytes(resp, &self.error_code)?;
+        write_partitions(
+            resp,
+            &self.partitions,
+            |p| &p.topic,
+            true,
+            |resp, p| {
+                writer::write_bytes(resp, &p.index)?;
+                writer::write_bytes(resp, &p.error_code)?;
+                write_snapshot_id(resp, &p.snapshot_id)?;
+                writer::write_bytes(resp, &p.size)?;
+                writer::write_bytes(resp, &p.position)?;
+                writer::write_string(resp, &p.unaligned_records, true)
+            },
+        )?;
+        writer::write_tagged_fields(resp, true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vote_request(version: u16) -> VoteRequest {
+        VoteRequest {
+            version,
+            cluster_id: Some("test".to_string()),
+            voter_id: if version >= 1 { 2 } else { -1 },
+            partitions: vec![VotePartition {
+                topic: "__cluster_metadata".to_string(),
+                partition_index: 0,
+                candidate_epoch: 5,
+                candidate_id: 1,
+                candidate_directory_id: if version >= 1 { [1; 16] } else { [0; 16] },
+                voter_directory_id: if version >= 1 { [2; 16] } else { [0; 16] },
+                last_offset_epoch: 4,
+                last_offset: 100,
+            }],
+        }
+    }
+
+    #[test]
+    fn test_vote_request_versions() {
+        for version in [0, 1] {
+            let request = vote_request(version);
+            let mut body = vec![];
+            request.serialize(&mut body).unwrap();
+            let read = VoteRequest::new(&mut &body[..], version).unwrap();
+            assert_eq!(format!("{read:?}"), format!("{request:?}"));
+        }
+        // the voter id and two directory ids
+        let size = |version| {
+            let mut body = vec![];
+            vote_request(version).serialize(&mut body).unwrap();
+            body.len()
+        };
+        assert_eq!(size(1) - size(0), 4 + 2 * 16);
+    }
+
+    #[test]
+    fn test_quorum_epoch_request_versions() {
+        for (version, flexible) in [(0, false), (1, true)] {
+            let v1 = version >= 1;
+            let leader_endpoints = if v1 {
+                vec![Listener::parse("CONTROLLER://host1:19091").unwrap()]
+            } else {
+                vec![]
+            };
+            let begin = BeginQuorumEpochRequest {
+                version,
+                cluster_id: Some("test".to_string()),
+                voter_id: if v1 { 2 } else { -1 },
+                partitions: vec![QuorumEpochPartition {
+                    topic: "__cluster_metadata".to_string(),
+                    partition_index: 0,
+                    voter_directory_id: if v1 { [2; 16] } else { [0; 16] },
+                    leader_id: 1,
+                    leader_epoch: 5,
+                }],
+                leader_endpoints: leader_endpoints.clone(),
+            };
+            let mut body = vec![];
+            begin.serialize(&mut body, flexible).unwrap();
+            let read = BeginQuorumEpochRequest::new(&mut &body[..], version, flexible).unwrap();
+            assert_eq!(format!("{read:?}"), format!("{begin:?}"));
+
+            let end = EndQuorumEpochRequest {
+                version,
+                cluster_id: None,
+                partitions: vec![EndQuorumEpochPartition {
+                    topic: "__cluster_metadata".to_string(),
+                    partition_index: 0,
+                    leader_id: 1,
+                    leader_epoch: 5,
+                    preferred_candidates: [3, 2]
+                        .into_iter()
+                        .map(|id| ReplicaKey {
+                            id,
+                            directory_id: if v1 { [id as u8; 16] } else { [0; 16] },
+                        })
+                        .collect(),
+                }],
+                leader_endpoints,
+            };
+            let mut body = vec![];
+            end.serialize(&mut body, flexible).unwrap();
+            let read = EndQuorumEpochRequest::new(&mut &body[..], version, flexible).unwrap();
+            assert_eq!(format!("{read:?}"), format!("{end:?}"));
+        }
+    }
+}
@@ -0,0 +1,2525 @@
+// raft for the __cluster_metadata partition (KIP-595), among the nodes
+// listed in controller.quorum.voters. A candidate asks the other voters
+// for their Vote in a new epoch and leads once a majority granted it; it
+// then tells everyone with BeginQuorumEpoch and starts the epoch with a
+// LeaderChange control batch. Followers pull the log with Fetch, which is
+// also how the leader learns how far each of them got: the high watermark
+// is the offset a majority has reached, and nothing past it is applied to
+// the metadata image. A follower whose log went further than the leader's
+// in an older epoch is told where they diverge and truncates.
+//
+// Epoch, leader and vote survive restarts in the quorum-state file next to
+// the log, so a node never votes twice in an epoch.
+//
+// The voters either come from controller.quorum.voters or, in a dynamic
+// quorum, from the latest VotersRecord of the log (see voters.rs). A node
+// that isn't a voter is an observer: it never stands for election, it
+// finds the leader through controller.quorum.bootstrap.servers and
+// follows the log like a follower does. That's how a new controller
+// catches up before AddRaftVoter makes it a voter.
+use crate::kafka::client::NodeClient;
+use crate::kafka::config::{BrokerConfig, Listener, QuorumVoter};
+use crate::kafka::metadata_records::KafkaRecordValue;
+use crate::kafka::quorum::{
+    self, BeginQuorumEpochRequest, CurrentLeader, DescribeQuorumPartition, DescribeQuorumRequest,
+    DescribeQuorumResponse, DivergingEpoch, EndQuorumEpochPartition, EndQuorumEpochRequest,
+    FetchSnapshotPartition, FetchSnapshotRequest, FetchSnapshotResponse,
+    FetchSnapshotResponsePartition, MetadataFetchRequest, MetadataFetchResponse,
+    QuorumEpochPartition, QuorumEpochResponse, QuorumEpochResponsePartition, ReplicaKey,
+    ReplicaState, VotePartition, VoteRequest, VoteResponse, VoteResponsePartition,
+};
+use crate::kafka::records::{BatchHeader, KafkaRecord, RawBatch, RecordsBatch};
+use crate::kafka::voters::{
+    self, AddRaftVoterRequest, RaftVoterResponse, RemoveRaftVoterRequest, UpdateRaftVoterRequest,
+    UpdateRaftVoterResponse, Voter, KRAFT_VERSION_DYNAMIC, KRAFT_VERSION_STATIC,
+};
+use crate::kafka::zerocopy::FileRegion;
+use crate::kafka::{apikey, config, controller, errors, log, snapshot, storage, writer};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicI32, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+
+pub const QUORUM_STATE_FILE: &str = "quorum-state";
+
+pub const OFFSET_OUT_OF_RANGE: i16 = 1;
+pub const UNKNOWN_TOPIC_OR_PARTITION: i16 = 3;
+pub const NOT_LEADER_OR_FOLLOWER: i16 = 6;
+pub const REQUEST_TIMED_OUT: i16 = 7;
+pub const UNSUPPORTED_VERSION: i16 = 35;
+pub const NOT_CONTROLLER: i16 = 41;
+pub const INVALID_REQUEST: i16 = 42;
+pub const FENCED_LEADER_EPOCH: i16 = 74;
+pub const UNKNOWN_LEADER_EPOCH: i16 = 75;
+pub const INCONSISTENT_VOTER_SET: i16 = 94;
+pub const SNAPSHOT_NOT_FOUND: i16 = 98;
+pub const POSITION_OUT_OF_RANGE: i16 = 99;
+pub const INCONSISTENT_CLUSTER_ID: i16 = 104;
+pub const INVALID_VOTER_KEY: i16 = 125;
+pub const DUPLICATE_VOTER: i16 = 126;
+pub const VOTER_NOT_FOUND: i16 = 127;
+
+// control record type of the batch starting an epoch
+const CONTROL_TYPE_LEADER_CHANGE: i16 = 2;
+const LEADER_CHANGE_VERSION: i16 = 0;
+
+// how much a follower asks for per fetch
+const FETCH_MAX_BYTES: i32 = 1 << 20;
+// how often the driver looks at timers when there's nothing to fetch
+const DRIVER_TICK_MS: u64 = 50;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Role {
+    // no leader known for the epoch and no vote cast in it
+    Unattached,
+    Voted(i32),
+    Candidate,
+    Follower(i32),
+    Leader,
+}
+
+// what the leader knows of another replica, from its fetches
+#[derive(Debug, Clone, Copy, Default)]
+struct Replica {
+    // of its metadata log dir, zero if it fetches with a version before 17
+    directory_id: [u8; 16],
+    end_offset: u64,
+    last_fetch_ms: i64,
+    last_caught_up_ms: i64,
+}
+
+#[derive(Debug)]
+struct RaftState {
+    epoch: i32,
+    role: Role,
+    // votes a candidate got, itself included
+    granted: BTreeSet<i32>,
+    log: log::PartitionLog,
+    // (epoch, offset of its first batch) for every epoch in the log
+    epochs: Vec<(i32, u64)>,
+    // (offset, voters) for every VotersRecord in the log, the last one in
+    // effect. Before the first, the voters the node started with.
+    voter_sets: Vec<(u64, Vec<Voter>)>,
+    bootstrap_voters: Vec<Voter>,
+    // segment.bytes for the metadata log
+    log_config: config::TopicConfig,
+    // end offset of the log when it was last cleaned
+    cleaned_at: u64,
+    // leader only: where this epoch's LeaderChange batch went, and the
+    // replicas that fetched in it, observers included
+    epoch_start_offset: u64,
+    replicas: BTreeMap<i32, Replica>,
+    // when the role's timer runs out: the election timeout of a voter
+    // without a leader, the fetch timeout of a follower, the next round of
+    // BeginQuorumEpoch for a leader
+    deadline: Instant,
+}
+
+impl RaftState {
+    fn leader_id(&self, node_id: i32) -> Option<i32> {
+        match self.role {
+            Role::Leader => Some(node_id),
+            Role::Follower(leader) => Some(leader),
+            _ => None,
+        }
+    }
+
+    fn voted_id(&self, node_id: i32) -> Option<i32> {
+        match self.role {
+            Role::Voted(id) => Some(id),
+            Role::Candidate => Some(node_id),
+            _ => None,
+        }
+    }
+
+    fn end_offset(&self) -> u64 {
+        self.log.next_offset()
+    }
+
+    fn voters(&self) -> &[Voter] {
+        self.voter_sets
+            .last()
+            .map_or(&self.bootstrap_voters, |(_, voters)| voters)
+    }
+
+    fn voter(&self, id: i32) -> Option<&Voter> {
+        self.voters().iter().find(|v| v.id == id)
+    }
+
+    fn is_voter(&self, id: i32) -> bool {
+        self.voter(id).is_some()
+    }
+
+    // a voter set appended and not committed yet
+    fn voters_pending(&self, high_watermark: u64) -> bool {
+        self.voter_sets
+            .last()
+            .is_some_and(|(offset, _)| *offset >= high_watermark)
+    }
+
+    fn last_epoch(&self) -> i32 {
+        self.epochs.last().map_or(0, |(epoch, _)| *epoch)
+    }
+
+    // the largest epoch of the log up to epoch and the offset it ends at,
+    // (-1, 0) if the log has nothing that old
+    fn end_offset_for_epoch(&self, epoch: i32) -> (i32, u64) {
+        let Some(i) = self.epochs.iter().rposition(|(e, _)| *e <= epoch) else {
+            return (-1, 0);
+        };
+        let end = self
+            .epochs
+            .get(i + 1)
+            .map_or(self.end_offset(), |(_, start)| *start);
+        (self.epochs[i].0, end)
+    }
+
+    // appends batches that already carry their epoch, fsynced before
+    // anyone is told about them
+    fn append(&mut self, payload: &[u8]) -> errors::Result<()> {
+        let mut offset = self.end_offset();
+        for batch in RawBatch::split(payload)? {
+            index_batch(&mut self.epochs, &mut self.voter_sets, &batch, offset)?;
+            offset += batch.next_offset() - batch.base_offset();
+        }
+        self.log.append(payload, &self.log_config)?;
+        self.log.flush()
+    }
+
+    fn truncate_to(&mut self, offset: u64) -> errors::Result<()> {
+        self.log.truncate_to(offset)?;
+        self.epochs.retain(|(_, start)| *start < offset);
+        self.voter_sets.retain(|(at, _)| *at < offset);
+        Ok(())
+    }
+
+    // drops what a follower has past the point its log and the leader's
+    // part, the offset the log now ends at
+    fn truncate_to_diverging(&mut self, diverging: DivergingEpoch) -> errors::Result<u64> {
+        let (_, end) = self.end_offset_for_epoch(diverging.epoch);
+        let offset = end.min(diverging.end_offset.max(0) as u64);
+        self.truncate_to(offset)?;
+        Ok(offset)
+    }
+
+    // the epoch of a snapshot the log starts at or after, so a fetch that
+    // resumes right where the snapshot ends isn't taken as diverging
+    fn index_snapshot(&mut self, id: snapshot::SnapshotId) {
+        if self
+            .epochs
+            .first()
+            .map_or(true, |(epoch, _)| *epoch > id.epoch)
+        {
+            let start = id.end_offset.min(self.log.log_start_offset);
+            self.epochs.insert(0, (id.epoch, start));
+        }
+    }
+
+    // swaps the whole log for a snapshot fetched from the leader: the log
+    // starts over, empty, where the snapshot ends
+    fn install_snapshot(
+        &mut self,
+        id: snapshot::SnapshotId,
+        voters: Option<Vec<Voter>>,
+    ) -> errors::Result<()> {
+        self.log.truncate_fully_and_start_at(id.end_offset)?;
+        self.epochs.clear();
+        self.index_snapshot(id);
+        if let Some(voters) = voters {
+            self.voter_sets = vec![(id.end_offset.saturating_sub(1), voters)];
+        }
+        Ok(())
+    }
+
+    // drops the oldest segments while the log is bigger than retention_bytes,
+    // as long as the latest snapshot covers them. Only once the log grew
+    // since the last time, so the snapshot dir isn't read on every tick.
+    fn clean_log(&mut self, retention_bytes: u64) -> errors::Result<()> {
+        if self.end_offset() == self.cleaned_at {
+            return Ok(());
+        }
+        self.cleaned_at = self.end_offset();
+        let size = |log: &log::PartitionLog| log.segments.values().map(|s| s.size).sum::<u64>();
+        if size(&self.log) <= retention_bytes {
+            return Ok(());
+        }
+        let Some(snapshot) = snapshot::latest(&self.log.dir)? else {
+            return Ok(());
+        };
+        while size(&self.log) > retention_bytes {
+            // a segment ends where the next one starts
+            let mut bases = self.log.segments.keys();
+            let (Some(&oldest), Some(&next)) = (bases.next(), bases.next()) else {
+                break;
+            };
+            if next > snapshot.end_offset {
+                break;
+            }
+            self.log.delete_segment(oldest)?;
+            println!(
+                "raft: dropped metadata log segment {oldest}, snapshot {} covers it",
+                snapshot.file_name()
+            );
+        }
+        Ok(())
+    }
+}
+
+// what the leader answers a fetch of the metadata partition with
+#[derive(Debug, Clone)]
+pub struct MetadataFetch {
+    pub error_code: i16,
+    pub high_watermark: i64,
+    pub log_start_offset: i64,
+    pub records: Option<FileRegion>,
+    pub diverging_epoch: Option<DivergingEpoch>,
+    pub current_leader: CurrentLeader,
+    // set when the fetch offset is older than the log
+    pub snapshot_id: Option<quorum::SnapshotId>,
+}
+
+pub struct RaftClient {
+    node_id: i32,
+    // of the metadata log dir, what tells this voter from an earlier one
+    // with the same id
+    directory_id: [u8; 16],
+    cluster_id: String,
+    kraft_version: i16,
+    // the controller listener: its name, and where it's reached
+    listener: String,
+    endpoints: Vec<Listener>,
+    bootstrap_servers: Vec<(String, u16)>,
+    election_timeout_ms: u64,
+    election_backoff_max_ms: u64,
+    fetch_timeout_ms: u64,
+    retention_bytes: u64,
+    state_path: PathBuf,
+    state: Mutex<RaftState>,
+    // signalled when the high watermark moves or the role changes
+    changed: Condvar,
+    high_watermark: Arc<AtomicU64>,
+    // the follower's connection to its leader, by address
+    fetch_client: Mutex<Option<(QuorumVoter, NodeClient)>>,
+    // the bootstrap server an observer tries next
+    next_bootstrap: AtomicUsize,
+    // the epoch this voter last sent its endpoints in
+    endpoints_sent: AtomicI32,
+}
+
+impl RaftClient {
+    pub fn open(config: &BrokerConfig, cluster_id: String) -> errors::Result<Self> {
+        let controller_listener = config.controller_listener().cloned().unwrap_or(Listener {
+            name: "CONTROLLER".to_string(),
+            host: String::new(),
+            port: 9093,
+        });
+        let listener = controller_listener.name.clone();
+        let directory_id = storage::MetaProperties::read(Path::new(config.metadata_log_dir()))?
+            .and_then(|p| p.directory_id)
+            .unwrap_or_default();
+        let log = log::PartitionLog::open(
+            Path::new(config.metadata_log_dir()),
+            log::METADATA_TOPIC,
+            0,
+            0,
+        )?;
+        let mut state = RaftState {
+            epoch: 0,
+            role: Role::Unattached,
+            granted: BTreeSet::new(),
+            log,
+            epochs: vec![],
+            voter_sets: vec![],
+            bootstrap_voters: vec![],
+            log_config: config::TopicConfig {
+                segment_bytes: config.metadata_log_segment_bytes,
+                ..Default::default()
+            },
+            cleaned_at: 0,
+            epoch_start_offset: 0,
+            replicas: BTreeMap::new(),
+            deadline: Instant::now(),
+        };
+        for segment in state.log.segments.values() {
+            for batch in segment.batches_from(segment.base_offset)? {
+                let batch = batch?;
+                let offset = batch.base_offset();
+                // only control batches can hold a voter set worth reading
+                if batch.is_control() {
+                    let batch = batch.load()?;
+                    index_batch(&mut state.epochs, &mut state.voter_sets, &batch, offset)?;
+                } else {
+                    index_epoch(&mut state.epochs, batch.partition_leader_epoch(), offset);
+                }
+            }
+        }
+        // the voter set of a dynamic quorum as of the latest snapshot: the
+        // one format leaves, or one that replaced the log holding it
+        let mut snapshot_voters = None;
+        if let Some(id) = snapshot::latest(&state.log.dir)? {
+            for (control_type, value) in
+                snapshot::control_records(&state.log.dir.join(id.file_name()))?
+            {
+                if control_type == voters::CONTROL_TYPE_VOTERS {
+                    snapshot_voters = Some((id, voters::read_voters_record(&value)?));
+                }
+            }
+            if id.end_offset > 0 {
+                state.index_snapshot(id);
+            }
+        }
+        let kraft_version = if !config.controller_quorum_voters.is_empty() {
+            state.voter_sets.clear();
+            state.bootstrap_voters = config
+                .controller_quorum_voters
+                .iter()
+                .map(|v| Voter::from_static(v, &listener))
+                .collect();
+            KRAFT_VERSION_STATIC
+        } else if let Some((id, voters)) = snapshot_voters {
+            if id.end_offset == 0 {
+                state.bootstrap_voters = voters;
+            } else {
+                state.voter_sets.insert(0, (id.end_offset - 1, voters));
+            }
+            KRAFT_VERSION_DYNAMIC
+        } else if !state.voter_sets.is_empty()
+            || !config.controller_quorum_bootstrap_servers.is_empty()
+        {
+            // joining, or joined, a dynamic quorum
+            KRAFT_VERSION_DYNAMIC
+        } else {
+            // a quorum of this node alone
+            state.bootstrap_voters = vec![Voter::from_static(
+                &QuorumVoter {
+                    id: config.node_id,
+                    host: controller_listener.advertised_host().to_string(),
+                    port: controller_listener.port,
+                },
+                &listener,
+            )];
+            KRAFT_VERSION_STATIC
+        };
+        if kraft_version == KRAFT_VERSION_STATIC && !state.is_voter(config.node_id) {
+            return Err(errors::KafkaErrors::InvalidWriterArg(format!(
+                "node {} isn't one of the controller.quorum.voters",
+                config.node_id
+            ))
+            .into());
+        }
+        let state_path = state.log.dir.join(QUORUM_STATE_FILE);
+        let (epoch, leader_id, voted_id) = read_quorum_state(&state_path)?;
+        state.epoch = epoch;
+        state.role = match (leader_id, voted_id) {
+            // a leader that restarted has to win a new election, and it
+            // voted for itself in this epoch
+            (Some(leader), _) if leader == config.node_id => Role::Candidate,
+            (Some(leader), _) => Role::Follower(leader),
+            (_, Some(voted)) if voted == config.node_id => Role::Candidate,
+            (_, Some(voted)) => Role::Voted(voted),
+            _ => Role::Unattached,
+        };
+        let role = state.role;
+        println!(
+            "raft: node {} starts in epoch {epoch} as {role:?}, kraft.version {kraft_version}, voters {:?}",
+            config.node_id,
+            state.voters().iter().map(|v| v.id).collect::<Vec<_>>()
+        );
+        let raft = Self {
+            node_id: config.node_id,
+            directory_id,
+            cluster_id,
+            kraft_version,
+            listener,
+            endpoints: vec![Listener {
+                host: controller_listener.advertised_host().to_string(),
+                ..controller_listener
+            }],
+            bootstrap_servers: config.controller_quorum_bootstrap_servers.clone(),
+            election_timeout_ms: config.controller_quorum_election_timeout_ms,
+            election_backoff_max_ms: config.controller_quorum_election_backoff_max_ms,
+            fetch_timeout_ms: config.controller_quorum_fetch_timeout_ms,
+            retention_bytes: config.metadata_max_retention_bytes,
+            state_path,
+            state: Mutex::new(state),
+            changed: Condvar::new(),
+            high_watermark: Arc::new(AtomicU64::new(0)),
+            fetch_client: Mutex::new(None),
+            next_bootstrap: AtomicUsize::new(0),
+            endpoints_sent: AtomicI32::new(-1),
+        };
+        {
+            let mut state = raft.state.lock().unwrap();
+            state.deadline = match role {
+                Role::Follower(_) => raft.fetch_deadline(),
+                _ if !state.is_voter(raft.node_id) => Instant::now(),
+                _ => raft.election_deadline()?,
+            };
+            // alone there's nobody to wait for
+            if state.voters().len() == 1 && state.is_voter(raft.node_id) {
+                raft.start_election(&mut state)?;
+            }
+        }
+        Ok(raft)
+    }
+
+    // runs elections, follower fetches and leader heartbeats
+    pub fn start(raft: Arc<Self>) -> thread::JoinHandle<()> {
+        thread::spawn(move || loop {
+            match raft.tick() {
+                Ok(true) => (),
+                Ok(false) => thread::sleep(Duration::from_millis(DRIVER_TICK_MS)),
+                Err(e) => {
+                    println!("raft: {e}");
+                    thread::sleep(Duration::from_millis(DRIVER_TICK_MS));
+                }
+            }
+        })
+    }
+
+    pub fn node_id(&self) -> i32 {
+        self.node_id
+    }
+
+    // the voters in effect and where they're reached
+    pub fn voters(&self) -> Vec<QuorumVoter> {
+        self.state
+            .lock()
+            .unwrap()
+            .voters()
+            .iter()
+            .filter_map(|v| v.address(&self.listener))
+            .collect()
+    }
+
+    pub fn leader_id(&self) -> Option<i32> {
+        self.state.lock().unwrap().leader_id(self.node_id)
+    }
+
+    // the offset up to which (exclusive) the log is committed
+    pub fn high_watermark(&self) -> Arc<AtomicU64> {
+        Arc::clone(&self.high_watermark)
+    }
+
+    // waits, up to the fetch timeout, until this node leads an epoch whose
+    // start is committed, i.e. until everything earlier leaders wrote is
+    // committed too and can be applied before new records get checked
+    pub fn wait_for_leadership(&self) -> errors::Result<()> {
+        self.ready_leader().map(|_| ())
+    }
+
+    fn ready_leader(&self) -> errors::Result<MutexGuard<'_, RaftState>> {
+        let deadline = Instant::now() + Duration::from_millis(self.fetch_timeout_ms);
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if state.role != Role::Leader {
+                return Err(self.not_controller(&state));
+            }
+            if self.high_watermark.load(Ordering::SeqCst) > state.epoch_start_offset {
+                return Ok(state);
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(request_failed(
+                    REQUEST_TIMED_OUT,
+                    format!("epoch {} isn't committed yet", state.epoch),
+                ));
+            }
+            state = self.changed.wait_timeout(state, deadline - now).unwrap().0;
+        }
+    }
+
+    // appends records as one batch of the current epoch and waits until a
+    // majority has it. records gets the offset the batch starts at. The
+    // last offset written, None if there was nothing to write.
+    pub fn append(
+        &self,
+        records: impl FnOnce(u64) -> errors::Result<Vec<KafkaRecordValue>>,
+    ) -> errors::Result<Option<u64>> {
+        let mut state = self.ready_leader()?;
+        let epoch = state.epoch;
+        let base_offset = state.end_offset();
+        let values = records(base_offset)?;
+        if values.is_empty() {
+            return Ok(None);
+        }
+        let now = log::now_ms();
+        let batch = RecordsBatch {
+            partition_leader_epoch: epoch,
+            last_offset_delta: values.len() as i32 - 1,
+            base_timestamp: now,
+            max_timestamp: now,
+            records: values
+                .iter()
+                .enumerate()
+                .map(|(i, value)| {
+                    Ok(KafkaRecord {
+                        offset_delta: i as i32,
+                        value: Some(value.to_bytes()?),
+                        ..KafkaRecord::new()
+                    })
+                })
+                .collect::<errors::Result<_>>()?,
+            ..RecordsBatch::new()
+        };
+        state.append(&batch.to_bytes()?)?;
+        self.update_high_watermark(&mut state);
+        let last = base_offset + values.len() as u64 - 1;
+        self.wait_committed(state, epoch, last)?;
+        Ok(Some(last))
+    }
+
+    // waits until the leader of epoch committed offset
+    fn wait_committed(
+        &self,
+        mut state: MutexGuard<'_, RaftState>,
+        epoch: i32,
+        offset: u64,
+    ) -> errors::Result<()> {
+        let deadline = Instant::now() + Duration::from_millis(self.fetch_timeout_ms);
+        while self.high_watermark.load(Ordering::SeqCst) <= offset {
+            if state.role != Role::Leader || state.epoch != epoch {
+                return Err(request_failed(
+                    NOT_CONTROLLER,
+                    format!(
+                        "lost the leadership of epoch {epoch} before offset {offset} committed"
+                    ),
+                ));
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(request_failed(
+                    REQUEST_TIMED_OUT,
+                    format!("offset {offset} wasn't replicated to a majority in time"),
+                ));
+            }
+            state = self.changed.wait_timeout(state, deadline - now).unwrap().0;
+        }
+        Ok(())
+    }
+
+    // one round of whatever the role calls for, true if there's more to do
+    // right away
+    fn tick(&self) -> errors::Result<bool> {
+        let mut state = self.state.lock().unwrap();
+        state.clean_log(self.retention_bytes)?;
+        let expired = Instant::now() >= state.deadline;
+        match state.role {
+            Role::Leader => {
+                if !expired {
+                    return Ok(false);
+                }
+                // voters that didn't fetch in this epoch yet may not know
+                let epoch = state.epoch;
+                let pending = state
+                    .voters()
+                    .iter()
+                    .filter(|v| v.id != self.node_id && !state.replicas.contains_key(&v.id))
+                    .filter_map(|v| Some((v.address(&self.listener)?, v.directory_id)))
+                    .collect::<Vec<_>>();
+                state.deadline =
+                    Instant::now() + Duration::from_millis(self.election_timeout_ms / 2);
+                let outdated = self.outdated_endpoints(&state);
+                drop(state);
+                for (voter, directory_id) in pending {
+                    if let Err(e) = self.begin_quorum_epoch(&voter, directory_id, epoch) {
+                        println!("raft: BeginQuorumEpoch to {} failed: {e}", voter.id);
+                    }
+                }
+                if let Some(voter) = outdated {
+                    if let Err(e) = self.update_voter(voter) {
+                        println!("raft: unable to update its own endpoints: {e}");
+                    }
+                }
+                Ok(false)
+            }
+            Role::Follower(leader) => {
+                if expired {
+                    println!(
+                        "raft: nothing from leader {leader} in {}ms",
+                        self.fetch_timeout_ms
+                    );
+                    if state.is_voter(self.node_id) {
+                        self.start_election(&mut state)?;
+                    } else {
+                        self.lose_leader(&mut state)?;
+                    }
+                    return Ok(true);
+                }
+                let outdated = self.outdated_endpoints(&state);
+                drop(state);
+                if let Some(voter) = outdated {
+                    if let Err(e) = self.send_endpoints(leader, voter) {
+                        println!("raft: UpdateRaftVoter to {leader} failed: {e}");
+                    }
+                }
+                self.fetch_from(leader)
+            }
+            _ if !state.is_voter(self.node_id) => {
+                if !expired {
+                    return Ok(false);
+                }
+                state.deadline = Instant::now() + Duration::from_millis(self.election_timeout_ms);
+                drop(state);
+                self.find_leader()
+            }
+            Role::Unattached | Role::Voted(_) | Role::Candidate => {
+                if !expired {
+                    return Ok(false);
+                }
+                self.start_election(&mut state)?;
+                let epoch = state.epoch;
+                let request = VoteRequest {
+                    version: quorum::VOTE_VERSION,
+                    cluster_id: Some(self.cluster_id.clone()),
+                    voter_id: -1,
+                    partitions: vec![VotePartition {
+                        topic: log::METADATA_TOPIC.to_string(),
+                        partition_index: 0,
+                        candidate_epoch: epoch,
+                        candidate_id: self.node_id,
+                        candidate_directory_id: self.directory_id,
+                        voter_directory_id: [0; 16],
+                        last_offset_epoch: state.last_epoch(),
+                        last_offset: state.end_offset() as i64,
+                    }],
+                };
+                let voters = state
+                    .voters()
+                    .iter()
+                    .filter(|v| v.id != self.node_id)
+                    .filter_map(|v| Some((v.address(&self.listener)?, v.directory_id)))
+                    .collect::<Vec<_>>();
+                drop(state);
+                self.request_votes(&voters, &request, epoch);
+                Ok(false)
+            }
+        }
+    }
+
+    // a new epoch voting for itself, leader right away if that's a majority
+    fn start_election(&self, state: &mut RaftState) -> errors::Result<()> {
+        state.epoch += 1;
+        state.role = Role::Candidate;
+        state.granted = BTreeSet::from([self.node_id]);
+        state.deadline = self.election_deadline()?;
+        self.persist(state)?;
+        println!(
+            "raft: node {} is a candidate in epoch {}",
+            self.node_id, state.epoch
+        );
+        self.changed.notify_all();
+        if is_majority(state, &state.granted) {
+            self.become_leader(state)?;
+        }
+        Ok(())
+    }
+
+    // an observer whose leader went quiet looks for the next one
+    fn lose_leader(&self, state: &mut RaftState) -> errors::Result<()> {
+        state.role = Role::Unattached;
+        state.deadline = Instant::now();
+        self.persist(state)?;
+        *self.fetch_client.lock().unwrap() = None;
+        self.changed.notify_all();
+        Ok(())
+    }
+
+    // asks every voter, each named in its request by id and directory id
+    fn request_votes(&self, voters: &[(QuorumVoter, [u8; 16])], request: &VoteRequest, epoch: i32) {
+        thread::scope(|scope| {
+            for (voter, directory_id) in voters {
+                let mut request = request.clone();
+                request.voter_id = voter.id;
+                for p in &mut request.partitions {
+                    p.voter_directory_id = *directory_id;
+                }
+                let mut body = vec![];
+                if let Err(e) = request.serialize(&mut body) {
+                    println!("raft: unable to encode Vote: {e}");
+                    continue;
+                }
+                scope.spawn(move || {
+                    let response = self
+                        .send(voter, apikey::ApiKey::Vote, request.version, &body)
+                        .and_then(|mut r| VoteResponse::read(&mut r));
+                    match response {
+                        Ok(response) => {
+                            if let Err(e) = self.vote_received(voter.id, epoch, &response) {
+                                println!("raft: vote from {} not handled: {e}", voter.id);
+                            }
+                        }
+                        Err(e) => println!("raft: Vote to {} failed: {e}", voter.id),
+                    }
+                });
+            }
+        });
+    }
+
+    fn vote_received(&self, voter: i32, epoch: i32, response: &VoteResponse) -> errors::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if response.error_code != 0 {
+            println!(
+                "raft: Vote from {voter} failed with {}",
+                response.error_code
+            );
+            return Ok(());
+        }
+        for p in &response.partitions {
+            if p.leader_epoch > state.epoch {
+                self.observe_epoch(&mut state, p.leader_epoch, leader(p.leader_id))?;
+            } else if p.vote_granted && state.role == Role::Candidate && state.epoch == epoch {
+                state.granted.insert(voter);
+                if is_majority(&state, &state.granted) {
+                    self.become_leader(&mut state)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn become_leader(&self, state: &mut RaftState) -> errors::Result<()> {
+        state.role = Role::Leader;
+        state.replicas.clear();
+        state.epoch_start_offset = state.end_offset();
+        self.persist(state)?;
+        println!(
+            "raft: node {} leads epoch {} from offset {}",
+            self.node_id, state.epoch, state.epoch_start_offset
+        );
+        // the epoch starts with a record of who leads it and who elected it
+        let mut value = vec![];
+        writer::write_bytes(&mut value, &LEADER_CHANGE_VERSION)?;
+        writer::write_bytes(&mut value, &self.node_id)?;
+        for ids in [
+            state.voters().iter().map(|v| v.id).collect::<Vec<_>>(),
+            state.granted.iter().copied().collect(),
+        ] {
+            writer::write_array_len(&mut value, ids.len(), true)?;
+            for id in ids {
+                writer::write_bytes(&mut value, &id)?;
+                writer::write_tagged_fields(&mut value, true)?;
+            }
+        }
+        writer::write_tagged_fields(&mut value, true)?;
+        let mut batch = snapshot::control_batch(
+            state.epoch,
+            log::now_ms(),
+            CONTROL_TYPE_LEADER_CHANGE,
+            value,
+        )?;
+        batch.base_offset = state.epoch_start_offset;
+        state.append(&batch.to_bytes()?)?;
+        // the first leader of a dynamic quorum puts the voters it was
+        // formatted with in the log, where later changes go too
+        if self.kraft_version == KRAFT_VERSION_DYNAMIC && state.voter_sets.is_empty() {
+            let now = log::now_ms();
+            for (control_type, value) in [
+                (
+                    voters::CONTROL_TYPE_KRAFT_VERSION,
+                    voters::kraft_version_record(KRAFT_VERSION_DYNAMIC)?,
+                ),
+                (
+                    voters::CONTROL_TYPE_VOTERS,
+                    voters::voters_record(&state.bootstrap_voters)?,
+                ),
+            ] {
+                let mut batch = snapshot::control_batch(state.epoch, now, control_type, value)?;
+                batch.base_offset = state.end_offset();
+                state.append(&batch.to_bytes()?)?;
+            }
+        }
+        state.deadline = Instant::now();
+        self.update_high_watermark(state);
+        self.changed.notify_all();
+        Ok(())
+    }
+
+    // moves to a newer epoch, or learns the leader of the current one
+    fn observe_epoch(
+        &self,
+        state: &mut RaftState,
+        epoch: i32,
+        leader_id: Option<i32>,
+    ) -> errors::Result<()> {
+        let new_epoch = epoch > state.epoch;
+        let new_leader = epoch == state.epoch
+            && leader_id.is_some()
+            && !matches!(state.role, Role::Leader | Role::Follower(_));
+        if !new_epoch && !new_leader {
+            return Ok(());
+        }
+        state.epoch = epoch;
+        state.granted.clear();
+        match leader_id {
+            Some(leader) => {
+                state.role = Role::Follower(leader);
+                state.deadline = self.fetch_deadline();
+            }
+            None => {
+                state.role = Role::Unattached;
+                state.deadline = self.election_deadline()?;
+            }
+        }
+        self.persist(state)?;
+        println!(
+            "raft: node {} is {:?} in epoch {epoch}",
+            self.node_id, state.role
+        );
+        self.changed.notify_all();
+        Ok(())
+    }
+
+    // the fetch loop of a follower, true when it got records
+    fn fetch_from(&self, leader_id: i32) -> errors::Result<bool> {
+        let (request, address) = {
+            let state = self.state.lock().unwrap();
+            // an observer that doesn't know the voters yet tries the
+            // bootstrap servers until it hits the leader
+            let address = match state.voter(leader_id) {
+                Some(voter) => voter.address(&self.listener),
+                None => self.bootstrap_server(),
+            };
+            let Some(address) = address else {
+                return Err(request_failed(
+                    INCONSISTENT_VOTER_SET,
+                    format!("no address for leader {leader_id}"),
+                ));
+            };
+            (self.fetch_request(&state), address)
+        };
+        let response = match self.fetch(&address, &request) {
+            Ok(response) => response,
+            Err(e) => {
+                *self.fetch_client.lock().unwrap() = None;
+                return Err(e);
+            }
+        };
+        let mut state = self.state.lock().unwrap();
+        if state.epoch != request.current_leader_epoch || state.role != Role::Follower(leader_id) {
+            return Ok(false);
+        }
+        if response.error_code != 0 {
+            return Err(request_failed(
+                response.error_code,
+                format!("fetch from leader {leader_id} failed"),
+            ));
+        }
+        match response.partition_error_code {
+            0 => (),
+            FENCED_LEADER_EPOCH | UNKNOWN_LEADER_EPOCH | NOT_LEADER_OR_FOLLOWER => {
+                if let Some(current) = response.current_leader {
+                    self.observe_epoch(
+                        &mut state,
+                        current.leader_epoch,
+                        leader(current.leader_id),
+                    )?;
+                }
+                if state.role == Role::Follower(leader_id) {
+                    // not the leader after all, on to the next one
+                    self.next_bootstrap.fetch_add(1, Ordering::SeqCst);
+                    *self.fetch_client.lock().unwrap() = None;
+                }
+                return Ok(false);
+            }
+            code => {
+                return Err(request_failed(
+                    code,
+                    format!("fetch from leader {leader_id} failed"),
+                ))
+            }
+        }
+        state.deadline = self.fetch_deadline();
+        if let Some(diverging) = response.diverging_epoch {
+            let offset = state.truncate_to_diverging(diverging)?;
+            println!(
+                "raft: log diverges from leader {leader_id} in epoch {}, truncated to {offset}",
+                diverging.epoch
+            );
+            return Ok(true);
+        }
+        if let Some(snapshot_id) = response.snapshot_id {
+            println!(
+                "raft: leader {leader_id} only has a snapshot up to {}",
+                snapshot_id.end_offset
+            );
+            let epoch = state.epoch;
+            drop(state);
+            return self.fetch_snapshot(leader_id, &address, epoch, snapshot_id);
+        }
+        let fetched = !response.records.is_empty();
+        if fetched {
+            state.append(&response.records)?;
+        }
+        let high_watermark = (response.high_watermark.max(0) as u64).min(state.end_offset());
+        if high_watermark > self.high_watermark.load(Ordering::SeqCst) {
+            self.high_watermark.store(high_watermark, Ordering::SeqCst);
+            self.changed.notify_all();
+        }
+        Ok(fetched)
+    }
+
+    // downloads the snapshot the leader pointed to and puts it in place of
+    // the log, the metadata listener picks the image up from it
+    fn fetch_snapshot(
+        &self,
+        leader_id: i32,
+        address: &QuorumVoter,
+        epoch: i32,
+        snapshot_id: quorum::SnapshotId,
+    ) -> errors::Result<bool> {
+        let id = snapshot::SnapshotId {
+            end_offset: snapshot_id.end_offset.max(0) as u64,
+            epoch: snapshot_id.epoch,
+        };
+        let dir = self.state.lock().unwrap().log.dir.clone();
+        let mut download = snapshot::SnapshotDownload::create(&dir, id)?;
+        loop {
+            let request = FetchSnapshotRequest {
+                cluster_id: Some(self.cluster_id.clone()),
+                replica_id: self.node_id,
+                max_bytes: FETCH_MAX_BYTES,
+                partitions: vec![FetchSnapshotPartition {
+                    topic: log::METADATA_TOPIC.to_string(),
+                    partition: 0,
+                    current_leader_epoch: epoch,
+                    snapshot_id,
+                    position: download.position as i64,
+                }],
+            };
+            let mut body = vec![];
+            request.serialize(&mut body)?;
+            let mut response = self.send(address, apikey::ApiKey::FetchSnapshot, 0, &body)?;
+            let response = FetchSnapshotResponse::read(&mut response)?;
+            let Some(p) = response.partitions.first() else {
+                return Err(request_failed(
+                    response.error_code,
+                    format!("no snapshot {} from leader {leader_id}", id.file_name()),
+                ));
+            };
+            if response.error_code != 0 || p.error_code != 0 {
+                return Err(request_failed(
+                    if response.error_code != 0 {
+                        response.error_code
+                    } else {
+                        p.error_code
+                    },
+                    format!(
+                        "fetching snapshot {} from leader {leader_id}",
+                        id.file_name()
+                    ),
+                ));
+            }
+            if p.position != download.position as i64 || p.unaligned_records.is_empty() {
+                return Err(request_failed(
+                    POSITION_OUT_OF_RANGE,
+                    format!(
+                        "leader {leader_id} sent position {} of snapshot {} instead of {}",
+                        p.position,
+                        id.file_name(),
+                        download.position
+                    ),
+                ));
+            }
+            download.append(&p.unaligned_records)?;
+            if download.position >= p.size.max(0) as u64 {
+                break;
+            }
+        }
+        download.finish()?;
+        let voters = snapshot::control_records(&dir.join(id.file_name()))?
+            .into_iter()
+            .filter(|(control_type, _)| *control_type == voters::CONTROL_TYPE_VOTERS)
+            .map(|(_, value)| voters::read_voters_record(&value))
+            .next_back()
+            .transpose()?;
+        let mut state = self.state.lock().unwrap();
+        if state.epoch != epoch || state.role != Role::Follower(leader_id) {
+            return Ok(false);
+        }
+        state.install_snapshot(id, voters)?;
+        snapshot::delete_before(&dir, id)?;
+        if id.end_offset > self.high_watermark.load(Ordering::SeqCst) {
+            self.high_watermark.store(id.end_offset, Ordering::SeqCst);
+            self.changed.notify_all();
+        }
+        println!(
+            "raft: installed snapshot {} from leader {leader_id}",
+            id.file_name()
+        );
+        Ok(true)
+    }
+
+    fn fetch_request(&self, state: &RaftState) -> MetadataFetchRequest {
+        MetadataFetchRequest {
+            cluster_id: self.cluster_id.clone(),
+            replica_id: self.node_id,
+            replica_directory_id: self.directory_id,
+            current_leader_epoch: state.epoch,
+            fetch_offset: state.end_offset() as i64,
+            last_fetched_epoch: state.last_epoch(),
+            max_bytes: FETCH_MAX_BYTES,
+        }
+    }
+
+    fn fetch(
+        &self,
+        address: &QuorumVoter,
+        request: &MetadataFetchRequest,
+    ) -> errors::Result<MetadataFetchResponse> {
+        let mut body = vec![];
+        request.serialize(&mut body)?;
+        let mut client = self.fetch_client.lock().unwrap();
+        if client.as_ref().map(|(a, _)| a) != Some(address) {
+            *client = Some((
+                address.clone(),
+                NodeClient::connect(&address.host, address.port, self.request_timeout())?,
+            ));
+        }
+        let (_, connection) = client.as_mut().unwrap();
+        let mut response =
+            connection.send(apikey::ApiKey::Fetch, quorum::METADATA_FETCH_VERSION, &body)?;
+        MetadataFetchResponse::read(&mut response)
+    }
+
+    // an observer without a leader asks around, the bootstrap servers and
+    // the voters it knows of, who leads. True once it found out.
+    fn find_leader(&self) -> errors::Result<bool> {
+        let (request, mut candidates) = {
+            let state = self.state.lock().unwrap();
+            let voters = state
+                .voters()
+                .iter()
+                .filter(|v| v.id != self.node_id)
+                .filter_map(|v| v.address(&self.listener))
+                .collect::<Vec<_>>();
+            (self.fetch_request(&state), voters)
+        };
+        candidates.extend(
+            self.bootstrap_servers
+                .iter()
+                .map(|(host, port)| QuorumVoter {
+                    id: -1,
+                    host: host.clone(),
+                    port: *port,
+                }),
+        );
+        for candidate in candidates {
+            let mut body = vec![];
+            request.serialize(&mut body)?;
+            let response = self
+                .send(
+                    &candidate,
+                    apikey::ApiKey::Fetch,
+                    quorum::METADATA_FETCH_VERSION,
+                    &body,
+                )
+                .and_then(|mut r| MetadataFetchResponse::read(&mut r));
+            let current = match response {
+                Ok(response) => response.current_leader,
+                Err(e) => {
+                    println!(
+                        "raft: {}:{} doesn't answer: {e}",
+                        candidate.host, candidate.port
+                    );
+                    continue;
+                }
+            };
+            if let Some(current) = current.filter(|c| c.leader_id >= 0) {
+                let mut state = self.state.lock().unwrap();
+                if current.leader_epoch >= state.epoch {
+                    self.observe_epoch(&mut state, current.leader_epoch, Some(current.leader_id))?;
+                }
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    // where an observer that doesn't know the leader's address fetches
+    fn bootstrap_server(&self) -> Option<QuorumVoter> {
+        if self.bootstrap_servers.is_empty() {
+            return None;
+        }
+        let i = self.next_bootstrap.load(Ordering::SeqCst) % self.bootstrap_servers.len();
+        let (host, port) = &self.bootstrap_servers[i];
+        Some(QuorumVoter {
+            id: -1,
+            host: host.clone(),
+            port: *port,
+        })
+    }
+
+    fn begin_quorum_epoch(
+        &self,
+        voter: &QuorumVoter,
+        directory_id: [u8; 16],
+        epoch: i32,
+    ) -> errors::Result<()> {
+        let request = BeginQuorumEpochRequest {
+            version: quorum::QUORUM_EPOCH_VERSION,
+            cluster_id: Some(self.cluster_id.clone()),
+            voter_id: voter.id,
+            partitions: vec![QuorumEpochPartition {
+                topic: log::METADATA_TOPIC.to_string(),
+                partition_index: 0,
+                voter_directory_id: directory_id,
+                leader_id: self.node_id,
+                leader_epoch: epoch,
+            }],
+            leader_endpoints: self.endpoints.clone(),
+        };
+        let mut body = vec![];
+        request.serialize(&mut body, true)?;
+        let mut response = self.send(
+            voter,
+            apikey::ApiKey::BeginQuorumEpoch,
+            request.version,
+            &body,
+        )?;
+        let response = QuorumEpochResponse::read(&mut response, true)?;
+        let mut state = self.state.lock().unwrap();
+        for p in &response.partitions {
+            self.observe_epoch(&mut state, p.leader_epoch, leader(p.leader_id))?;
+        }
+        Ok(())
+    }
+
+    // a leader stepping down, e.g. on shutdown: the other voters are asked
+    // to elect someone else, the most caught up first
+    pub fn resign(&self) {
+        let (epoch, successors, voters) = {
+            let state = self.state.lock().unwrap();
+            if state.role != Role::Leader {
+                return;
+            }
+            let mut successors = state
+                .voters()
+                .iter()
+                .filter_map(|v| Some((v, state.replicas.get(&v.id)?)))
+                .filter(|(v, r)| v.id != self.node_id && v.is(v.id, &r.directory_id))
+                .map(|(v, r)| {
+                    let key = ReplicaKey {
+                        id: v.id,
+                        directory_id: v.directory_id,
+                    };
+                    (key, r.end_offset)
+                })
+                .collect::<Vec<_>>();
+            successors.sort_by_key(|s| std::cmp::Reverse(s.1));
+            let voters = state
+                .voters()
+                .iter()
+                .filter(|v| v.id != self.node_id)
+                .filter_map(|v| v.address(&self.listener))
+                .collect::<Vec<_>>();
+            (
+                state.epoch,
+                successors
+                    .into_iter()
+                    .map(|(key, _)| key)
+                    .collect::<Vec<_>>(),
+                voters,
+            )
+        };
+        let request = EndQuorumEpochRequest {
+            version: quorum::QUORUM_EPOCH_VERSION,
+            cluster_id: Some(self.cluster_id.clone()),
+            partitions: vec![EndQuorumEpochPartition {
+                topic: log::METADATA_TOPIC.to_string(),
+                partition_index: 0,
+                leader_id: self.node_id,
+                leader_epoch: epoch,
+                preferred_candidates: successors,
+            }],
+            leader_endpoints: self.endpoints.clone(),
+        };
+        let mut body = vec![];
+        if request.serialize(&mut body, true).is_err() {
+            return;
+        }
+        for voter in &voters {
+            match self.send(
+                voter,
+                apikey::ApiKey::EndQuorumEpoch,
+                request.version,
+                &body,
+            ) {
+                Ok(_) => println!("raft: told {} that epoch {epoch} ends", voter.id),
+                Err(e) => println!("raft: EndQuorumEpoch to {} failed: {e}", voter.id),
+            }
+        }
+    }
+
+    pub fn handle_vote(&self, request: &VoteRequest) -> errors::Result<VoteResponse> {
+        if request
+            .cluster_id
+            .as_ref()
+            .is_some_and(|c| *c != self.cluster_id)
+        {
+            return Ok(VoteResponse {
+                error_code: INCONSISTENT_CLUSTER_ID,
+                partitions: vec![],
+            });
+        }
+        let mut partitions = vec![];
+        for p in &request.partitions {
+            let mut state = self.state.lock().unwrap();
+            let mut error_code = 0;
+            let mut vote_granted = false;
+            // a voter the candidate knows by another dir isn't this one
+            let candidate_known = state
+                .voter(p.candidate_id)
+                .map_or(true, |v| v.is(p.candidate_id, &p.candidate_directory_id));
+            if !is_metadata_partition(&p.topic, p.partition_index) {
+                error_code = UNKNOWN_TOPIC_OR_PARTITION;
+            } else if !self.is_addressee(request.voter_id, &p.voter_directory_id) {
+                error_code = INVALID_VOTER_KEY;
+            } else if self.kraft_version == KRAFT_VERSION_STATIC && !state.is_voter(p.candidate_id)
+            {
+                // a dynamic voter set may have a candidate this node didn't
+                // hear of yet
+                error_code = INCONSISTENT_VOTER_SET;
+            } else if p.candidate_epoch >= state.epoch {
+                self.observe_epoch(&mut state, p.candidate_epoch, None)?;
+                // only as up to date a log gets the vote
+                let log_ok = (p.last_offset_epoch, p.last_offset)
+                    >= (state.last_epoch(), state.end_offset() as i64);
+                vote_granted = log_ok
+                    && candidate_known
+                    && state.is_voter(self.node_id)
+                    && match state.role {
+                        Role::Unattached => true,
+                        Role::Voted(id) => id == p.candidate_id,
+                        _ => false,
+                    };
+                if vote_granted && state.role == Role::Unattached {
+                    state.role = Role::Voted(p.candidate_id);
+                    state.deadline = self.election_deadline()?;
+                    self.persist(&state)?;
+                    println!(
+                        "raft: node {} votes for {} in epoch {}",
+                        self.node_id, p.candidate_id, state.epoch
+                    );
+                }
+            }
+            partitions.push(VoteResponsePartition {
+                topic: p.topic.clone(),
+                partition_index: p.partition_index,
+                error_code,
+                leader_id: state.leader_id(self.node_id).unwrap_or(-1),
+                leader_epoch: state.epoch,
+                vote_granted,
+            });
+        }
+        Ok(VoteResponse {
+            error_code: 0,
+            partitions,
+        })
+    }
+
+    pub fn handle_begin_quorum_epoch(
+        &self,
+        request: &BeginQuorumEpochRequest,
+    ) -> errors::Result<QuorumEpochResponse> {
+        self.handle_epoch_change(
+            &request.cluster_id,
+            request.partitions.iter().map(|p| {
+                (
+                    &p.topic,
+                    p.partition_index,
+                    self.is_addressee(request.voter_id, &p.voter_directory_id),
+                    p.leader_id,
+                    p.leader_epoch,
+                    None,
+                )
+            }),
+        )
+    }
+
+    pub fn handle_end_quorum_epoch(
+        &self,
+        request: &EndQuorumEpochRequest,
+    ) -> errors::Result<QuorumEpochResponse> {
+        self.handle_epoch_change(
+            &request.cluster_id,
+            request.partitions.iter().map(|p| {
+                (
+                    &p.topic,
+                    p.partition_index,
+                    true,
+                    p.leader_id,
+                    p.leader_epoch,
+                    Some(p.preferred_candidates.iter().map(|c| c.id).collect()),
+                )
+            }),
+        )
+    }
+
+    // a leader starting its epoch or, with successors, ending it. Each
+    // partition says if the request was meant for this replica.
+    fn handle_epoch_change<'a>(
+        &self,
+        cluster_id: &Option<String>,
+        partitions: impl Iterator<Item = (&'a String, i32, bool, i32, i32, Option<Vec<i32>>)>,
+    ) -> errors::Result<QuorumEpochResponse> {
+        if cluster_id.as_ref().is_some_and(|c| *c != self.cluster_id) {
+            return Ok(QuorumEpochResponse {
+                error_code: INCONSISTENT_CLUSTER_ID,
+                partitions: vec![],
+            });
+        }
+        let mut responses = vec![];
+        for (topic, partition_index, addressed, leader_id, leader_epoch, successors) in partitions {
+            let mut state = self.state.lock().unwrap();
+            let error_code = if !is_metadata_partition(topic, partition_index) {
+                UNKNOWN_TOPIC_OR_PARTITION
+            } else if !addressed {
+                INVALID_VOTER_KEY
+            } else if leader_epoch < state.epoch {
+                FENCED_LEADER_EPOCH
+            } else {
+                match successors {
+                    None => self.observe_epoch(&mut state, leader_epoch, Some(leader_id))?,
+                    Some(successors) => {
+                        self.observe_epoch(&mut state, leader_epoch, None)?;
+                        if state.role == Role::Follower(leader_id) {
+                            state.role = Role::Unattached;
+                            self.persist(&state)?;
+                        }
+                        if state.role != Role::Leader {
+                            // the preferred successors stand first, the rest wait
+                            let rank = successors
+                                .iter()
+                                .position(|id| *id == self.node_id)
+                                .unwrap_or(successors.len())
+                                as u64;
+                            state.deadline = Instant::now()
+                                + Duration::from_millis(rank * self.election_backoff_max_ms);
+                        }
+                    }
+                }
+                0
+            };
+            responses.push(QuorumEpochResponsePartition {
+                topic: topic.clone(),
+                partition_index,
+                error_code,
+                leader_id: state.leader_id(self.node_id).unwrap_or(-1),
+                leader_epoch: state.epoch,
+            });
+        }
+        Ok(QuorumEpochResponse {
+            error_code: 0,
+            partitions: responses,
+        })
+    }
+
+    // a fetch of the metadata partition, by a follower or an observer
+    pub fn handle_fetch(
+        &self,
+        cluster_id: Option<&str>,
+        replica: ReplicaKey,
+        current_leader_epoch: i32,
+        fetch_offset: u64,
+        last_fetched_epoch: i32,
+        max_bytes: u64,
+    ) -> MetadataFetch {
+        let mut state = self.state.lock().unwrap();
+        let mut fetch = MetadataFetch {
+            error_code: 0,
+            high_watermark: self.high_watermark.load(Ordering::SeqCst) as i64,
+            log_start_offset: state.log.log_start_offset as i64,
+            records: None,
+            diverging_epoch: None,
+            current_leader: CurrentLeader {
+                leader_id: state.leader_id(self.node_id).unwrap_or(-1),
+                leader_epoch: state.epoch,
+            },
+            snapshot_id: None,
+        };
+        fetch.error_code = self.leader_error(&state, cluster_id, current_leader_epoch);
+        if fetch.error_code != 0 {
+            return fetch;
+        }
+        // too far behind for the log, the snapshot comes first
+        if fetch_offset < state.log.log_start_offset {
+            match snapshot::latest(&state.log.dir) {
+                Ok(Some(id)) if id.end_offset >= state.log.log_start_offset => {
+                    fetch.snapshot_id = Some(quorum::SnapshotId {
+                        end_offset: id.end_offset as i64,
+                        epoch: id.epoch,
+                    });
+                }
+                _ => fetch.error_code = OFFSET_OUT_OF_RANGE,
+            }
+            return fetch;
+        }
+        if fetch_offset > 0 {
+            let (epoch, end) = state.end_offset_for_epoch(last_fetched_epoch);
+            if epoch != last_fetched_epoch || fetch_offset > end {
+                fetch.diverging_epoch = Some(DivergingEpoch {
+                    epoch,
+                    end_offset: end as i64,
+                });
+                return fetch;
+            }
+        }
+        match state.log.read(fetch_offset, max_bytes) {
+            Ok(records) => fetch.records = records,
+            Err(e) => {
+                println!(
+                    "raft: fetch by {} at {fetch_offset} failed: {e}",
+                    replica.id
+                );
+                fetch.error_code = -1;
+                return fetch;
+            }
+        }
+        if replica.id >= 0 {
+            let now = log::now_ms();
+            let end_offset = state.end_offset();
+            let directory_id = replica.directory_id;
+            let replica = state.replicas.entry(replica.id).or_default();
+            replica.directory_id = directory_id;
+            replica.end_offset = fetch_offset;
+            replica.last_fetch_ms = now;
+            if fetch_offset >= end_offset {
+                replica.last_caught_up_ms = now;
+            }
+            self.update_high_watermark(&mut state);
+            fetch.high_watermark = self.high_watermark.load(Ordering::SeqCst) as i64;
+            // a voter to be may be waiting for this one to catch up
+            self.changed.notify_all();
+        }
+        fetch
+    }
+
+    // why this node can't answer a request for the leader of epoch
+    fn leader_error(&self, state: &RaftState, cluster_id: Option<&str>, epoch: i32) -> i16 {
+        if cluster_id.is_some_and(|c| c != self.cluster_id) {
+            INCONSISTENT_CLUSTER_ID
+        } else if epoch < state.epoch {
+            FENCED_LEADER_EPOCH
+        } else if epoch > state.epoch {
+            UNKNOWN_LEADER_EPOCH
+        } else if state.role != Role::Leader {
+            NOT_LEADER_OR_FOLLOWER
+        } else {
+            0
+        }
+    }
+
+    // FetchSnapshot: a chunk of one of the leader's snapshot files, from
+    // where the replica got to
+    pub fn handle_fetch_snapshot(&self, request: &FetchSnapshotRequest) -> FetchSnapshotResponse {
+        let state = self.state.lock().unwrap();
+        let max_bytes = (request.max_bytes.max(0) as u64).min(FETCH_MAX_BYTES as u64);
+        let partitions = request
+            .partitions
+            .iter()
+            .map(|p| {
+                let mut response = FetchSnapshotResponsePartition {
+                    topic: p.topic.clone(),
+                    index: p.partition,
+                    snapshot_id: p.snapshot_id,
+                    ..Default::default()
+                };
+                response.error_code = if !is_metadata_partition(&p.topic, p.partition) {
+                    UNKNOWN_TOPIC_OR_PARTITION
+                } else {
+                    self.leader_error(
+                        &state,
+                        request.cluster_id.as_deref(),
+                        p.current_leader_epoch,
+                    )
+                };
+                if response.error_code != 0 {
+                    return response;
+                }
+                let id = snapshot::SnapshotId {
+                    end_offset: p.snapshot_id.end_offset.max(0) as u64,
+                    epoch: p.snapshot_id.epoch,
+                };
+                if p.snapshot_id.end_offset < 0 || !state.log.dir.join(id.file_name()).exists() {
+                    response.error_code = SNAPSHOT_NOT_FOUND;
+                    return response;
+                }
+                match snapshot::read_chunk(&state.log.dir, id, p.position.max(0) as u64, max_bytes)
+                {
+                    Ok((size, _)) if p.position < 0 || p.position as u64 >= size => {
+                        response.error_code = POSITION_OUT_OF_RANGE;
+                    }
+                    Ok((size, chunk)) => {
+                        response.size = size as i64;
+                        response.position = p.position;
+                        response.unaligned_records = chunk;
+                    }
+                    Err(e) => {
+                        println!(
+                            "raft: reading snapshot {} for {} failed: {e}",
+                            id.file_name(),
+                            request.replica_id
+                        );
+                        response.error_code = -1;
+                    }
+                }
+                response
+            })
+            .collect();
+        FetchSnapshotResponse {
+            error_code: 0,
+            partitions,
+        }
+    }
+
+    pub fn handle_describe_quorum(
+        &self,
+        request: &DescribeQuorumRequest,
+    ) -> DescribeQuorumResponse {
+        let state = self.state.lock().unwrap();
+        let now = log::now_ms();
+        let partitions = request
+            .partitions
+            .iter()
+            .map(|(topic, partition_index)| {
+                let mut p = DescribeQuorumPartition {
+                    topic: topic.clone(),
+                    partition_index: *partition_index,
+                    leader_id: state.leader_id(self.node_id).unwrap_or(-1),
+                    leader_epoch: state.epoch,
+                    high_watermark: self.high_watermark.load(Ordering::SeqCst) as i64,
+                    ..Default::default()
+                };
+                if !is_metadata_partition(topic, *partition_index) {
+                    p.error_code = UNKNOWN_TOPIC_OR_PARTITION;
+                } else if state.role != Role::Leader {
+                    p.error_code = NOT_LEADER_OR_FOLLOWER;
+                } else {
+                    let replica_state =
+                        |id: i32, directory_id: [u8; 16]| match state.replicas.get(&id) {
+                            _ if id == self.node_id => ReplicaState {
+                                replica_id: id,
+                                replica_directory_id: self.directory_id,
+                                log_end_offset: state.end_offset() as i64,
+                                last_fetch_timestamp: now,
+                                last_caught_up_timestamp: now,
+                            },
+                            Some(r) => ReplicaState {
+                                replica_id: id,
+                                replica_directory_id: directory_id,
+                                log_end_offset: r.end_offset as i64,
+                                last_fetch_timestamp: r.last_fetch_ms,
+                                last_caught_up_timestamp: r.last_caught_up_ms,
+                            },
+                            None => ReplicaState {
+                                replica_id: id,
+                                replica_directory_id: directory_id,
+                                log_end_offset: -1,
+                                last_fetch_timestamp: -1,
+                                last_caught_up_timestamp: -1,
+                            },
+                        };
+                    p.current_voters = state
+                        .voters()
+                        .iter()
+                        .map(|v| replica_state(v.id, v.directory_id))
+                        .collect();
+                    p.observers = state
+                        .replicas
+                        .iter()
+                        .filter(|(id, _)| !state.is_voter(**id))
+                        .map(|(id, r)| replica_state(*id, r.directory_id))
+                        .collect();
+                }
+                p
+            })
+            .collect();
+        DescribeQuorumResponse {
+            version: request.version,
+            error_code: 0,
+            partitions,
+            nodes: state
+                .voters()
+                .iter()
+                .map(|v| (v.id, v.endpoints.clone()))
+                .collect(),
+        }
+    }
+
+    pub fn handle_add_voter(&self, request: &AddRaftVoterRequest) -> RaftVoterResponse {
+        RaftVoterResponse::new(self.check_cluster_id(&request.cluster_id).and_then(|_| {
+            let voter = Voter {
+                id: request.voter_id,
+                directory_id: request.voter_directory_id,
+                endpoints: request.listeners.clone(),
+                min_kraft_version: KRAFT_VERSION_STATIC,
+                max_kraft_version: KRAFT_VERSION_DYNAMIC,
+            };
+            let timeout = Duration::from_millis(request.timeout_ms.max(0) as u64);
+            self.add_voter(voter, timeout, request.ack_when_committed)
+        }))
+    }
+
+    pub fn handle_remove_voter(&self, request: &RemoveRaftVoterRequest) -> RaftVoterResponse {
+        RaftVoterResponse::new(
+            self.check_cluster_id(&request.cluster_id)
+                .and_then(|_| self.remove_voter(request.voter_id, request.voter_directory_id)),
+        )
+    }
+
+    pub fn handle_update_voter(&self, request: &UpdateRaftVoterRequest) -> UpdateRaftVoterResponse {
+        let result = self
+            .check_cluster_id(&request.cluster_id)
+            .and_then(|_| self.update_voter(request.voter()));
+        if let Err(e) = &result {
+            println!("raft: voter {} not updated: {e}", request.voter_id);
+        }
+        UpdateRaftVoterResponse {
+            error_code: result.err().map_or(0, |e| controller::error_code(&e)),
+            current_leader: Some(self.current_leader()),
+            ..Default::default()
+        }
+    }
+
+    // whether a request for voter_id and its directory id is meant for this
+    // replica, v0 ones naming neither
+    fn is_addressee(&self, voter_id: i32, directory_id: &[u8; 16]) -> bool {
+        (voter_id < 0 || voter_id == self.node_id)
+            && (*directory_id == [0; 16] || *directory_id == self.directory_id)
+    }
+
+    fn check_cluster_id(&self, cluster_id: &Option<String>) -> errors::Result<()> {
+        match cluster_id {
+            Some(c) if *c != self.cluster_id => Err(request_failed(
+                INCONSISTENT_CLUSTER_ID,
+                format!("cluster {c} isn't {}", self.cluster_id),
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    // AddRaftVoter: the new voter has to be fetching already, as an
+    // observer, and to have caught up with what was committed when the
+    // request came in before the voter set with it is appended
+    pub fn add_voter(
+        &self,
+        voter: Voter,
+        timeout: Duration,
+        ack_when_committed: bool,
+    ) -> errors::Result<()> {
+        let deadline = Instant::now() + timeout;
+        let mut state = self.state.lock().unwrap();
+        self.reconfigurable(&state)?;
+        if state.is_voter(voter.id) {
+            return Err(request_failed(
+                DUPLICATE_VOTER,
+                format!("{} is a voter already", voter.id),
+            ));
+        }
+        let epoch = state.epoch;
+        let caught_up = self.high_watermark.load(Ordering::SeqCst);
+        // fetching from the directory it's to be added with
+        while state
+            .replicas
+            .get(&voter.id)
+            .filter(|r| r.directory_id == voter.directory_id)
+            .map_or(true, |r| r.end_offset < caught_up)
+        {
+            if state.role != Role::Leader || state.epoch != epoch {
+                return Err(self.not_leader(&state));
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(request_failed(
+                    REQUEST_TIMED_OUT,
+                    format!("{} didn't catch up to offset {caught_up} in time", voter.id),
+                ));
+            }
+            state = self.changed.wait_timeout(state, deadline - now).unwrap().0;
+        }
+        // something else may have changed the voters meanwhile
+        self.reconfigurable(&state)?;
+        let mut voters = state.voters().to_vec();
+        voters.push(voter);
+        self.append_voters(state, voters, ack_when_committed)
+    }
+
+    pub fn remove_voter(&self, id: i32, directory_id: [u8; 16]) -> errors::Result<()> {
+        let state = self.state.lock().unwrap();
+        self.reconfigurable(&state)?;
+        if !state.voters().iter().any(|v| v.is(id, &directory_id)) {
+            return Err(request_failed(
+                VOTER_NOT_FOUND,
+                format!("{id} isn't a voter"),
+            ));
+        }
+        let voters = state
+            .voters()
+            .iter()
+            .filter(|v| !v.is(id, &directory_id))
+            .cloned()
+            .collect::<Vec<_>>();
+        if voters.is_empty() {
+            return Err(request_failed(
+                INVALID_REQUEST,
+                format!("{id} is the last voter"),
+            ));
+        }
+        self.append_voters(state, voters, true)?;
+        // a leader that removed itself hands over once that's committed
+        if id == self.node_id {
+            self.step_down()?;
+        }
+        Ok(())
+    }
+
+    // UpdateRaftVoter: new endpoints or kraft.version range of a voter
+    pub fn update_voter(&self, voter: Voter) -> errors::Result<()> {
+        let state = self.state.lock().unwrap();
+        self.reconfigurable(&state)?;
+        let Some(i) = state
+            .voters()
+            .iter()
+            .position(|v| v.is(voter.id, &voter.directory_id))
+        else {
+            return Err(request_failed(
+                VOTER_NOT_FOUND,
+                format!("{} isn't a voter", voter.id),
+            ));
+        };
+        let mut voters = state.voters().to_vec();
+        if voters[i].endpoints == voter.endpoints
+            && (voters[i].min_kraft_version, voters[i].max_kraft_version)
+                == (voter.min_kraft_version, voter.max_kraft_version)
+        {
+            return Ok(());
+        }
+        voters[i] = Voter {
+            directory_id: voters[i].directory_id,
+            ..voter
+        };
+        self.append_voters(state, voters, true)
+    }
+
+    // the leader and where it's reached, for UpdateRaftVoter answers
+    pub fn current_leader(&self) -> (i32, i32, Option<QuorumVoter>) {
+        let state = self.state.lock().unwrap();
+        let leader_id = state.leader_id(self.node_id).unwrap_or(-1);
+        let address = state
+            .voter(leader_id)
+            .and_then(|v| v.address(&self.listener));
+        (leader_id, state.epoch, address)
+    }
+
+    // voter changes need a dynamic quorum, a leader with a committed epoch
+    // and no other change on the way
+    fn reconfigurable(&self, state: &RaftState) -> errors::Result<()> {
+        if self.kraft_version == KRAFT_VERSION_STATIC {
+            return Err(request_failed(
+                UNSUPPORTED_VERSION,
+                format!(
+                    "the voters come from {}, kraft.version is {KRAFT_VERSION_STATIC}",
+                    config::CONTROLLER_QUORUM_VOTERS_CONFIG
+                ),
+            ));
+        }
+        if state.role != Role::Leader {
+            return Err(self.not_leader(state));
+        }
+        let high_watermark = self.high_watermark.load(Ordering::SeqCst);
+        if high_watermark <= state.epoch_start_offset {
+            return Err(request_failed(
+                REQUEST_TIMED_OUT,
+                format!("epoch {} isn't committed yet", state.epoch),
+            ));
+        }
+        if state.voters_pending(high_watermark) {
+            return Err(request_failed(
+                REQUEST_TIMED_OUT,
+                "the previous voter change isn't committed yet".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    // appends a VotersRecord, in effect right away, and waits for it to be
+    // committed if asked to
+    fn append_voters(
+        &self,
+        mut state: MutexGuard<'_, RaftState>,
+        voters: Vec<Voter>,
+        wait: bool,
+    ) -> errors::Result<()> {
+        let epoch = state.epoch;
+        let offset = state.end_offset();
+        let mut batch = snapshot::control_batch(
+            epoch,
+            log::now_ms(),
+            voters::CONTROL_TYPE_VOTERS,
+            voters::voters_record(&voters)?,
+        )?;
+        batch.base_offset = offset;
+        state.append(&batch.to_bytes()?)?;
+        println!(
+            "raft: voters {:?} from offset {offset}",
+            voters.iter().map(|v| v.id).collect::<Vec<_>>()
+        );
+        self.persist(&state)?;
+        self.update_high_watermark(&mut state);
+        if wait {
+            self.wait_committed(state, epoch, offset)?;
+        }
+        Ok(())
+    }
+
+    // a leader that isn't a voter anymore lets the others elect a new one
+    // and follows the log from then on as an observer
+    fn step_down(&self) -> errors::Result<()> {
+        self.resign();
+        let mut state = self.state.lock().unwrap();
+        if state.role == Role::Leader {
+            println!("raft: node {} left the voters, stepping down", self.node_id);
+            self.lose_leader(&mut state)?;
+        }
+        Ok(())
+    }
+
+    // this voter as the voter set has it, if its endpoints changed since
+    // and it didn't say so in this epoch yet
+    fn outdated_endpoints(&self, state: &RaftState) -> Option<Voter> {
+        if self.kraft_version == KRAFT_VERSION_STATIC {
+            return None;
+        }
+        let voter = state.voter(self.node_id)?;
+        if voter.endpoints == self.endpoints
+            || self.endpoints_sent.swap(state.epoch, Ordering::SeqCst) == state.epoch
+        {
+            return None;
+        }
+        Some(Voter {
+            directory_id: self.directory_id,
+            endpoints: self.endpoints.clone(),
+            min_kraft_version: KRAFT_VERSION_STATIC,
+            max_kraft_version: KRAFT_VERSION_DYNAMIC,
+            ..voter.clone()
+        })
+    }
+
+    // a follower telling its leader about new endpoints
+    fn send_endpoints(&self, leader_id: i32, voter: Voter) -> errors::Result<()> {
+        let (epoch, address) = {
+            let state = self.state.lock().unwrap();
+            (
+                state.epoch,
+                state
+                    .voter(leader_id)
+                    .and_then(|v| v.address(&self.listener)),
+            )
+        };
+        let Some(address) = address else {
+            return Ok(());
+        };
+        let request = UpdateRaftVoterRequest {
+            cluster_id: Some(self.cluster_id.clone()),
+            current_leader_epoch: epoch,
+            voter_id: voter.id,
+            voter_directory_id: voter.directory_id,
+            listeners: voter.endpoints,
+            min_kraft_version: voter.min_kraft_version,
+            max_kraft_version: voter.max_kraft_version,
+        };
+        let mut body = vec![];
+        request.serialize(&mut body)?;
+        let mut response = self.send(&address, apikey::ApiKey::UpdateRaftVoter, 0, &body)?;
+        let response = UpdateRaftVoterResponse::read(&mut response)?;
+        if response.error_code != 0 {
+            return Err(request_failed(
+                response.error_code,
+                format!("leader {leader_id} didn't take the new endpoints"),
+            ));
+        }
+        println!("raft: leader {leader_id} has the new endpoints");
+        Ok(())
+    }
+
+    // the offset a majority of voters has, once it's in the leader's epoch.
+    // A leader on its way out of the voters doesn't count itself.
+    fn update_high_watermark(&self, state: &mut RaftState) {
+        if state.role != Role::Leader || state.voters().is_empty() {
+            return;
+        }
+        let mut offsets = state
+            .voters()
+            .iter()
+            .map(|v| {
+                if v.id == self.node_id {
+                    state.end_offset()
+                } else {
+                    state
+                        .replicas
+                        .get(&v.id)
+                        .filter(|r| v.is(v.id, &r.directory_id))
+                        .map_or(0, |r| r.end_offset)
+                }
+            })
+            .collect::<Vec<_>>();
+        offsets.sort_unstable_by(|a, b| b.cmp(a));
+        let majority = offsets[offsets.len() / 2];
+        if majority > state.epoch_start_offset
+            && majority > self.high_watermark.load(Ordering::SeqCst)
+        {
+            self.high_watermark.store(majority, Ordering::SeqCst);
+            self.changed.notify_all();
+        }
+    }
+
+    fn not_leader(&self, state: &RaftState) -> anyhow::Error {
+        request_failed(
+            NOT_LEADER_OR_FOLLOWER,
+            format!("node {} doesn't lead epoch {}", self.node_id, state.epoch),
+        )
+    }
+
+    fn not_controller(&self, state: &RaftState) -> anyhow::Error {
+        request_failed(
+            NOT_CONTROLLER,
+            match state.leader_id(self.node_id) {
+                Some(leader) => format!("node {leader} is the active controller"),
+                None => format!("no active controller in epoch {}", state.epoch),
+            },
+        )
+    }
+
+    fn send(
+        &self,
+        voter: &QuorumVoter,
+        api_key: apikey::ApiKey,
+        version: u16,
+        body: &[u8],
+    ) -> errors::Result<std::io::Cursor<Vec<u8>>> {
+        NodeClient::connect(&voter.host, voter.port, self.request_timeout())?
+            .send(api_key, version, body)
+    }
+
+    fn request_timeout(&self) -> Duration {
+        Duration::from_millis(self.election_timeout_ms.max(1))
+    }
+
+    fn fetch_deadline(&self) -> Instant {
+        Instant::now() + Duration::from_millis(self.fetch_timeout_ms)
+    }
+
+    // the election timeout plus a random backoff, so candidates don't keep
+    // splitting the vote
+    fn election_deadline(&self) -> errors::Result<Instant> {
+        let random = u64::from_be_bytes(controller::random_uuid()?[..8].try_into().unwrap());
+        let backoff = random % (self.election_backoff_max_ms + 1);
+        Ok(Instant::now() + Duration::from_millis(self.election_timeout_ms + backoff))
+    }
+
+    // the quorum-state file, in the layout the java controller writes
+    fn persist(&self, state: &RaftState) -> errors::Result<()> {
+        let voters = state
+            .voters()
+            .iter()
+            .map(|v| format!("{{\"voterId\":{}}}", v.id))
+            .collect::<Vec<_>>()
+            .join(",");
+        let json = format!(
+            "{{\"clusterId\":\"{}\",\"leaderId\":{},\"leaderEpoch\":{},\"votedId\":{},\"appliedOffset\":0,\"currentVoters\":[{voters}],\"data_version\":0}}",
+            self.cluster_id,
+            state.leader_id(self.node_id).unwrap_or(-1),
+            state.epoch,
+            state.voted_id(self.node_id).unwrap_or(-1),
+        );
+        let tmp = self.state_path.with_extension("tmp");
+        let mut f = File::create(&tmp)?;
+        f.write_all(json.as_bytes())?;
+        f.sync_all()?;
+        fs::rename(&tmp, &self.state_path)?;
+        Ok(())
+    }
+}
+
+// epoch, leader and vote from the quorum-state file, zero and none if it
+// isn't there yet
+fn read_quorum_state(path: &Path) -> errors::Result<(i32, Option<i32>, Option<i32>)> {
+    let json = match fs::read_to_string(path) {
+        Ok(json) => json,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok((0, None, None)),
+        Err(e) => return Err(e.into()),
+    };
+    let field = |key: &str| -> errors::Result<i32> {
+        let pattern = format!("\"{key}\":");
+        let value = json
+            .find(&pattern)
+            .map(|at| &json[at + pattern.len()..])
+            .map(|rest| {
+                rest.trim_start()
+                    .chars()
+                    .take_while(|c| *c == '-' || c.is_ascii_digit())
+                    .collect::<String>()
+            })
+            .and_then(|v| v.parse().ok());
+        value.ok_or_else(|| {
+            errors::KafkaErrors::InvalidCheckpoint(format!("{}: no {key}", path.display())).into()
+        })
+    };
+    Ok((
+        field("leaderEpoch")?,
+        leader(field("leaderId")?),
+        leader(field("votedId")?),
+    ))
+}
+
+// whether ids make up a majority of the voters
+fn is_majority(state: &RaftState, ids: &BTreeSet<i32>) -> bool {
+    let votes = ids.iter().filter(|id| state.is_voter(**id)).count();
+    votes > state.voters().len() / 2
+}
+
+// notes the epoch of a batch at offset and any voter set it holds
+fn index_batch(
+    epochs: &mut Vec<(i32, u64)>,
+    voter_sets: &mut Vec<(u64, Vec<Voter>)>,
+    batch: &RawBatch,
+    offset: u64,
+) -> errors::Result<()> {
+    index_epoch(epochs, batch.partition_leader_epoch(), offset);
+    if !batch.is_control() {
+        return Ok(());
+    }
+    for rec in batch.iter_records()? {
+        let rec = rec?;
+        if snapshot::control_type(&rec)? == voters::CONTROL_TYPE_VOTERS {
+            let voters = voters::read_voters_record(rec.value.as_deref().unwrap_or_default())?;
+            voter_sets.push((offset + rec.offset_delta as u64, voters));
+        }
+    }
+    Ok(())
+}
+
+fn index_epoch(epochs: &mut Vec<(i32, u64)>, epoch: i32, offset: u64) {
+    if epochs.last().map(|(e, _)| *e) != Some(epoch) {
+        epochs.push((epoch, offset));
+    }
+}
+
+// -1 stands for no node on the wire
+fn leader(id: i32) -> Option<i32> {
+    (id >= 0).then_some(id)
+}
+
+pub fn is_metadata_partition(topic: &str, partition: i32) -> bool {
+    topic == log::METADATA_TOPIC && partition == 0
+}
+
+fn request_failed(code: i16, message: String) -> anyhow::Error {
+    errors::KafkaErrors::RequestFailed(code, message).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kafka::testing::{record, records_batch, temp_root};
+
+    // node 1 of a static quorum of voters, nothing is sent as long as the
+    // driver isn't started. A lone voter leads right away.
+    fn open(root: &Path, voters: &[i32]) -> RaftClient {
+        let config = BrokerConfig {
+            metadata_log_dir: Some(root.display().to_string()),
+            controller_quorum_voters: voters
+                .iter()
+                .map(|&id| QuorumVoter {
+                    id,
+                    host: "localhost".to_string(),
+                    port: 19090 + id as u16,
+                })
+                .collect(),
+            ..Default::default()
+        };
+        RaftClient::open(&config, "test".to_string()).unwrap()
+    }
+
+    fn batch(epoch: i32, count: i32) -> Vec<u8> {
+        RecordsBatch {
+            partition_leader_epoch: epoch,
+            ..records_batch((0..count).map(record).collect())
+        }
+        .to_bytes()
+        .unwrap()
+    }
+
+    // a replica fetching without a directory id, like static voters may
+    fn replica(id: i32) -> ReplicaKey {
+        ReplicaKey {
+            id,
+            directory_id: [0; 16],
+        }
+    }
+
+    fn vote_request(candidate: ReplicaKey, epoch: i32, last: (i32, i64)) -> VoteRequest {
+        VoteRequest {
+            version: quorum::VOTE_VERSION,
+            cluster_id: Some("test".to_string()),
+            voter_id: 1,
+            partitions: vec![VotePartition {
+                topic: log::METADATA_TOPIC.to_string(),
+                partition_index: 0,
+                candidate_epoch: epoch,
+                candidate_id: candidate.id,
+                candidate_directory_id: candidate.directory_id,
+                voter_directory_id: [0; 16],
+                last_offset_epoch: last.0,
+                last_offset: last.1,
+            }],
+        }
+    }
+
+    fn answer(raft: &RaftClient, request: &VoteRequest) -> (i16, bool) {
+        let response = raft.handle_vote(request).unwrap();
+        let p = &response.partitions[0];
+        (p.error_code, p.vote_granted)
+    }
+
+    fn vote(raft: &RaftClient, candidate_id: i32, epoch: i32, last: (i32, i64)) -> (i16, bool) {
+        answer(raft, &vote_request(replica(candidate_id), epoch, last))
+    }
+
+    #[test]
+    fn test_vote_granting() {
+        let root = temp_root("raft-vote");
+        let raft = open(&root, &[1, 2, 3]);
+
+        // one vote per epoch, asked for again by the same candidate is fine
+        assert_eq!(vote(&raft, 2, 1, (0, 0)), (0, true));
+        assert_eq!(vote(&raft, 3, 1, (0, 0)), (0, false));
+        assert_eq!(vote(&raft, 2, 1, (0, 0)), (0, true));
+        assert_eq!(
+            read_quorum_state(&raft.state_path).unwrap(),
+            (1, None, Some(2))
+        );
+        // an older epoch or a node that isn't a voter never gets it
+        assert_eq!(vote(&raft, 3, 0, (0, 0)), (0, false));
+        assert_eq!(vote(&raft, 4, 2, (0, 0)), (INCONSISTENT_VOTER_SET, false));
+        let other_cluster = raft
+            .handle_vote(&VoteRequest {
+                cluster_id: Some("other".to_string()),
+                ..vote_request(replica(3), 2, (0, 0))
+            })
+            .unwrap();
+        assert_eq!(other_cluster.error_code, INCONSISTENT_CLUSTER_ID);
+        // nor does one that was meant for another voter
+        let request = VoteRequest {
+            voter_id: 2,
+            ..vote_request(replica(3), 2, (0, 0))
+        };
+        assert_eq!(answer(&raft, &request), (INVALID_VOTER_KEY, false));
+
+        // a newer epoch frees the vote, for a log at least as up to date
+        raft.state.lock().unwrap().append(&batch(1, 3)).unwrap();
+        assert_eq!(vote(&raft, 3, 2, (1, 2)), (0, false));
+        assert_eq!(vote(&raft, 3, 2, (0, 10)), (0, false));
+        assert_eq!(vote(&raft, 3, 2, (1, 3)), (0, true));
+        assert_eq!(raft.state.lock().unwrap().role, Role::Voted(3));
+        let _ = fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn test_high_watermark_is_the_majority_offset() {
+        let root = temp_root("raft-high-watermark");
+        let raft = open(&root, &[1, 2, 3]);
+        let high_watermark = || raft.high_watermark.load(Ordering::SeqCst);
+        let fetch = |replica_id, offset| {
+            let fetch = raft.handle_fetch(Some("test"), replica(replica_id), 1, offset, 1, 1 << 20);
+            assert_eq!(fetch.error_code, 0);
+            fetch.high_watermark
+        };
+
+        // voted in by 2, the epoch starts with the LeaderChange at 0
+        raft.start_election(&mut raft.state.lock().unwrap())
+            .unwrap();
+        let granted = VoteResponse {
+            error_code: 0,
+            partitions: vec![VoteResponsePartition {
+                leader_epoch: 1,
+                vote_granted: true,
+                ..Default::default()
+            }],
+        };
+        raft.vote_received(2, 1, &granted).unwrap();
+        assert_eq!(raft.leader_id(), Some(1));
+        assert_eq!(raft.state.lock().unwrap().end_offset(), 1);
+        assert_eq!(high_watermark(), 0);
+
+        // the leader and one follower are a majority of three
+        assert_eq!(fetch(2, 1), 1);
+        {
+            let mut state = raft.state.lock().unwrap();
+            state.append(&batch(1, 2)).unwrap();
+            raft.update_high_watermark(&mut state);
+        }
+        assert_eq!(high_watermark(), 1);
+        // replicas report what they have by where they fetch from
+        assert_eq!(fetch(2, 2), 2);
+        assert_eq!(fetch(3, 3), 3);
+        // a replica falling back doesn't move it back
+        assert_eq!(fetch(2, 1), 3);
+        let _ = fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn test_diverging_epoch_truncation() {
+        // leader: epoch 1 from 0, epoch 3 from 3, up to 5
+        let root = temp_root("raft-diverging");
+        let leader = open(&root.join("leader"), &[]);
+        {
+            let mut state = leader.state.lock().unwrap();
+            state.append(&batch(1, 2)).unwrap();
+            state.append(&batch(3, 2)).unwrap();
+            assert_eq!(state.epochs, vec![(1, 0), (3, 3)]);
+            assert_eq!(state.end_offset_for_epoch(0), (-1, 0));
+            assert_eq!(state.end_offset_for_epoch(1), (1, 3));
+            assert_eq!(state.end_offset_for_epoch(2), (1, 3));
+            assert_eq!(state.end_offset_for_epoch(3), (3, 5));
+            assert_eq!(state.end_offset_for_epoch(4), (3, 5));
+        }
+        // a follower that wrote on in an epoch 2 the leader never had
+        let follower = open(&root.join("follower"), &[]);
+        let mut state = follower.state.lock().unwrap();
+        state.append(&batch(1, 2)).unwrap();
+        state.append(&batch(2, 3)).unwrap();
+        assert_eq!(state.end_offset(), 6);
+
+        let fetch = leader.handle_fetch(Some("test"), replica(2), 1, 6, 2, 1 << 20);
+        let diverging = fetch.diverging_epoch.unwrap();
+        assert_eq!(
+            diverging,
+            DivergingEpoch {
+                epoch: 1,
+                end_offset: 3
+            }
+        );
+        assert!(fetch.records.is_none());
+
+        assert_eq!(state.truncate_to_diverging(diverging).unwrap(), 3);
+        assert_eq!(state.end_offset(), 3);
+        assert_eq!(state.epochs, vec![(1, 0)]);
+        // where it resumes the leader has nothing to object to
+        let fetch = leader.handle_fetch(Some("test"), replica(2), 1, 3, 1, 1 << 20);
+        assert!(fetch.diverging_epoch.is_none());
+        assert!(fetch.records.is_some());
+        drop(state);
+        let _ = fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn test_quorum_state_round_trip() {
+        let root = temp_root("raft-quorum-state");
+        let raft = open(&root, &[]);
+        assert_eq!(
+            read_quorum_state(&raft.state_path).unwrap(),
+            (1, Some(1), None)
+        );
+        let json = fs::read_to_string(&raft.state_path).unwrap();
+        assert!(json.contains("\"clusterId\":\"test\""), "{json}");
+
+        {
+            let mut state = raft.state.lock().unwrap();
+            state.epoch = 7;
+            state.role = Role::Voted(2);
+            raft.persist(&state).unwrap();
+        }
+        assert_eq!(
+            read_quorum_state(&raft.state_path).unwrap(),
+            (7, None, Some(2))
+        );
+        drop(raft);
+
+        // a restarted node picks up where it was, never earlier
+        let raft = open(&root, &[1, 2, 3]);
+        let state = raft.state.lock().unwrap();
+        assert_eq!((state.epoch, state.role), (7, Role::Voted(2)));
+        drop(state);
+        assert_eq!(
+            read_quorum_state(&root.join("missing")).unwrap(),
+            (0, None, None)
+        );
+        let _ = fs::remove_dir_all(&root);
+    }
+
+    // node 1 formatted --standalone, the lone voter of a dynamic quorum
+    fn open_standalone(root: &Path) -> RaftClient {
+        let config = BrokerConfig {
+            log_dirs: vec![root.display().to_string()],
+            metadata_log_dir: Some(root.display().to_string()),
+            ..Default::default()
+        };
+        let options = storage::FormatOptions {
+            standalone: true,
+            ..Default::default()
+        };
+        let cluster_id = storage::format(&config, &options).unwrap();
+        RaftClient::open(&config, cluster_id).unwrap()
+    }
+
+    // nobody listens on port 1, whatever is sent there fails right away
+    fn new_voter(id: i32, directory_id: [u8; 16]) -> Voter {
+        Voter {
+            id,
+            directory_id,
+            endpoints: vec![Listener {
+                name: "CONTROLLER".to_string(),
+                host: "localhost".to_string(),
+                port: 1,
+            }],
+            min_kraft_version: KRAFT_VERSION_STATIC,
+            max_kraft_version: KRAFT_VERSION_DYNAMIC,
+        }
+    }
+
+    fn voter_ids(raft: &RaftClient) -> Vec<i32> {
+        let state = raft.state.lock().unwrap();
+        state.voters().iter().map(|v| v.id).collect()
+    }
+
+    // what a replica fetching from its end offset in epoch 1 tells the leader
+    fn fetch_at_end(raft: &RaftClient, replica: ReplicaKey) {
+        let end = raft.state.lock().unwrap().end_offset();
+        raft.handle_fetch(None, replica, 1, end, 1, 1 << 20);
+    }
+
+    // runs f while replica keeps fetching from the end of the log
+    fn while_fetching<T: Send>(
+        raft: &RaftClient,
+        replica: ReplicaKey,
+        f: impl FnOnce() -> T + Send,
+    ) -> T {
+        thread::scope(|scope| {
+            let f = scope.spawn(f);
+            while !f.is_finished() {
+                fetch_at_end(raft, replica);
+                thread::sleep(Duration::from_millis(10));
+            }
+            f.join().unwrap()
+        })
+    }
+
+    fn error_code(result: errors::Result<()>) -> i16 {
+        result.map_or_else(|e| controller::error_code(&e), |_| 0)
+    }
+
+    #[test]
+    fn test_add_voter_waits_for_catch_up() {
+        let root = temp_root("raft-add-voter");
+        let raft = open_standalone(&root);
+        assert_eq!(raft.leader_id(), Some(1));
+        let timeout = Duration::from_millis(50);
+        let voter = new_voter(2, [2; 16]);
+        let key = ReplicaKey {
+            id: 2,
+            directory_id: [2; 16],
+        };
+
+        // not fetching, behind, or fetching from another dir isn't enough
+        assert_eq!(
+            error_code(raft.add_voter(voter.clone(), timeout, false)),
+            REQUEST_TIMED_OUT
+        );
+        raft.handle_fetch(None, key, 1, 1, 1, 1 << 20);
+        assert_eq!(
+            error_code(raft.add_voter(voter.clone(), timeout, false)),
+            REQUEST_TIMED_OUT
+        );
+        let other_dir = ReplicaKey {
+            directory_id: [3; 16],
+            ..key
+        };
+        fetch_at_end(&raft, other_dir);
+        assert_eq!(
+            error_code(raft.add_voter(voter.clone(), timeout, false)),
+            REQUEST_TIMED_OUT
+        );
+        assert_eq!(voter_ids(&raft), vec![1]);
+
+        // caught up while waiting, and committed once it fetched past it
+        let added = while_fetching(&raft, key, || {
+            raft.add_voter(voter.clone(), Duration::from_secs(5), true)
+        });
+        assert_eq!(error_code(added), 0);
+        assert_eq!(voter_ids(&raft), vec![1, 2]);
+        assert_eq!(
+            error_code(raft.add_voter(voter, timeout, false)),
+            DUPLICATE_VOTER
+        );
+        let _ = fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn test_voter_changes_one_at_a_time() {
+        let root = temp_root("raft-one-at-a-time");
+        let raft = open_standalone(&root);
+        let key = ReplicaKey {
+            id: 2,
+            directory_id: [2; 16],
+        };
+        fetch_at_end(&raft, key);
+        raft.add_voter(new_voter(2, [2; 16]), Duration::from_secs(5), false)
+            .unwrap();
+
+        // 2 didn't fetch the new voter set yet, so it isn't committed
+        let pending = [
+            raft.add_voter(new_voter(3, [3; 16]), Duration::from_secs(5), false),
+            raft.remove_voter(2, [2; 16]),
+            raft.update_voter(new_voter(2, [2; 16])),
+        ];
+        for result in pending {
+            assert_eq!(error_code(result), REQUEST_TIMED_OUT);
+        }
+        fetch_at_end(&raft, key);
+        assert!(raft
+            .state
+            .lock()
+            .unwrap()
+            .voter_sets
+            .last()
+            .is_some_and(|(offset, _)| *offset < raft.high_watermark.load(Ordering::SeqCst)));
+
+        // a voter is removed by its id and directory id both
+        assert_eq!(error_code(raft.remove_voter(2, [9; 16])), VOTER_NOT_FOUND);
+        assert_eq!(error_code(raft.remove_voter(2, [2; 16])), 0);
+        assert_eq!(voter_ids(&raft), vec![1]);
+        let _ = fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn test_remove_voter() {
+        let root = temp_root("raft-remove-voter");
+        let raft = open_standalone(&root);
+        assert_eq!(
+            error_code(raft.remove_voter(1, raft.directory_id)),
+            INVALID_REQUEST
+        );
+
+        // with 2 in, the leader can remove itself and hands over
+        let key = ReplicaKey {
+            id: 2,
+            directory_id: [2; 16],
+        };
+        let added = while_fetching(&raft, key, || {
+            raft.add_voter(new_voter(2, [2; 16]), Duration::from_secs(5), true)
+        });
+        assert_eq!(error_code(added), 0);
+        let removed = while_fetching(&raft, key, || raft.remove_voter(1, raft.directory_id));
+        assert_eq!(error_code(removed), 0);
+        assert_eq!(voter_ids(&raft), vec![2]);
+        assert_eq!(raft.leader_id(), None);
+        assert_eq!(raft.state.lock().unwrap().role, Role::Unattached);
+        let _ = fs::remove_dir_all(&root);
+    }
+}
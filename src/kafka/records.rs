@@ -1,31 +1,36 @@
-use crate::kafka::{KAFKA_RECORDTYPE_FEATURE, KAFKA_RECORDTYPE_PARTITION, KAFKA_RECORDTYPE_TOPIC};
-
-use super::{errors, metadata, parser, writer};
+use super::compression::CompressionType;
+use super::metadata_records::KafkaRecordValue;
+use super::{errors, legacy, metadata, parser, writer};
 use core::fmt;
 use crc32c::crc32c;
 use std::fmt::Write;
-use std::io::{BufWriter, Read};
+use std::io::Read;
 
 fn size_of<T: Sized>(_v: &T) -> usize {
     std::mem::size_of::<T>()
 }
 
+// a decoded v2 record batch. Encoding it again gives back the exact bytes it
+// was decoded from, except for batch_length and crc which are always
+// recomputed from the content. Legacy (magic 0/1) entries are up-converted
+// to v2 on decode. Compressed batches are decoded transparently
+// and recompressed with the codec in attributes when encoded, so only their
+// records (not the compressed bytes) round-trip.
 #[allow(dead_code)]
-#[derive(Debug, Clone, Default)]
+#[derive(Debug, Clone, Default, PartialEq)]
 pub(crate) struct RecordsBatch {
     pub base_offset: u64,
     pub batch_length: i32,
     pub partition_leader_epoch: i32,
     pub magic: i8,
-    pub crc: i32,
+    pub crc: u32,
     pub attributes: i16,
     pub last_offset_delta: i32,
-    pub base_timestamp: u64,
-    pub max_timestamp: u64,
-    pub producer_id: u64,
+    pub base_timestamp: i64,
+    pub max_timestamp: i64,
+    pub producer_id: i64,
     pub producer_epoch: i16,
     pub base_sequence: i32,
-    pub rec_length: i32,
     pub records: Vec<KafkaRecord>,
 }
 
@@ -52,28 +57,34 @@ impl std::fmt::Display for RecordsBatch {
         .expect("filed to write 3!");
         writeln!(
             &mut msg,
-            "Base timestamp: {:0x}, Max timestamp: {:0x}",
+            "Base timestamp: {}, Max timestamp: {}",
             self.base_timestamp, self.max_timestamp
         )
         .expect("filed to write 4!");
         writeln!(
             &mut msg,
-            "produced ID: {:0x}, producer epoch: {:0x}, num records: {}",
-            self.producer_id, self.producer_epoch, self.rec_length
+            "produced ID: {}, producer epoch: {}, num records: {}",
+            self.producer_id,
+            self.producer_epoch,
+            self.records.len()
         )
         .expect("filed to write 5!");
         self.records.iter().enumerate().for_each(|(i, record)| {
-            writeln!(&mut msg, "record {}:\n {:?}", i, record).expect("failed to write record!");
+            writeln!(&mut msg, "record {}:\n {}", i, record).expect("failed to write record!");
         });
         writeln!(f, "{}", msg)
     }
 }
 
+#[allow(dead_code)]
 impl RecordsBatch {
     #[allow(dead_code)]
     pub fn new() -> Self {
         Self {
-            records: vec![KafkaRecord::new()],
+            magic: 2,
+            producer_id: -1,
+            producer_epoch: -1,
+            base_sequence: -1,
             ..Default::default()
         }
     }
@@ -85,126 +96,119 @@ impl RecordsBatch {
 
         rec.base_offset = parser::read_u64(input_buffer)?;
         rec.batch_length = parser::read_int(input_buffer)?;
+        if rec.batch_length < MIN_ENTRY_LENGTH {
+            return Err(errors::KafkaErrors::CorruptRecord(format!(
+                "invalid batch length {}",
+                rec.batch_length
+            ))
+            .into());
+        }
 
-        // lets create a cursor over batch length slice
-        // TODO - should have to copy but create it over the existing data
         let mut data_buffer: Vec<u8> = vec![0_u8; rec.batch_length as usize];
         input_buffer.read_exact(&mut data_buffer)?;
-        let mut buffer = std::io::Cursor::new(data_buffer);
+        // magic 0/1 message set entries come back up-converted to v2
+        let magic = data_buffer[MAGIC_OFFSET - BATCH_OVERHEAD] as i8;
+        if magic < 2 {
+            let mut entry = Vec::with_capacity(BATCH_OVERHEAD + data_buffer.len());
+            entry.extend_from_slice(&rec.base_offset.to_be_bytes());
+            entry.extend_from_slice(&rec.batch_length.to_be_bytes());
+            entry.extend_from_slice(&data_buffer);
+            return legacy::to_batch(&legacy::read_message_set(&entry)?);
+        }
+        if (rec.batch_length as usize) < BATCH_HEADER_LEN - BATCH_OVERHEAD {
+            return Err(errors::KafkaErrors::CorruptRecord(format!(
+                "invalid batch length {}",
+                rec.batch_length
+            ))
+            .into());
+        }
+        let mut buffer = std::io::Cursor::new(&data_buffer[..]);
 
         rec.partition_leader_epoch = parser::read_int(&mut buffer)?;
         rec.magic = parser::read_byte(&mut buffer)?;
-        rec.crc = parser::read_int(&mut buffer)?;
+        if rec.magic != 2 {
+            return Err(errors::KafkaErrors::CorruptRecord(format!(
+                "unsupported magic {}",
+                rec.magic
+            ))
+            .into());
+        }
+        rec.crc = parser::read_int(&mut buffer)? as u32;
         rec.attributes = parser::read_short(&mut buffer)?;
         rec.last_offset_delta = parser::read_int(&mut buffer)?;
-        rec.base_timestamp = parser::read_u64(&mut buffer)?;
-        rec.max_timestamp = parser::read_u64(&mut buffer)?;
-        rec.producer_id = parser::read_u64(&mut buffer)?;
+        rec.base_timestamp = parser::read_u64(&mut buffer)? as i64;
+        rec.max_timestamp = parser::read_u64(&mut buffer)? as i64;
+        rec.producer_id = parser::read_u64(&mut buffer)? as i64;
         rec.producer_epoch = parser::read_short(&mut buffer)?;
         rec.base_sequence = parser::read_int(&mut buffer)?;
-        rec.rec_length = parser::read_int(&mut buffer)?;
-
-        for _i in 0..rec.rec_length {
-            match KafkaRecord::deserialize(&mut buffer) {
-                Ok(r) => rec.records.push(r),
-                Err(e) => {
-                    println!("*********************** Unable to parse KafkaRecord!! Err: {e:?}");
-                    println!(
-                        "Original Records length: {}, updated: {}",
-                        rec.rec_length,
-                        rec.records.len()
-                    );
-                    rec.rec_length = rec.records.len() as i32;
-                }
-            }
-        }
-
+        let count = parser::read_int(&mut buffer)?;
+        rec.records = decode_records(
+            rec.compression()?,
+            &data_buffer[buffer.position() as usize..],
+            count,
+        )?;
         Ok(rec)
     }
 
-    #[allow(dead_code)]
-    fn calc_meta(&self) -> errors::Result<(u32, i32)> {
-        let mut buf = vec![0_u8; 1500];
-        let mut copybuf = BufWriter::new(&mut buf);
-        writer::write_bytes(&mut copybuf, &self.attributes)?;
-        writer::write_bytes(&mut copybuf, &self.last_offset_delta)?;
-        writer::write_bytes(&mut copybuf, &self.base_timestamp)?;
-        writer::write_bytes(&mut copybuf, &self.max_timestamp)?;
-        writer::write_bytes(&mut copybuf, &self.producer_id)?;
-        writer::write_bytes(&mut copybuf, &self.producer_epoch)?;
-        writer::write_bytes(&mut copybuf, &self.base_sequence)?;
-        writer::write_bytes(&mut copybuf, &(self.rec_length as u32))?;
-        self.records
-            .iter()
-            .try_for_each(|record| record.serialize(&mut copybuf))?;
-        let batch_length = copybuf.buffer().len() as i32;
-        drop(copybuf);
-        Ok((crc32c(&buf[0..batch_length as usize]), batch_length))
+    // everything the crc covers: attributes up to the end of the records
+    fn encode_crc_covered(&self) -> errors::Result<Vec<u8>> {
+        let mut buf = Vec::with_capacity(self.size() - ATTRIBUTES_OFFSET);
+        writer::write_bytes(&mut buf, &self.attributes)?;
+        writer::write_bytes(&mut buf, &self.last_offset_delta)?;
+        writer::write_bytes(&mut buf, &self.base_timestamp)?;
+        writer::write_bytes(&mut buf, &self.max_timestamp)?;
+        writer::write_bytes(&mut buf, &self.producer_id)?;
+        writer::write_bytes(&mut buf, &self.producer_epoch)?;
+        writer::write_bytes(&mut buf, &self.base_sequence)?;
+        writer::write_bytes(&mut buf, &(self.records.len() as i32))?;
+        buf.extend_from_slice(&encode_records(self.compression()?, &self.records)?);
+        Ok(buf)
     }
 
-    #[allow(dead_code)]
+    pub fn compression(&self) -> errors::Result<CompressionType> {
+        CompressionType::from_id(self.attributes & COMPRESSION_CODEC_MASK)
+    }
+
+    pub fn compute_crc(&self) -> errors::Result<u32> {
+        Ok(crc32c(&self.encode_crc_covered()?))
+    }
+
+    // writes the batch with its length and crc worked out from the content
     pub fn serialize<W: std::io::Write>(&self, resp: &mut W) -> errors::Result<()> {
-        //let (crc, batch_length) = self.calc_meta()?;
-        //println!(
-        //    "---------- crc: {crc:#x} vs {:#x}, batch length: {batch_length} vs {} ----------",
-        //    self.crc, self.batch_length
-        //);
-        let length = self.size() as i32;
-        println!("=================== calculated batch length: {length} =================");
+        let covered = self.encode_crc_covered()?;
+        let length = (CRC_OFFSET + 4 - BATCH_OVERHEAD + covered.len()) as i32;
         writer::write_bytes(resp, &self.base_offset)?;
         writer::write_bytes(resp, &length)?;
         writer::write_bytes(resp, &self.partition_leader_epoch)?;
         writer::write_bytes(resp, &self.magic)?;
-        writer::write_bytes(resp, &self.crc)?;
-        writer::write_bytes(resp, &self.attributes)?;
-        writer::write_bytes(resp, &self.last_offset_delta)?;
-        writer::write_bytes(resp, &self.base_timestamp)?;
-        writer::write_bytes(resp, &self.max_timestamp)?;
-        writer::write_bytes(resp, &self.producer_id)?;
-        writer::write_bytes(resp, &self.producer_epoch)?;
-        writer::write_bytes(resp, &self.base_sequence)?;
-        // this length is 32-bit
-        writer::write_bytes(resp, &(self.rec_length as u32 - 1))?;
-        self.records
-            .iter()
-            .try_for_each(|record| record.serialize(resp))?;
-
-        let _ = writer::write_bytes(resp, &0_i8);
+        writer::write_bytes(resp, &crc32c(&covered))?;
+        resp.write_all(&covered)?;
         Ok(())
     }
 
-    #[allow(dead_code)]
+    pub fn to_bytes(&self) -> errors::Result<Vec<u8>> {
+        let mut buf = Vec::with_capacity(self.size());
+        self.serialize(&mut buf)?;
+        Ok(buf)
+    }
+
+    // encoded size, base offset and length included. For compressed batches
+    // this is the uncompressed size, the real one is only known once encoded.
     pub fn size(&self) -> usize {
-        //size_of(&self.base_offset)
-        //    + size_of(&self.batch_length)
-        size_of(&self.partition_leader_epoch)
-            + size_of(&self.magic)
-            + size_of(&self.crc)
-            + size_of(&self.attributes)
-            + size_of(&self.last_offset_delta)
-            + size_of(&self.base_timestamp)
-            + size_of(&self.max_timestamp)
-            + size_of(&self.producer_id)
-            + size_of(&self.producer_epoch)
-            + size_of(&self.base_sequence)
-            + size_of(&self.rec_length)
-            + self.records.iter().take(1).fold(0, |acc, r| acc + r.size())
-            + 1
+        BATCH_HEADER_LEN + self.records.iter().map(|r| r.encoded_size()).sum::<usize>()
     }
 }
 
+// a single record inside a v2 batch. Key and value are kept as raw bytes,
+// metadata_value() decodes the payload of metadata log records.
 #[allow(dead_code)]
-#[derive(Debug, Clone, Default)]
+#[derive(Debug, Clone, Default, PartialEq)]
 pub struct KafkaRecord {
-    pub length: i8,
     pub attributes: i8,
-    pub timestamp_delta: i8,
-    pub offset_delta: i8,
-    pub key_length: i8,
-    pub key: Vec<u8>,
-    pub value_length: i8,
-    pub value: KafkaRecordValue,
-    pub header_count: i8,
+    pub timestamp_delta: i64,
+    pub offset_delta: i32,
+    pub key: Option<Vec<u8>>,
+    pub value: Option<Vec<u8>>,
     pub headers: Vec<KafkaRecordHeader>,
 }
 
@@ -213,26 +217,15 @@ impl std::fmt::Display for KafkaRecord {
         let mut msg: String = String::new();
         writeln!(
             &mut msg,
-            "length: {}, attributes: {}, timestamp_delta: {}, offset_delta: {}",
-            self.length, self.attributes, self.timestamp_delta, self.offset_delta
+            "attributes: {}, timestamp_delta: {}, offset_delta: {}",
+            self.attributes, self.timestamp_delta, self.offset_delta
         )
         .expect("KR write failed 1!");
-        writeln!(
-            &mut msg,
-            "key length: {}, key: {:?}",
-            self.key_length, self.key
-        )
-        .expect("KR write failed 2!");
-        writeln!(
-            &mut msg,
-            "value length: {}, value: {:?}",
-            self.value_length, self.value
-        )
-        .expect("KR write failed 3!");
-        writeln!(&mut msg, "num headers: {}", self.header_count).expect("KR write failed 4!");
-        for i in 0..self.header_count {
-            writeln!(&mut msg, "Header {} - {:?}", i, self.headers[i as usize])
-                .expect("KR Write failed 5!");
+        writeln!(&mut msg, "key: {:?}", self.key).expect("KR write failed 2!");
+        writeln!(&mut msg, "value: {:?}", self.value).expect("KR write failed 3!");
+        writeln!(&mut msg, "num headers: {}", self.headers.len()).expect("KR write failed 4!");
+        for (i, header) in self.headers.iter().enumerate() {
+            writeln!(&mut msg, "Header {} - {}", i, header).expect("KR Write failed 5!");
         }
 
         writeln!(f, "{}", msg)
@@ -243,7 +236,6 @@ impl KafkaRecord {
     #[allow(dead_code)]
     pub fn new() -> Self {
         Self {
-            headers: vec![KafkaRecordHeader::new()],
             ..Default::default()
         }
     }
@@ -251,89 +243,101 @@ impl KafkaRecord {
     pub fn deserialize<R: Read>(buffer: &mut R) -> errors::Result<Self> {
         let length = parser::read_varint(buffer)?;
         if length <= 0 {
-            return Err(
-                errors::KafkaErrors::InvalidWriterArg("Invalid record length".to_string()).into(),
-            );
+            return Err(errors::KafkaErrors::CorruptRecord(format!(
+                "invalid record length {length}"
+            ))
+            .into());
         }
 
         let mut record_data = vec![0u8; length as usize];
         buffer.read_exact(&mut record_data)?;
-        let mut record_cursor = std::io::Cursor::new(record_data);
+        let mut record_cursor = std::io::Cursor::new(&record_data[..]);
 
         let attributes = parser::read_byte(&mut record_cursor)?;
-        let timestamp_delta = parser::read_varint(&mut record_cursor)? as i8; // Assuming it fits
-        let offset_delta = parser::read_varint(&mut record_cursor)? as i8; // Assuming it fits
-        let key_length = parser::read_varint(&mut record_cursor)? as i8;
-        let mut key = vec![];
-        if key_length > 0 {
-            key = vec![0_u8; key_length as usize];
-            record_cursor.read_exact(&mut key)?;
+        let timestamp_delta = parser::read_varlong(&mut record_cursor)?;
+        let offset_delta = parser::read_varint(&mut record_cursor)?;
+        let key = read_nullable_bytes(&mut record_cursor)?;
+        let value = read_nullable_bytes(&mut record_cursor)?;
+        let header_count = parser::read_varint(&mut record_cursor)?;
+        let headers = (0..header_count.max(0))
+            .map(|_| KafkaRecordHeader::deserialize(&mut record_cursor))
+            .collect::<errors::Result<_>>()?;
+        if (record_cursor.position() as usize) < record_data.len() {
+            return Err(errors::KafkaErrors::CorruptRecord(format!(
+                "record length {length} is larger than its fields"
+            ))
+            .into());
         }
 
-        let value_length = parser::read_varint(&mut record_cursor)?;
-        let value = if value_length > 0 {
-            KafkaRecordValue::deserialize(&mut record_cursor, value_length as usize)?
-        } else {
-            KafkaRecordValue::Invalid
-        };
-
-        let header_count = parser::read_varint(&mut record_cursor)? as i8;
-        // Header parsing logic would go here if needed
-
         Ok(Self {
-            length: length as i8,
             attributes,
             timestamp_delta,
             offset_delta,
-            key_length,
             key,
-            value_length: value_length as i8,
             value,
-            header_count,
-            headers: vec![],
+            headers,
         })
     }
 
+    // the length prefix counts everything after itself
     fn size(&self) -> usize {
         size_of(&self.attributes)
-            + size_of(&self.timestamp_delta)
-            + size_of(&self.offset_delta)
-            + size_of(&self.key_length)
-            + self.key.len()
-            //+ size_of(&self.value_length)
-            + self.value.size()
-            + size_of(&self.header_count)
-            + self.headers.iter().fold(0, |acc, h| acc + h.size())
+            + writer::varlong_size(self.timestamp_delta)
+            + writer::varlong_size(self.offset_delta as i64)
+            + nullable_bytes_size(self.key.as_deref())
+            + nullable_bytes_size(self.value.as_deref())
+            + writer::varlong_size(self.headers.len() as i64)
+            + self.headers.iter().map(|h| h.size()).sum::<usize>()
+    }
+
+    #[allow(dead_code)]
+    pub fn encoded_size(&self) -> usize {
+        let size = self.size();
+        writer::varlong_size(size as i64) + size
     }
 
     pub fn serialize<W: std::io::Write>(&self, resp: &mut W) -> errors::Result<()> {
-        // need to determine length dynamically
         writer::write_varint_main(resp, self.size() as i32)?;
         writer::write_bytes(resp, &self.attributes)?;
-        writer::write_varint(resp, self.timestamp_delta as usize)?;
-        writer::write_varint_main(resp, self.offset_delta as i32)?;
-        writer::write_compact_string(resp, &self.key)?;
-        // if valus is compact string, ensure that it gets
-        // 1 byte accounted for
-        self.value.serialize(resp)?;
-        writer::write_bytes(resp, &(self.headers.len() as u8))?;
+        writer::write_varlong(resp, self.timestamp_delta)?;
+        writer::write_varint_main(resp, self.offset_delta)?;
+        writer::write_varint_bytes(resp, self.key.as_deref())?;
+        writer::write_varint_bytes(resp, self.value.as_deref())?;
+        writer::write_varint_main(resp, self.headers.len() as i32)?;
         self.headers.iter().try_for_each(|h| h.serialize(resp))
     }
+
+    // decodes the value as a metadata log record (__cluster_metadata)
+    pub fn metadata_value(&self) -> errors::Result<KafkaRecordValue> {
+        match &self.value {
+            Some(v) if !v.is_empty() => KafkaRecordValue::deserialize(&mut std::io::Cursor::new(v)),
+            _ => Ok(KafkaRecordValue::Invalid),
+        }
+    }
 }
 
 #[allow(dead_code)]
-#[derive(Debug, Clone, Default)]
+#[derive(Debug, Clone, Default, PartialEq)]
 pub struct KafkaRecordHeader {
     pub key: Vec<u8>,
-    pub value: Vec<u8>,
+    pub value: Option<Vec<u8>>,
 }
 
 impl std::fmt::Display for KafkaRecordHeader {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let mut msg: String = String::new();
-        writeln!(&mut msg, "key: {:?}, value: {:?}", self.key, self.value)
-            .expect("KRH write failed 1!");
-        writeln!(f, "{}", msg)
+        match &self.value {
+            Some(v) => write!(
+                f,
+                "key: {}, value: {}",
+                String::from_utf8_lossy(&self.key),
+                String::from_utf8_lossy(v)
+            ),
+            None => write!(
+                f,
+                "key: {}, value: null",
+                String::from_utf8_lossy(&self.key)
+            ),
+        }
     }
 }
 
@@ -341,420 +345,537 @@ impl KafkaRecordHeader {
     #[allow(dead_code)]
     pub fn new() -> Self {
         Self {
-            //key: "key-header".into(),
-            //value: "value-header".into(),
             ..Default::default()
         }
     }
 
-    #[allow(dead_code)]
-    pub fn deserialize<R: Read>(_data: &R) -> errors::Result<Self> {
-        let d = Self {
-            ..Default::default()
-        };
-        Ok(d)
+    // header keys are never null, values may be
+    pub fn deserialize<R: Read>(buffer: &mut R) -> errors::Result<Self> {
+        let key = read_nullable_bytes(buffer)?.ok_or_else(|| {
+            errors::KafkaErrors::CorruptRecord("null record header key".to_string())
+        })?;
+        let value = read_nullable_bytes(buffer)?;
+        Ok(Self { key, value })
     }
 
     fn size(&self) -> usize {
-        self.key.len() + 1 + self.value.len() + 1
+        nullable_bytes_size(Some(&self.key)) + nullable_bytes_size(self.value.as_deref())
     }
 
     pub fn serialize<W: std::io::Write>(&self, resp: &mut W) -> errors::Result<()> {
-        writer::write_compact_string(resp, &self.key)?;
-        writer::write_compact_string(resp, &self.value)?;
+        writer::write_varint_bytes(resp, Some(&self.key))?;
+        writer::write_varint_bytes(resp, self.value.as_deref())?;
         Ok(())
     }
 }
 
-#[derive(Clone, Debug, Default)]
-pub enum KafkaRecordValue {
-    #[default]
-    Invalid,
-    KafkaRecordFeatureType(KafkaRecordFeature),
-    KafkaRecordTopicRecordType(KafkaRecordTopicRecord),
-    KafkaRecordPartitionType(KafkaRecordPartitionRecord),
+fn read_nullable_bytes<R: Read>(buffer: &mut R) -> errors::Result<Option<Vec<u8>>> {
+    let len = parser::read_varint(buffer)?;
+    if len < 0 {
+        return Ok(None);
+    }
+    let mut data = vec![0_u8; len as usize];
+    buffer.read_exact(&mut data)?;
+    Ok(Some(data))
 }
 
-impl std::fmt::Display for KafkaRecordValue {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            Self::Invalid => writeln!(f, "Invalid!"),
-            Self::KafkaRecordFeatureType(v) => writeln!(f, "{}", v),
-            Self::KafkaRecordTopicRecordType(v) => writeln!(f, "{}", v),
-            Self::KafkaRecordPartitionType(v) => writeln!(f, "{}", v),
-        }
+fn nullable_bytes_size(val: Option<&[u8]>) -> usize {
+    match val {
+        Some(b) => writer::varlong_size(b.len() as i64) + b.len(),
+        None => writer::varlong_size(-1),
     }
 }
 
-impl KafkaRecordValue {
-    pub fn deserialize<R: Read>(
-        buffer: &mut R,
-        data_len: usize,
-    ) -> errors::Result<KafkaRecordValue> {
-        let frame_version = parser::read_byte(buffer)?;
-        let frame_type = parser::read_byte(buffer)?;
-        let version = parser::read_byte(buffer)?;
-        println!(
-            "frame version: {}, frame type: {}, version: {}, data_len: {}",
-            frame_version, frame_type, version, data_len
-        );
-        let mut value_reader = buffer.take(data_len as u64 - 3); // -3 for the fields we just read
-        match frame_type {
-            KAFKA_RECORDTYPE_FEATURE => {
-                // feature record
-                let mut rec = KafkaRecordFeature::deserialize(&mut value_reader)?;
-                rec.frame_version = frame_version;
-                rec.frame_type = frame_type;
-                rec.version = version;
-                Ok(Self::KafkaRecordFeatureType(rec))
-            }
-            KAFKA_RECORDTYPE_TOPIC => {
-                // topic record
-                let mut rec = KafkaRecordTopicRecord::deserialize(&mut value_reader)?;
-                rec.frame_version = frame_version;
-                rec.frame_type = frame_type;
-                rec.version = version;
-                Ok(Self::KafkaRecordTopicRecordType(rec))
+// byte level view over a v2 record batch, used wherever batches are moved
+// around without needing the decoded metadata payloads (log append, cleaner).
+// A legacy (magic 0/1) message set entry shares the offset, length and magic
+// positions, so the same view frames those too: its accessors read the
+// legacy fields, with the entry's offset as both base and last offset.
+//
+// batch layout:
+//   base_offset(8) batch_length(4) leader_epoch(4) magic(1) crc(4)
+//   attributes(2) last_offset_delta(4) base_timestamp(8) max_timestamp(8)
+//   producer_id(8) producer_epoch(2) base_sequence(4) records_count(4)
+pub const BATCH_LENGTH_OFFSET: usize = 8;
+pub const BATCH_OVERHEAD: usize = 12; // base_offset + batch_length
+pub const PARTITION_LEADER_EPOCH_OFFSET: usize = 12;
+pub const MAGIC_OFFSET: usize = 16;
+pub const CRC_OFFSET: usize = 17;
+pub const ATTRIBUTES_OFFSET: usize = 21;
+pub const LAST_OFFSET_DELTA_OFFSET: usize = 23;
+pub const BASE_TIMESTAMP_OFFSET: usize = 27;
+pub const MAX_TIMESTAMP_OFFSET: usize = 35;
+pub const PRODUCER_ID_OFFSET: usize = 43;
+pub const RECORDS_COUNT_OFFSET: usize = 57;
+pub const BATCH_HEADER_LEN: usize = 61;
+
+// smallest entry either format can have: a magic 0 message with null key
+// and value (crc, magic, attributes and two lengths)
+pub const MIN_ENTRY_LENGTH: i32 = 14;
+
+pub const COMPRESSION_CODEC_MASK: i16 = 0x07;
+pub const TIMESTAMP_TYPE_MASK: i16 = 0x08; // set for LogAppendTime
+pub const CONTROL_FLAG_MASK: i16 = 0x20;
+
+#[derive(Debug, Clone)]
+pub struct RawBatch {
+    pub data: Vec<u8>,
+}
+
+impl RawBatch {
+    // reads the next batch, None on a clean end of input. A truncated tail
+    // (partial write before a crash) is treated as the end as well.
+    pub fn read<R: Read>(input: &mut R) -> errors::Result<Option<Self>> {
+        let mut data = vec![0_u8; BATCH_OVERHEAD];
+        if let Err(e) = input.read_exact(&mut data) {
+            if e.kind() == std::io::ErrorKind::UnexpectedEof {
+                return Ok(None);
             }
-            KAFKA_RECORDTYPE_PARTITION => {
-                // partition record
-                let mut rec = KafkaRecordPartitionRecord::deserialize(&mut value_reader)?;
-                rec.frame_version = frame_version;
-                rec.frame_type = frame_type;
-                rec.version = version;
-                Ok(Self::KafkaRecordPartitionType(rec))
+            return Err(e.into());
+        }
+        let batch_length = i32::from_be_bytes(data[8..12].try_into()?);
+        if batch_length < MIN_ENTRY_LENGTH {
+            return Err(errors::KafkaErrors::CorruptRecord(format!(
+                "invalid batch length {batch_length}"
+            ))
+            .into());
+        }
+        data.resize(BATCH_OVERHEAD + batch_length as usize, 0);
+        if let Err(e) = input.read_exact(&mut data[BATCH_OVERHEAD..]) {
+            if e.kind() == std::io::ErrorKind::UnexpectedEof {
+                return Ok(None);
             }
-            //112 => {}
-            _ => todo!("Kafka Record type {} not implemented!", frame_type),
+            return Err(e.into());
         }
-    }
-
-    pub fn size(&self) -> usize {
-        match self {
-            Self::Invalid => 0,
-            Self::KafkaRecordFeatureType(v) => v.size(),
-            Self::KafkaRecordTopicRecordType(v) => v.size(),
-            Self::KafkaRecordPartitionType(v) => v.size(),
+        if data[MAGIC_OFFSET] as i8 >= 2 && data.len() < BATCH_HEADER_LEN {
+            return Err(errors::KafkaErrors::CorruptRecord(format!(
+                "invalid batch length {batch_length}"
+            ))
+            .into());
         }
+        Ok(Some(Self { data }))
     }
 
-    pub fn serialize<W: std::io::Write>(&self, resp: &mut W) -> errors::Result<()> {
-        match self {
-            Self::Invalid => Ok(()),
-            Self::KafkaRecordFeatureType(v) => v.serialize(resp),
-            Self::KafkaRecordTopicRecordType(v) => v.serialize(resp),
-            Self::KafkaRecordPartitionType(v) => v.serialize(resp),
+    // splits a produce payload into its batches
+    pub fn split(buffer: &[u8]) -> errors::Result<Vec<Self>> {
+        let mut reader = std::io::Cursor::new(buffer);
+        let mut batches = vec![];
+        while let Some(batch) = Self::read(&mut reader)? {
+            batches.push(batch);
         }
+        if (reader.position() as usize) < buffer.len() {
+            return Err(errors::KafkaErrors::CorruptRecord(
+                "trailing bytes after last batch".to_string(),
+            )
+            .into());
+        }
+        Ok(batches)
     }
-}
 
-#[allow(dead_code)]
-#[derive(Debug, Clone, Default)]
-pub struct KafkaRecordFeature {
-    pub frame_version: i8,
-    pub frame_type: i8,
-    pub version: i8,
-    pub name_length: u8,
-    pub name: Vec<u8>,
-    pub feature_level: i16,
-    pub tagged_field_count: i8,
-}
+    // base_offset is outside the crc, so the log can assign offsets in place
+    pub fn set_base_offset(&mut self, offset: u64) {
+        self.data[0..8].copy_from_slice(&offset.to_be_bytes());
+    }
 
-impl std::fmt::Display for KafkaRecordFeature {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let mut msg = String::new();
-        writeln!(
-            &mut msg,
-            "Frame version: {}, Frame Type: {}, Version: {}",
-            self.frame_version, self.frame_type, self.version
-        )
-        .expect("KafkaRecordFeature write failed 1");
+    pub fn compute_crc(&self) -> u32 {
+        if self.is_legacy() {
+            let mut crc = flate2::Crc::new();
+            crc.update(&self.data[legacy::LEGACY_MAGIC_OFFSET..]);
+            return crc.sum();
+        }
+        crc32c(&self.data[ATTRIBUTES_OFFSET..])
+    }
 
-        writeln!(
-            &mut msg,
-            "Name length: {}, Name: {:?}",
-            self.name_length, self.name
-        )
-        .expect("KafkaRecordFeature write failed 1");
+    // stamps the batch with the broker's clock: both timestamps become the
+    // append time and the timestamp type bit is set, so readers take every
+    // record's timestamp to be max_timestamp. The crc is redone.
+    pub fn set_log_append_time(&mut self, now: i64) {
+        let attributes = self.attributes() | TIMESTAMP_TYPE_MASK;
+        self.data[ATTRIBUTES_OFFSET..ATTRIBUTES_OFFSET + 2]
+            .copy_from_slice(&attributes.to_be_bytes());
+        self.data[BASE_TIMESTAMP_OFFSET..BASE_TIMESTAMP_OFFSET + 8]
+            .copy_from_slice(&now.to_be_bytes());
+        self.data[MAX_TIMESTAMP_OFFSET..MAX_TIMESTAMP_OFFSET + 8]
+            .copy_from_slice(&now.to_be_bytes());
+        let crc = self.compute_crc();
+        self.data[CRC_OFFSET..CRC_OFFSET + 4].copy_from_slice(&crc.to_be_bytes());
+    }
 
-        writeln!(
-            &mut msg,
-            "feature level: {}, tagged field count: {}",
-            self.feature_level, self.tagged_field_count
-        )
-        .expect("KafkaRecordFeature write failed 1");
-        writeln!(f, "{}", msg)
+    // the records in this batch, decompressed if need be
+    pub fn records(&self) -> errors::Result<Vec<KafkaRecord>> {
+        self.iter_records()?.collect()
     }
-}
 
-impl KafkaRecordFeature {
-    #[allow(dead_code)]
-    pub fn new() -> Self {
-        Self {
-            ..Default::default()
+    pub fn iter_records(&self) -> errors::Result<RecordIter> {
+        if self.is_legacy() {
+            return RecordIter::legacy(self, &self.data);
         }
+        RecordIter::new(
+            self.compression()?,
+            self.data[BATCH_HEADER_LEN..].to_vec(),
+            self.records_count(),
+        )
     }
 
-    pub fn deserialize<R: Read>(buffer: &mut R) -> errors::Result<Self> {
-        let mut rec = Self {
-            ..Default::default()
-        };
-
-        rec.name = parser::read_compact_string(buffer)?;
-        rec.name_length = rec.name.len() as u8;
-        rec.feature_level = parser::read_short(buffer)?;
-        rec.tagged_field_count = parser::read_varint(buffer)? as i8;
-
-        Ok(rec)
+    // rebuilds this batch around a subset of its records. Offsets and
+    // last_offset_delta are preserved, the count, length and crc are redone.
+    pub fn with_records(&self, records: &[KafkaRecord]) -> errors::Result<Self> {
+        self.rebuild(self.compression()?, records)
     }
 
-    fn size(&self) -> usize {
-        size_of(&self.frame_version)
-            + size_of(&self.frame_type)
-            + size_of(&self.version)
-            + size_of(&self.name_length)
-            + size_of(&self.name)
-            + size_of(&self.feature_level)
-            + size_of(&self.tagged_field_count)
+    // the same batch stored with another codec
+    pub fn recompressed(&self, codec: CompressionType) -> errors::Result<Self> {
+        if self.compression()? == codec {
+            return Ok(self.clone());
+        }
+        self.rebuild(codec, &self.records()?)
+    }
+
+    fn rebuild(&self, codec: CompressionType, records: &[KafkaRecord]) -> errors::Result<Self> {
+        let mut data = self.data[..BATCH_HEADER_LEN].to_vec();
+        data.extend_from_slice(&encode_records(codec, records)?);
+        let attributes = (self.attributes() & !COMPRESSION_CODEC_MASK) | codec.id();
+        data[ATTRIBUTES_OFFSET..ATTRIBUTES_OFFSET + 2].copy_from_slice(&attributes.to_be_bytes());
+        let batch_length = (data.len() - BATCH_OVERHEAD) as i32;
+        data[BATCH_LENGTH_OFFSET..BATCH_LENGTH_OFFSET + 4]
+            .copy_from_slice(&batch_length.to_be_bytes());
+        data[RECORDS_COUNT_OFFSET..RECORDS_COUNT_OFFSET + 4]
+            .copy_from_slice(&(records.len() as i32).to_be_bytes());
+        let crc = crc32c(&data[ATTRIBUTES_OFFSET..]);
+        data[CRC_OFFSET..CRC_OFFSET + 4].copy_from_slice(&crc.to_be_bytes());
+        Ok(Self { data })
     }
+}
 
-    pub fn serialize<W: std::io::Write>(&self, resp: &mut W) -> errors::Result<()> {
-        writer::write_bytes(resp, &self.frame_version)?;
-        writer::write_bytes(resp, &self.frame_type)?;
-        writer::write_bytes(resp, &self.version)?;
-        writer::write_varint(resp, self.name_length as usize)?;
-        writer::write_compact_string(resp, &self.name)?;
-        writer::write_bytes(resp, &self.feature_level)?;
-        writer::write_varint(resp, self.tagged_field_count as usize)?;
-        Ok(())
+impl BatchHeader for RawBatch {
+    fn header_bytes(&self) -> &[u8] {
+        &self.data
     }
 }
 
+// header fields of a batch, shared by RawBatch and the lazily read segment
+// views. header_bytes() covers at least the 61 byte v2 header, or the whole
+// entry for a shorter legacy one; the legacy fields sit at other positions
+// and the entry's offset is both its base and last offset.
 #[allow(dead_code)]
-#[derive(Debug, Clone, Default)]
-pub struct KafkaRecordTopicRecord {
-    pub frame_version: i8,
-    pub frame_type: i8,
-    pub version: i8,
-    pub name_length: u8,
-    pub topic_name: Vec<u8>,
-    pub topic_uuid: [u8; 16],
-    pub tagged_field_count: i8,
-}
+pub trait BatchHeader {
+    fn header_bytes(&self) -> &[u8];
 
-impl std::fmt::Display for KafkaRecordTopicRecord {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let mut msg = String::new();
-        writeln!(
-            &mut msg,
-            "Frame version: {}, Frame Type: {}, Version: {}",
-            self.frame_version, self.frame_type, self.version
-        )
-        .expect("KafkaRecordFeature write failed 1");
+    fn base_offset(&self) -> u64 {
+        be_i64(self.header_bytes(), 0) as u64
+    }
 
-        writeln!(
-            &mut msg,
-            "Name length: {}, topic Name: {:?}",
-            self.name_length, self.topic_name
-        )
-        .expect("KafkaRecordFeature write failed 1");
+    fn batch_length(&self) -> i32 {
+        be_i32(self.header_bytes(), BATCH_LENGTH_OFFSET)
+    }
 
-        writeln!(
-            &mut msg,
-            "topic UUID: {:?}, tagged field count: {}",
-            self.topic_uuid, self.tagged_field_count
-        )
-        .expect("KafkaRecordFeature write failed 1");
-        writeln!(f, "{}", msg)
+    // encoded size, base offset and length included
+    fn size(&self) -> usize {
+        BATCH_OVERHEAD + self.batch_length().max(0) as usize
     }
-}
 
-impl KafkaRecordTopicRecord {
-    #[allow(dead_code)]
-    pub fn new() -> Self {
-        Self {
-            ..Default::default()
+    fn partition_leader_epoch(&self) -> i32 {
+        if self.is_legacy() {
+            return -1;
         }
+        be_i32(self.header_bytes(), PARTITION_LEADER_EPOCH_OFFSET)
     }
 
-    pub fn deserialize<R: Read>(buffer: &mut R) -> errors::Result<Self> {
-        let mut rec = Self {
-            ..Default::default()
-        };
-
-        rec.topic_name = parser::read_compact_string(buffer)?;
-        rec.name_length = rec.topic_name.len() as u8;
+    fn magic(&self) -> i8 {
+        self.header_bytes()[MAGIC_OFFSET] as i8
+    }
 
-        buffer
-            .read_exact(&mut rec.topic_uuid)
-            .expect("Failed to read from buffer!");
+    fn is_legacy(&self) -> bool {
+        self.magic() < 2
+    }
 
-        rec.tagged_field_count = parser::read_varint(buffer)? as i8;
+    fn crc(&self) -> u32 {
+        if self.is_legacy() {
+            return be_i32(self.header_bytes(), legacy::LEGACY_CRC_OFFSET) as u32;
+        }
+        be_i32(self.header_bytes(), CRC_OFFSET) as u32
+    }
 
-        Ok(rec)
+    // legacy attributes are a single byte, with the codec and timestamp type
+    // in the same bits
+    fn attributes(&self) -> i16 {
+        if self.is_legacy() {
+            return self.header_bytes()[legacy::LEGACY_ATTRIBUTES_OFFSET] as i8 as i16;
+        }
+        be_i16(self.header_bytes(), ATTRIBUTES_OFFSET)
     }
 
-    // Only value (name) is part of record response
-    fn size(&self) -> usize {
-        self.topic_name.len() + 1
-        //size_of(&self.tagged_field_count)
+    fn is_compressed(&self) -> bool {
+        self.attributes() & COMPRESSION_CODEC_MASK != 0
     }
 
-    pub fn serialize<W: std::io::Write>(&self, resp: &mut W) -> errors::Result<()> {
-        writer::write_compact_string(resp, &self.topic_name)?;
+    fn compression(&self) -> errors::Result<CompressionType> {
+        CompressionType::from_id(self.attributes() & COMPRESSION_CODEC_MASK)
+    }
 
-        //writer::write_varint(resp, self.tagged_field_count as usize)?;
-        Ok(())
+    fn is_control(&self) -> bool {
+        self.attributes() & CONTROL_FLAG_MASK != 0
     }
-}
 
-#[allow(dead_code)]
-#[derive(Debug, Clone, Default)]
-pub struct KafkaRecordPartitionRecord {
-    pub frame_version: i8,
-    pub frame_type: i8,
-    pub version: i8,
-    pub partition_id: i32,
-    pub topic_uuid: [u8; 16],
-    pub replica_array_length: i8,
-    pub replica_array: Vec<i32>,
-    pub insync_replica_array_length: i8,
-    pub insync_replica_array: Vec<i32>,
-    pub removing_replica_array_length: i8,
-    pub adding_replica_array_length: i8,
-    pub leader: i32,
-    pub leader_epoch: i32,
-    pub partition_epoch: i32,
-    pub dir_array_length: i8,
-    pub dir_array: Vec<[u8; 16]>, // eachmemer is 16 byte - interpret as UUID
-    pub tagged_field_count: i8,
-}
+    fn is_log_append_time(&self) -> bool {
+        self.attributes() & TIMESTAMP_TYPE_MASK != 0
+    }
 
-impl std::fmt::Display for KafkaRecordPartitionRecord {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let mut msg = String::new();
-        writeln!(
-            &mut msg,
-            "Frame version: {}, Frame Type: {}, Version: {}",
-            self.frame_version, self.frame_type, self.version
-        )
-        .expect("KafkaRecordPartitionRecord failed 1");
+    fn last_offset_delta(&self) -> i32 {
+        if self.is_legacy() {
+            return 0;
+        }
+        be_i32(self.header_bytes(), LAST_OFFSET_DELTA_OFFSET)
+    }
 
-        writeln!(
-            &mut msg,
-            "Partition ID: {}, topic UUID: {:?}",
-            self.partition_id, self.topic_uuid
-        )
-        .expect("KafkaRecordPartitionRecord write failed 2");
+    fn last_offset(&self) -> u64 {
+        self.base_offset() + self.last_offset_delta() as u64
+    }
 
-        writeln!(
-            &mut msg,
-            "replica array length: {}, replica array: {:?}",
-            self.replica_array_length, self.replica_array
-        )
-        .expect("KafkaRecordPartitionRecord write failed 3");
+    fn next_offset(&self) -> u64 {
+        self.last_offset() + 1
+    }
 
-        writeln!(
-            &mut msg,
-            "insync replica array length: {}, insync replica array: {:?}",
-            self.insync_replica_array_length, self.insync_replica_array
-        )
-        .expect("KafkaRecordPartitionRecord write failed 4");
+    fn base_timestamp(&self) -> i64 {
+        if self.is_legacy() {
+            return legacy_timestamp(self.header_bytes());
+        }
+        be_i64(self.header_bytes(), BASE_TIMESTAMP_OFFSET)
+    }
 
-        writeln!(
-            &mut msg,
-            "removing replica array length: {}, adding replica array length: {}",
-            self.removing_replica_array_length, self.adding_replica_array_length
-        )
-        .expect("KafkaRecordPartitionRecord write failed 5");
+    fn max_timestamp(&self) -> i64 {
+        if self.is_legacy() {
+            return legacy_timestamp(self.header_bytes());
+        }
+        be_i64(self.header_bytes(), MAX_TIMESTAMP_OFFSET)
+    }
 
-        writeln!(
-            &mut msg,
-            "leader: {}, leader epoch: {}, partition_epoch: {}, dir length: {}",
-            self.leader, self.leader_epoch, self.partition_epoch, self.dir_array_length
-        )
-        .expect("KafkaRecordPartitionRecord write failed 6");
+    fn producer_id(&self) -> i64 {
+        if self.is_legacy() {
+            return -1;
+        }
+        be_i64(self.header_bytes(), PRODUCER_ID_OFFSET)
+    }
 
-        for i in 0..self.dir_array_length {
-            writeln!(&mut msg, "Dir {}: {:?}", i, self.dir_array[i as usize])
-                .expect("KafkaRecordPartitionRecord write failed 7");
+    // a legacy entry counts as one, whatever a compressed wrapper holds
+    fn records_count(&self) -> i32 {
+        if self.is_legacy() {
+            return 1;
         }
+        be_i32(self.header_bytes(), RECORDS_COUNT_OFFSET)
+    }
+}
 
-        writeln!(&mut msg, "tagged field count: {}", self.tagged_field_count)
-            .expect("KafkaRecordPartitionRecord write failed 8");
+fn be_i16(bytes: &[u8], at: usize) -> i16 {
+    i16::from_be_bytes([bytes[at], bytes[at + 1]])
+}
 
-        write!(f, "{}", msg)
-    }
+fn be_i32(bytes: &[u8], at: usize) -> i32 {
+    let mut b = [0_u8; 4];
+    b.copy_from_slice(&bytes[at..at + 4]);
+    i32::from_be_bytes(b)
 }
 
-#[allow(dead_code)]
-impl KafkaRecordPartitionRecord {
-    #[allow(dead_code)]
-    pub fn new() -> Self {
-        Self {
-            ..Default::default()
-        }
+fn be_i64(bytes: &[u8], at: usize) -> i64 {
+    let mut b = [0_u8; 8];
+    b.copy_from_slice(&bytes[at..at + 8]);
+    i64::from_be_bytes(b)
+}
+
+fn legacy_timestamp(header: &[u8]) -> i64 {
+    match header[MAGIC_OFFSET] {
+        1 => be_i64(header, legacy::LEGACY_TIMESTAMP_OFFSET),
+        _ => -1,
     }
+}
 
-    pub fn deserialize<R: Read>(buffer: &mut R) -> errors::Result<Self> {
-        let mut rec = Self {
-            ..Default::default()
+// decodes the records of one batch as they are asked for. The records
+// section is held decompressed in memory, so a scan costs at most one batch.
+pub enum RecordIter {
+    Encoded {
+        buffer: std::io::Cursor<Vec<u8>>,
+        remaining: i32,
+    },
+    Decoded(std::vec::IntoIter<KafkaRecord>),
+}
+
+impl RecordIter {
+    // over the records section of a v2 batch, everything after the header
+    pub fn new(codec: CompressionType, section: Vec<u8>, count: i32) -> errors::Result<Self> {
+        let section = if codec == CompressionType::None {
+            section
+        } else {
+            codec.decompress(&section)?
         };
-        rec.partition_id = parser::read_int(buffer)?;
-        buffer
-            .read_exact(&mut rec.topic_uuid)
-            .expect("Failed to deserialize topic uuid!");
-        rec.replica_array_length = parser::read_varint(buffer)? as i8;
-        rec.replica_array = vec![0_i32; rec.replica_array_length as usize];
-        for i in 0..rec.replica_array_length as usize {
-            rec.replica_array[i] = parser::read_int(buffer)?;
-        }
+        Ok(Self::Encoded {
+            buffer: std::io::Cursor::new(section),
+            remaining: count.max(0),
+        })
+    }
 
-        rec.insync_replica_array_length = parser::read_varint(buffer)? as i8;
-        rec.insync_replica_array = vec![0_i32; rec.insync_replica_array_length as usize];
-        for i in 0..rec.insync_replica_array_length as usize {
-            rec.insync_replica_array[i] = parser::read_int(buffer)?;
-        }
+    // over a whole legacy entry. Messages get their offset and timestamp
+    // relative to the entry's.
+    pub fn legacy(header: &impl BatchHeader, entry: &[u8]) -> errors::Result<Self> {
+        let base_offset = header.base_offset() as i64;
+        let base_timestamp = header.base_timestamp();
+        let records: Vec<KafkaRecord> = legacy::read_message_set(entry)?
+            .into_iter()
+            .map(|m| KafkaRecord {
+                offset_delta: (m.offset as i64 - base_offset) as i32,
+                timestamp_delta: m.timestamp - base_timestamp,
+                key: m.key,
+                value: m.value,
+                ..Default::default()
+            })
+            .collect();
+        Ok(Self::Decoded(records.into_iter()))
+    }
+}
+
+impl Iterator for RecordIter {
+    type Item = errors::Result<KafkaRecord>;
 
-        rec.removing_replica_array_length = parser::read_varint(buffer)? as i8;
-        rec.adding_replica_array_length = parser::read_varint(buffer)? as i8;
-        rec.leader = parser::read_int(buffer)?;
-        rec.leader_epoch = parser::read_int(buffer)?;
-        rec.partition_epoch = parser::read_int(buffer)?;
-
-        rec.dir_array_length = parser::read_varint(buffer)? as i8;
-        rec.dir_array = vec![[0_u8; 16]; rec.dir_array_length as usize];
-        for i in 0..rec.dir_array_length as usize {
-            buffer
-                .read_exact(&mut rec.dir_array[i])
-                .expect("Failed to read directory array!");
+    fn next(&mut self) -> Option<Self::Item> {
+        match self {
+            Self::Decoded(records) => records.next().map(Ok),
+            Self::Encoded { buffer, remaining } => {
+                let left = buffer.get_ref().len() - buffer.position() as usize;
+                if *remaining == 0 {
+                    if left == 0 {
+                        return None;
+                    }
+                    // reported once, then the iterator is done
+                    buffer.set_position(buffer.get_ref().len() as u64);
+                    return Some(Err(errors::KafkaErrors::CorruptRecord(format!(
+                        "{left} trailing bytes after the last record"
+                    ))
+                    .into()));
+                }
+                *remaining -= 1;
+                let record = KafkaRecord::deserialize(buffer);
+                if record.is_err() {
+                    *remaining = 0;
+                    buffer.set_position(buffer.get_ref().len() as u64);
+                }
+                Some(record)
+            }
         }
+    }
+}
 
-        rec.tagged_field_count = parser::read_varint(buffer)? as i8;
+// decodes the records section of a batch, everything after the 61 byte
+// header, which holds exactly `count` records once decompressed
+fn decode_records(
+    codec: CompressionType,
+    section: &[u8],
+    count: i32,
+) -> errors::Result<Vec<KafkaRecord>> {
+    RecordIter::new(codec, section.to_vec(), count)?.collect()
+}
 
-        Ok(rec)
+fn encode_records(codec: CompressionType, records: &[KafkaRecord]) -> errors::Result<Vec<u8>> {
+    let mut buf = Vec::with_capacity(records.iter().map(|r| r.encoded_size()).sum());
+    records.iter().try_for_each(|r| r.serialize(&mut buf))?;
+    if codec == CompressionType::None {
+        return Ok(buf);
     }
+    codec.compress(&buf)
+}
 
-    fn size(&self) -> usize {
-        size_of(&self.frame_version)
-            + size_of(&self.frame_type)
-            + size_of(&self.version)
-            + size_of(&self.partition_id)
-            + size_of(&self.topic_uuid)
-            + size_of(&self.replica_array_length)
-            + size_of(&self.insync_replica_array_length)
-            + size_of(&self.insync_replica_array)
-            + size_of(&self.removing_replica_array_length)
-            + size_of(&self.adding_replica_array_length)
-            + size_of(&self.leader)
-            + size_of(&self.leader_epoch)
-            + size_of(&self.partition_epoch)
-            + size_of(&self.dir_array_length)
-            + size_of(&self.dir_array)
-            + size_of(&self.tagged_field_count)
-    }
-
-    pub fn serialize<W: std::io::Write>(&self, _resp: &mut W) -> errors::Result<()> {
-        todo!()
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unhex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    // a batch as a java producer writes it: a null key with a 200 byte value
+    // (multi byte varints), a null value with a timestamp delta past i32 and
+    // two headers, one with a null value, and an empty key and value
+    fn sample_batch() -> Vec<u8> {
+        let mut data = unhex(concat!(
+            "00000000000000070000011e0000000002249eab790000000000020000018bcfe568",
+            "000000018bcfe56800ffffffffffffffffffffffffffff000000039e030009000190",
+            "03"
+        ));
+        data.extend_from_slice(&[b'v'; 200]);
+        data.extend_from_slice(&unhex(
+            "00280080f882ad1602026b01040468310278046832010c000004000000",
+        ));
+        data
+    }
+
+    #[test]
+    fn test_batch_round_trip() {
+        let data = sample_batch();
+        let batch = RecordsBatch::deserialize(&mut std::io::Cursor::new(&data)).unwrap();
+        assert_eq!(batch.base_offset, 7);
+        assert_eq!(batch.base_timestamp, 1_700_000_000_000);
+        assert_eq!(batch.producer_id, -1);
+        assert_eq!(batch.records.len(), 3);
+        assert_eq!(batch.records[0].key, None);
+        assert_eq!(batch.records[0].timestamp_delta, -5);
+        assert_eq!(batch.records[0].value.as_ref().map(Vec::len), Some(200));
+        assert_eq!(batch.records[1].timestamp_delta, 3_000_000_000);
+        assert_eq!(batch.records[1].value, None);
+        assert_eq!(
+            batch.records[1].headers,
+            vec![
+                KafkaRecordHeader {
+                    key: b"h1".to_vec(),
+                    value: Some(b"x".to_vec())
+                },
+                KafkaRecordHeader {
+                    key: b"h2".to_vec(),
+                    value: None
+                },
+            ]
+        );
+        assert_eq!(batch.records[2].key, Some(vec![]));
+        assert_eq!(batch.compute_crc().unwrap(), batch.crc);
+        assert_eq!(batch.size(), data.len());
+        assert_eq!(batch.to_bytes().unwrap(), data);
+
+        // edits get a fresh length and crc
+        let mut edited = batch.clone();
+        edited.records[2].value = Some(b"changed".to_vec());
+        let bytes = edited.to_bytes().unwrap();
+        let raw = RawBatch::read(&mut std::io::Cursor::new(&bytes))
+            .unwrap()
+            .unwrap();
+        assert_eq!(raw.size(), bytes.len());
+        assert_eq!(raw.crc(), raw.compute_crc());
+        let decoded = RecordsBatch::deserialize(&mut std::io::Cursor::new(&bytes)).unwrap();
+        assert_eq!(decoded.records, edited.records);
+    }
+
+    #[test]
+    fn test_compressed_batches() {
+        let data = sample_batch();
+        let raw = RawBatch::read(&mut std::io::Cursor::new(&data))
+            .unwrap()
+            .unwrap();
+        let plain = raw.records().unwrap();
+        for codec in [
+            CompressionType::Gzip,
+            CompressionType::Snappy,
+            CompressionType::Lz4,
+            CompressionType::Zstd,
+        ] {
+            let compressed = raw.recompressed(codec).unwrap();
+            assert_eq!(compressed.compression().unwrap(), codec);
+            assert_eq!(compressed.crc(), compressed.compute_crc());
+            assert_eq!(compressed.records().unwrap(), plain);
+            let batch =
+                RecordsBatch::deserialize(&mut std::io::Cursor::new(&compressed.data)).unwrap();
+            assert_eq!(batch.records, plain);
+            assert_eq!(batch.to_bytes().unwrap(), compressed.data);
+            let back = compressed.recompressed(CompressionType::None).unwrap();
+            assert_eq!(back.data, data);
+        }
     }
 }
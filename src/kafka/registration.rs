@@ -0,0 +1,240 @@
+// BrokerRegistration (key 62): a broker asking the active controller for
+// a place in the cluster. Every node sends it at startup, to itself when it
+// leads the quorum and over the wire otherwise, until a controller takes it.
+//
+// https://kafka.apache.org/protocol.html#The_Messages_BrokerRegistration
+use crate::kafka::client::NodeClient;
+use crate::kafka::metadata_records::BrokerEndpoint;
+use crate::kafka::{apikey, controller, errors, parser, raft, writer};
+use std::io::{Read, Write};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+// how long a broker waits before trying to register again
+const REGISTRATION_RETRY_MS: u64 = 500;
+
+#[derive(Debug, Clone, Default)]
+pub struct BrokerRegistrationRequest {
+    pub version: u16,
+    pub broker_id: i32,
+    pub cluster_id: String,
+    pub incarnation_id: [u8; 16],
+    pub listeners: Vec<BrokerEndpoint>,
+    pub features: Vec<(String, i16, i16)>,
+    pub rack: Option<String>,
+    pub is_migrating_zk_broker: bool, // v1+
+    pub log_dirs: Vec<[u8; 16]>,      // v2+
+    pub previous_broker_epoch: i64,   // v3+
+}
+
+impl BrokerRegistrationRequest {
+    // always flexible
+    pub fn new<R: Read>(req: &mut R, version: u16) -> errors::Result<Self> {
+        let broker_id = parser::read_int(req)?;
+        let cluster_id = String::from_utf8(parser::read_string(req, true)?)?;
+        let incarnation_id = parser::read_u128(req)?.to_be_bytes();
+        let mut listeners = vec![];
+        for _ in 0..parser::read_array_len(req, true)?.unwrap_or_default() {
+            listeners.push(BrokerEndpoint {
+                name: parser::read_string(req, true)?,
+                host: parser::read_string(req, true)?,
+                port: parser::read_short(req)? as u16,
+                security_protocol: parser::read_short(req)?,
+            });
+            parser::skip_tagged_fields(req)?;
+        }
+        let mut features = vec![];
+        for _ in 0..parser::read_array_len(req, true)?.unwrap_or_default() {
+            features.push((
+                String::from_utf8(parser::read_string(req, true)?)?,
+                parser::read_short(req)?,
+                parser::read_short(req)?,
+            ));
+            parser::skip_tagged_fields(req)?;
+        }
+        let rack = parser::read_nullable_string(req, true)?
+            .map(String::from_utf8)
+            .transpose()?;
+        let is_migrating_zk_broker = version >= 1 && parser::read_byte(req)? != 0;
+        let mut log_dirs = vec![];
+        if version >= 2 {
+            for _ in 0..parser::read_array_len(req, true)?.unwrap_or_default() {
+                log_dirs.push(parser::read_u128(req)?.to_be_bytes());
+            }
+        }
+        let previous_broker_epoch = if version >= 3 {
+            parser::read_u64(req)? as i64
+        } else {
+            -1
+        };
+        parser::skip_tagged_fields(req)?;
+        Ok(Self {
+            version,
+            broker_id,
+            cluster_id,
+            incarnation_id,
+            listeners,
+            features,
+            rack,
+            is_migrating_zk_broker,
+            log_dirs,
+            previous_broker_epoch,
+        })
+    }
+
+    pub fn serialize<W: Write>(&self, resp: &mut W) -> errors::Result<()> {
+        writer::write_bytes(resp, &self.broker_id)?;
+        writer::write_string(resp, self.cluster_id.as_bytes(), true)?;
+        writer::write_bytes(resp, &u128::from_be_bytes(self.incarnation_id))?;
+        writer::write_array_len(resp, self.listeners.len(), true)?;
+        for l in &self.listeners {
+            writer::write_string(resp, &l.name, true)?;
+            writer::write_string(resp, &l.host, true)?;
+            writer::write_bytes(resp, &l.port)?;
+            writer::write_bytes(resp, &l.security_protocol)?;
+            writer::write_tagged_fields(resp, true)?;
+        }
+        writer::write_array_len(resp, self.features.len(), true)?;
+        for (name, min, max) in &self.features {
+            writer::write_string(resp, name.as_bytes(), true)?;
+            writer::write_bytes(resp, min)?;
+            writer::write_bytes(resp, max)?;
+            writer::write_tagged_fields(resp, true)?;
+        }
+        writer::write_nullable_string(resp, self.rack.as_ref().map(String::as_bytes), true)?;
+        if self.version >= 1 {
+            writer::write_bytes(resp, &self.is_migrating_zk_broker)?;
+        }
+        if self.version >= 2 {
+            writer::write_array_len(resp, self.log_dirs.len(), true)?;
+            for dir in &self.log_dirs {
+                writer::write_bytes(resp, &u128::from_be_bytes(*dir))?;
+            }
+        }
+        if self.version >= 3 {
+            writer::write_bytes(resp, &self.previous_broker_epoch)?;
+        }
+        writer::write_tagged_fields(resp, true)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct BrokerRegistrationResponse {
+    pub throttle_time_ms: i32,
+    pub error_code: i16,
+    pub broker_epoch: i64,
+}
+
+impl BrokerRegistrationResponse {
+    pub fn new(
+        request: &BrokerRegistrationRequest,
+        controller: &Arc<controller::Controller>,
+    ) -> Self {
+        if request.cluster_id != controller.cluster_id() {
+            return Self {
+                error_code: raft::INCONSISTENT_CLUSTER_ID,
+                broker_epoch: -1,
+                ..Default::default()
+            };
+        }
+        match controller.register_broker(
+            request.broker_id,
+            request.incarnation_id,
+            request.listeners.clone(),
+            request.log_dirs.clone(),
+        ) {
+            Ok(broker_epoch) => Self {
+                broker_epoch,
+                ..Default::default()
+            },
+            Err(e) => {
+                println!("registration of broker {} failed: {e}", request.broker_id);
+                Self {
+                    error_code: controller::error_code(&e),
+                    broker_epoch: -1,
+                    ..Default::default()
+                }
+            }
+        }
+    }
+
+    pub fn read<R: Read>(resp: &mut R) -> errors::Result<Self> {
+        let response = Self {
+            throttle_time_ms: parser::read_int(resp)?,
+            error_code: parser::read_short(resp)?,
+            broker_epoch: parser::read_u64(resp)? as i64,
+        };
+        parser::skip_tagged_fields(resp)?;
+        Ok(response)
+    }
+
+    pub fn serialize<W: Write>(&self, resp: &mut W) -> errors::Result<()> {
+        writer::write_bytes(resp, &self.throttle_time_ms)?;
+        writer::write_bytes(resp, &self.error_code)?;
+        writer::write_bytes(resp, &self.broker_epoch)?;
+        writer::write_tagged_fields(resp, true)
+    }
+}
+
+// registers this node with whichever controller is active, in the
+// background, trying again until one takes it
+pub fn start(
+    controller: Arc<controller::Controller>,
+    request: BrokerRegistrationRequest,
+) -> thread::JoinHandle<()> {
+    thread::spawn(move || loop {
+        match register(&controller, &request) {
+            Ok(epoch) => {
+                println!("registered broker {} with epoch {epoch}", request.broker_id);
+                return;
+            }
+            Err(e) => println!("broker registration: {e}"),
+        }
+        thread::sleep(Duration::from_millis(REGISTRATION_RETRY_MS));
+    })
+}
+
+fn register(
+    controller: &Arc<controller::Controller>,
+    request: &BrokerRegistrationRequest,
+) -> errors::Result<i64> {
+    let raft = controller.raft();
+    let leader = raft.leader_id().ok_or_else(|| {
+        errors::KafkaErrors::RequestFailed(raft::NOT_CONTROLLER, "no active controller".into())
+    })?;
+    if leader == raft.node_id() {
+        return controller.register_broker(
+            request.broker_id,
+            request.incarnation_id,
+            request.listeners.clone(),
+            request.log_dirs.clone(),
+        );
+    }
+    let voter = raft
+        .voters()
+        .into_iter()
+        .find(|v| v.id == leader)
+        .ok_or_else(|| {
+            errors::KafkaErrors::RequestFailed(
+                raft::INCONSISTENT_VOTER_SET,
+                format!("leader {leader} isn't a voter"),
+            )
+        })?;
+    let mut body = vec![];
+    request.serialize(&mut body)?;
+    let mut response = NodeClient::connect(&voter.host, voter.port, Duration::from_secs(5))?.send(
+        apikey::ApiKey::BrokerRegistration,
+        request.version,
+        &body,
+    )?;
+    let response = BrokerRegistrationResponse::read(&mut response)?;
+    if response.error_code != 0 {
+        return Err(errors::KafkaErrors::RequestFailed(
+            response.error_code,
+            format!("controller {leader} turned the registration down"),
+        )
+        .into());
+    }
+    Ok(response.broker_epoch)
+}
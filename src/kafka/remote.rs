@@ -0,0 +1,647 @@
+// tiered storage (KIP-405)
+//
+// Closed segments of topics with remote.storage.enable=true are copied, with
+// their index, to a remote tier through a RemoteStorageManager. Once a
+// segment is there local.retention.ms decides how long it also stays on
+// local disk, while retention.ms applies to the remote copy. Fetches below
+// the local log start offset are served from the remote tier.
+//
+// Which segments live remotely is tracked by the RemoteLogMetadataManager,
+// kept next to the remote data so it outlives the local disks. The only
+// storage shipped is a plain directory, which keeps everything working
+// offline; a network mount can stand in for an object store.
+use crate::kafka::checkpoint::TopicPartition;
+use crate::kafka::log::{self, LogManager, LogSegment, PartitionLog};
+use crate::kafka::zerocopy::FileRegion;
+use crate::kafka::{config, errors, metadata};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+pub const REMOTE_LOG_METADATA_DIR: &str = "__remote_log_metadata";
+const REMOTE_LOG_METADATA_VERSION: u32 = 0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RemoteLogSegmentState {
+    CopySegmentStarted,
+    CopySegmentFinished,
+    DeleteSegmentStarted,
+}
+
+impl RemoteLogSegmentState {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::CopySegmentStarted => "COPY_SEGMENT_STARTED",
+            Self::CopySegmentFinished => "COPY_SEGMENT_FINISHED",
+            Self::DeleteSegmentStarted => "DELETE_SEGMENT_STARTED",
+        }
+    }
+
+    fn parse(s: &str) -> Option<Self> {
+        match s {
+            "COPY_SEGMENT_STARTED" => Some(Self::CopySegmentStarted),
+            "COPY_SEGMENT_FINISHED" => Some(Self::CopySegmentFinished),
+            "DELETE_SEGMENT_STARTED" => Some(Self::DeleteSegmentStarted),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RemoteLogSegmentMetadata {
+    pub topic: String,
+    pub partition: i32,
+    pub base_offset: u64,
+    pub end_offset: u64, // last offset in the segment
+    pub max_timestamp: i64,
+    pub segment_size: u64,
+    pub state: RemoteLogSegmentState,
+}
+
+impl RemoteLogSegmentMetadata {
+    pub fn next_offset(&self) -> u64 {
+        self.end_offset + 1
+    }
+}
+
+// the remote tier itself. Implementations only move bytes around, the
+// bookkeeping of what is where belongs to the RemoteLogManager.
+pub trait RemoteStorageManager: Send + Sync + std::fmt::Debug {
+    // copies a closed segment and its index to the remote tier
+    fn copy_log_segment_data(
+        &self,
+        metadata: &RemoteLogSegmentMetadata,
+        segment: &LogSegment,
+    ) -> errors::Result<()>;
+
+    // the segment data, as a file region covering it from start_position to
+    // the end. Stores that are not file backed stage it in a local file.
+    fn fetch_log_segment(
+        &self,
+        metadata: &RemoteLogSegmentMetadata,
+        start_position: u64,
+    ) -> errors::Result<FileRegion>;
+
+    // the raw offset index that was copied with the segment
+    fn fetch_index(&self, metadata: &RemoteLogSegmentMetadata) -> errors::Result<Vec<u8>>;
+
+    fn delete_log_segment_data(&self, metadata: &RemoteLogSegmentMetadata) -> errors::Result<()>;
+}
+
+// remote storage on a local (or mounted) directory, laid out like a log dir:
+// {root}/{topic}-{partition}/{base offset}.log and .index
+#[derive(Debug)]
+pub struct LocalTieredStorage {
+    root: PathBuf,
+}
+
+impl LocalTieredStorage {
+    pub fn new(root: &Path) -> errors::Result<Self> {
+        fs::create_dir_all(root)?;
+        Ok(Self {
+            root: root.to_path_buf(),
+        })
+    }
+
+    fn segment_path(&self, metadata: &RemoteLogSegmentMetadata) -> PathBuf {
+        self.root
+            .join(log::partition_dir_name(&metadata.topic, metadata.partition))
+            .join(log::segment_file_name(metadata.base_offset))
+    }
+
+    fn index_path(&self, metadata: &RemoteLogSegmentMetadata) -> PathBuf {
+        self.segment_path(metadata)
+            .with_extension(log::INDEX_FILE_SUFFIX)
+    }
+}
+
+fn tmp_path(path: &Path) -> PathBuf {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    PathBuf::from(tmp)
+}
+
+// copies through a temporary name so a crash never leaves a partial file
+// under the final one
+fn copy_file(from: &Path, to: &Path) -> errors::Result<()> {
+    let tmp = tmp_path(to);
+    fs::copy(from, &tmp)?;
+    File::open(&tmp)?.sync_all()?;
+    fs::rename(&tmp, to)?;
+    Ok(())
+}
+
+impl RemoteStorageManager for LocalTieredStorage {
+    fn copy_log_segment_data(
+        &self,
+        metadata: &RemoteLogSegmentMetadata,
+        segment: &LogSegment,
+    ) -> errors::Result<()> {
+        let path = self.segment_path(metadata);
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        copy_file(&segment.path, &path)?;
+        copy_file(&segment.index_path(), &self.index_path(metadata))?;
+        Ok(())
+    }
+
+    fn fetch_log_segment(
+        &self,
+        metadata: &RemoteLogSegmentMetadata,
+        start_position: u64,
+    ) -> errors::Result<FileRegion> {
+        let file = File::open(self.segment_path(metadata))?;
+        Ok(FileRegion {
+            file: Arc::new(file),
+            position: start_position,
+            length: metadata.segment_size.saturating_sub(start_position),
+        })
+    }
+
+    fn fetch_index(&self, metadata: &RemoteLogSegmentMetadata) -> errors::Result<Vec<u8>> {
+        Ok(fs::read(self.index_path(metadata))?)
+    }
+
+    fn delete_log_segment_data(&self, metadata: &RemoteLogSegmentMetadata) -> errors::Result<()> {
+        for path in [self.segment_path(metadata), self.index_path(metadata)] {
+            match fs::remove_file(&path) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                _ => (),
+            }
+        }
+        Ok(())
+    }
+}
+
+type SegmentMap = BTreeMap<u64, RemoteLogSegmentMetadata>;
+
+// what has been copied to the remote tier, one file per partition under
+// {root}/__remote_log_metadata, in the same line based layout as the offset
+// checkpoints: version, count, then one segment per line
+#[derive(Debug)]
+pub struct RemoteLogMetadataManager {
+    dir: PathBuf,
+    cache: Mutex<HashMap<TopicPartition, SegmentMap>>,
+}
+
+impl RemoteLogMetadataManager {
+    pub fn new(root: &Path) -> errors::Result<Self> {
+        let dir = root.join(REMOTE_LOG_METADATA_DIR);
+        fs::create_dir_all(&dir)?;
+        Ok(Self {
+            dir,
+            cache: Mutex::new(HashMap::new()),
+        })
+    }
+
+    fn path(&self, tp: &TopicPartition) -> PathBuf {
+        self.dir.join(log::partition_dir_name(&tp.0, tp.1))
+    }
+
+    fn load(&self, tp: &TopicPartition) -> errors::Result<SegmentMap> {
+        let contents = match fs::read_to_string(self.path(tp)) {
+            Ok(c) => c,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(SegmentMap::new()),
+            Err(e) => return Err(e.into()),
+        };
+        let invalid = |what: &str| {
+            errors::KafkaErrors::InvalidCheckpoint(format!("{}: {what}", self.path(tp).display()))
+        };
+        let mut lines = contents.lines();
+        if lines.next().and_then(|v| v.trim().parse::<u32>().ok())
+            != Some(REMOTE_LOG_METADATA_VERSION)
+        {
+            return Err(invalid("unsupported version").into());
+        }
+        let count: usize = lines
+            .next()
+            .and_then(|c| c.trim().parse().ok())
+            .ok_or_else(|| invalid("missing count"))?;
+        let mut segments = SegmentMap::new();
+        for line in lines.take(count) {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let [base, end, max_ts, size, state] = fields[..] else {
+                return Err(invalid(line).into());
+            };
+            let metadata = RemoteLogSegmentMetadata {
+                topic: tp.0.clone(),
+                partition: tp.1,
+                base_offset: base.parse()?,
+                end_offset: end.parse()?,
+                max_timestamp: max_ts.parse()?,
+                segment_size: size.parse()?,
+                state: RemoteLogSegmentState::parse(state).ok_or_else(|| invalid(state))?,
+            };
+            segments.insert(metadata.base_offset, metadata);
+        }
+        Ok(segments)
+    }
+
+    fn store(&self, tp: &TopicPartition, segments: &SegmentMap) -> errors::Result<()> {
+        let path = self.path(tp);
+        let tmp = tmp_path(&path);
+        let mut f = File::create(&tmp)?;
+        writeln!(f, "{REMOTE_LOG_METADATA_VERSION}")?;
+        writeln!(f, "{}", segments.len())?;
+        for s in segments.values() {
+            writeln!(
+                f,
+                "{} {} {} {} {}",
+                s.base_offset,
+                s.end_offset,
+                s.max_timestamp,
+                s.segment_size,
+                s.state.as_str()
+            )?;
+        }
+        f.sync_all()?;
+        fs::rename(&tmp, &path)?;
+        Ok(())
+    }
+
+    // runs f over the partition's segments, loading them on first use
+    fn with_segments<T>(
+        &self,
+        tp: &TopicPartition,
+        f: impl FnOnce(&mut SegmentMap) -> errors::Result<T>,
+    ) -> errors::Result<T> {
+        let mut cache = self.cache.lock().unwrap();
+        if !cache.contains_key(tp) {
+            cache.insert(tp.clone(), self.load(tp)?);
+        }
+        f(cache.get_mut(tp).unwrap())
+    }
+
+    fn update(&self, tp: &TopicPartition, f: impl FnOnce(&mut SegmentMap)) -> errors::Result<()> {
+        self.with_segments(tp, |segments| {
+            f(segments);
+            self.store(tp, segments)
+        })
+    }
+
+    // all known segments in offset order, whatever their state
+    pub fn list_segments(
+        &self,
+        tp: &TopicPartition,
+    ) -> errors::Result<Vec<RemoteLogSegmentMetadata>> {
+        self.with_segments(tp, |segments| Ok(segments.values().cloned().collect()))
+    }
+
+    pub fn put(&self, metadata: &RemoteLogSegmentMetadata) -> errors::Result<()> {
+        let tp = (metadata.topic.clone(), metadata.partition);
+        self.update(&tp, |segments| {
+            segments.insert(metadata.base_offset, metadata.clone());
+        })
+    }
+
+    pub fn remove(&self, metadata: &RemoteLogSegmentMetadata) -> errors::Result<()> {
+        let tp = (metadata.topic.clone(), metadata.partition);
+        self.update(&tp, |segments| {
+            segments.remove(&metadata.base_offset);
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct RemoteLogManager {
+    storage: Box<dyn RemoteStorageManager>,
+    metadata: RemoteLogMetadataManager,
+}
+
+impl RemoteLogManager {
+    pub fn new(config: &config::BrokerConfig) -> errors::Result<Self> {
+        let root = PathBuf::from(&config.remote_log_storage_dir);
+        Ok(Self {
+            storage: Box::new(LocalTieredStorage::new(&root)?),
+            metadata: RemoteLogMetadataManager::new(&root)?,
+        })
+    }
+
+    // segments whose copy completed, the only ones fetches may use
+    fn copied_segments(
+        &self,
+        tp: &TopicPartition,
+    ) -> errors::Result<Vec<RemoteLogSegmentMetadata>> {
+        Ok(self
+            .metadata
+            .list_segments(tp)?
+            .into_iter()
+            .filter(|s| s.state == RemoteLogSegmentState::CopySegmentFinished)
+            .collect())
+    }
+
+    // offset following the last one in the remote tier, 0 when nothing was
+    // copied yet. Local segments below it may be dropped.
+    pub fn copied_upto(&self, tp: &TopicPartition) -> errors::Result<u64> {
+        Ok(self
+            .copied_segments(tp)?
+            .last()
+            .map_or(0, RemoteLogSegmentMetadata::next_offset))
+    }
+
+    pub fn log_start_offset(&self, tp: &TopicPartition) -> Option<u64> {
+        self.copied_segments(tp)
+            .ok()?
+            .first()
+            .map(|s| s.base_offset)
+    }
+
+    // copies every closed segment that isn't remote yet, oldest first
+    pub fn copy_log_segments(
+        &self,
+        tp: &TopicPartition,
+        segments: &[LogSegment],
+    ) -> errors::Result<usize> {
+        self.abort_unfinished(tp)?;
+        let copied_upto = self.copied_upto(tp)?;
+        let mut copied = 0;
+        for segment in segments
+            .iter()
+            .filter(|s| !s.is_empty() && s.next_offset > copied_upto)
+        {
+            let mut metadata = RemoteLogSegmentMetadata {
+                topic: tp.0.clone(),
+                partition: tp.1,
+                base_offset: segment.base_offset,
+                end_offset: segment.next_offset - 1,
+                max_timestamp: segment.max_timestamp,
+                segment_size: segment.size,
+                state: RemoteLogSegmentState::CopySegmentStarted,
+            };
+            self.metadata.put(&metadata)?;
+            self.storage.copy_log_segment_data(&metadata, segment)?;
+            metadata.state = RemoteLogSegmentState::CopySegmentFinished;
+            self.metadata.put(&metadata)?;
+            copied += 1;
+        }
+        Ok(copied)
+    }
+
+    // cleans up after copies or deletes a crash interrupted
+    fn abort_unfinished(&self, tp: &TopicPartition) -> errors::Result<()> {
+        for s in self.metadata.list_segments(tp)? {
+            if s.state != RemoteLogSegmentState::CopySegmentFinished {
+                self.storage.delete_log_segment_data(&s)?;
+                self.metadata.remove(&s)?;
+            }
+        }
+        Ok(())
+    }
+
+    // applies retention.ms to the remote copy
+    pub fn delete_expired_segments(
+        &self,
+        tp: &TopicPartition,
+        config: &config::TopicConfig,
+        now: i64,
+    ) -> errors::Result<usize> {
+        if config.retention_ms < 0 {
+            return Ok(0);
+        }
+        let horizon = now - config.retention_ms;
+        let mut deleted = 0;
+        for mut s in self
+            .copied_segments(tp)?
+            .into_iter()
+            .take_while(|s| s.max_timestamp < horizon)
+        {
+            s.state = RemoteLogSegmentState::DeleteSegmentStarted;
+            self.metadata.put(&s)?;
+            self.storage.delete_log_segment_data(&s)?;
+            self.metadata.remove(&s)?;
+            deleted += 1;
+        }
+        Ok(deleted)
+    }
+
+    // same contract as PartitionLog::read, over the remote tier
+    pub fn read(
+        &self,
+        tp: &TopicPartition,
+        fetch_offset: u64,
+        max_bytes: u64,
+    ) -> errors::Result<Option<FileRegion>> {
+        let segments = self.copied_segments(tp)?;
+        if !segments
+            .first()
+            .is_some_and(|s| fetch_offset >= s.base_offset)
+        {
+            return Err(errors::KafkaErrors::OffsetOutOfRange(format!(
+                "{fetch_offset} is not in remote storage for {}-{}",
+                tp.0, tp.1
+            ))
+            .into());
+        }
+        for s in segments.iter().filter(|s| s.next_offset() > fetch_offset) {
+            let index = log::decode_index(s.base_offset, &self.storage.fetch_index(s)?);
+            let start = log::index_floor(&index, fetch_offset);
+            let region = self.storage.fetch_log_segment(s, 0)?;
+            let end = region.position + region.length;
+            if let Some(position) =
+                log::find_batch_position(&region.file, start, end, fetch_offset)?
+            {
+                return Ok(Some(log::batches_region(
+                    region.file,
+                    position,
+                    end,
+                    max_bytes,
+                )?));
+            }
+        }
+        Ok(None)
+    }
+}
+
+// background task copying segments to the remote tier and expiring them
+// there, one pass every remote.log.manager.task.interval.ms
+pub struct RemoteLogTask {
+    logs: Arc<LogManager>,
+    metadata: Arc<metadata::MetadataCache>,
+}
+
+impl RemoteLogTask {
+    pub fn new(logs: Arc<LogManager>, metadata: Arc<metadata::MetadataCache>) -> Self {
+        Self { logs, metadata }
+    }
+
+    pub fn start(self, interval_ms: u64) -> thread::JoinHandle<()> {
+        thread::spawn(move || loop {
+            thread::sleep(Duration::from_millis(interval_ms));
+            if let Err(e) = self.run_once() {
+                println!("remote log task failed: {e}");
+            }
+        })
+    }
+
+    pub fn run_once(&self) -> errors::Result<()> {
+        let Some(remote) = self.logs.remote() else {
+            return Ok(());
+        };
+        let now = log::now_ms();
+        for log in self.logs.all_logs() {
+            // only closed segments are copied, so a snapshot is enough and
+            // appends don't wait for the copy
+            let (tp, closed) = {
+                let log = log.lock().unwrap();
+                (log.topic_partition(), closed_segments(&log))
+            };
+            let config = self.metadata.image().topic_config(&tp.0);
+            // compacted topics rewrite segments in place, they can't be tiered
+            if !config.remote_storage_enable || config.cleanup_policy.compact {
+                continue;
+            }
+            let copied = remote.copy_log_segments(&tp, &closed)?;
+            let deleted = remote.delete_expired_segments(&tp, &config, now)?;
+            if copied > 0 || deleted > 0 {
+                println!(
+                    "{}-{}: copied {copied} segments to remote storage, expired {deleted}",
+                    tp.0, tp.1
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+fn closed_segments(log: &PartitionLog) -> Vec<LogSegment> {
+    let active = log.active_segment().base_offset;
+    log.segments
+        .values()
+        .filter(|s| s.base_offset != active)
+        .cloned()
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kafka::cleaner::LogCleaner;
+    use crate::kafka::metadata::{MetadataCache, MetadataImage};
+    use crate::kafka::metadata_records::CONFIG_RESOURCE_TOPIC;
+    use crate::kafka::records;
+    use crate::kafka::testing::{record, records_batch, temp_root};
+
+    fn batch(timestamp: i64) -> Vec<u8> {
+        records::RecordsBatch {
+            base_timestamp: timestamp,
+            max_timestamp: timestamp,
+            ..records_batch(vec![record(0)])
+        }
+        .to_bytes()
+        .unwrap()
+    }
+
+    fn publish_config(metadata: &MetadataCache, overrides: &[(&str, String)]) {
+        let overrides = overrides
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.clone()))
+            .collect();
+        metadata.publish(MetadataImage {
+            configs: HashMap::from([((CONFIG_RESOURCE_TOPIC, "events".to_string()), overrides)]),
+            ..Default::default()
+        });
+    }
+
+    #[test]
+    fn test_tiered_segments_copied_expired_and_fetched() {
+        let root = temp_root("remote-tier");
+        let (local, remote_dir) = (root.join("local"), root.join("remote"));
+        let logs = Arc::new(
+            LogManager::open(&config::BrokerConfig {
+                log_dirs: vec![local.display().to_string()],
+                remote_log_storage_system_enable: true,
+                remote_log_storage_dir: remote_dir.display().to_string(),
+                ..Default::default()
+            })
+            .unwrap(),
+        );
+        let metadata = Arc::new(MetadataCache::default());
+        let timestamp = log::now_ms() - 10_000;
+        let size = batch(timestamp).len();
+        publish_config(
+            &metadata,
+            &[
+                (config::REMOTE_STORAGE_ENABLE_CONFIG, "true".to_string()),
+                (config::SEGMENT_BYTES_CONFIG, size.to_string()),
+                (config::LOCAL_RETENTION_MS_CONFIG, "0".to_string()),
+                (config::RETENTION_MS_CONFIG, "3600000".to_string()),
+            ],
+        );
+        // one batch per segment, the last one stays active
+        let config = metadata.image().topic_config("events");
+        for _ in 0..3 {
+            logs.with_log("events", 0, |l| l.append(&batch(timestamp), &config))
+                .unwrap();
+        }
+        let tp = ("events".to_string(), 0);
+
+        // nothing is dropped locally before it is in the remote tier
+        let cleaner = LogCleaner::new(Arc::clone(&logs), Arc::clone(&metadata));
+        assert_eq!(cleaner.clean_all().unwrap().segments_deleted, 0);
+
+        RemoteLogTask::new(Arc::clone(&logs), Arc::clone(&metadata))
+            .run_once()
+            .unwrap();
+        let remote = logs.remote().unwrap();
+        assert_eq!(remote.copied_upto(&tp).unwrap(), 2);
+        let partition_dir = remote_dir.join(log::partition_dir_name("events", 0));
+        for base_offset in [0, 1] {
+            let segment = partition_dir.join(log::segment_file_name(base_offset));
+            assert_eq!(fs::read(&segment).unwrap().len(), size);
+            assert!(segment.with_extension(log::INDEX_FILE_SUFFIX).exists());
+        }
+        assert!(!partition_dir.join(log::segment_file_name(2)).exists());
+
+        // local.retention.ms drops the copied segments from local disk
+        assert_eq!(cleaner.clean_all().unwrap().segments_deleted, 2);
+        let log = logs.get("events", 0).unwrap();
+        let log = log.lock().unwrap();
+        assert_eq!(log.log_start_offset, 2);
+        assert_eq!(log.segments.len(), 1);
+        assert_eq!(logs.log_start_offset(&log), 0);
+
+        // fetches below the local log start are served from the remote tier
+        for offset in [0, 1] {
+            let region = logs.read(&log, offset, u64::MAX).unwrap().unwrap();
+            let data = region.read_to_vec().unwrap();
+            assert_eq!(data.len(), size);
+            assert_eq!(u64::from_be_bytes(data[..8].try_into().unwrap()), offset);
+        }
+        assert_eq!(
+            logs.read(&log, 2, u64::MAX)
+                .unwrap()
+                .unwrap()
+                .read_to_vec()
+                .unwrap()
+                .len(),
+            size
+        );
+        drop(log);
+
+        // retention.ms then expires the remote copies
+        publish_config(
+            &metadata,
+            &[
+                (config::REMOTE_STORAGE_ENABLE_CONFIG, "true".to_string()),
+                (config::RETENTION_MS_CONFIG, "1000".to_string()),
+            ],
+        );
+        RemoteLogTask::new(Arc::clone(&logs), Arc::clone(&metadata))
+            .run_once()
+            .unwrap();
+        assert_eq!(remote.log_start_offset(&tp), None);
+        assert!(!partition_dir.join(log::segment_file_name(0)).exists());
+        let log = logs.get("events", 0).unwrap();
+        let read = logs.read(&log.lock().unwrap(), 0, u64::MAX);
+        assert!(matches!(
+            read.unwrap_err().downcast_ref::<errors::KafkaErrors>(),
+            Some(errors::KafkaErrors::OffsetOutOfRange(_))
+        ));
+        let _ = fs::remove_dir_all(&root);
+    }
+}
@@ -0,0 +1,657 @@
+// metadata snapshots, <end offset>-<epoch>.checkpoint files next to the
+// __cluster_metadata log segments, in the layout the java controller uses:
+// record batches holding the whole image as metadata records, between a
+// SnapshotHeader and a SnapshotFooter control record. The end offset is
+// exclusive, the log is replayed from there on top of the snapshot.
+//
+// Snapshots are written to a .checkpoint.part file which is renamed once
+// complete, so a .checkpoint file is never partial. That goes for the ones
+// a lagging replica downloads from the leader with FetchSnapshot too.
+use crate::kafka::errors::{self, KafkaErrors};
+use crate::kafka::metadata::{MetadataDelta, MetadataImage};
+use crate::kafka::metadata_records::KafkaRecordValue;
+use crate::kafka::records::{self, BatchHeader, KafkaRecord, RecordsBatch};
+use crate::kafka::{parser, voters, writer};
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Cursor, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+pub const SNAPSHOT_FILE_SUFFIX: &str = "checkpoint";
+const PARTIAL_SNAPSHOT_FILE_SUFFIX: &str = "part";
+
+// control record types (the key of a record in a control batch)
+const CONTROL_TYPE_SNAPSHOT_HEADER: i16 = 3;
+const CONTROL_TYPE_SNAPSHOT_FOOTER: i16 = 4;
+const CONTROL_RECORD_VERSION: i16 = 0;
+
+// metadata records per batch in a snapshot
+const RECORDS_PER_BATCH: usize = 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct SnapshotId {
+    pub end_offset: u64,
+    pub epoch: i32,
+}
+
+impl SnapshotId {
+    pub fn file_name(&self) -> String {
+        format!(
+            "{:020}-{:010}.{SNAPSHOT_FILE_SUFFIX}",
+            self.end_offset, self.epoch
+        )
+    }
+
+    pub fn parse(file_name: &str) -> Option<Self> {
+        let stem = file_name.strip_suffix(&format!(".{SNAPSHOT_FILE_SUFFIX}"))?;
+        let (end_offset, epoch) = stem.split_once('-')?;
+        Some(Self {
+            end_offset: end_offset.parse().ok()?,
+            epoch: epoch.parse().ok()?,
+        })
+    }
+}
+
+// the snapshots in dir, oldest first. Ones still being written or
+// downloaded don't count.
+pub fn list(dir: &Path) -> errors::Result<Vec<SnapshotId>> {
+    let mut ids = vec![];
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let name = path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .unwrap_or_default();
+        if let Some(id) = SnapshotId::parse(name) {
+            ids.push(id);
+        }
+    }
+    ids.sort();
+    Ok(ids)
+}
+
+// the image from the newest snapshot that reads back fine, an empty one
+// if there's none. Leftovers of an interrupted write are removed first.
+pub fn load_latest(dir: &Path) -> errors::Result<MetadataImage> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.to_string_lossy().ends_with(&format!(
+            ".{SNAPSHOT_FILE_SUFFIX}.{PARTIAL_SNAPSHOT_FILE_SUFFIX}"
+        )) {
+            fs::remove_file(&path)?;
+        }
+    }
+    for id in list(dir)?.into_iter().rev() {
+        match read(dir, id) {
+            Ok(image) => {
+                println!("loaded metadata snapshot {}", id.file_name());
+                return Ok(image);
+            }
+            Err(e) => println!("skipping metadata snapshot {}: {e}", id.file_name()),
+        }
+    }
+    Ok(MetadataImage::default())
+}
+
+pub fn read(dir: &Path, id: SnapshotId) -> errors::Result<MetadataImage> {
+    let mut delta = MetadataDelta::new(&MetadataImage::default());
+    // unlike the log, a record that doesn't apply means a broken snapshot
+    for (offset, value) in read_records(&dir.join(id.file_name()))?.iter().enumerate() {
+        delta.replay(offset as u64, value)?;
+    }
+    let mut image = delta.apply();
+    image.offset = id.end_offset.checked_sub(1);
+    image.epoch = id.epoch;
+    Ok(image)
+}
+
+// the metadata records of a file in snapshot layout, checking it's complete
+pub fn read_records(path: &Path) -> errors::Result<Vec<KafkaRecordValue>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut values = vec![];
+    let mut header = false;
+    let mut footer = false;
+    while let Some(batch) = records::RawBatch::read(&mut reader)? {
+        if footer {
+            return Err(corrupt(path, "records after the footer"));
+        }
+        if batch.is_control() {
+            for rec in batch.iter_records()? {
+                match control_type(&rec?)? {
+                    CONTROL_TYPE_SNAPSHOT_HEADER if !header => header = true,
+                    CONTROL_TYPE_SNAPSHOT_FOOTER if header => footer = true,
+                    // the voter set, raft's business
+                    voters::CONTROL_TYPE_KRAFT_VERSION | voters::CONTROL_TYPE_VOTERS if header => {}
+                    t => return Err(corrupt(path, &format!("unexpected control record {t}"))),
+                }
+            }
+            continue;
+        }
+        if !header {
+            return Err(corrupt(path, "no header"));
+        }
+        for rec in batch.iter_records()? {
+            values.push(rec?.metadata_value()?);
+        }
+    }
+    if !footer {
+        return Err(corrupt(path, "no footer"));
+    }
+    Ok(values)
+}
+
+// the control records of a snapshot besides its header and footer, by
+// type, the voter set a formatted controller starts with among them
+pub fn control_records(path: &Path) -> errors::Result<Vec<(i16, Vec<u8>)>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut controls = vec![];
+    while let Some(batch) = records::RawBatch::read(&mut reader)? {
+        if !batch.is_control() {
+            continue;
+        }
+        for rec in batch.iter_records()? {
+            let rec = rec?;
+            match control_type(&rec)? {
+                CONTROL_TYPE_SNAPSHOT_HEADER | CONTROL_TYPE_SNAPSHOT_FOOTER => (),
+                t => controls.push((t, rec.value.unwrap_or_default())),
+            }
+        }
+    }
+    Ok(controls)
+}
+
+// writes a snapshot of image, which must have applied some records, with
+// the voter set control records as of then
+pub fn write(
+    dir: &Path,
+    image: &MetadataImage,
+    controls: &[(i16, Vec<u8>)],
+    last_contained_timestamp: i64,
+) -> errors::Result<SnapshotId> {
+    let Some(offset) = image.offset else {
+        return Err(
+            KafkaErrors::InvalidCheckpoint("no metadata records to snapshot".to_string()).into(),
+        );
+    };
+    let id = SnapshotId {
+        end_offset: offset + 1,
+        epoch: image.epoch,
+    };
+    write_records(
+        &dir.join(id.file_name()),
+        id.epoch,
+        controls,
+        &image.records(),
+        last_contained_timestamp,
+    )?;
+    Ok(id)
+}
+
+// writes records in snapshot layout, header and footer around them and
+// any control records right after the header. The file only shows up under
+// its name once complete.
+pub fn write_records(
+    path: &Path,
+    epoch: i32,
+    controls: &[(i16, Vec<u8>)],
+    values: &[KafkaRecordValue],
+    last_contained_timestamp: i64,
+) -> errors::Result<()> {
+    let mut part = path.as_os_str().to_owned();
+    part.push(format!(".{PARTIAL_SNAPSHOT_FILE_SUFFIX}"));
+    let part = PathBuf::from(part);
+    let mut out = BufWriter::new(File::create(&part)?);
+    let mut base_offset = 0;
+
+    let mut header = vec![];
+    writer::write_bytes(&mut header, &CONTROL_RECORD_VERSION)?;
+    writer::write_bytes(&mut header, &last_contained_timestamp)?;
+    writer::write_uvarint(&mut header, 0)?; // tagged fields
+    let batch = control_batch(
+        epoch,
+        last_contained_timestamp,
+        CONTROL_TYPE_SNAPSHOT_HEADER,
+        header,
+    )?;
+    batch.serialize(&mut out)?;
+    base_offset += 1;
+
+    for (control_type, value) in controls {
+        let mut batch = control_batch(
+            epoch,
+            last_contained_timestamp,
+            *control_type,
+            value.clone(),
+        )?;
+        batch.base_offset = base_offset;
+        batch.serialize(&mut out)?;
+        base_offset += 1;
+    }
+
+    for chunk in values.chunks(RECORDS_PER_BATCH) {
+        let records = chunk
+            .iter()
+            .enumerate()
+            .map(|(i, value)| {
+                Ok(KafkaRecord {
+                    offset_delta: i as i32,
+                    value: Some(value.to_bytes()?),
+                    ..KafkaRecord::new()
+                })
+            })
+            .collect::<errors::Result<Vec<_>>>()?;
+        batch_of(epoch, base_offset, last_contained_timestamp, 0, records).serialize(&mut out)?;
+        base_offset += chunk.len() as u64;
+    }
+
+    let mut footer = vec![];
+    writer::write_bytes(&mut footer, &CONTROL_RECORD_VERSION)?;
+    writer::write_uvarint(&mut footer, 0)?;
+    let mut batch = control_batch(
+        epoch,
+        last_contained_timestamp,
+        CONTROL_TYPE_SNAPSHOT_FOOTER,
+        footer,
+    )?;
+    batch.base_offset = base_offset;
+    batch.serialize(&mut out)?;
+
+    let file = out.into_inner().map_err(|e| e.into_error())?;
+    file.sync_all()?;
+    fs::rename(&part, path)?;
+    if let Some(dir) = path.parent() {
+        File::open(dir)?.sync_all()?;
+    }
+    Ok(())
+}
+
+// the newest snapshot, None if there's none
+pub fn latest(dir: &Path) -> errors::Result<Option<SnapshotId>> {
+    Ok(list(dir)?.pop())
+}
+
+// up to max_bytes of a snapshot file from position on, for FetchSnapshot,
+// and the size of the whole file
+pub fn read_chunk(
+    dir: &Path,
+    id: SnapshotId,
+    position: u64,
+    max_bytes: u64,
+) -> errors::Result<(u64, Vec<u8>)> {
+    let mut file = File::open(dir.join(id.file_name()))?;
+    let size = file.metadata()?.len();
+    file.seek(SeekFrom::Start(position))?;
+    let mut chunk = vec![];
+    file.take(max_bytes).read_to_end(&mut chunk)?;
+    Ok((size, chunk))
+}
+
+// a snapshot fetched from the leader chunk by chunk into its .part file,
+// only renamed into place once it reads back as a complete snapshot
+#[derive(Debug)]
+pub struct SnapshotDownload {
+    path: PathBuf,
+    part: PathBuf,
+    file: File,
+    pub position: u64,
+}
+
+impl SnapshotDownload {
+    pub fn create(dir: &Path, id: SnapshotId) -> errors::Result<Self> {
+        let path = dir.join(id.file_name());
+        let mut part = path.as_os_str().to_owned();
+        part.push(format!(".{PARTIAL_SNAPSHOT_FILE_SUFFIX}"));
+        let part = PathBuf::from(part);
+        Ok(Self {
+            file: File::create(&part)?,
+            path,
+            part,
+            position: 0,
+        })
+    }
+
+    pub fn append(&mut self, chunk: &[u8]) -> errors::Result<()> {
+        self.file.write_all(chunk)?;
+        self.position += chunk.len() as u64;
+        Ok(())
+    }
+
+    pub fn finish(self) -> errors::Result<()> {
+        self.file.sync_all()?;
+        if let Err(e) = read_records(&self.part) {
+            fs::remove_file(&self.part)?;
+            return Err(e);
+        }
+        fs::rename(&self.part, &self.path)?;
+        if let Some(dir) = self.path.parent() {
+            File::open(dir)?.sync_all()?;
+        }
+        Ok(())
+    }
+}
+
+// drops the snapshots older than id, the log still covers everything after
+// them
+pub fn delete_before(dir: &Path, id: SnapshotId) -> errors::Result<()> {
+    for older in list(dir)?.into_iter().filter(|s| *s < id) {
+        fs::remove_file(dir.join(older.file_name()))?;
+    }
+    Ok(())
+}
+
+// writes a snapshot whenever the metadata log grew by
+// metadata.log.max.record.bytes.between.snapshots since the last one
+#[derive(Debug)]
+pub struct SnapshotGenerator {
+    dir: PathBuf,
+    max_bytes_between: u64,
+    bytes_since: u64,
+    // the latest KRaftVersion and Voters records, carried into snapshots
+    // so the voter set outlives the log segments holding it
+    controls: Vec<(i16, Vec<u8>)>,
+}
+
+impl SnapshotGenerator {
+    pub fn new(dir: &Path, max_bytes_between: u64) -> Self {
+        Self {
+            dir: dir.to_path_buf(),
+            max_bytes_between,
+            bytes_since: 0,
+            controls: vec![],
+        }
+    }
+
+    // starts over from the snapshot the image was just loaded from
+    pub fn snapshot_loaded(&mut self, id: Option<SnapshotId>) {
+        self.bytes_since = 0;
+        self.controls = id
+            .and_then(|id| control_records(&self.dir.join(id.file_name())).ok())
+            .unwrap_or_default();
+    }
+
+    // counts a batch just replayed into image. A failed write is only
+    // logged, the next batch tries again.
+    pub fn batch_applied(&mut self, batch: &records::RawBatch, image: &MetadataImage) {
+        self.bytes_since += batch.size() as u64;
+        if batch.is_control() {
+            self.control_batch_applied(batch);
+        }
+        if self.bytes_since < self.max_bytes_between {
+            return;
+        }
+        let result = write(&self.dir, image, &self.controls, batch.max_timestamp())
+            .and_then(|id| delete_before(&self.dir, id).map(|_| id));
+        match result {
+            Ok(id) => {
+                println!("wrote metadata snapshot {}", id.file_name());
+                self.bytes_since = 0;
+            }
+            Err(e) => println!("unable to write metadata snapshot: {e}"),
+        }
+    }
+
+    // keeps the latest voter set records seen
+    fn control_batch_applied(&mut self, batch: &records::RawBatch) {
+        let Ok(recs) = batch.iter_records() else {
+            return;
+        };
+        for rec in recs.flatten() {
+            let Ok(t) = control_type(&rec) else {
+                continue;
+            };
+            if t == voters::CONTROL_TYPE_KRAFT_VERSION || t == voters::CONTROL_TYPE_VOTERS {
+                self.controls.retain(|(c, _)| *c != t);
+                self.controls.push((t, rec.value.unwrap_or_default()));
+                self.controls.sort_by_key(|(c, _)| *c);
+            }
+        }
+    }
+}
+
+pub fn control_batch(
+    epoch: i32,
+    timestamp: i64,
+    control_type: i16,
+    value: Vec<u8>,
+) -> errors::Result<RecordsBatch> {
+    let mut key = vec![];
+    writer::write_bytes(&mut key, &CONTROL_RECORD_VERSION)?;
+    writer::write_bytes(&mut key, &control_type)?;
+    let record = KafkaRecord {
+        key: Some(key),
+        value: Some(value),
+        ..KafkaRecord::new()
+    };
+    Ok(batch_of(
+        epoch,
+        0,
+        timestamp,
+        records::CONTROL_FLAG_MASK,
+        vec![record],
+    ))
+}
+
+fn batch_of(
+    epoch: i32,
+    base_offset: u64,
+    timestamp: i64,
+    attributes: i16,
+    records: Vec<KafkaRecord>,
+) -> RecordsBatch {
+    RecordsBatch {
+        base_offset,
+        partition_leader_epoch: epoch,
+        attributes,
+        last_offset_delta: records.len() as i32 - 1,
+        base_timestamp: timestamp,
+        max_timestamp: timestamp,
+        records,
+        ..RecordsBatch::new()
+    }
+}
+
+// the type of a control record, from its key
+pub fn control_type(rec: &KafkaRecord) -> errors::Result<i16> {
+    let key = rec.key.as_deref().unwrap_or_default();
+    let mut cursor = Cursor::new(key);
+    let _version = parser::read_short(&mut cursor)?;
+    parser::read_short(&mut cursor)
+}
+
+fn corrupt(path: &Path, what: &str) -> anyhow::Error {
+    KafkaErrors::InvalidCheckpoint(format!("{}: {what}", path.display())).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kafka::metadata::MetadataCache;
+    use crate::kafka::metadata_listener::MetadataListener;
+    use crate::kafka::metadata_records::{
+        KafkaRecordConfigRecord, KafkaRecordPartitionRecord, KafkaRecordTopicRecord,
+        CONFIG_RESOURCE_TOPIC,
+    };
+    use crate::kafka::testing::temp_root;
+    use std::sync::atomic::AtomicU64;
+    use std::sync::Arc;
+
+    fn topic(name: &str, uuid: u8) -> Vec<KafkaRecordValue> {
+        vec![
+            KafkaRecordValue::KafkaRecordTopicRecordType(KafkaRecordTopicRecord {
+                topic_name: name.as_bytes().to_vec(),
+                topic_uuid: [uuid; 16],
+                ..Default::default()
+            }),
+            KafkaRecordValue::KafkaRecordPartitionType(KafkaRecordPartitionRecord {
+                topic_uuid: [uuid; 16],
+                replicas: vec![1],
+                isr: vec![1],
+                leader: 1,
+                ..Default::default()
+            }),
+        ]
+    }
+
+    // a metadata log segment with one batch per group of records
+    fn segment(base_offset: u64, groups: &[Vec<KafkaRecordValue>]) -> Vec<u8> {
+        let mut out = vec![];
+        let mut offset = base_offset;
+        for group in groups {
+            let records = group
+                .iter()
+                .enumerate()
+                .map(|(i, v)| KafkaRecord {
+                    offset_delta: i as i32,
+                    value: Some(v.to_bytes().unwrap()),
+                    ..KafkaRecord::new()
+                })
+                .collect();
+            batch_of(1, offset, 0, 0, records)
+                .serialize(&mut out)
+                .unwrap();
+            offset += group.len() as u64;
+        }
+        out
+    }
+
+    fn listener(dir: &Path, max_bytes_between: u64) -> (MetadataListener, Arc<MetadataCache>) {
+        let metadata = Arc::new(MetadataCache::default());
+        let snapshots = SnapshotGenerator::new(dir, max_bytes_between);
+        let mut listener = MetadataListener::new(
+            dir,
+            Arc::clone(&metadata),
+            snapshots,
+            Arc::new(AtomicU64::new(u64::MAX)),
+        );
+        listener.poll().unwrap();
+        (listener, metadata)
+    }
+
+    #[test]
+    fn test_snapshot_then_log_suffix() {
+        let dir = temp_root("snapshot-test");
+        fs::create_dir_all(&dir).unwrap();
+
+        let mut config = topic("foo", 1);
+        config.push(KafkaRecordValue::KafkaRecordConfigType(
+            KafkaRecordConfigRecord {
+                resource_type: CONFIG_RESOURCE_TOPIC,
+                resource_name: b"foo".to_vec(),
+                name: b"retention.ms".to_vec(),
+                value: Some(b"1000".to_vec()),
+                ..Default::default()
+            },
+        ));
+        fs::write(
+            dir.join(crate::kafka::log::segment_file_name(0)),
+            segment(0, &[config, topic("bar", 2)]),
+        )
+        .unwrap();
+
+        // everything in one go, then a snapshot of it
+        let (_, metadata) = listener(&dir, 1);
+        let image = metadata.image();
+        assert_eq!(image.offset, Some(4));
+        let ids = list(&dir).unwrap();
+        assert_eq!(
+            ids,
+            vec![SnapshotId {
+                end_offset: 5,
+                epoch: 1
+            }]
+        );
+        assert_eq!(read(&dir, ids[0]).unwrap(), *image);
+
+        // a later segment is replayed on top of the snapshot
+        fs::write(
+            dir.join(crate::kafka::log::segment_file_name(5)),
+            segment(5, &[topic("baz", 3)]),
+        )
+        .unwrap();
+        fs::remove_file(dir.join(crate::kafka::log::segment_file_name(0))).unwrap();
+        let (mut listener, metadata) = listener(&dir, u64::MAX);
+        let image = metadata.image();
+        assert_eq!(image.offset, Some(6));
+        assert!(image.partition("baz", 0).is_some());
+        assert_eq!(image.topic_config("foo").retention_ms, 1000);
+
+        // appends and a roll are picked up by the next poll
+        let mut active = fs::OpenOptions::new()
+            .append(true)
+            .open(dir.join(crate::kafka::log::segment_file_name(5)))
+            .unwrap();
+        active.write_all(&segment(7, &[topic("qux", 4)])).unwrap();
+        fs::write(
+            dir.join(crate::kafka::log::segment_file_name(9)),
+            segment(9, &[topic("quux", 5)]),
+        )
+        .unwrap();
+        assert_eq!(listener.poll().unwrap(), 2);
+        assert_eq!(listener.poll().unwrap(), 0);
+        let next = metadata.image();
+        assert_eq!(next.offset, Some(10));
+        assert!(next.partition("qux", 0).is_some());
+        assert!(next.partition("quux", 0).is_some());
+        // a handler holding the old image still sees it as it was
+        assert!(image.topic_by_name("qux").is_none());
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_snapshot_fetched_in_chunks() {
+        let root = temp_root("snapshot-fetch");
+        let (leader, follower) = (root.join("leader"), root.join("follower"));
+        fs::create_dir_all(&leader).unwrap();
+        fs::create_dir_all(&follower).unwrap();
+
+        let id = SnapshotId {
+            end_offset: 4,
+            epoch: 2,
+        };
+        let mut values = topic("foo", 1);
+        values.extend(topic("bar", 2));
+        let controls = vec![(voters::CONTROL_TYPE_KRAFT_VERSION, vec![0, 0, 0, 1])];
+        write_records(
+            &leader.join(id.file_name()),
+            id.epoch,
+            &controls,
+            &values,
+            0,
+        )
+        .unwrap();
+
+        let mut download = SnapshotDownload::create(&follower, id).unwrap();
+        loop {
+            let (size, chunk) = read_chunk(&leader, id, download.position, 100).unwrap();
+            assert!(chunk.len() <= 100);
+            download.append(&chunk).unwrap();
+            if download.position >= size {
+                break;
+            }
+        }
+        // not under its name until complete
+        assert_eq!(list(&follower).unwrap(), vec![]);
+        download.finish().unwrap();
+        assert_eq!(list(&follower).unwrap(), vec![id]);
+        assert_eq!(read(&follower, id).unwrap(), read(&leader, id).unwrap());
+        assert_eq!(
+            control_records(&follower.join(id.file_name())).unwrap(),
+            controls
+        );
+
+        // a cut short download never shows up
+        let mut partial = SnapshotDownload::create(
+            &follower,
+            SnapshotId {
+                end_offset: 9,
+                epoch: 2,
+            },
+        )
+        .unwrap();
+        partial
+            .append(&read_chunk(&leader, id, 0, 100).unwrap().1)
+            .unwrap();
+        assert!(partial.finish().is_err());
+        assert_eq!(list(&follower).unwrap(), vec![id]);
+        let _ = fs::remove_dir_all(&root);
+    }
+}
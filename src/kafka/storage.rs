@@ -0,0 +1,452 @@
+// data directory formatting, as done by `format` (kafka-storage format)
+// before the first start. Every log dir, the metadata log dir included,
+// gets a meta.properties naming the cluster and node it belongs to plus an
+// id of its own. The metadata log dir also gets bootstrap.checkpoint, the
+// records the controller writes to a still empty metadata log: the
+// metadata.version chosen and any other feature levels. A dynamic quorum's
+// initial voters go into an empty snapshot of the metadata partition.
+use crate::kafka::config::{self, BrokerConfig};
+use crate::kafka::controller::random_uuid;
+use crate::kafka::errors::{self, KafkaErrors};
+use crate::kafka::metadata_records::{KafkaRecordFeature, KafkaRecordValue};
+use crate::kafka::voters::{self, Voter};
+use crate::kafka::{log, snapshot};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+pub const META_PROPERTIES_FILE: &str = "meta.properties";
+pub const BOOTSTRAP_CHECKPOINT_FILE: &str = "bootstrap.checkpoint";
+pub const METADATA_VERSION_FEATURE: &str = "metadata.version";
+const META_PROPERTIES_VERSION: &str = "1";
+
+// metadata.version level of the latest version of each release
+const RELEASE_VERSIONS: &[(&str, i16)] = &[
+    ("3.3", 7),
+    ("3.4", 8),
+    ("3.5", 11),
+    ("3.6", 14),
+    ("3.7", 19),
+    ("3.8", 20),
+    ("3.9", 21),
+];
+pub const DEFAULT_RELEASE_VERSION: &str = "3.9";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MetaProperties {
+    pub cluster_id: String,
+    pub node_id: i32,
+    pub directory_id: Option<[u8; 16]>, // missing in dirs formatted before 3.7
+}
+
+impl MetaProperties {
+    // None for a dir that was never formatted
+    pub fn read(dir: &Path) -> errors::Result<Option<Self>> {
+        let path = dir.join(META_PROPERTIES_FILE);
+        let Ok(contents) = fs::read_to_string(&path) else {
+            return Ok(None);
+        };
+        let props = config::parse_properties(&contents);
+        let invalid =
+            |what: &str| KafkaErrors::InvalidCheckpoint(format!("{}: {what}", path.display()));
+        if props.get("version").map(String::as_str) != Some(META_PROPERTIES_VERSION) {
+            return Err(invalid("only version 1 (KRaft) is supported").into());
+        }
+        let cluster_id = props
+            .get("cluster.id")
+            .ok_or_else(|| invalid("no cluster.id"))?
+            .clone();
+        let node_id = props
+            .get("node.id")
+            .and_then(|n| n.parse().ok())
+            .ok_or_else(|| invalid("no valid node.id"))?;
+        let directory_id = match props.get("directory.id") {
+            Some(id) => Some(parse_uuid(id).ok_or_else(|| invalid("invalid directory.id"))?),
+            None => None,
+        };
+        Ok(Some(Self {
+            cluster_id,
+            node_id,
+            directory_id,
+        }))
+    }
+
+    pub fn write(&self, dir: &Path) -> errors::Result<()> {
+        let mut contents = format!(
+            "#\n#written by format at {}\nversion={META_PROPERTIES_VERSION}\ncluster.id={}\nnode.id={}\n",
+            log::now_ms(),
+            self.cluster_id,
+            self.node_id
+        );
+        if let Some(id) = &self.directory_id {
+            contents.push_str(&format!("directory.id={}\n", uuid_to_string(id)));
+        }
+        let path = dir.join(META_PROPERTIES_FILE);
+        let tmp = path.with_extension("properties.tmp");
+        fs::write(&tmp, contents)?;
+        fs::File::open(&tmp)?.sync_all()?;
+        fs::rename(&tmp, &path)?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct FormatOptions {
+    pub cluster_id: Option<String>,
+    pub release_version: Option<String>,
+    pub features: Vec<(String, i16)>,
+    pub ignore_formatted: bool,
+    // the voters of a dynamic quorum: this node alone, or id@host:port:dir
+    // entries. Neither for a controller that joins one later.
+    pub standalone: bool,
+    pub initial_controllers: Option<String>,
+    pub no_initial_controllers: bool,
+}
+
+// formats every dir of the broker, the cluster id it used
+pub fn format(config: &BrokerConfig, options: &FormatOptions) -> errors::Result<String> {
+    // dirs skipped as already formatted decide the cluster of the others
+    let mut formatted = None;
+    for dir in data_dirs(config) {
+        if let Some(props) = MetaProperties::read(&dir)? {
+            formatted = Some(props.cluster_id);
+            break;
+        }
+    }
+    let cluster_id = match &options.cluster_id {
+        Some(id) if parse_uuid(id).is_none() => {
+            return Err(KafkaErrors::InvalidCheckpoint(format!(
+                "cluster id {id} isn't a base64 encoded uuid"
+            ))
+            .into());
+        }
+        Some(id) => id.clone(),
+        None => match formatted.filter(|_| options.ignore_formatted) {
+            Some(id) => id,
+            None => uuid_to_string(&random_uuid()?),
+        },
+    };
+    let release = options
+        .release_version
+        .as_deref()
+        .unwrap_or(DEFAULT_RELEASE_VERSION);
+    let Some(level) = metadata_version_level(release) else {
+        return Err(KafkaErrors::InvalidCheckpoint(format!(
+            "unknown release version {release}, known are {}",
+            RELEASE_VERSIONS
+                .iter()
+                .map(|(r, _)| *r)
+                .collect::<Vec<_>>()
+                .join(", ")
+        ))
+        .into());
+    };
+
+    let dynamic_options = [
+        options.standalone,
+        options.initial_controllers.is_some(),
+        options.no_initial_controllers,
+    ];
+    if dynamic_options.iter().filter(|o| **o).count() > 1 {
+        return Err(KafkaErrors::InvalidWriterArg(
+            "only one of --standalone, --initial-controllers and --no-initial-controllers"
+                .to_string(),
+        )
+        .into());
+    }
+    if dynamic_options.contains(&true) && !config.controller_quorum_voters.is_empty() {
+        return Err(KafkaErrors::InvalidWriterArg(format!(
+            "a dynamic quorum doesn't take {}",
+            config::CONTROLLER_QUORUM_VOTERS_CONFIG
+        ))
+        .into());
+    }
+    let listener = config
+        .controller_listener()
+        .map_or("CONTROLLER", |l| l.name.as_str());
+    let mut initial_voters = match &options.initial_controllers {
+        Some(list) => {
+            let mut voters = vec![];
+            for entry in list.split(',').map(str::trim) {
+                let Some(voter) = Voter::parse(entry, listener) else {
+                    return Err(KafkaErrors::InvalidWriterArg(format!(
+                        "initial controller {entry} isn't id@host:port:directory-id"
+                    ))
+                    .into());
+                };
+                voters.push(voter);
+            }
+            Some(voters)
+        }
+        None => None,
+    };
+
+    let metadata_dir = PathBuf::from(config.metadata_log_dir());
+    for dir in data_dirs(config) {
+        if MetaProperties::read(&dir)?.is_some() {
+            if options.ignore_formatted {
+                println!("{} is already formatted, skipping", dir.display());
+                continue;
+            }
+            return Err(KafkaErrors::InvalidCheckpoint(format!(
+                "{} is already formatted",
+                dir.display()
+            ))
+            .into());
+        }
+        fs::create_dir_all(&dir)?;
+        // the metadata dir of an initial controller is the one it names
+        let directory_id = initial_voters
+            .iter()
+            .flatten()
+            .find(|v| v.id == config.node_id && dir == metadata_dir)
+            .map_or_else(random_uuid, |v| Ok(v.directory_id))?;
+        let props = MetaProperties {
+            cluster_id: cluster_id.clone(),
+            node_id: config.node_id,
+            directory_id: Some(directory_id),
+        };
+        props.write(&dir)?;
+        if dir == metadata_dir {
+            if options.standalone {
+                let endpoint = config
+                    .controller_listener()
+                    .cloned()
+                    .unwrap_or(config::Listener {
+                        name: listener.to_string(),
+                        host: String::new(),
+                        port: 9093,
+                    });
+                initial_voters = Some(vec![Voter {
+                    id: config.node_id,
+                    directory_id,
+                    endpoints: vec![config::Listener {
+                        host: endpoint.advertised_host().to_string(),
+                        ..endpoint
+                    }],
+                    min_kraft_version: voters::KRAFT_VERSION_STATIC,
+                    max_kraft_version: voters::KRAFT_VERSION_DYNAMIC,
+                }]);
+            }
+            if let Some(voters) = &initial_voters {
+                write_bootstrap_voters(&dir, voters)?;
+            }
+            let mut features = vec![(METADATA_VERSION_FEATURE.to_string(), level)];
+            features.extend(options.features.iter().cloned());
+            let records = features
+                .into_iter()
+                .map(|(name, feature_level)| {
+                    KafkaRecordValue::KafkaRecordFeatureType(KafkaRecordFeature {
+                        version: 0,
+                        name: name.into_bytes(),
+                        feature_level,
+                    })
+                })
+                .collect::<Vec<_>>();
+            snapshot::write_records(
+                &dir.join(BOOTSTRAP_CHECKPOINT_FILE),
+                0,
+                &[],
+                &records,
+                log::now_ms(),
+            )?;
+        }
+        println!("formatted {}", dir.display());
+    }
+    Ok(cluster_id)
+}
+
+// the voter set a dynamic quorum starts with goes into an empty snapshot
+// of the metadata partition, which raft reads before there's any log
+fn write_bootstrap_voters(dir: &Path, voters: &[Voter]) -> errors::Result<()> {
+    let partition_dir = dir.join(log::partition_dir_name(log::METADATA_TOPIC, 0));
+    fs::create_dir_all(&partition_dir)?;
+    let id = snapshot::SnapshotId {
+        end_offset: 0,
+        epoch: 0,
+    };
+    snapshot::write_records(
+        &partition_dir.join(id.file_name()),
+        id.epoch,
+        &[
+            (
+                voters::CONTROL_TYPE_KRAFT_VERSION,
+                voters::kraft_version_record(voters::KRAFT_VERSION_DYNAMIC)?,
+            ),
+            (voters::CONTROL_TYPE_VOTERS, voters::voters_record(voters)?),
+        ],
+        &[],
+        log::now_ms(),
+    )
+}
+
+// checks every dir was formatted for this node and the same cluster, the
+// cluster id and the ids of the dirs
+pub fn verify(config: &BrokerConfig) -> errors::Result<(String, Vec<[u8; 16]>)> {
+    let mut cluster_id: Option<String> = None;
+    let mut directory_ids = vec![];
+    for dir in data_dirs(config) {
+        let Some(props) = MetaProperties::read(&dir)? else {
+            return Err(KafkaErrors::InvalidCheckpoint(format!(
+                "{} isn't formatted, run format first",
+                dir.display()
+            ))
+            .into());
+        };
+        if props.node_id != config.node_id {
+            return Err(KafkaErrors::InvalidCheckpoint(format!(
+                "{} belongs to node {}, this is node {}",
+                dir.display(),
+                props.node_id,
+                config.node_id
+            ))
+            .into());
+        }
+        match &cluster_id {
+            Some(id) if *id != props.cluster_id => {
+                return Err(KafkaErrors::InvalidCheckpoint(format!(
+                    "{} belongs to cluster {}, other dirs to {id}",
+                    dir.display(),
+                    props.cluster_id
+                ))
+                .into());
+            }
+            Some(_) => (),
+            None => cluster_id = Some(props.cluster_id.clone()),
+        }
+        directory_ids.extend(props.directory_id);
+    }
+    Ok((cluster_id.unwrap_or_default(), directory_ids))
+}
+
+// the records to start an empty metadata log with
+pub fn bootstrap_records(config: &BrokerConfig) -> errors::Result<Vec<KafkaRecordValue>> {
+    let path = Path::new(config.metadata_log_dir()).join(BOOTSTRAP_CHECKPOINT_FILE);
+    if !path.exists() {
+        return Ok(vec![]);
+    }
+    snapshot::read_records(&path)
+}
+
+pub fn metadata_version_level(release: &str) -> Option<i16> {
+    RELEASE_VERSIONS
+        .iter()
+        .find(|(r, _)| *r == release)
+        .map(|(_, level)| *level)
+}
+
+// the log dirs and the metadata log dir, each once
+fn data_dirs(config: &BrokerConfig) -> Vec<PathBuf> {
+    let mut dirs = config
+        .log_dirs
+        .iter()
+        .map(PathBuf::from)
+        .collect::<Vec<_>>();
+    let metadata_dir = PathBuf::from(config.metadata_log_dir());
+    if !dirs.contains(&metadata_dir) {
+        dirs.push(metadata_dir);
+    }
+    dirs
+}
+
+const BASE64_URL: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+
+// uuids are written the java way: url safe base64 without padding
+pub fn uuid_to_string(uuid: &[u8; 16]) -> String {
+    let mut out = String::with_capacity(22);
+    for chunk in uuid.chunks(3) {
+        let mut buf = [0_u8; 3];
+        buf[..chunk.len()].copy_from_slice(chunk);
+        let n = u32::from_be_bytes([0, buf[0], buf[1], buf[2]]);
+        for i in 0..=chunk.len() {
+            out.push(BASE64_URL[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+        }
+    }
+    out
+}
+
+pub fn parse_uuid(s: &str) -> Option<[u8; 16]> {
+    if s.len() != 22 {
+        return None;
+    }
+    let mut bits = 0_u32;
+    let mut nbits = 0;
+    let mut out = Vec::with_capacity(16);
+    for c in s.bytes() {
+        let v = BASE64_URL.iter().position(|b| *b == c)? as u32;
+        bits = (bits << 6) | v;
+        nbits += 6;
+        if nbits >= 8 {
+            nbits -= 8;
+            out.push((bits >> nbits) as u8);
+            bits &= (1 << nbits) - 1;
+        }
+    }
+    // like java, whatever is left in the last 4 bits is ignored
+    out.try_into().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kafka::testing::temp_root;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_format_then_verify() {
+        let uuid = random_uuid().unwrap();
+        assert_eq!(parse_uuid(&uuid_to_string(&uuid)), Some(uuid));
+        // the cluster id the java tooling documents as an example
+        assert!(parse_uuid("MkU3OEVBNTcwNTJENDM2Qk").is_some());
+        assert!(parse_uuid("MkU3OEVBNTcwNTJENDM2Q").is_none());
+
+        let root = temp_root("storage-test");
+        let props = HashMap::from([
+            (
+                "log.dirs".to_string(),
+                root.join("data").display().to_string(),
+            ),
+            (
+                "metadata.log.dir".to_string(),
+                root.join("meta").display().to_string(),
+            ),
+        ]);
+        let config = BrokerConfig::from_props(&props);
+        assert!(verify(&config).is_err());
+
+        let options = FormatOptions {
+            release_version: Some("3.7".to_string()),
+            features: vec![("kraft.version".to_string(), 1)],
+            ..Default::default()
+        };
+        let cluster_id = format(&config, &options).unwrap();
+        let (verified_id, directory_ids) = verify(&config).unwrap();
+        assert_eq!((verified_id, directory_ids.len()), (cluster_id.clone(), 2));
+        assert!(format(&config, &options).is_err());
+        let levels = bootstrap_records(&config)
+            .unwrap()
+            .into_iter()
+            .map(|r| match r {
+                KafkaRecordValue::KafkaRecordFeatureType(f) => (f.name, f.feature_level),
+                other => panic!("unexpected bootstrap record {other:?}"),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            levels,
+            vec![
+                (METADATA_VERSION_FEATURE.as_bytes().to_vec(), 19),
+                (b"kraft.version".to_vec(), 1)
+            ]
+        );
+
+        // a dir of another cluster is refused
+        let other = MetaProperties {
+            cluster_id: uuid_to_string(&random_uuid().unwrap()),
+            node_id: config.node_id,
+            directory_id: None,
+        };
+        assert_ne!(other.cluster_id, cluster_id);
+        other.write(&root.join("data")).unwrap();
+        assert!(verify(&config).is_err());
+        let _ = fs::remove_dir_all(&root);
+    }
+}
@@ -0,0 +1,54 @@
+// helpers shared by the unit tests
+use crate::kafka::config;
+use crate::kafka::log::LogManager;
+use crate::kafka::records::{KafkaRecord, RecordsBatch};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+// an empty path under the system temp dir, unique to this test run. Callers
+// remove it again when done.
+pub fn temp_root(name: &str) -> PathBuf {
+    let root = std::env::temp_dir().join(format!("{name}-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&root);
+    root
+}
+
+// a log manager with one log dir per name under root
+pub fn log_manager(root: &Path, dirs: &[&str]) -> Arc<LogManager> {
+    Arc::new(
+        LogManager::open(&config::BrokerConfig {
+            log_dirs: dirs
+                .iter()
+                .map(|d| root.join(d).display().to_string())
+                .collect(),
+            ..Default::default()
+        })
+        .unwrap(),
+    )
+}
+
+// an unkeyed record valued "v"
+pub fn record(offset_delta: i32) -> KafkaRecord {
+    KafkaRecord {
+        offset_delta,
+        value: Some(b"v".to_vec()),
+        ..Default::default()
+    }
+}
+
+// a v2 batch around the records, last_offset_delta taken from their count
+pub fn records_batch(records: Vec<KafkaRecord>) -> RecordsBatch {
+    RecordsBatch {
+        last_offset_delta: records.len() as i32 - 1,
+        records,
+        ..RecordsBatch::new()
+    }
+}
+
+// an encoded batch of `count` records valued "v"
+pub fn batch(count: i32) -> Vec<u8> {
+    records_batch((0..count).map(record).collect())
+        .to_bytes()
+        .unwrap()
+}
@@ -0,0 +1,300 @@
+// broker side checks on produced record batches before they reach the log,
+// along the lines of kafka's LogValidator. A single bad batch rejects the
+// whole partition payload.
+use crate::kafka::records::BatchHeader;
+use crate::kafka::{config, records};
+
+// a record that made its batch get dropped, batch_index being the record's
+// position inside the batch
+#[derive(Debug, Clone, PartialEq)]
+pub struct RecordError {
+    pub batch_index: i32,
+    pub message: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ValidationError {
+    CorruptMessage(String),
+    InvalidRecord {
+        message: String,
+        record_errors: Vec<RecordError>,
+    },
+    MessageTooLarge(String),
+}
+
+impl std::fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::CorruptMessage(m) | Self::MessageTooLarge(m) => write!(f, "{m}"),
+            Self::InvalidRecord { message, .. } => write!(f, "{message}"),
+        }
+    }
+}
+
+pub fn validate(
+    payload: &[u8],
+    config: &config::TopicConfig,
+    now: i64,
+) -> Result<(), ValidationError> {
+    let batches = records::RawBatch::split(payload)
+        .map_err(|e| ValidationError::CorruptMessage(e.to_string()))?;
+    batches
+        .iter()
+        .try_for_each(|batch| validate_batch(batch, config, now))
+}
+
+fn validate_batch(
+    batch: &records::RawBatch,
+    config: &config::TopicConfig,
+    now: i64,
+) -> Result<(), ValidationError> {
+    if batch.size() as u64 > config.max_message_bytes {
+        return Err(ValidationError::MessageTooLarge(format!(
+            "batch of {} bytes exceeds max.message.bytes {}",
+            batch.size(),
+            config.max_message_bytes
+        )));
+    }
+    if batch.magic() != 2 {
+        return Err(ValidationError::CorruptMessage(format!(
+            "unsupported magic {}",
+            batch.magic()
+        )));
+    }
+    if batch.crc() != batch.compute_crc() {
+        return Err(ValidationError::CorruptMessage(format!(
+            "crc mismatch: stored {:0x}, computed {:0x}",
+            batch.crc(),
+            batch.compute_crc()
+        )));
+    }
+    let records = batch
+        .records()
+        .map_err(|e| ValidationError::CorruptMessage(e.to_string()))?;
+    if batch.last_offset_delta() as i64 + 1 != records.len() as i64 {
+        return Err(ValidationError::InvalidRecord {
+            message: format!(
+                "last offset delta {} doesn't match a count of {} records",
+                batch.last_offset_delta(),
+                records.len()
+            ),
+            record_errors: vec![],
+        });
+    }
+
+    let record_errors: Vec<RecordError> = records
+        .iter()
+        .enumerate()
+        .filter_map(|(i, record)| {
+            check_record(batch, record, i as i32, config, now).map(|message| RecordError {
+                batch_index: i as i32,
+                message,
+            })
+        })
+        .collect();
+    match record_errors.first() {
+        Some(first) => Err(ValidationError::InvalidRecord {
+            message: first.message.clone(),
+            record_errors,
+        }),
+        None => Ok(()),
+    }
+}
+
+// what is wrong with a single record, if anything. The record comes fully
+// decoded, headers included.
+fn check_record(
+    batch: &records::RawBatch,
+    record: &records::KafkaRecord,
+    index: i32,
+    config: &config::TopicConfig,
+    now: i64,
+) -> Option<String> {
+    // offset deltas must count up from 0 without gaps
+    if record.offset_delta != index {
+        return Some(format!(
+            "offset delta {} of record {index} isn't {index}",
+            record.offset_delta
+        ));
+    }
+    if config.cleanup_policy.compact && record.key.is_none() {
+        return Some("compacted topic can't accept a record without a key".to_string());
+    }
+    // the broker overwrites LogAppendTime timestamps, no point checking them
+    if config.message_timestamp_type == config::TimestampType::LogAppendTime {
+        return None;
+    }
+    let timestamp = batch
+        .base_timestamp()
+        .saturating_add(record.timestamp_delta);
+    if timestamp < now.saturating_sub(config.message_timestamp_before_max_ms)
+        || timestamp > now.saturating_add(config.message_timestamp_after_max_ms)
+    {
+        return Some(format!(
+            "timestamp {timestamp} of record {index} is out of range of broker time {now}"
+        ));
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kafka::testing::{record, records_batch};
+
+    const NOW: i64 = 1_700_000_000_000;
+
+    fn batch(offset_deltas: &[i32], last_offset_delta: i32, timestamp: i64) -> Vec<u8> {
+        let records = offset_deltas
+            .iter()
+            .map(|&offset_delta| records::KafkaRecord {
+                key: Some(b"k".to_vec()),
+                ..record(offset_delta)
+            })
+            .collect();
+        records::RecordsBatch {
+            last_offset_delta,
+            base_timestamp: timestamp,
+            max_timestamp: timestamp,
+            ..records_batch(records)
+        }
+        .to_bytes()
+        .unwrap()
+    }
+
+    #[test]
+    fn test_valid_batch() {
+        let config = config::TopicConfig::default();
+        assert_eq!(validate(&batch(&[0, 1, 2], 2, NOW), &config, NOW), Ok(()));
+    }
+
+    #[test]
+    fn test_batch_with_headers() {
+        let config = config::TopicConfig::default();
+        let header = records::KafkaRecordHeader {
+            key: vec![],
+            value: Some(b"x".to_vec()),
+        };
+        let batch = records::RecordsBatch {
+            base_timestamp: NOW,
+            max_timestamp: NOW,
+            ..records_batch(vec![records::KafkaRecord {
+                headers: vec![header],
+                ..record(0)
+            }])
+        };
+        let mut payload = batch.to_bytes().unwrap();
+        assert_eq!(validate(&payload, &config, NOW), Ok(()));
+
+        // turn the empty header key into a null one, which no record may have
+        let at = payload
+            .windows(3)
+            .position(|w| w == [0x00, 0x02, b'x'])
+            .unwrap();
+        payload[at] = 0x01;
+        let crc = records::RawBatch {
+            data: payload.clone(),
+        }
+        .compute_crc();
+        payload[records::CRC_OFFSET..records::CRC_OFFSET + 4].copy_from_slice(&crc.to_be_bytes());
+        assert!(matches!(
+            validate(&payload, &config, NOW),
+            Err(ValidationError::CorruptMessage(m)) if m.contains("null record header key")
+        ));
+    }
+
+    #[test]
+    fn test_crc_mismatch_is_corrupt() {
+        let mut payload = batch(&[0], 0, NOW);
+        let last = payload.len() - 1;
+        payload[last] ^= 0xff;
+        let result = validate(&payload, &config::TopicConfig::default(), NOW);
+        assert!(
+            matches!(&result, Err(ValidationError::CorruptMessage(m)) if m.contains("crc")),
+            "{result:?}"
+        );
+    }
+
+    #[test]
+    fn test_last_offset_delta_must_match_record_count() {
+        let result = validate(
+            &batch(&[0, 1], 4, NOW),
+            &config::TopicConfig::default(),
+            NOW,
+        );
+        assert!(
+            matches!(&result, Err(ValidationError::InvalidRecord { message, .. })
+                if message.contains("last offset delta 4")),
+            "{result:?}"
+        );
+    }
+
+    #[test]
+    fn test_offset_deltas_must_count_up() {
+        let result = validate(
+            &batch(&[0, 2, 1], 2, NOW),
+            &config::TopicConfig::default(),
+            NOW,
+        );
+        let Err(ValidationError::InvalidRecord {
+            message,
+            record_errors,
+        }) = result
+        else {
+            panic!("expected an invalid record, got {result:?}");
+        };
+        assert_eq!(
+            record_errors
+                .iter()
+                .map(|e| e.batch_index)
+                .collect::<Vec<_>>(),
+            vec![1, 2]
+        );
+        assert_eq!(message, record_errors[0].message);
+    }
+
+    #[test]
+    fn test_oversize_batch() {
+        let payload = batch(&[0], 0, NOW);
+        let config = config::TopicConfig {
+            max_message_bytes: payload.len() as u64 - 1,
+            ..Default::default()
+        };
+        assert!(matches!(
+            validate(&payload, &config, NOW),
+            Err(ValidationError::MessageTooLarge(_))
+        ));
+        let config = config::TopicConfig {
+            max_message_bytes: payload.len() as u64,
+            ..Default::default()
+        };
+        assert_eq!(validate(&payload, &config, NOW), Ok(()));
+    }
+
+    #[test]
+    fn test_timestamp_drift() {
+        let config = config::TopicConfig {
+            message_timestamp_before_max_ms: 1000,
+            message_timestamp_after_max_ms: 1000,
+            ..Default::default()
+        };
+        for timestamp in [NOW - 1001, NOW + 1001] {
+            let result = validate(&batch(&[0], 0, timestamp), &config, NOW);
+            assert!(matches!(
+                result,
+                Err(ValidationError::InvalidRecord { record_errors, .. })
+                    if record_errors.len() == 1
+            ));
+        }
+        for timestamp in [NOW - 1000, NOW + 1000] {
+            assert_eq!(validate(&batch(&[0], 0, timestamp), &config, NOW), Ok(()));
+        }
+
+        // LogAppendTime topics get the broker's time anyway
+        let config = config::TopicConfig {
+            message_timestamp_type: config::TimestampType::LogAppendTime,
+            ..config
+        };
+        assert_eq!(validate(&batch(&[0], 0, 0), &config, NOW), Ok(()));
+    }
+}
@@ -0,0 +1,383 @@
+// the voter set of the metadata quorum as KIP-853 has it: kept in the log
+// itself as VotersRecord control records, the latest one in effect as soon
+// as it's appended, and changed one voter at a time with AddRaftVoter
+// (key 80), RemoveRaftVoter (81) and UpdateRaftVoter (82). A voter is an
+// id plus the directory id of its metadata log dir, so a controller that
+// lost its disk comes back as a different voter.
+//
+// Clusters whose voters come from controller.quorum.voters run
+// kraft.version 0 and can't change them.
+//
+// https://cwiki.apache.org/confluence/display/KAFKA/KIP-853%3A+KRaft+Controller+Membership+Changes
+use crate::kafka::config::{Listener, QuorumVoter};
+use crate::kafka::{controller, errors, parser, storage, writer};
+use std::io::{Read, Write};
+
+// control record types of the voter set
+pub const CONTROL_TYPE_KRAFT_VERSION: i16 = 5;
+pub const CONTROL_TYPE_VOTERS: i16 = 6;
+const KRAFT_VERSION_RECORD_VERSION: i16 = 0;
+const VOTERS_RECORD_VERSION: i16 = 0;
+
+// the kraft.version feature: 0 static voters, 1 voters in the log
+pub const KRAFT_VERSION_STATIC: i16 = 0;
+pub const KRAFT_VERSION_DYNAMIC: i16 = 1;
+
+// tag of the current leader in an UpdateRaftVoter response
+const TAG_CURRENT_LEADER: u32 = 0;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Voter {
+    pub id: i32,
+    pub directory_id: [u8; 16],
+    pub endpoints: Vec<Listener>,
+    // the kraft.version levels it runs with
+    pub min_kraft_version: i16,
+    pub max_kraft_version: i16,
+}
+
+impl Voter {
+    // a voter of controller.quorum.voters, known by its address alone
+    pub fn from_static(voter: &QuorumVoter, listener: &str) -> Self {
+        Self {
+            id: voter.id,
+            directory_id: [0; 16],
+            endpoints: vec![Listener {
+                name: listener.to_string(),
+                host: voter.host.clone(),
+                port: voter.port,
+            }],
+            min_kraft_version: KRAFT_VERSION_STATIC,
+            max_kraft_version: KRAFT_VERSION_STATIC,
+        }
+    }
+
+    // parses id@host:port:directory-id, an --initial-controllers entry
+    pub fn parse(s: &str, listener: &str) -> Option<Self> {
+        let (address, directory_id) = s.rsplit_once(':')?;
+        let mut voter = Self::from_static(&QuorumVoter::parse(address)?, listener);
+        voter.directory_id = storage::parse_uuid(directory_id)?;
+        voter.max_kraft_version = KRAFT_VERSION_DYNAMIC;
+        Some(voter)
+    }
+
+    // where other controllers reach it, on listener if it has that one
+    pub fn address(&self, listener: &str) -> Option<QuorumVoter> {
+        let endpoint = self
+            .endpoints
+            .iter()
+            .find(|e| e.name == listener)
+            .or(self.endpoints.first())?;
+        Some(QuorumVoter {
+            id: self.id,
+            host: endpoint.advertised_host().to_string(),
+            port: endpoint.port,
+        })
+    }
+
+    // the same voter. One of controller.quorum.voters has no directory id
+    // and is known by its id alone, any other needs both to match.
+    pub fn is(&self, id: i32, directory_id: &[u8; 16]) -> bool {
+        self.id == id && (self.directory_id == [0; 16] || self.directory_id == *directory_id)
+    }
+}
+
+pub fn read_endpoints<R: Read>(req: &mut R) -> errors::Result<Vec<Listener>> {
+    let mut endpoints = vec![];
+    for _ in 0..parser::read_array_len(req, true)?.unwrap_or_default() {
+        endpoints.push(Listener {
+            name: String::from_utf8(parser::read_string(req, true)?)?,
+            host: String::from_utf8(parser::read_string(req, true)?)?,
+            port: parser::read_short(req)? as u16,
+        });
+        parser::skip_tagged_fields(req)?;
+    }
+    Ok(endpoints)
+}
+
+pub fn write_endpoints<W: Write>(resp: &mut W, endpoints: &[Listener]) -> errors::Result<()> {
+    writer::write_array_len(resp, endpoints.len(), true)?;
+    for e in endpoints {
+        writer::write_string(resp, e.name.as_bytes(), true)?;
+        writer::write_string(resp, e.advertised_host().as_bytes(), true)?;
+        writer::write_bytes(resp, &e.port)?;
+        writer::write_tagged_fields(resp, true)?;
+    }
+    Ok(())
+}
+
+fn read_cluster_id<R: Read>(req: &mut R) -> errors::Result<Option<String>> {
+    Ok(parser::read_nullable_string(req, true)?
+        .map(String::from_utf8)
+        .transpose()?)
+}
+
+// the value of a VotersRecord
+pub fn voters_record(voters: &[Voter]) -> errors::Result<Vec<u8>> {
+    let mut value = vec![];
+    writer::write_bytes(&mut value, &VOTERS_RECORD_VERSION)?;
+    writer::write_array_len(&mut value, voters.len(), true)?;
+    for v in voters {
+        writer::write_bytes(&mut value, &v.id)?;
+        writer::write_bytes(&mut value, &u128::from_be_bytes(v.directory_id))?;
+        write_endpoints(&mut value, &v.endpoints)?;
+        writer::write_bytes(&mut value, &v.min_kraft_version)?;
+        writer::write_bytes(&mut value, &v.max_kraft_version)?;
+        writer::write_tagged_fields(&mut value, true)?;
+        writer::write_tagged_fields(&mut value, true)?;
+    }
+    writer::write_tagged_fields(&mut value, true)?;
+    Ok(value)
+}
+
+pub fn read_voters_record(mut value: &[u8]) -> errors::Result<Vec<Voter>> {
+    let value = &mut value;
+    let _version = parser::read_short(value)?;
+    let mut voters = vec![];
+    for _ in 0..parser::read_array_len(value, true)?.unwrap_or_default() {
+        let id = parser::read_int(value)?;
+        let directory_id = parser::read_u128(value)?.to_be_bytes();
+        let endpoints = read_endpoints(value)?;
+        let min_kraft_version = parser::read_short(value)?;
+        let max_kraft_version = parser::read_short(value)?;
+        parser::skip_tagged_fields(value)?;
+        parser::skip_tagged_fields(value)?;
+        voters.push(Voter {
+            id,
+            directory_id,
+            endpoints,
+            min_kraft_version,
+            max_kraft_version,
+        });
+    }
+    Ok(voters)
+}
+
+// the value of a KRaftVersionRecord
+pub fn kraft_version_record(kraft_version: i16) -> errors::Result<Vec<u8>> {
+    let mut value = vec![];
+    writer::write_bytes(&mut value, &KRAFT_VERSION_RECORD_VERSION)?;
+    writer::write_bytes(&mut value, &kraft_version)?;
+    writer::write_tagged_fields(&mut value, true)?;
+    Ok(value)
+}
+
+// AddRaftVoter, versions 0 and 1, always flexible
+#[derive(Debug, Clone)]
+pub struct AddRaftVoterRequest {
+    pub cluster_id: Option<String>,
+    pub timeout_ms: i32,
+    pub voter_id: i32,
+    pub voter_directory_id: [u8; 16],
+    pub listeners: Vec<Listener>,
+    pub ack_when_committed: bool, // v1+
+}
+
+impl AddRaftVoterRequest {
+    pub fn new<R: Read>(req: &mut R, version: u16) -> errors::Result<Self> {
+        let request = Self {
+            cluster_id: read_cluster_id(req)?,
+            timeout_ms: parser::read_int(req)?,
+            voter_id: parser::read_int(req)?,
+            voter_directory_id: parser::read_u128(req)?.to_be_bytes(),
+            listeners: read_endpoints(req)?,
+            ack_when_committed: version < 1 || parser::read_byte(req)? != 0,
+        };
+        parser::skip_tagged_fields(req)?;
+        Ok(request)
+    }
+}
+
+// RemoveRaftVoter, version 0 and always flexible
+#[derive(Debug, Clone)]
+pub struct RemoveRaftVoterRequest {
+    pub cluster_id: Option<String>,
+    pub voter_id: i32,
+    pub voter_directory_id: [u8; 16],
+}
+
+impl RemoveRaftVoterRequest {
+    pub fn new<R: Read>(req: &mut R) -> errors::Result<Self> {
+        let request = Self {
+            cluster_id: read_cluster_id(req)?,
+            voter_id: parser::read_int(req)?,
+            voter_directory_id: parser::read_u128(req)?.to_be_bytes(),
+        };
+        parser::skip_tagged_fields(req)?;
+        Ok(request)
+    }
+}
+
+// the answer to both AddRaftVoter and RemoveRaftVoter
+#[derive(Debug, Clone, Default)]
+pub struct RaftVoterResponse {
+    pub throttle_time_ms: i32,
+    pub error_code: i16,
+    pub error_message: Option<String>,
+}
+
+impl RaftVoterResponse {
+    pub fn new(result: errors::Result<()>) -> Self {
+        match result {
+            Ok(()) => Self::default(),
+            Err(e) => {
+                println!("raft: voter change refused: {e}");
+                Self {
+                    error_code: controller::error_code(&e),
+                    error_message: Some(e.to_string()),
+                    ..Default::default()
+                }
+            }
+        }
+    }
+
+    pub fn serialize<W: Write>(&self, resp: &mut W) -> errors::Result<()> {
+        writer::write_bytes(resp, &self.throttle_time_ms)?;
+        writer::write_bytes(resp, &self.error_code)?;
+        writer::write_nullable_string(
+            resp,
+            self.error_message.as_ref().map(String::as_bytes),
+            true,
+        )?;
+        writer::write_tagged_fields(resp, true)
+    }
+}
+
+// UpdateRaftVoter, version 0 and always flexible. A voter sends it to the
+// leader when its endpoints differ from the ones in the voter set.
+#[derive(Debug, Clone)]
+pub struct UpdateRaftVoterRequest {
+    pub cluster_id: Option<String>,
+    pub current_leader_epoch: i32,
+    pub voter_id: i32,
+    pub voter_directory_id: [u8; 16],
+    pub listeners: Vec<Listener>,
+    pub min_kraft_version: i16,
+    pub max_kraft_version: i16,
+}
+
+impl UpdateRaftVoterRequest {
+    pub fn new<R: Read>(req: &mut R) -> errors::Result<Self> {
+        let cluster_id = read_cluster_id(req)?;
+        let current_leader_epoch = parser::read_int(req)?;
+        let voter_id = parser::read_int(req)?;
+        let voter_directory_id = parser::read_u128(req)?.to_be_bytes();
+        let listeners = read_endpoints(req)?;
+        let min_kraft_version = parser::read_short(req)?;
+        let max_kraft_version = parser::read_short(req)?;
+        parser::skip_tagged_fields(req)?;
+        parser::skip_tagged_fields(req)?;
+        Ok(Self {
+            cluster_id,
+            current_leader_epoch,
+            voter_id,
+            voter_directory_id,
+            listeners,
+            min_kraft_version,
+            max_kraft_version,
+        })
+    }
+
+    pub fn serialize<W: Write>(&self, resp: &mut W) -> errors::Result<()> {
+        writer::write_nullable_string(resp, self.cluster_id.as_ref().map(String::as_bytes), true)?;
+        writer::write_bytes(resp, &self.current_leader_epoch)?;
+        writer::write_bytes(resp, &self.voter_id)?;
+        writer::write_bytes(resp, &u128::from_be_bytes(self.voter_directory_id))?;
+        write_endpoints(resp, &self.listeners)?;
+        writer::write_bytes(resp, &self.min_kraft_version)?;
+        writer::write_bytes(resp, &self.max_kraft_version)?;
+        writer::write_tagged_fields(resp, true)?;
+        writer::write_tagged_fields(resp, true)
+    }
+
+    pub fn voter(&self) -> Voter {
+        Voter {
+            id: self.voter_id,
+            directory_id: self.voter_directory_id,
+            endpoints: self.listeners.clone(),
+            min_kraft_version: self.min_kraft_version,
+            max_kraft_version: self.max_kraft_version,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct UpdateRaftVoterResponse {
+    pub throttle_time_ms: i32,
+    pub error_code: i16,
+    // the leader as this node knows it, with its address if it has one
+    pub current_leader: Option<(i32, i32, Option<QuorumVoter>)>,
+}
+
+impl UpdateRaftVoterResponse {
+    pub fn read<R: Read>(resp: &mut R) -> errors::Result<Self> {
+        let mut response = Self {
+            throttle_time_ms: parser::read_int(resp)?,
+            error_code: parser::read_short(resp)?,
+            current_leader: None,
+        };
+        for (tag, data) in parser::read_tagged_fields(resp)? {
+            if tag == TAG_CURRENT_LEADER {
+                let data = &mut &data[..];
+                response.current_leader =
+                    Some((parser::read_int(data)?, parser::read_int(data)?, None));
+            }
+        }
+        Ok(response)
+    }
+
+    pub fn serialize<W: Write>(&self, resp: &mut W) -> errors::Result<()> {
+        writer::write_bytes(resp, &self.throttle_time_ms)?;
+        writer::write_bytes(resp, &self.error_code)?;
+        let mut fields = vec![];
+        if let Some((leader_id, leader_epoch, address)) = &self.current_leader {
+            let mut data = vec![];
+            writer::write_bytes(&mut data, leader_id)?;
+            writer::write_bytes(&mut data, leader_epoch)?;
+            let (host, port) = address
+                .as_ref()
+                .map_or(("", -1), |a| (a.host.as_str(), a.port as i32));
+            writer::write_string(&mut data, host.as_bytes(), true)?;
+            writer::write_bytes(&mut data, &port)?;
+            writer::write_tagged_fields(&mut data, true)?;
+            fields.push((TAG_CURRENT_LEADER, data));
+        }
+        writer::write_tagged_fields_with(resp, &fields)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn voters_record_round_trip() {
+        let voters = vec![
+            Voter::parse("1@localhost:19091:AAAAAAAAAAAAAAAAAAAAAQ", "CONTROLLER").unwrap(),
+            Voter {
+                id: 2,
+                directory_id: [7; 16],
+                endpoints: vec![
+                    Listener::parse("CONTROLLER://host2:19092").unwrap(),
+                    Listener::parse("SSL://host2:19192").unwrap(),
+                ],
+                min_kraft_version: 0,
+                max_kraft_version: 1,
+            },
+        ];
+        let value = voters_record(&voters).unwrap();
+        assert_eq!(read_voters_record(&value).unwrap(), voters);
+        assert_eq!(voters[0].directory_id[15], 1);
+        assert_eq!(
+            voters[1].address("SSL").map(|a| (a.host, a.port)),
+            Some(("host2".to_string(), 19192))
+        );
+        assert!(voters[1].is(2, &[7; 16]));
+        assert!(!voters[1].is(2, &[0; 16]));
+        assert!(!voters[1].is(2, &[8; 16]));
+        assert!(!voters[1].is(1, &[7; 16]));
+        // a static voter is whatever dir it runs with
+        let voter = Voter::from_static(&QuorumVoter::parse("3@localhost:19093").unwrap(), "C");
+        assert!(voter.is(3, &[0; 16]) && voter.is(3, &[9; 16]));
+    }
+}
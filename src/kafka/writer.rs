@@ -42,15 +42,43 @@ pub fn write_varint<W: Write>(resp: &mut W, val: usize) -> errors::Result<()> {
     (val as u8).write(resp)
 }
 
-#[allow(dead_code)]
+// zigzag encoded signed varint, as used inside records
 pub fn write_varint_main<W: Write>(resp: &mut W, x: i32) -> errors::Result<()> {
-    let mut ux = (x as u64) << 1;
-    if x < 0 {
-        ux = !ux;
+    write_varlong(resp, x as i64)
+}
+
+pub fn write_varlong<W: Write>(resp: &mut W, x: i64) -> errors::Result<()> {
+    let mut ux = ((x << 1) ^ (x >> 63)) as u64;
+    while ux >= 0x80 {
+        ((ux as u8) | 0x80).write(resp)?;
+        ux >>= 7;
     }
     (ux as u8).write(resp)
 }
 
+// bytes with a varint length, -1 for null (record keys, values, headers)
+pub fn write_varint_bytes<W: Write>(resp: &mut W, val: Option<&[u8]>) -> errors::Result<()> {
+    match val {
+        Some(b) => {
+            write_varint_main(resp, b.len() as i32)?;
+            resp.write_all(b)?;
+        }
+        None => write_varint_main(resp, -1)?,
+    }
+    Ok(())
+}
+
+// encoded size of a zigzag varint/varlong
+pub fn varlong_size(x: i64) -> usize {
+    let mut ux = ((x << 1) ^ (x >> 63)) as u64;
+    let mut size = 1;
+    while ux >= 0x80 {
+        ux >>= 7;
+        size += 1;
+    }
+    size
+}
+
 #[allow(dead_code)]
 pub fn write_uvarint<W: Write>(resp: &mut W, x: i32) -> errors::Result<()> {
     let mut x = x;
@@ -88,6 +116,59 @@ pub fn write_nullable_compact_string<W: Write>(
     }
 }
 
+// version aware counterparts of the parser helpers
+pub fn write_string<W: Write>(resp: &mut W, s: &[u8], flexible: bool) -> errors::Result<()> {
+    if flexible {
+        write_uvarint(resp, s.len() as i32 + 1)?;
+    } else {
+        (s.len() as i16).write(resp)?;
+    }
+    resp.write_all(s)?;
+    Ok(())
+}
+
+pub fn write_nullable_string<W: Write>(
+    resp: &mut W,
+    s: Option<&[u8]>,
+    flexible: bool,
+) -> errors::Result<()> {
+    match s {
+        Some(s) => write_string(resp, s, flexible),
+        None if flexible => 0_u8.write(resp),
+        None => (-1_i16).write(resp),
+    }
+}
+
+pub fn write_array_len<W: Write>(resp: &mut W, len: usize, flexible: bool) -> errors::Result<()> {
+    if flexible {
+        write_uvarint(resp, len as i32 + 1)
+    } else {
+        (len as i32).write(resp)
+    }
+}
+
+// no tagged fields, only present in flexible versions
+pub fn write_tagged_fields<W: Write>(resp: &mut W, flexible: bool) -> errors::Result<()> {
+    if flexible {
+        0_u8.write(resp)?;
+    }
+    Ok(())
+}
+
+// a tagged field section carrying fields, each already encoded, in tag order
+pub fn write_tagged_fields_with<W: Write>(
+    resp: &mut W,
+    fields: &[(u32, Vec<u8>)],
+) -> errors::Result<()> {
+    write_uvarint(resp, fields.len() as i32)?;
+    for (tag, data) in fields {
+        write_uvarint(resp, *tag as i32)?;
+        write_uvarint(resp, data.len() as i32)?;
+        resp.write_all(data)?;
+    }
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -102,18 +183,21 @@ mod tests {
         u8_val.write(&mut buffer).unwrap();
         assert_eq!(buffer.get_ref(), &vec![123]);
         buffer.get_mut().clear();
+        buffer.set_position(0);
 
         // Test with u16
         let u16_val: u16 = 0x1234;
         u16_val.write(&mut buffer).unwrap();
         assert_eq!(buffer.get_ref(), &vec![0x12, 0x34]);
         buffer.get_mut().clear();
+        buffer.set_position(0);
 
         // Test with u32
         let u32_val: u32 = 0x12345678;
         u32_val.write(&mut buffer).unwrap();
         assert_eq!(buffer.get_ref(), &vec![0x12, 0x34, 0x56, 0x78]);
         buffer.get_mut().clear();
+        buffer.set_position(0);
 
         // Test with u64
         let u64_val: u64 = 0x123456789ABCDEF0;
@@ -123,6 +207,7 @@ mod tests {
             &vec![0x12, 0x34, 0x56, 0x78, 0x9A, 0xBC, 0xDE, 0xF0]
         );
         buffer.get_mut().clear();
+        buffer.set_position(0);
 
         // Test with u128
         let u128_val: u128 = 0x123456789ABCDEF0123456789ABCDEF0;
@@ -137,6 +222,7 @@ mod tests {
         buffer.get_mut().clear();
 
         // Test with a type that doesn't implement KafkaWrite - should not compile
+        #[allow(dead_code)]
         #[derive(Debug)]
         struct TestStruct {
             a: u32,
@@ -0,0 +1,224 @@
+// responses made of inline bytes plus regions of segment files
+//
+// Fetch responses are mostly record data that already sits in the log in
+// wire format. Instead of reading it into memory and copying it into the
+// response, the handler only records where it lives and the bytes go from
+// the page cache straight to the socket with sendfile(2).
+use crate::kafka::errors;
+use std::fs::File;
+use std::io::{self, Write};
+use std::net::{Shutdown, TcpStream};
+use std::os::unix::fs::FileExt;
+use std::sync::Arc;
+
+#[derive(Debug, Clone)]
+pub struct FileRegion {
+    pub file: Arc<File>,
+    pub position: u64,
+    pub length: u64,
+}
+
+impl FileRegion {
+    // copies the region into memory, for the few callers that need to look
+    // at the bytes rather than ship them. Positional reads, as clones of the
+    // file are shared between threads.
+    pub fn read_to_vec(&self) -> errors::Result<Vec<u8>> {
+        let mut data = vec![0_u8; self.length as usize];
+        self.file.read_exact_at(&mut data, self.position)?;
+        Ok(data)
+    }
+
+    // false once the file was truncated below the end of the region
+    fn is_intact(&self) -> errors::Result<bool> {
+        Ok(self.file.metadata()?.len() >= self.position + self.length)
+    }
+}
+
+#[derive(Debug)]
+enum Chunk {
+    Bytes(Vec<u8>),
+    Region(FileRegion),
+}
+
+impl Chunk {
+    fn len(&self) -> u64 {
+        match self {
+            Self::Bytes(b) => b.len() as u64,
+            Self::Region(r) => r.length,
+        }
+    }
+}
+
+// a writer that can also take file regions
+pub trait RegionWrite: Write {
+    fn write_region(&mut self, region: &FileRegion) -> errors::Result<()>;
+}
+
+// plain in-memory buffers get a copy of the region
+impl RegionWrite for Vec<u8> {
+    fn write_region(&mut self, region: &FileRegion) -> errors::Result<()> {
+        self.extend_from_slice(&region.read_to_vec()?);
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct ResponseBuffer {
+    chunks: Vec<Chunk>,
+}
+
+impl ResponseBuffer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> u64 {
+        self.chunks.iter().map(Chunk::len).sum()
+    }
+
+    // writes the 4 byte size prefix and the whole response. The size is
+    // fixed up front, so a region truncated since fails the response
+    // before anything is written.
+    pub fn write_frame<W: RegionWrite>(&self, out: &mut W) -> errors::Result<()> {
+        for chunk in &self.chunks {
+            if let Chunk::Region(r) = chunk {
+                if !r.is_intact()? {
+                    return Err(errors::KafkaErrors::StorageError(format!(
+                        "segment truncated below {} while responding",
+                        r.position + r.length
+                    ))
+                    .into());
+                }
+            }
+        }
+        out.write_all(&(self.len() as u32).to_be_bytes())?;
+        for chunk in &self.chunks {
+            match chunk {
+                Chunk::Bytes(b) => out.write_all(b)?,
+                Chunk::Region(r) => out.write_region(r)?,
+            }
+        }
+        out.flush()?;
+        Ok(())
+    }
+
+    // a response that fails part way closes the connection, otherwise the
+    // client would wait for the rest of a frame that never comes
+    pub fn send(&self, stream: &mut TcpStream) -> errors::Result<()> {
+        let result = self.write_frame(stream);
+        if result.is_err() {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+        result
+    }
+}
+
+impl Write for ResponseBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if let Some(Chunk::Bytes(b)) = self.chunks.last_mut() {
+            b.extend_from_slice(buf);
+        } else {
+            self.chunks.push(Chunk::Bytes(buf.to_vec()));
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl RegionWrite for ResponseBuffer {
+    fn write_region(&mut self, region: &FileRegion) -> errors::Result<()> {
+        if region.length > 0 {
+            self.chunks.push(Chunk::Region(region.clone()));
+        }
+        Ok(())
+    }
+}
+
+// sockets get regions with sendfile
+impl RegionWrite for TcpStream {
+    fn write_region(&mut self, region: &FileRegion) -> errors::Result<()> {
+        send_region(self, region)
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn send_region(stream: &mut TcpStream, region: &FileRegion) -> errors::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    let mut offset = region.position as libc::off_t;
+    let mut remaining = region.length as usize;
+    while remaining > 0 {
+        // SAFETY: both descriptors stay open for the duration of the call and
+        // offset points to a live off_t
+        let sent = unsafe {
+            libc::sendfile(
+                stream.as_raw_fd(),
+                region.file.as_raw_fd(),
+                &mut offset,
+                remaining,
+            )
+        };
+        if sent < 0 {
+            let err = io::Error::last_os_error();
+            if err.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(err.into());
+        }
+        if sent == 0 {
+            // file got shorter underneath us
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+        remaining -= sent as usize;
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn send_region(stream: &mut TcpStream, region: &FileRegion) -> errors::Result<()> {
+    stream.write_all(&region.read_to_vec()?)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kafka::testing::temp_root;
+
+    #[test]
+    fn test_response_buffer_framing() {
+        let path = temp_root("zerocopy-test");
+        std::fs::write(&path, b"0123456789").unwrap();
+        let region = FileRegion {
+            file: Arc::new(File::open(&path).unwrap()),
+            position: 2,
+            length: 5,
+        };
+        let mut response = ResponseBuffer::new();
+        response.write_all(b"ab").unwrap();
+        response.write_region(&region).unwrap();
+        // an empty region adds nothing, the next bytes get a chunk of their own
+        response
+            .write_region(&FileRegion {
+                length: 0,
+                ..region.clone()
+            })
+            .unwrap();
+        response.write_all(b"c").unwrap();
+        assert_eq!(response.len(), 8);
+
+        let mut frame = vec![];
+        response.write_frame(&mut frame).unwrap();
+        assert_eq!(frame, b"\x00\x00\x00\x08ab23456c");
+
+        // truncated below the region, nothing goes out
+        File::create(&path).unwrap().set_len(4).unwrap();
+        let mut frame = vec![];
+        assert!(response.write_frame(&mut frame).is_err());
+        assert!(frame.is_empty());
+        let _ = std::fs::remove_file(&path);
+    }
+}
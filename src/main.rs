@@ -2,17 +2,18 @@
 use std::io::{self, BufReader, BufWriter, Read, Write};
 use std::net::{Shutdown, TcpListener, TcpStream};
 use std::prelude::*;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
 use std::thread;
 
 mod kafka;
 
-const METADATA_FILENAME: &str =
-    "/tmp/kraft-combined-logs/__cluster_metadata-0/00000000000000000000.log";
-
 fn process_connection(
     mut stream: TcpStream,
-    metadata: Arc<Mutex<kafka::metadata::Metadata>>,
+    roles: kafka::config::ListenerRoles,
+    metadata: Arc<kafka::metadata::MetadataCache>,
+    logs: Arc<kafka::log::LogManager>,
+    controller: Arc<kafka::controller::Controller>,
 ) -> kafka::errors::Result<()> {
     let mut size = [0; 4];
     loop {
@@ -38,37 +39,124 @@ fn process_connection(
         let req_processor = kafka::incoming::Request::new(&mut req_reader)?;
         println!("Request processor: {:?}", req_processor);
 
-        // Use a dynamically sized Vec for the response buffer to avoid overflows.
-        let mut response_body_writer = BufWriter::new(Vec::new());
-        req_processor.process(&mut response_body_writer, &metadata)?;
-        let response_body = response_body_writer.into_inner()?;
-        let message_size = response_body.len() as u32;
-
-        // Write the size prefix followed by the response body.
-        stream.write_all(&message_size.to_be_bytes())?;
-        stream.write_all(&response_body)?;
+        // The response is inline bytes plus segment file regions (fetch),
+        // the latter go out with sendfile.
+        let mut response = kafka::zerocopy::ResponseBuffer::new();
+        req_processor.process(&mut response, roles, &metadata, &logs, &controller)?;
+        response.send(&mut stream)?;
     }
     let _ = stream.shutdown(Shutdown::Both);
     Ok(())
 }
 
-fn process_tcp() -> kafka::errors::Result<()> {
-    let metadata: Arc<Mutex<kafka::metadata::Metadata>> = Arc::new(Mutex::new(
-        kafka::metadata::Metadata::new(METADATA_FILENAME)?,
-    ));
-    println!("read metadata: {:?}", metadata);
-    let listener = TcpListener::bind("127.0.0.1:9092")?;
-    println!("Listening on {}", listener.local_addr()?);
+fn process_tcp(
+    config: kafka::config::BrokerConfig,
+    cluster_id: String,
+    directory_ids: Vec<[u8; 16]>,
+) -> kafka::errors::Result<()> {
+    let metadata_dir = std::path::Path::new(config.metadata_log_dir()).join(
+        kafka::log::partition_dir_name(kafka::log::METADATA_TOPIC, 0),
+    );
+    let snapshots = kafka::snapshot::SnapshotGenerator::new(
+        &metadata_dir,
+        config.metadata_log_max_record_bytes_between_snapshots,
+    );
+    let metadata = Arc::new(kafka::metadata::MetadataCache::default());
+    // the quorum decides how much of the metadata log is committed
+    let raft = Arc::new(kafka::raft::RaftClient::open(&config, cluster_id.clone())?);
+    let listener = Arc::new(Mutex::new(kafka::metadata_listener::MetadataListener::new(
+        &metadata_dir,
+        Arc::clone(&metadata),
+        snapshots,
+        raft.high_watermark(),
+    )));
+    // catch up before serving anything, then follow the log
+    listener.lock().unwrap().poll()?;
+    kafka::metadata_listener::MetadataListener::start(
+        Arc::clone(&listener),
+        kafka::metadata_listener::METADATA_LISTENER_POLL_MS,
+    );
+    let controller = Arc::new(kafka::controller::Controller::open(
+        &config,
+        cluster_id.clone(),
+        Arc::clone(&metadata),
+        listener,
+        Arc::clone(&raft),
+    )?);
+    let logs = Arc::new(kafka::log::LogManager::open(&config)?);
+    kafka::cleaner::LogCleaner::new(Arc::clone(&logs), Arc::clone(&metadata))
+        .start(config.log_cleaner_backoff_ms);
+    kafka::flusher::LogFlusher::new(Arc::clone(&logs), Arc::clone(&metadata))
+        .start(config.log_flush_scheduler_interval_ms);
+    if logs.remote().is_some() {
+        kafka::remote::RemoteLogTask::new(Arc::clone(&logs), Arc::clone(&metadata))
+            .start(config.remote_log_manager_task_interval_ms);
+    }
 
+    // every listener is up before the other voters need us
+    let mut sockets = vec![];
+    for l in &config.listeners {
+        let socket = TcpListener::bind(l.bind_address())?;
+        println!("Listening on {} ({})", socket.local_addr()?, l.name);
+        sockets.push((socket, config.listener_roles(l)));
+    }
+    kafka::raft::RaftClient::start(Arc::clone(&raft));
+    resign_on_shutdown(Arc::clone(&raft));
+    kafka::registration::start(
+        Arc::clone(&controller),
+        kafka::registration::BrokerRegistrationRequest {
+            version: kafka::MAX_SUPPORTED_BROKER_REGISTRATION_VER,
+            broker_id: config.node_id,
+            cluster_id,
+            incarnation_id: kafka::controller::random_uuid()?,
+            listeners: config
+                .broker_listeners()
+                .map(|l| kafka::metadata_records::BrokerEndpoint {
+                    name: l.name.as_bytes().to_vec(),
+                    host: l.advertised_host().as_bytes().to_vec(),
+                    port: l.port,
+                    security_protocol: 0,
+                })
+                .collect(),
+            log_dirs: directory_ids,
+            previous_broker_epoch: -1,
+            ..Default::default()
+        },
+    );
+
+    let mut accept_threads = vec![];
+    for (socket, roles) in sockets {
+        let metadata = Arc::clone(&metadata);
+        let logs = Arc::clone(&logs);
+        let controller = Arc::clone(&controller);
+        accept_threads.push(thread::spawn(move || {
+            accept_connections(socket, roles, metadata, logs, controller)
+        }));
+    }
+    for t in accept_threads {
+        let _ = t.join();
+    }
+    Ok(())
+}
+
+fn accept_connections(
+    listener: TcpListener,
+    roles: kafka::config::ListenerRoles,
+    metadata: Arc<kafka::metadata::MetadataCache>,
+    logs: Arc<kafka::log::LogManager>,
+    controller: Arc<kafka::controller::Controller>,
+) {
     // The main loop for accepting connections should not die on a single error.
     for stream in listener.incoming() {
         match stream {
             Ok(stream) => {
                 println!("Accepted new connection.");
                 let mclone = Arc::clone(&metadata);
+                let lclone = Arc::clone(&logs);
+                let cclone = Arc::clone(&controller);
                 // Handle errors within the thread to prevent panics from taking down the server.
                 thread::spawn(move || {
-                    if let Err(e) = process_connection(stream, mclone) {
+                    if let Err(e) = process_connection(stream, roles, mclone, lclone, cclone) {
                         println!("Error processing connection: {}", e);
                     }
                 });
@@ -78,11 +166,121 @@ fn process_tcp() -> kafka::errors::Result<()> {
             }
         }
     }
+}
+
+static SHUTDOWN: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn request_shutdown(_signal: libc::c_int) {
+    SHUTDOWN.store(true, Ordering::SeqCst);
+}
+
+// on SIGTERM or SIGINT a leader hands over before the process exits
+fn resign_on_shutdown(raft: Arc<kafka::raft::RaftClient>) {
+    let handler = request_shutdown as extern "C" fn(libc::c_int);
+    unsafe {
+        libc::signal(libc::SIGTERM, handler as libc::sighandler_t);
+        libc::signal(libc::SIGINT, handler as libc::sighandler_t);
+    }
+    thread::spawn(move || loop {
+        thread::sleep(std::time::Duration::from_millis(100));
+        if SHUTDOWN.load(Ordering::SeqCst) {
+            raft.resign();
+            println!("shutting down");
+            std::process::exit(0);
+        }
+    });
+}
+
+// format [-c config] [-t cluster-id] [-r release-version] [-f name=level]... [-g]
+//        [-s | -I id@host:port:directory-id,... | -N]
+fn format_storage(args: &[String]) -> kafka::errors::Result<()> {
+    let mut config_file = None;
+    let mut options = kafka::storage::FormatOptions::default();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next().cloned().ok_or_else(|| {
+                kafka::errors::KafkaErrors::InvalidWriterArg(format!("{arg} needs a value"))
+            })
+        };
+        match arg.as_str() {
+            "-c" | "--config" => config_file = Some(value()?),
+            "-t" | "--cluster-id" => options.cluster_id = Some(value()?),
+            "-r" | "--release-version" => options.release_version = Some(value()?),
+            "-f" | "--feature" => {
+                let feature = value()?;
+                let parsed = feature
+                    .split_once('=')
+                    .and_then(|(name, level)| Some((name.to_string(), level.parse().ok()?)));
+                match parsed {
+                    Some(f) => options.features.push(f),
+                    None => {
+                        return Err(kafka::errors::KafkaErrors::InvalidWriterArg(format!(
+                            "feature {feature} isn't name=level"
+                        ))
+                        .into())
+                    }
+                }
+            }
+            "-g" | "--ignore-formatted" => options.ignore_formatted = true,
+            "-s" | "--standalone" => options.standalone = true,
+            "-I" | "--initial-controllers" => options.initial_controllers = Some(value()?),
+            "-N" | "--no-initial-controllers" => options.no_initial_controllers = true,
+            _ => {
+                return Err(kafka::errors::KafkaErrors::InvalidWriterArg(format!(
+                    "unknown format option {arg}"
+                ))
+                .into())
+            }
+        }
+    }
+    let config = kafka::config::BrokerConfig::load(config_file.as_deref())?;
+    let cluster_id = kafka::storage::format(&config, &options)?;
+    println!(
+        "formatted storage of node {} for cluster {cluster_id}",
+        config.node_id
+    );
     Ok(())
 }
 
 fn main() {
-    match process_tcp() {
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    match args.first().map(String::as_str) {
+        Some("format") => {
+            if let Err(e) = format_storage(&args[1..]) {
+                println!("Error formatting storage: {e}");
+                std::process::exit(1);
+            }
+            return;
+        }
+        Some("random-uuid") => {
+            match kafka::controller::random_uuid() {
+                Ok(uuid) => println!("{}", kafka::storage::uuid_to_string(&uuid)),
+                Err(e) => println!("Error generating uuid: {e}"),
+            }
+            return;
+        }
+        _ => (),
+    }
+    // the broker is started with the path of its server.properties
+    let config_file = args.first().cloned();
+    let config = match kafka::config::BrokerConfig::load(config_file.as_deref()) {
+        Ok(c) => c,
+        Err(e) => {
+            println!("Error loading broker config: {e:?}");
+            return;
+        }
+    };
+    println!("broker config: {:?}", config);
+    // refuse to run on dirs that weren't formatted for this node
+    let (cluster_id, directory_ids) = match kafka::storage::verify(&config) {
+        Ok(verified) => verified,
+        Err(e) => {
+            println!("Error checking storage: {e}");
+            std::process::exit(1);
+        }
+    };
+    match process_tcp(config, cluster_id, directory_ids) {
         Ok(_) => (),
         Err(e) => println!("Error processing connection: {e:?}"),
     };